@@ -0,0 +1,288 @@
+use std::collections::HashMap;
+use std::fmt::Write;
+
+use chrono::{DateTime, FixedOffset};
+use serde::Serialize;
+
+use crate::client::{rfc3339, Address, Mail};
+
+/// The address headers collected by [`AddressBook`]
+#[derive(clap::ArgEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AddressField {
+    From,
+    To,
+    Cc,
+    All,
+}
+
+/// A person found in the collected mails
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Contact {
+    /// Lower-cased
+    pub email: String,
+    /// The display name used most often, the first one seen of equal counts
+    pub name: Option<String>,
+    /// Number of mails the address was found in
+    pub count: usize,
+    #[serde(with = "rfc3339")]
+    pub first_seen: DateTime<FixedOffset>,
+    #[serde(with = "rfc3339")]
+    pub last_seen: DateTime<FixedOffset>,
+}
+
+/// What is known of an address so far, the names with how often and how early they
+/// were seen
+struct Entry {
+    names: HashMap<String, (usize, usize)>,
+    count: usize,
+    first_seen: DateTime<FixedOffset>,
+    last_seen: DateTime<FixedOffset>,
+}
+
+/// The distinct addresses of mails added one at a time, only the addresses are kept
+/// so it can take the mails of a stream
+pub struct AddressBook {
+    field: AddressField,
+    /// Domains left out, subdomains included
+    excluded_domains: Vec<String>,
+    entries: HashMap<String, Entry>,
+    /// Number of names seen, orders the names of equal counts
+    names_seen: usize,
+}
+
+impl AddressBook {
+    pub fn new(field: AddressField, excluded_domains: &[String]) -> Self {
+        AddressBook {
+            field,
+            excluded_domains: excluded_domains
+                .iter()
+                .map(|domain| domain.trim_start_matches('@').to_lowercase())
+                .collect(),
+            entries: HashMap::new(),
+            names_seen: 0,
+        }
+    }
+
+    /// Count the addresses of `mail`, once per mail however many of its headers
+    /// hold an address
+    pub fn add(&mut self, mail: &Mail) {
+        let mut addresses = addresses_of(mail, self.field);
+        addresses.retain(|address| !self.is_excluded(&address.email));
+        let mut counted: Vec<String> = vec![];
+        for address in addresses {
+            let email = address.email.to_lowercase();
+            let entry = self.entries.entry(email.clone()).or_insert(Entry {
+                names: HashMap::new(),
+                count: 0,
+                first_seen: mail.internal_date,
+                last_seen: mail.internal_date,
+            });
+            if let Some(name) = address.name {
+                let order = self.names_seen;
+                entry.names.entry(name).or_insert((0, order)).0 += 1;
+                self.names_seen += 1;
+            }
+            if counted.contains(&email) {
+                continue;
+            }
+            entry.count += 1;
+            entry.first_seen = entry.first_seen.min(mail.internal_date);
+            entry.last_seen = entry.last_seen.max(mail.internal_date);
+            counted.push(email);
+        }
+    }
+
+    fn is_excluded(&self, email: &str) -> bool {
+        let domain = match email.rsplit_once('@') {
+            Some((_, domain)) => domain.to_lowercase(),
+            None => return false,
+        };
+        self.excluded_domains.iter().any(|excluded| {
+            domain == *excluded
+                || domain
+                    .strip_suffix(excluded.as_str())
+                    .is_some_and(|sub| sub.ends_with('.'))
+        })
+    }
+
+    /// The contacts, the most frequent first, then by email
+    pub fn contacts(self) -> Vec<Contact> {
+        let mut contacts = self
+            .entries
+            .into_iter()
+            .map(|(email, entry)| Contact {
+                email,
+                name: entry
+                    .names
+                    .into_iter()
+                    .max_by(|(_, (a, a_order)), (_, (b, b_order))| {
+                        a.cmp(b).then_with(|| b_order.cmp(a_order))
+                    })
+                    .map(|(name, _)| name),
+                count: entry.count,
+                first_seen: entry.first_seen,
+                last_seen: entry.last_seen,
+            })
+            .collect::<Vec<_>>();
+        contacts.sort_by(|a, b| b.count.cmp(&a.count).then_with(|| a.email.cmp(&b.email)));
+        contacts
+    }
+}
+
+/// The addresses of the `field` headers of `mail`
+fn addresses_of(mail: &Mail, field: AddressField) -> Vec<Address> {
+    let mut addresses = vec![];
+    if matches!(field, AddressField::From | AddressField::All) {
+        addresses.extend(mail.from_address.clone());
+    }
+    if matches!(field, AddressField::To | AddressField::All) {
+        addresses.extend(parse_addresses(&mail.to));
+    }
+    if matches!(field, AddressField::Cc | AddressField::All) {
+        addresses.extend(parse_addresses(&mail.cc));
+    }
+    addresses
+}
+
+/// The mailboxes of an address list split by [`Mail::to`], the members of groups
+/// included
+fn parse_addresses(list: &[String]) -> Vec<Address> {
+    // rejoined since a quoted name may hold a comma, e.g. "Doe, John" <john@qq.com>
+    let parsed = match mailparse::addrparse(&list.join(", ")) {
+        Ok(parsed) => parsed,
+        Err(_) => return vec![],
+    };
+    parsed
+        .iter()
+        .flat_map(|address| match address {
+            mailparse::MailAddr::Single(single) => vec![single.clone()],
+            mailparse::MailAddr::Group(group) => group.addrs.clone(),
+        })
+        .map(|single| Address {
+            name: single
+                .display_name
+                .map(|name| name.split_whitespace().collect::<Vec<_>>().join(" "))
+                .filter(|name| !name.is_empty()),
+            email: single.addr,
+        })
+        .collect()
+}
+
+/// `contacts` as CSV with a header row, quoted where needed
+pub fn to_csv(contacts: &[Contact]) -> String {
+    let mut csv = String::from("email,name,count,first_seen,last_seen\n");
+    for contact in contacts {
+        writeln!(
+            csv,
+            "{},{},{},{},{}",
+            csv_field(&contact.email),
+            csv_field(contact.name.as_deref().unwrap_or_default()),
+            contact.count,
+            contact.first_seen.to_rfc3339(),
+            contact.last_seen.to_rfc3339()
+        )
+        .unwrap();
+    }
+    csv
+}
+
+fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mail(date: &str, from: Option<(&str, &str)>, to: &[&str], cc: &[&str]) -> Mail {
+        let mut mail: Mail = serde_json::from_value(serde_json::json!({
+            "subject": "report",
+            "from": "",
+            "to": to,
+            "cc": cc,
+            "references": [],
+            "id": 1,
+            "body": "",
+            "snippet": "",
+            "date": date,
+            "attachments": [],
+        }))
+        .unwrap();
+        mail.from_address = from.map(|(name, email)| Address {
+            name: Some(name.to_string()).filter(|name| !name.is_empty()),
+            email: email.to_string(),
+        });
+        mail
+    }
+
+    #[test]
+    fn collect_the_distinct_addresses() {
+        let mut book = AddressBook::new(AddressField::All, &["corp.com".to_string()]);
+        book.add(&mail(
+            "2024-03-01T09:00:00+08:00",
+            Some(("Alice", "alice@qq.com")),
+            &["\"Doe", "John\" <John@qq.com>", "me@corp.com"],
+            &["alice@qq.com"],
+        ));
+        book.add(&mail(
+            "2024-01-01T09:00:00+08:00",
+            Some(("Alice Liddell", "ALICE@qq.com")),
+            &["bob@mail.corp.com"],
+            &[],
+        ));
+        book.add(&mail(
+            "2024-02-01T09:00:00+08:00",
+            Some(("Alice", "alice@qq.com")),
+            &["team: john@qq.com;"],
+            &[],
+        ));
+
+        let contacts = book.contacts();
+
+        assert_eq!(
+            contacts,
+            vec![
+                Contact {
+                    email: "alice@qq.com".to_string(),
+                    name: Some("Alice".to_string()),
+                    count: 3,
+                    first_seen: DateTime::parse_from_rfc3339("2024-01-01T09:00:00+08:00").unwrap(),
+                    last_seen: DateTime::parse_from_rfc3339("2024-03-01T09:00:00+08:00").unwrap(),
+                },
+                Contact {
+                    email: "john@qq.com".to_string(),
+                    name: Some("Doe, John".to_string()),
+                    count: 2,
+                    first_seen: DateTime::parse_from_rfc3339("2024-02-01T09:00:00+08:00").unwrap(),
+                    last_seen: DateTime::parse_from_rfc3339("2024-03-01T09:00:00+08:00").unwrap(),
+                },
+            ]
+        );
+        assert_eq!(
+            to_csv(&contacts[1..]),
+            "email,name,count,first_seen,last_seen\n\
+             john@qq.com,\"Doe, John\",2,2024-02-01T09:00:00+08:00,2024-03-01T09:00:00+08:00\n"
+        );
+    }
+
+    #[test]
+    fn collect_one_field() {
+        let mut book = AddressBook::new(AddressField::From, &[]);
+        book.add(&mail(
+            "2024-03-01T09:00:00+08:00",
+            Some(("", "alice@qq.com")),
+            &["bob@qq.com"],
+            &[],
+        ));
+
+        let contacts = book.contacts();
+
+        assert_eq!(contacts.len(), 1);
+        assert_eq!(contacts[0].email, "alice@qq.com");
+        assert_eq!(contacts[0].name, None);
+    }
+}
@@ -0,0 +1,361 @@
+use std::{collections::BTreeMap, fmt::Display, str::FromStr};
+
+use chrono::{DateTime, FixedOffset};
+
+use crate::client::{Batch, BatchOutcome, Client, ClientError};
+use crate::safety::{Change, Safety};
+use crate::search::SearchOptions;
+use crate::template::valid_date_format;
+
+/// The name of the mail box mails are archived to, with strftime placeholders filled
+/// from the date each mail was received, e.g. `Archive/%Y`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Destination(String);
+
+impl Destination {
+    pub fn name(&self, date: &DateTime<FixedOffset>) -> String {
+        date.format(&self.0).to_string()
+    }
+}
+
+impl FromStr for Destination {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if !valid_date_format(s) {
+            return Err(format!("invalid date placeholder in `{}`", s));
+        }
+        Ok(Destination(s.to_string()))
+    }
+}
+
+impl Display for Destination {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+/// Move the mails matching `options` to `destination`, creating the mail boxes that
+/// don't exist yet, in `batch` commands, once `safety` approved the moves. A dry run
+/// changes nothing on the server.
+///
+/// Returns the outcome of the moves per destination by name, without any command on a
+/// dry run, `None` when the moves were declined. Mails already in their destination
+/// stay where they are. With `batch.fail_fast` the destinations after a failed command
+/// get no command either.
+pub fn run(
+    client: &Client,
+    options: &SearchOptions,
+    destination: &Destination,
+    batch: Batch,
+    safety: &Safety,
+) -> Result<Option<Vec<(String, BatchOutcome)>>, ClientError> {
+    let mail_box = client.get(&options.mail_box)?;
+    let mails = options.filter(&mail_box).fetch_headers()?;
+
+    let mut moves: BTreeMap<String, Vec<_>> = BTreeMap::new();
+    for mail in mails.iter() {
+        // dates are split in the timezone the cutoff was given in
+        let date = mail
+            .internal_date
+            .with_timezone(options.end_datetime.offset());
+        moves.entry(destination.name(&date)).or_default().push(mail);
+    }
+    moves.remove(mail_box.name());
+
+    let changes = moves
+        .iter()
+        .map(|(name, mails)| Change {
+            description: format!(
+                "move {} mails from {} to {}",
+                mails.len(),
+                mail_box.name(),
+                name
+            ),
+            mails: mails.clone(),
+        })
+        .collect::<Vec<_>>();
+    if !changes.is_empty() && !safety.approve(&changes) && !safety.dry_run {
+        return Ok(None);
+    }
+
+    if safety.dry_run {
+        return Ok(Some(
+            moves
+                .into_iter()
+                .map(|(name, mails)| {
+                    let outcome = BatchOutcome {
+                        total: mails.len(),
+                        chunks: vec![],
+                    };
+                    (name, outcome)
+                })
+                .collect(),
+        ));
+    }
+    let mut moved = vec![];
+    let mut stopped = false;
+    if !moves.is_empty() {
+        let existing = client.mail_box_names()?;
+        for (name, mails) in moves.into_iter() {
+            let uids = mails.iter().map(|mail| mail.uid).collect::<Vec<_>>();
+            if stopped {
+                let outcome = BatchOutcome {
+                    total: uids.len(),
+                    chunks: vec![],
+                };
+                moved.push((name, outcome));
+                continue;
+            }
+            if !existing.contains(&name) {
+                client.create_mailbox(&name)?;
+            }
+            let outcome = mail_box.move_mails(&uids, &name, batch)?;
+            stopped = batch.fail_fast && outcome.failed().next().is_some();
+            moved.push((name, outcome));
+        }
+    }
+    Ok(Some(moved))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::{fetch_response, MockServer};
+
+    fn options() -> SearchOptions {
+        SearchOptions::between("1970-01-01T00:00:00+08:00", "2024-06-01T00:00:00+08:00")
+    }
+
+    fn safety(dry_run: bool) -> Safety {
+        Safety {
+            dry_run,
+            confirm_above: 10,
+            ask: None,
+        }
+    }
+
+    fn batch(size: usize) -> Batch {
+        Batch {
+            size,
+            fail_fast: false,
+        }
+    }
+
+    /// The name, number of mails and number moved of every destination
+    fn summary(moved: &[(String, BatchOutcome)]) -> Vec<(String, usize, usize)> {
+        moved
+            .iter()
+            .map(|(name, outcome)| (name.clone(), outcome.total, outcome.done()))
+            .collect()
+    }
+
+    fn server() -> MockServer {
+        let server = MockServer::new();
+        server
+            .on("CAPABILITY", "* CAPABILITY IMAP4rev1 MOVE\r\n")
+            .on(
+                "LIST",
+                "* LIST (\\HasNoChildren) \"/\" \"INBOX\"\r\n\
+                 * LIST (\\HasNoChildren) \"/\" \"Archive/2023\"\r\n",
+            )
+            .on("UID SEARCH", "* SEARCH 1 2 3 4\r\n")
+            .on(
+                "UID FETCH",
+                [
+                    (1, "31-Dec-2023 23:30:00 +0800"),
+                    (2, "01-Jan-2024 00:30:00 +0800"),
+                    (3, "02-May-2023 09:30:00 +0800"),
+                    (4, "20-May-2024 09:30:00 +0800"),
+                ]
+                .map(|(uid, date)| fetch_response(uid, date, "Subject: report\r\n\r\n", ""))
+                .concat(),
+            );
+        server
+    }
+
+    #[test]
+    fn move_by_year_in_batches() {
+        let server = server();
+        let client = server.client();
+        let destination = "Archive/%Y".parse::<Destination>().unwrap();
+
+        let moved = run(&client, &options(), &destination, batch(1), &safety(false))
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(
+            summary(&moved),
+            vec![
+                ("Archive/2023".to_string(), 2, 2),
+                ("Archive/2024".to_string(), 2, 2)
+            ]
+        );
+        let changes = server
+            .commands()
+            .into_iter()
+            .filter(|c| c.starts_with("CREATE") || c.starts_with("UID MOVE"))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            changes,
+            vec![
+                "UID MOVE 1 \"Archive/2023\"",
+                "UID MOVE 3 \"Archive/2023\"",
+                "CREATE \"Archive/2024\"",
+                "UID MOVE 2 \"Archive/2024\"",
+                "UID MOVE 4 \"Archive/2024\"",
+            ]
+        );
+    }
+
+    #[test]
+    fn go_on_after_a_failed_batch() {
+        let server = server();
+        server.fail("UID MOVE 3", "[OVERQUOTA] Archive/2023 is full");
+        let client = server.client();
+        let destination = "Archive/%Y".parse::<Destination>().unwrap();
+
+        let moved = run(&client, &options(), &destination, batch(1), &safety(false))
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(
+            summary(&moved),
+            vec![
+                ("Archive/2023".to_string(), 2, 1),
+                ("Archive/2024".to_string(), 2, 2)
+            ]
+        );
+        let failed = moved[0].1.failed().collect::<Vec<_>>();
+        assert_eq!(failed.len(), 1);
+        assert_eq!(
+            failed[0].to_string(),
+            "1 failed in range 3: UID MOVE Archive/2023 failed: No Response: [OVERQUOTA] Archive/2023 is full"
+        );
+        assert!(server
+            .commands()
+            .iter()
+            .any(|c| c.starts_with("UID MOVE 4")));
+    }
+
+    #[test]
+    fn stop_at_the_first_failed_batch() {
+        let server = server();
+        server.fail("UID MOVE 1", "[OVERQUOTA] Archive/2023 is full");
+        let client = server.client();
+        let destination = "Archive/%Y".parse::<Destination>().unwrap();
+        let batch = Batch {
+            size: 1,
+            fail_fast: true,
+        };
+
+        let moved = run(&client, &options(), &destination, batch, &safety(false))
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(
+            summary(&moved),
+            vec![
+                ("Archive/2023".to_string(), 2, 0),
+                ("Archive/2024".to_string(), 2, 0)
+            ]
+        );
+        assert_eq!(moved[0].1.chunks.len(), 1);
+        let changes = server
+            .commands()
+            .into_iter()
+            .filter(|c| c.starts_with("CREATE") || c.starts_with("UID MOVE"))
+            .collect::<Vec<_>>();
+        assert_eq!(changes, vec!["UID MOVE 1 \"Archive/2023\""]);
+    }
+
+    #[test]
+    fn dry_run_changes_nothing() {
+        let server = server();
+        let client = server.client();
+        let destination = "Archive/%Y".parse::<Destination>().unwrap();
+
+        let moved = run(&client, &options(), &destination, batch(500), &safety(true))
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(
+            summary(&moved),
+            vec![
+                ("Archive/2023".to_string(), 2, 0),
+                ("Archive/2024".to_string(), 2, 0)
+            ]
+        );
+        assert_no_changes(&server);
+    }
+
+    #[test]
+    fn declined_moves_change_nothing() {
+        let server = server();
+        let client = server.client();
+        let destination = "Archive/%Y".parse::<Destination>().unwrap();
+        let safety = Safety {
+            confirm_above: 3,
+            ask: Some(|question| {
+                assert_eq!(question, "Change 4 mails?");
+                false
+            }),
+            ..safety(false)
+        };
+
+        assert!(run(&client, &options(), &destination, batch(500), &safety)
+            .unwrap()
+            .is_none());
+        assert_no_changes(&server);
+    }
+
+    fn assert_no_changes(server: &MockServer) {
+        let mutating = [
+            "CREATE",
+            "SELECT",
+            "UID MOVE",
+            "UID COPY",
+            "UID STORE",
+            "UID EXPUNGE",
+            "EXPUNGE",
+        ];
+        assert!(!server
+            .commands()
+            .iter()
+            .any(|c| mutating.iter().any(|m| c.starts_with(m))));
+    }
+
+    #[test]
+    fn copy_without_the_move_extension() {
+        let server = server();
+        server.on("CAPABILITY", "* CAPABILITY IMAP4rev1 UIDPLUS\r\n");
+        let client = server.client();
+        let destination = "Archive/2023".parse::<Destination>().unwrap();
+
+        run(
+            &client,
+            &options(),
+            &destination,
+            batch(500),
+            &safety(false),
+        )
+        .unwrap();
+
+        let commands = server.commands();
+        let start = commands
+            .iter()
+            .position(|c| c == "SELECT \"INBOX\"")
+            .unwrap();
+        assert_eq!(
+            commands[start..start + 5],
+            [
+                "SELECT \"INBOX\"",
+                "UID COPY 1,2,3,4 \"Archive/2023\"",
+                "UID STORE 1,2,3,4 +FLAGS.SILENT (\\Deleted)",
+                "UID EXPUNGE 1,2,3,4",
+                "EXAMINE \"INBOX\"",
+            ]
+        );
+        assert!("Archive/%Q".parse::<Destination>().is_err());
+    }
+}
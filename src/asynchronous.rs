@@ -0,0 +1,758 @@
+//! An async mirror of [`Client`](crate::client::Client) for tokio applications, on
+//! async-imap.
+//!
+//! A client holds a single IMAP session: the calls on the clones of a client wait for
+//! each other, open a client per connection to search concurrently. The search itself
+//! is the one of [`MailFilter`](crate::client::MailFilter), see [`SearchCriteria`].
+
+use std::{future::Future, sync::Arc, time::Duration};
+
+use async_imap::imap_proto::{BodyParams, BodyStructure};
+use async_imap::types::{Fetch, Flag, Mailbox, Name, NameAttribute};
+use futures::TryStreamExt;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpStream;
+use tokio::sync::Mutex;
+
+use crate::client::{
+    self, Attachment, ClientError, During, FetchedMail, Mail, SearchCriteria, FETCH_CHUNK_SIZE,
+};
+use crate::folder::{self, Folder};
+use crate::proxy::Proxy;
+use crate::search::SearchOptions;
+use crate::throttle::is_throttled;
+
+/// Transport underneath an async IMAP session
+pub trait Stream: AsyncRead + AsyncWrite + Unpin + Send + std::fmt::Debug {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send + std::fmt::Debug> Stream for T {}
+
+type Session = async_imap::Session<Box<dyn Stream>>;
+
+impl<T> During<T> for Result<T, async_imap::error::Error> {
+    fn during<S: std::fmt::Display>(self, operation: S) -> Result<T, ClientError> {
+        self.map_err(|e| ClientError::new(operation.to_string(), imap_error(e)))
+    }
+}
+
+/// The error of the imap crate for an error of async-imap, so that both clients fail
+/// the same way
+fn imap_error(error: async_imap::error::Error) -> imap::Error {
+    use async_imap::error::Error;
+    match error {
+        Error::Io(e) => imap::Error::Io(e),
+        Error::Bad(message) => imap::Error::Bad(message),
+        Error::No(message) => imap::Error::No(message),
+        Error::ConnectionLost => imap::Error::ConnectionLost,
+        Error::Validate(e) => imap::Error::Validate(imap::error::ValidateError(e.0)),
+        Error::Append => imap::Error::Append,
+        e => imap::Error::Parse(imap::error::ParseError::Unexpected(e.to_string())),
+    }
+}
+
+/// Run `run`, failing with a timeout of `operation` once it took longer than `timeout`
+async fn within<T>(
+    timeout: Option<Duration>,
+    operation: &str,
+    run: impl Future<Output = Result<T, ClientError>>,
+) -> Result<T, ClientError> {
+    match timeout {
+        Some(timeout) => tokio::time::timeout(timeout, run)
+            .await
+            .unwrap_or_else(|_| Err(ClientError::Timeout(operation.to_string()))),
+        None => run.await,
+    }
+}
+
+/// A connection shared by the tasks holding a clone of it
+#[derive(Clone)]
+pub struct AsyncClient {
+    session: Arc<Mutex<Session>>,
+    timeout: Option<Duration>,
+}
+
+impl AsyncClient {
+    pub async fn connect(username: &str, password: &str) -> Result<Self, ClientError> {
+        Self::builder(username, password).connect().await
+    }
+
+    pub fn builder<'a>(username: &'a str, password: &'a str) -> AsyncClientBuilder<'a> {
+        AsyncClientBuilder {
+            username,
+            password,
+            host: client::DOMAIN,
+            port: client::PORT,
+            proxy: None,
+            timeout: Some(client::DEFAULT_TIMEOUT),
+        }
+    }
+
+    /// Read the greeting and log in over an already connected stream of any transport,
+    /// without a timeout
+    pub async fn login_stream<S: Stream + 'static>(
+        stream: S,
+        username: &str,
+        password: &str,
+    ) -> Result<Self, ClientError> {
+        let mut client = async_imap::Client::new(Box::new(stream) as Box<dyn Stream>);
+        let greeting = client
+            .read_response()
+            .await
+            .map_err(imap::Error::Io)
+            .and_then(|greeting| greeting.ok_or(imap::Error::ConnectionLost))
+            .during("CONNECT")?;
+        // LOGIN would only fail with an obscure error
+        if client::login_disabled(greeting.borrow_owner()) {
+            return Err(ClientError::LoginDisabled);
+        }
+        let session = client
+            .login(username, password)
+            .await
+            .map_err(|e| e.0)
+            .during("LOGIN")?;
+        Ok(AsyncClient {
+            session: Arc::new(Mutex::new(session)),
+            timeout: None,
+        })
+    }
+
+    /// End the session with LOGOUT, once the calls of the other clones are done
+    pub async fn logout(&self) -> Result<(), ClientError> {
+        let mut session = self.session.lock().await;
+        within(self.timeout, "LOGOUT", async {
+            session.logout().await.during("LOGOUT")
+        })
+        .await
+    }
+
+    /// The decoded names of all mail boxes, without selecting them
+    pub async fn mail_box_names(&self) -> Result<Vec<String>, ClientError> {
+        let mut session = self.session.lock().await;
+        let names = within(self.timeout, "LIST", list(&mut session)).await?;
+        Ok(names.iter().map(|name| decode(name.name())).collect())
+    }
+
+    /// The folder hierarchy, with the message counts of every selectable folder
+    pub async fn folders(&self) -> Result<Vec<Folder>, ClientError> {
+        let mut session = self.session.lock().await;
+        let names = within(self.timeout, "LIST", list(&mut session)).await?;
+        let mut folders = vec![];
+        for name in names.iter() {
+            let selectable = !name.attributes().contains(&NameAttribute::NoSelect);
+            let (messages, unseen) = if selectable {
+                let operation = format!("STATUS {}", name.name());
+                let status = within(self.timeout, &operation, async {
+                    session
+                        .status(name.name(), "(MESSAGES UNSEEN)")
+                        .await
+                        .during(&operation)
+                })
+                .await?;
+                (Some(status.exists), status.unseen)
+            } else {
+                (None, None)
+            };
+
+            let path = decode(name.name());
+            folders.push(Folder {
+                name: path.clone(),
+                path,
+                delimiter: name.delimiter().map(|d| d.to_string()),
+                selectable,
+                messages,
+                unseen,
+                children: vec![],
+            });
+        }
+
+        Ok(folder::tree(folders))
+    }
+
+    /// The mails matching `options`, see [`AsyncMailFilter`]
+    pub fn filter(&self, options: SearchOptions) -> AsyncMailFilter {
+        AsyncMailFilter {
+            client: self.clone(),
+            options: Arc::new(options),
+        }
+    }
+}
+
+/// Connects an [`AsyncClient`] the way [`ClientBuilder`](crate::client::ClientBuilder)
+/// connects a client
+pub struct AsyncClientBuilder<'a> {
+    username: &'a str,
+    password: &'a str,
+    host: &'a str,
+    port: u16,
+    proxy: Option<Proxy>,
+    timeout: Option<Duration>,
+}
+
+impl<'a> AsyncClientBuilder<'a> {
+    /// The IMAPS server, [`DOMAIN`](client::DOMAIN) on [`PORT`](client::PORT) by default
+    pub fn server(&mut self, host: &'a str, port: u16) -> &mut Self {
+        self.host = host;
+        self.port = port;
+        self
+    }
+
+    pub fn proxy(&mut self, proxy: Option<Proxy>) -> &mut Self {
+        self.proxy = proxy;
+        self
+    }
+
+    /// Time limit of every step of the connection and of every command, 60 seconds by
+    /// default. `None` waits forever.
+    pub fn timeout(&mut self, timeout: Option<Duration>) -> &mut Self {
+        self.timeout = timeout;
+        self
+    }
+
+    pub async fn connect(&self) -> Result<AsyncClient, ClientError> {
+        let operation = format!("CONNECT {}", self.host);
+        let stream = within(self.timeout, &operation, async {
+            self.connect_tcp()
+                .await
+                .map_err(imap::Error::Io)
+                .during(&operation)
+        })
+        .await?;
+
+        let tls = native_tls::TlsConnector::new()
+            .map_err(imap::Error::Tls)
+            .during("TLS handshake")?;
+        let tls = tokio_native_tls::TlsConnector::from(tls);
+        let stream = within(self.timeout, "TLS handshake", async {
+            tls.connect(self.host, stream)
+                .await
+                .map_err(imap::Error::Tls)
+                .during("TLS handshake")
+        })
+        .await?;
+
+        let login = AsyncClient::login_stream(stream, self.username, self.password);
+        let mut client = within(self.timeout, "LOGIN", login).await?;
+        client.timeout = self.timeout;
+        Ok(client)
+    }
+
+    async fn connect_tcp(&self) -> std::io::Result<TcpStream> {
+        let proxy = match &self.proxy {
+            None => return TcpStream::connect((self.host, self.port)).await,
+            Some(proxy) => proxy.clone(),
+        };
+        // the proxy handshake is blocking, the tunnel is a plain TCP stream once open
+        let (host, port) = (self.host.to_string(), self.port);
+        let stream = tokio::task::spawn_blocking(move || proxy.connect((&host, port)))
+            .await
+            .map_err(std::io::Error::other)??;
+        stream.set_nonblocking(true)?;
+        TcpStream::from_std(stream)
+    }
+}
+
+/// The async mirror of [`SearchOptions::fetch_scanned`] and [`SearchOptions::count`],
+/// over the session of an [`AsyncClient`]
+#[derive(Clone)]
+pub struct AsyncMailFilter {
+    client: AsyncClient,
+    options: Arc<SearchOptions>,
+}
+
+impl AsyncMailFilter {
+    /// See [`SearchOptions::fetch_scanned`], the skipped mails are left out
+    pub async fn fetch(&self) -> Result<Vec<Mail>, ClientError> {
+        let mut session = self.client.session.lock().await;
+        let mut found = vec![];
+        for raw_name in self.mail_boxes(&mut session).await? {
+            let mut search = self.search(&mut session, &raw_name).await?;
+            found.push(search.fetch().await?);
+        }
+        Ok(self.options.merge(found))
+    }
+
+    /// See [`SearchOptions::count`]
+    pub async fn count(&self) -> Result<usize, ClientError> {
+        let mut session = self.client.session.lock().await;
+        let mut count = 0;
+        for raw_name in self.mail_boxes(&mut session).await? {
+            count += self.search(&mut session, &raw_name).await?.count().await?;
+        }
+        Ok(count)
+    }
+
+    /// The raw names of the mail boxes searched, see [`SearchOptions::fetch_scanned`]
+    async fn mail_boxes(&self, session: &mut Session) -> Result<Vec<String>, ClientError> {
+        let pattern = &self.options.mail_box;
+        let names = within(self.client.timeout, "LIST", list(session))
+            .await?
+            .into_iter()
+            .filter(|name| !name.attributes().contains(&NameAttribute::NoSelect))
+            .collect::<Vec<_>>();
+        if !folder::is_pattern(pattern) {
+            let names = names
+                .iter()
+                .map(|name| (decode(name.name()), name.name().to_string()))
+                .collect::<Vec<_>>();
+            let position = client::find_name(pattern, &names)?;
+            return Ok(vec![names[position].1.clone()]);
+        }
+
+        let mut raw_names = names
+            .iter()
+            .filter(|name| folder::matches(pattern, &decode(name.name()), name.delimiter()))
+            .map(|name| name.name().to_string())
+            .collect::<Vec<_>>();
+        if raw_names.is_empty() {
+            return Err(ClientError::MailBoxNotFound(pattern.to_string(), vec![]));
+        }
+        self.options.search_order(&mut raw_names);
+        Ok(raw_names)
+    }
+
+    /// EXAMINE the mail box `raw_name` to search it
+    async fn search<'s>(
+        &self,
+        session: &'s mut Session,
+        raw_name: &str,
+    ) -> Result<Search<'s>, ClientError> {
+        let operation = format!("EXAMINE {}", raw_name);
+        let mail_box = within(self.client.timeout, &operation, async {
+            session.examine(raw_name).await.during(&operation)
+        })
+        .await?;
+        Ok(Search {
+            session,
+            timeout: self.client.timeout,
+            criteria: self.options.criteria(),
+            name: decode(raw_name),
+            mail_box,
+            text_client_side: false,
+        })
+    }
+}
+
+/// A search of one mail box, see [`MailFilter`](crate::client::MailFilter)
+struct Search<'s> {
+    session: &'s mut Session,
+    timeout: Option<Duration>,
+    criteria: SearchCriteria,
+    /// The decoded name of the mail box
+    name: String,
+    mail_box: Mailbox,
+    /// Set once the server refused the TEXT/BODY keys
+    text_client_side: bool,
+}
+
+impl Search<'_> {
+    /// See [`MailFilter::fetch`](crate::client::MailFilter::fetch), the windows are
+    /// fetched one after the other until the limit is reached
+    async fn fetch(&mut self) -> Result<Vec<Mail>, ClientError> {
+        let mut mails = vec![];
+        if !self.criteria.has_uids_in_range(self.mail_box.uid_next) {
+            return Ok(mails);
+        }
+        for (since, before) in self.criteria.windows() {
+            let uids = self
+                .uid_search(|criteria, text_client_side| {
+                    criteria.search_query_between(since, before, text_client_side)
+                })
+                .await?;
+            let mut window = self.fetch_mails(&uids).await?;
+            self.criteria.sort(&mut window);
+            mails.extend(window);
+            if let Some(limit) = self.criteria.result_limit() {
+                if mails.len() >= limit {
+                    mails.truncate(limit);
+                    break;
+                }
+            }
+        }
+        Ok(mails)
+    }
+
+    /// See [`MailFilter::count`](crate::client::MailFilter::count)
+    async fn count(&mut self) -> Result<usize, ClientError> {
+        if !self.criteria.has_uids_in_range(self.mail_box.uid_next) {
+            return Ok(0);
+        }
+        let uids = self
+            .uid_search(|criteria, text_client_side| {
+                criteria.server_search_query(text_client_side).0
+            })
+            .await?;
+        if self.text_client_side {
+            // the headers aren't enough to match the text
+            return Ok(self.fetch().await?.len());
+        }
+        let (_, server_side_subject) = self.criteria.server_search_query(false);
+        if uids.is_empty() || self.criteria.counted_by_search(server_side_subject) {
+            return Ok(uids.len());
+        }
+
+        let set = uid_set(&uids);
+        let operation = format!("UID FETCH {}", set);
+        let messages = within(self.timeout, &operation, async {
+            fetch(self.session, &set, self.criteria.header_fetch_query())
+                .await
+                .during(&operation)
+        })
+        .await?;
+        Ok(messages
+            .iter()
+            .filter_map(|message| {
+                let attachments = message
+                    .bodystructure()
+                    .map(attachments_of_structure)
+                    .unwrap_or_default();
+                self.criteria.header_of(
+                    message.uid?,
+                    message.internal_date()?,
+                    message.header(),
+                    &attachments,
+                )
+            })
+            .count())
+    }
+
+    /// UID SEARCH the query built by `query`, see
+    /// [`MailFilter::uid_search`](crate::client::MailFilter)
+    async fn uid_search(
+        &mut self,
+        query: impl Fn(&SearchCriteria, bool) -> String,
+    ) -> Result<Vec<u32>, ClientError> {
+        let first = query(&self.criteria, self.text_client_side);
+        let query_utf8 = !first.is_ascii();
+        let uids = match self.uid_search_once(first).await {
+            Err(ClientError::Imap(_, imap::Error::No(message)))
+                if query_utf8 && self.criteria.searches_text() && !self.text_client_side =>
+            {
+                client::warn_text_client_side(&message);
+                self.text_client_side = true;
+                let query = query(&self.criteria, true);
+                self.uid_search_once(query).await
+            }
+            result => result,
+        }?;
+        let mut uids = uids.into_iter().collect::<Vec<_>>();
+        uids.sort_unstable();
+        Ok(uids)
+    }
+
+    async fn uid_search_once(
+        &mut self,
+        query: String,
+    ) -> Result<std::collections::HashSet<u32>, ClientError> {
+        let query = match query.is_ascii() {
+            true => query,
+            false => format!("CHARSET UTF-8 {}", query),
+        };
+        within(self.timeout, "UID SEARCH", async {
+            self.session.uid_search(&query).await.during("UID SEARCH")
+        })
+        .await
+    }
+
+    /// Fetch and build the matching mails of `uids` in chunks of [`FETCH_CHUNK_SIZE`],
+    /// leaving out those the server refuses or that are malformed
+    async fn fetch_mails(&mut self, uids: &[u32]) -> Result<Vec<Mail>, ClientError> {
+        let query = self.criteria.fetch_query();
+        let mut mails = vec![];
+        let mut batches = uids
+            .chunks(FETCH_CHUNK_SIZE)
+            .rev()
+            .map(|chunk| chunk.to_vec())
+            .collect::<Vec<_>>();
+        while let Some(uids) = batches.pop() {
+            let set = uid_set(&uids);
+            let operation = format!("UID FETCH {}", set);
+            let messages = match within(self.timeout, &operation, async {
+                fetch(self.session, &set, &query).await.during(&operation)
+            })
+            .await
+            {
+                Ok(messages) => messages,
+                // a single mail the server refuses fails the whole chunk, fetch them
+                // one by one to skip only that one
+                Err(ClientError::Imap(_, err))
+                    if matches!(err, imap::Error::No(_)) && !is_throttled(&err) =>
+                {
+                    if uids.len() > 1 {
+                        batches.extend(uids.into_iter().rev().map(|uid| vec![uid]));
+                    }
+                    continue;
+                }
+                Err(err) => return Err(err),
+            };
+            for message in messages.iter() {
+                let fetched = FetchedMail {
+                    uid: message.uid,
+                    internal_date: message.internal_date(),
+                    header: message.header(),
+                    text: message.text(),
+                    attachments: message.bodystructure().map(attachments_of_structure),
+                    flags: message.flags().map(flag_name).collect(),
+                };
+                let uid_validity = self.mail_box.uid_validity;
+                if let Ok(mail) =
+                    self.criteria
+                        .mail_of(fetched, &self.name, uid_validity, self.text_client_side)
+                {
+                    mails.extend(mail);
+                }
+            }
+        }
+        Ok(mails)
+    }
+}
+
+async fn list(session: &mut Session) -> Result<Vec<Name>, ClientError> {
+    session
+        .list(None, Some("*"))
+        .await
+        .during("LIST")?
+        .try_collect()
+        .await
+        .during("LIST")
+}
+
+async fn fetch(
+    session: &mut Session,
+    set: &str,
+    query: &str,
+) -> Result<Vec<Fetch>, async_imap::error::Error> {
+    session.uid_fetch(set, query).await?.try_collect().await
+}
+
+fn uid_set(uids: &[u32]) -> String {
+    uids.iter()
+        .map(|uid| uid.to_string())
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+fn decode(raw_name: &str) -> String {
+    utf7_imap::decode_utf7_imap(raw_name.to_string())
+}
+
+/// The flag as the imap crate of [`Client`](crate::client::Client) writes it, e.g.
+/// `\Seen`
+fn flag_name(flag: Flag) -> String {
+    match flag {
+        Flag::Seen => "\\Seen".to_string(),
+        Flag::Answered => "\\Answered".to_string(),
+        Flag::Flagged => "\\Flagged".to_string(),
+        Flag::Deleted => "\\Deleted".to_string(),
+        Flag::Draft => "\\Draft".to_string(),
+        Flag::Recent => "\\Recent".to_string(),
+        Flag::MayCreate => "\\*".to_string(),
+        Flag::Custom(name) => name.into_owned(),
+    }
+}
+
+/// The attachments listed by a BODYSTRUCTURE of async-imap at any depth, see those of
+/// the imap crate in [`MailFilter`](crate::client::MailFilter)
+fn attachments_of_structure(bodystructure: &BodyStructure) -> Vec<Attachment> {
+    fn walk(structure: &BodyStructure, attachments: &mut Vec<Attachment>) {
+        let (common, other) = match structure {
+            BodyStructure::Multipart { bodies, .. } => {
+                for body in bodies.iter() {
+                    walk(body, attachments);
+                }
+                return;
+            }
+            BodyStructure::Basic { common, other, .. }
+            | BodyStructure::Text { common, other, .. }
+            | BodyStructure::Message { common, other, .. } => (common, other),
+        };
+        let param = |params: &BodyParams, key: &str| {
+            params
+                .iter()
+                .flatten()
+                .find_map(|(name, value)| name.eq_ignore_ascii_case(key).then(|| value.to_string()))
+        };
+        let disposition = common.disposition.as_ref();
+        let filename = disposition
+            .and_then(|disposition| param(&disposition.params, "filename"))
+            .or_else(|| param(&common.ty.params, "name"));
+        let is_attachment = disposition
+            .is_some_and(|disposition| disposition.ty.eq_ignore_ascii_case("attachment"));
+        let name = match filename {
+            Some(filename) => filename,
+            None if is_attachment => format!("attachment-{}", attachments.len() + 1),
+            None => return,
+        };
+        let size = disposition
+            .and_then(|disposition| param(&disposition.params, "size"))
+            .and_then(|size| size.parse().ok())
+            .unwrap_or(other.octets);
+        attachments.push(Attachment::new(
+            name,
+            Some(size),
+            format!("{}/{}", common.ty.ty, common.ty.subtype).to_lowercase(),
+        ));
+    }
+
+    let mut attachments = vec![];
+    walk(bodystructure, &mut attachments);
+    attachments
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::{fetch_response, fetch_response_with_structure, MockServer};
+
+    fn options() -> SearchOptions {
+        SearchOptions::between("2022-05-01T00:00:00+08:00", "2022-06-01T00:00:00+08:00")
+    }
+
+    fn runtime() -> tokio::runtime::Runtime {
+        tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap()
+    }
+
+    async fn client(server: &MockServer) -> AsyncClient {
+        let stream = TcpStream::connect(server.accept()).await.unwrap();
+        AsyncClient::login_stream(stream, "user", "password")
+            .await
+            .unwrap()
+    }
+
+    #[test]
+    fn fetch_over_async_imap() {
+        let server = MockServer::new();
+        server
+            .on(
+                "LIST",
+                "* LIST (\\HasNoChildren) \"/\" \"INBOX\"\r\n\
+                 * LIST (\\HasNoChildren) \"/\" \"Archive\"\r\n",
+            )
+            .on("UID SEARCH", "* SEARCH 1 2\r\n")
+            .on(
+                "UID FETCH",
+                [
+                    fetch_response(1, "02-May-2022 09:30:00 +0800", "Subject: a\r\n\r\n", ""),
+                    fetch_response(2, "03-May-2022 09:30:00 +0800", "Subject: b\r\n\r\n", ""),
+                ]
+                .concat(),
+            );
+
+        let (names, mails) = runtime().block_on(async {
+            let client = client(&server).await;
+            let filter = client.filter(options());
+            let names = client.mail_box_names().await.unwrap();
+            // a task of its own, as a server would run it
+            let mails = tokio::spawn(async move { filter.fetch().await });
+            (names, mails.await.unwrap().unwrap())
+        });
+
+        assert_eq!(names, vec!["INBOX", "Archive"]);
+        assert_eq!(
+            mails
+                .iter()
+                .map(|mail| (mail.uid, mail.subject.as_str()))
+                .collect::<Vec<_>>(),
+            vec![(2, "b"), (1, "a")]
+        );
+        assert_eq!(mails[0].folder.as_deref(), Some("INBOX"));
+        assert!(mails[0].thread_id.is_some());
+        let commands = server.commands();
+        assert!(commands.contains(&"EXAMINE \"INBOX\"".to_string()));
+        assert!(commands
+            .iter()
+            .any(|c| c.starts_with("UID SEARCH SINCE 01-May-2022 BEFORE ")));
+    }
+
+    #[test]
+    fn list_nested_attachments() {
+        let bodystructure = include_str!("../tests/fixtures/nested_attachments.bodystructure");
+        let server = MockServer::new();
+        server.on("UID SEARCH", "* SEARCH 1\r\n").on(
+            "UID FETCH",
+            fetch_response_with_structure(
+                1,
+                "02-May-2022 09:30:00 +0800",
+                "Subject: Fwd: invoice\r\n\r\n",
+                "",
+                bodystructure.trim(),
+            ),
+        );
+
+        let mails =
+            runtime().block_on(async { client(&server).await.filter(options()).fetch().await });
+
+        let attachments = &mails.unwrap()[0].attachments;
+        assert_eq!(attachments.len(), 1);
+        assert_eq!(attachments[0].name, "invoice.pdf");
+        assert_eq!(attachments[0].size, Some(1480));
+        assert_eq!(attachments[0].content_type, "application/pdf");
+    }
+
+    #[test]
+    fn count_what_fetch_finds() {
+        let server = MockServer::new();
+        server.on("UID SEARCH", "* SEARCH 1 2 3\r\n").on(
+            "UID FETCH",
+            [
+                (1, "02-May-2022 09:30:00 +0800", "Subject: Report\r\n\r\n"),
+                (2, "03-May-2022 09:30:00 +0800", "Subject: other\r\n\r\n"),
+                (3, "31-May-2022 18:30:00 +0800", "Subject: report\r\n\r\n"),
+            ]
+            .map(|(uid, date, header)| fetch_response(uid, date, header, ""))
+            .concat(),
+        );
+        let options = || {
+            let mut options =
+                SearchOptions::between("2022-05-01T00:00:00+08:00", "2022-05-31T12:00:00+08:00");
+            options.subject_query = "report".to_string();
+            options
+        };
+
+        let (count, fetched) = runtime().block_on(async {
+            let filter = client(&server).await.filter(options());
+            (filter.count().await.unwrap(), filter.fetch().await.unwrap())
+        });
+
+        assert_eq!(count, 1);
+        assert_eq!(fetched.len(), count);
+        assert_eq!(options().count(&server.client()).unwrap(), count);
+    }
+
+    #[test]
+    fn time_out_a_silent_server() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        let err = runtime()
+            .block_on(
+                AsyncClient::builder("user", "password")
+                    .server("127.0.0.1", port)
+                    .timeout(Some(Duration::from_millis(100)))
+                    .connect(),
+            )
+            .err()
+            .unwrap();
+
+        assert_eq!(err.to_string(), "TLS handshake timed out");
+        drop(listener);
+    }
+
+    #[test]
+    fn report_a_missing_mail_box() {
+        let server = MockServer::new();
+        let mut options = options();
+        options.mail_box = "inbx".to_string();
+
+        let err = runtime()
+            .block_on(async { client(&server).await.filter(options).fetch().await })
+            .unwrap_err();
+
+        assert_eq!(
+            err.to_string(),
+            "mail box inbx not found, did you mean INBOX?"
+        );
+    }
+}
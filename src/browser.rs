@@ -0,0 +1,121 @@
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+};
+
+use crate::client::HtmlBody;
+
+/// A directory for the pages opened during a run, removed with everything in it when
+/// dropped
+pub struct PageDir(PathBuf);
+
+impl PageDir {
+    pub fn new() -> io::Result<PageDir> {
+        let path = std::env::temp_dir().join(format!("qmail-{}", std::process::id()));
+        fs::create_dir_all(&path)?;
+        Ok(PageDir(path))
+    }
+
+    /// Write the HTML body of mail `uid` with its inline parts next to it, `cid:`
+    /// references are pointed at those files
+    pub fn write(&self, uid: u32, body: &HtmlBody) -> io::Result<PathBuf> {
+        let mut html = body.html.clone();
+        for (content_id, file_name, content) in body.inline.iter() {
+            let file_name = format!("{}-{}", uid, file_name);
+            fs::write(self.0.join(&file_name), content)?;
+            html = html.replace(&format!("cid:{}", content_id), &file_name);
+        }
+
+        let path = self.0.join(format!("{}.html", uid));
+        fs::write(&path, html)?;
+        Ok(path)
+    }
+
+    /// Write attachment `name` of mail `uid`, keeping its extension so that the
+    /// platform picks the application to open it with
+    pub fn write_attachment(&self, uid: u32, name: &str, content: &[u8]) -> io::Result<PathBuf> {
+        let name = name.replace(['/', '\\'], "_");
+        let path = self.0.join(format!("{}-{}", uid, name));
+        fs::write(&path, content)?;
+        Ok(path)
+    }
+}
+
+impl Drop for PageDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+/// Open `path` with the `browser` command, or with the default application of the
+/// platform. The command may carry arguments, the path is appended to them.
+pub fn open(path: &Path, browser: Option<&str>) -> io::Result<()> {
+    let mut command = match browser {
+        Some(browser) => command_line(browser)?,
+        None => default_opener(),
+    };
+    // the TUI owns the terminal, keep the opener off it
+    command
+        .arg(path)
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()?;
+    Ok(())
+}
+
+/// A command given as a program followed by its arguments, e.g. `less -R`
+pub fn command_line(line: &str) -> io::Result<Command> {
+    let mut words = line.split_whitespace();
+    let program = words
+        .next()
+        .ok_or_else(|| io::Error::other(format!("empty command `{}`", line)))?;
+    let mut command = Command::new(program);
+    command.args(words);
+    Ok(command)
+}
+
+#[cfg(target_os = "macos")]
+fn default_opener() -> Command {
+    Command::new("open")
+}
+
+#[cfg(windows)]
+fn default_opener() -> Command {
+    let mut command = Command::new("cmd");
+    command.args(["/C", "start", ""]);
+    command
+}
+
+#[cfg(not(any(target_os = "macos", windows)))]
+fn default_opener() -> Command {
+    Command::new("xdg-open")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn write_page_with_inline_parts() {
+        let dir = PageDir::new().unwrap();
+        let body = HtmlBody {
+            html: r#"<img src="cid:logo"><img src="cid:missing">"#.to_string(),
+            inline: vec![("logo".to_string(), "logo.png".to_string(), b"png".to_vec())],
+        };
+
+        let page = dir.write(42, &body).unwrap();
+        assert_eq!(
+            fs::read_to_string(&page).unwrap(),
+            r#"<img src="42-logo.png"><img src="cid:missing">"#
+        );
+        assert_eq!(fs::read(dir.0.join("42-logo.png")).unwrap(), b"png");
+        let attachment = dir.write_attachment(42, "../report.pdf", b"pdf").unwrap();
+        assert_eq!(attachment, dir.0.join("42-.._report.pdf"));
+
+        let path = dir.0.clone();
+        drop(dir);
+        assert!(!path.exists());
+    }
+}
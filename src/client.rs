@@ -1,303 +1,5381 @@
 #![allow(dead_code)]
 
-use std::{cell::RefCell, collections::HashMap, fmt::Display, vec};
+use std::{
+    cell::{Cell, RefCell},
+    collections::{HashSet, VecDeque},
+    fmt::Display,
+    io,
+    net::{Shutdown, TcpStream, ToSocketAddrs},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, OnceLock,
+    },
+    time::{Duration, Instant},
+    vec,
+};
 
-use chrono::FixedOffset;
-use imap_proto::{BodyContentCommon, ContentDisposition};
-use mailparse::{parse_header, MailHeaderMap};
-use native_tls::TlsStream;
+use chrono::{FixedOffset, NaiveDate};
+use imap::extensions::idle::SetReadTimeout;
+use imap::types::{NameAttribute, UnsolicitedResponse};
+use imap_proto::StatusAttribute;
+use imap_proto::{MessageSection, SectionPath};
+use mailparse::{parse_header, DispositionType, MailHeaderMap, ParsedMail};
+use serde::{Deserialize, Serialize};
 
-const DOMAIN: &str = "imap.exmail.qq.com";
+use crate::compress::Deflate;
+use crate::flowed::unflow;
+use crate::folder::{self, Folder};
+use crate::health::ByeWatch;
+use crate::printable::printable;
+use crate::proxy::Proxy;
+use crate::throttle::{is_throttled, retry, Throttle, Throttled};
+use crate::timings::{Stage, Timings};
+use crate::utf7::encode_utf7_imap;
+
+pub const DOMAIN: &str = "imap.exmail.qq.com";
+pub const PORT: u16 = 993;
+pub(crate) const DEFAULT_TIMEOUT: Duration = Duration::from_secs(60);
+/// UTC offset of the days SINCE and BEFORE are evaluated on by the server, China
+/// Standard Time for exmail
+const SERVER_UTC_OFFSET: i32 = 8 * 3600;
+/// How long a dropped client waits for the answer to its LOGOUT
+const LOGOUT_TIMEOUT: Duration = Duration::from_secs(2);
+/// Most mails of a single command of the batch operations, a longer UID set can
+/// exceed the line limit of the server
+pub const DEFAULT_BATCH_SIZE: usize = 500;
+
+#[derive(Debug)]
+pub enum ClientError {
+    /// The server did not answer the operation in time
+    Timeout(String),
+    /// The operation failed
+    Imap(String, imap::Error),
+    /// No mail box has the name, along with the closest existing names
+    MailBoxNotFound(String, Vec<String>),
+    MailNotFound(u32),
+    /// The server sent a mail that doesn't parse, along with the reason
+    InvalidMail(u32, String),
+    /// The server announced LOGINDISABLED, it doesn't take the account password
+    LoginDisabled,
+}
+
+impl ClientError {
+    pub(crate) fn new(operation: String, error: imap::Error) -> Self {
+        match &error {
+            imap::Error::Io(e)
+                if e.kind() == io::ErrorKind::TimedOut || e.kind() == io::ErrorKind::WouldBlock =>
+            {
+                ClientError::Timeout(operation)
+            }
+            _ => ClientError::Imap(operation, error),
+        }
+    }
+
+    /// Whether the error is caused by the connection to the server rather than by the
+    /// request itself
+    pub fn is_connection(&self) -> bool {
+        matches!(
+            self,
+            ClientError::Timeout(_)
+                | ClientError::Imap(_, imap::Error::Io(_))
+                | ClientError::Imap(_, imap::Error::ConnectionLost)
+                | ClientError::Imap(_, imap::Error::TlsHandshake(_))
+                | ClientError::Imap(_, imap::Error::Tls(_))
+        )
+    }
+
+    /// Whether the server turned the credentials down
+    pub fn is_auth(&self) -> bool {
+        match self {
+            ClientError::LoginDisabled => true,
+            ClientError::Imap(operation, imap::Error::No(_) | imap::Error::Bad(_)) => {
+                operation == "LOGIN"
+            }
+            _ => false,
+        }
+    }
+}
+
+impl Display for ClientError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ClientError::Timeout(operation) => write!(f, "{} timed out", operation),
+            ClientError::Imap(operation, error) => write!(f, "{} failed: {}", operation, error),
+            ClientError::MailBoxNotFound(name, suggestions) if suggestions.is_empty() => {
+                write!(f, "mail box {} not found", name)
+            }
+            ClientError::MailBoxNotFound(name, suggestions) => write!(
+                f,
+                "mail box {} not found, did you mean {}?",
+                name,
+                suggestions.join(", ")
+            ),
+            ClientError::MailNotFound(uid) => write!(f, "mail {} not found", uid),
+            ClientError::InvalidMail(uid, reason) => {
+                write!(f, "mail {} is invalid: {}", uid, reason)
+            }
+            ClientError::LoginDisabled => write!(
+                f,
+                "the server doesn't accept the account password (LOGINDISABLED), log in \
+                 with an authorization code generated in the mail settings instead, \
+                 see `qmail login`"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for ClientError {}
+
+pub(crate) trait During<T> {
+    /// Attach the IMAP operation that was running to an error
+    fn during<S: Display>(self, operation: S) -> Result<T, ClientError>;
+}
+
+impl<T> During<T> for Result<T, imap::Error> {
+    fn during<S: Display>(self, operation: S) -> Result<T, ClientError> {
+        self.map_err(|e| ClientError::new(operation.to_string(), e))
+    }
+}
+
+/// Transport underneath an IMAP session
+pub trait Stream: io::Read + io::Write + SetReadTimeout + Send {}
+
+impl<T: io::Read + io::Write + SetReadTimeout + Send> Stream for T {}
+
+impl SetReadTimeout for Box<dyn Stream> {
+    fn set_read_timeout(&mut self, timeout: Option<Duration>) -> imap::Result<()> {
+        (**self).set_read_timeout(timeout)
+    }
+}
+
+pub type Session = imap::Session<Box<dyn Stream>>;
 
 pub struct Client {
-    imap_session: RefCell<imap::Session<TlsStream<std::net::TcpStream>>>,
+    imap_session: RefCell<Session>,
+    // a handle on the socket underneath the session, to restore its timeouts
+    socket: Option<TcpStream>,
+    timeout: Option<Duration>,
+    read_only: bool,
+    /// Number of mails in the selected mail box, see [`exists`](Client::exists)
+    exists: Cell<Option<u32>>,
+    /// Set once LOGOUT was sent, so that dropping the client doesn't send it again
+    logged_out: bool,
+    /// When the connection started, see [`timings`](Client::timings)
+    started: Instant,
+    timings: Cell<Timings>,
+    report_timings: bool,
+    /// Switches the [`Deflate`] layer of the stream on, `None` for a session made
+    /// elsewhere
+    compression: Option<Arc<AtomicBool>>,
 }
 
 impl Client {
-    pub fn new(username: &str, password: &str) -> Result<Self, imap::Error> {
-        let tls = native_tls::TlsConnector::builder().build().unwrap();
-        let client = imap::connect((DOMAIN, 993), DOMAIN, &tls)?;
+    pub fn new(username: &str, password: &str) -> Result<Self, ClientError> {
+        Self::builder(username, password).connect()
+    }
 
-        Ok(Self {
-            imap_session: RefCell::new(client.login(username, password).map_err(|e| e.0)?),
-        })
+    pub fn builder<'a>(username: &'a str, password: &'a str) -> ClientBuilder<'a> {
+        ClientBuilder {
+            username,
+            password,
+            host: DOMAIN,
+            port: PORT,
+            proxy: None,
+            timeout: Some(DEFAULT_TIMEOUT),
+            throttle: None,
+            read_only: true,
+            compress: false,
+        }
+    }
+
+    /// Wrap an already logged in session, e.g. one over a plain TCP stream to a
+    /// development server. The timeouts of the stream are left as they are.
+    ///
+    /// The session has to run over a boxed stream, such as
+    /// `imap::Client::new(Box::new(stream) as Box<dyn Stream>)`, see also
+    /// [`login_stream`](Self::login_stream).
+    pub fn from_session(session: Session) -> Self {
+        Client {
+            imap_session: RefCell::new(session),
+            socket: None,
+            timeout: None,
+            read_only: true,
+            exists: Cell::new(None),
+            logged_out: false,
+            started: Instant::now(),
+            timings: Cell::default(),
+            report_timings: false,
+            compression: None,
+        }
+    }
+
+    /// Where the time of the connection went so far
+    pub fn timings(&self) -> Timings {
+        Timings {
+            total: self.started.elapsed(),
+            ..self.timings.get()
+        }
+    }
+
+    /// Run `run`, accounting its time to `stage`
+    fn time<T>(&self, stage: Stage, run: impl FnOnce() -> T) -> T {
+        let started = Instant::now();
+        let result = run();
+        let mut timings = self.timings.get();
+        timings.add(stage, started.elapsed());
+        self.timings.set(timings);
+        result
+    }
+
+    /// Open the mail boxes with EXAMINE, the default, so that fetching mails doesn't
+    /// mark them as seen, or read-write with SELECT.
+    /// [`get_writable`](Self::get_writable) always selects read-write.
+    pub fn set_read_only(&mut self, read_only: bool) {
+        self.read_only = read_only;
+    }
+
+    /// Print the [`timings`](Self::timings) to stderr on logging out, however the
+    /// client is logged out
+    pub fn set_report_timings(&mut self, report: bool) {
+        self.report_timings = report;
+    }
+
+    /// Read the greeting and log in over an already connected stream of any transport
+    pub fn login_stream<S: Stream + 'static>(
+        stream: S,
+        username: &str,
+        password: &str,
+    ) -> Result<Self, ClientError> {
+        let started = Instant::now();
+        let compression = Arc::new(AtomicBool::new(false));
+        let stream = Deflate::new(stream, compression.clone());
+        let mut client = imap::Client::new(Box::new(ByeWatch::new(stream)) as Box<dyn Stream>);
+        let greeting = client.read_greeting().during("CONNECT")?;
+        // LOGIN would only fail with an obscure error
+        if login_disabled(&greeting) {
+            return Err(ClientError::LoginDisabled);
+        }
+        let session = client
+            .login(username, password)
+            .map_err(|e| e.0)
+            .during("LOGIN")?;
+        let mut client = Self::from_session(session);
+        client.started = started;
+        client.compression = Some(compression);
+        client
+            .timings
+            .get_mut()
+            .add(Stage::Login, started.elapsed());
+        Ok(client)
+    }
+
+    /// End the session with LOGOUT and close the connection. Servers count the sessions
+    /// that just disappear against the connection limit of the account for a while.
+    ///
+    /// Dropping the client logs out too, but can't report the errors.
+    pub fn logout(mut self) -> Result<(), ClientError> {
+        self.close()
+    }
+
+    fn close(&mut self) -> Result<(), ClientError> {
+        self.logged_out = true;
+        let result = self.imap_session.get_mut().logout().during("LOGOUT");
+        if let Some(socket) = &self.socket {
+            let _ = socket.shutdown(Shutdown::Both);
+        }
+        if self.report_timings {
+            eprintln!("timings:\n{}", self.timings());
+        }
+        result
+    }
+
+    /// Compress the rest of the session with COMPRESS=DEFLATE (RFC 4978) when the
+    /// server supports it. Returns whether the session is compressed, a server without
+    /// the extension or refusing it is left alone.
+    pub fn compress(&mut self) -> Result<bool, ClientError> {
+        let compression = match &self.compression {
+            Some(compression) => compression.clone(),
+            None => return Ok(false),
+        };
+        if compression.load(Ordering::Relaxed) {
+            return Ok(true);
+        }
+        if !self.has_capability("COMPRESS=DEFLATE") {
+            return Ok(false);
+        }
+        match self
+            .imap_session
+            .get_mut()
+            .run_command_and_check_ok("COMPRESS DEFLATE")
+        {
+            Ok(()) => {
+                compression.store(true, Ordering::Relaxed);
+                Ok(true)
+            }
+            Err(imap::Error::No(_) | imap::Error::Bad(_)) => Ok(false),
+            result => result.during("COMPRESS").map(|_| false),
+        }
+    }
+
+    pub fn has_capability(&self, capability: &str) -> bool {
+        let mut session = self.imap_session.borrow_mut();
+        session
+            .capabilities()
+            .map(|capabilities| capabilities.has_str(capability))
+            .unwrap_or(false)
+    }
+
+    /// Number of mails in the selected mail box as of the last command: it starts from
+    /// the count of the SELECT and follows the EXISTS and EXPUNGE responses the server
+    /// sends along with the other commands, e.g. when new mails arrive
+    pub fn exists(&self) -> Option<u32> {
+        let mut session = self.imap_session.borrow_mut();
+        track(&mut session, &self.exists);
+        self.exists.get()
+    }
+
+    /// Send a NOOP, which gives the server a chance to report changes to the selected
+    /// mail box, see [`exists`](Self::exists)
+    pub fn noop(&self) -> Result<(), ClientError> {
+        let mut session = self.imap_session.borrow_mut();
+        retry("NOOP", || session.noop()).during("NOOP")?;
+        track(&mut session, &self.exists);
+        Ok(())
+    }
+
+    /// Block in IDLE on the selected mail box until the server reports a change
+    pub fn idle(&self) -> Result<(), ClientError> {
+        let mut session = self.imap_session.borrow_mut();
+        let idle = session.idle().during("IDLE")?;
+        let ret = idle.wait_keepalive().during("IDLE");
+        // waiting in IDLE resets the read timeout
+        if let Some(socket) = &self.socket {
+            let _ = socket.set_read_timeout(self.timeout);
+        }
+        ret
     }
 
-    pub fn mail_boxes(&self) -> Result<Vec<MailBox>, imap::Error> {
+    pub fn mail_boxes(&self) -> Result<Vec<MailBox<'_>>, ClientError> {
         let mut mail_boxes = vec![];
         let mut session = self.imap_session.borrow_mut();
-        for box_name in session.list(None, Some("*")).unwrap().iter() {
+        for box_name in session.list(None, Some("*")).during("LIST")?.iter() {
+            if box_name.attributes().contains(&NameAttribute::NoSelect) {
+                continue;
+            }
             mail_boxes.push(MailBox {
                 client: self,
                 name: utf7_imap::decode_utf7_imap(box_name.name().to_string()),
-                mail_box: session.select(box_name.name())?,
+                raw_name: box_name.name().to_string(),
+                mail_box: open(&mut session, self, box_name.name(), self.read_only)?,
+                read_only: self.read_only,
             })
         }
 
         Ok(mail_boxes)
     }
 
-    pub fn get(&self, mail_box_name: &str) -> Option<MailBox> {
-        let mail_boxes = self.mail_boxes().unwrap();
-        for mail_box in mail_boxes {
-            if mail_box.name == mail_box_name {
-                return Some(mail_box);
+    /// The decoded names of all mail boxes, without selecting them
+    pub fn mail_box_names(&self) -> Result<Vec<String>, ClientError> {
+        let mut session = self.imap_session.borrow_mut();
+        Ok(session
+            .list(None, Some("*"))
+            .during("LIST")?
+            .iter()
+            .map(|name| utf7_imap::decode_utf7_imap(name.name().to_string()))
+            .collect())
+    }
+
+    /// The raw names of the selectable mail boxes whose decoded name matches the glob
+    /// `pattern`, in the order of the LIST response, see [`folder::matches`]
+    pub fn matching_mail_boxes(&self, pattern: &str) -> Result<Vec<String>, ClientError> {
+        let mut session = self.imap_session.borrow_mut();
+        let raw_names = session
+            .list(None, Some("*"))
+            .during("LIST")?
+            .iter()
+            .filter(|name| !name.attributes().contains(&NameAttribute::NoSelect))
+            .filter(|name| {
+                let path = utf7_imap::decode_utf7_imap(name.name().to_string());
+                folder::matches(pattern, &path, name.delimiter())
+            })
+            .map(|name| name.name().to_string())
+            .collect::<Vec<_>>();
+        if raw_names.is_empty() {
+            return Err(ClientError::MailBoxNotFound(pattern.to_string(), vec![]));
+        }
+        Ok(raw_names)
+    }
+
+    /// The folder hierarchy, with the message counts of every selectable folder
+    pub fn folders(&self) -> Result<Vec<Folder>, ClientError> {
+        self.list_folders(false)
+    }
+
+    /// Same as [`folders`](Self::folders), limited to the subscribed folders and their
+    /// parents
+    pub fn subscribed_folders(&self) -> Result<Vec<Folder>, ClientError> {
+        self.list_folders(true)
+    }
+
+    pub fn subscribe(&self, name: &str) -> Result<(), ClientError> {
+        self.imap_session
+            .borrow_mut()
+            .subscribe(encode_utf7_imap(name))
+            .during(format_args!("SUBSCRIBE {}", name))
+    }
+
+    pub fn unsubscribe(&self, name: &str) -> Result<(), ClientError> {
+        self.imap_session
+            .borrow_mut()
+            .unsubscribe(encode_utf7_imap(name))
+            .during(format_args!("UNSUBSCRIBE {}", name))
+    }
+
+    fn list_folders(&self, subscribed: bool) -> Result<Vec<Folder>, ClientError> {
+        let mut session = self.imap_session.borrow_mut();
+        let names = if subscribed {
+            session.lsub(None, Some("*")).during("LSUB")?
+        } else {
+            session.list(None, Some("*")).during("LIST")?
+        };
+        let mut folders = vec![];
+        for name in names.iter() {
+            let selectable = !name.attributes().contains(&NameAttribute::NoSelect);
+            let (messages, unseen) = if selectable {
+                status(&mut session, &self.exists, name.name())?
+            } else {
+                (None, None)
+            };
+
+            let path = utf7_imap::decode_utf7_imap(name.name().to_string());
+            folders.push(Folder {
+                name: path.clone(),
+                path,
+                delimiter: name.delimiter().map(|d| d.to_string()),
+                selectable,
+                messages,
+                unseen,
+                children: vec![],
+            });
+        }
+
+        Ok(folder::tree(folders))
+    }
+
+    /// Create a mail box, `name` is encoded to modified UTF-7
+    pub fn create_mailbox(&self, name: &str) -> Result<(), ClientError> {
+        self.imap_session
+            .borrow_mut()
+            .create(encode_utf7_imap(name))
+            .during(format_args!("CREATE {}", name))
+    }
+
+    pub fn rename_mailbox(&self, old_name: &str, new_name: &str) -> Result<(), ClientError> {
+        self.imap_session
+            .borrow_mut()
+            .rename(encode_utf7_imap(old_name), encode_utf7_imap(new_name))
+            .during(format_args!("RENAME {} {}", old_name, new_name))
+    }
+
+    pub fn delete_mailbox(&self, name: &str) -> Result<(), ClientError> {
+        self.imap_session
+            .borrow_mut()
+            .delete(encode_utf7_imap(name))
+            .during(format_args!("DELETE {}", name))
+    }
+
+    /// Number of mails in a mail box, without selecting it
+    pub fn message_count(&self, name: &str) -> Result<u32, ClientError> {
+        let mut session = self.imap_session.borrow_mut();
+        let (messages, _) = status(&mut session, &self.exists, &encode_utf7_imap(name))?;
+        Ok(messages.unwrap_or(0))
+    }
+
+    /// Select a mail box by its raw name, without listing the mail boxes first
+    pub fn select(&self, raw_name: &str) -> Result<MailBox<'_>, ClientError> {
+        let mut session = self.imap_session.borrow_mut();
+        Ok(MailBox {
+            client: self,
+            name: utf7_imap::decode_utf7_imap(raw_name.to_string()),
+            raw_name: raw_name.to_string(),
+            mail_box: open(&mut session, self, raw_name, self.read_only)?,
+            read_only: self.read_only,
+        })
+    }
+
+    /// Select a mail box by its decoded name or its raw modified UTF-7 name. An exact
+    /// match is preferred over a case-insensitive one.
+    ///
+    /// The mail box is read-only unless [`set_read_only`](Self::set_read_only) turned
+    /// that off.
+    pub fn get(&self, mail_box_name: &str) -> Result<MailBox<'_>, ClientError> {
+        self.find(mail_box_name, self.read_only)
+    }
+
+    /// Same as [`get`](Self::get), but always selected read-write, for the operations
+    /// that change the mails
+    pub fn get_writable(&self, mail_box_name: &str) -> Result<MailBox<'_>, ClientError> {
+        self.find(mail_box_name, false)
+    }
+
+    fn find(&self, mail_box_name: &str, read_only: bool) -> Result<MailBox<'_>, ClientError> {
+        let mut mail_boxes = self.mail_boxes()?;
+        let names = mail_boxes
+            .iter()
+            .map(|mail_box| (mail_box.name.clone(), mail_box.raw_name.clone()))
+            .collect::<Vec<_>>();
+        let mail_box = mail_boxes.swap_remove(find_name(mail_box_name, &names)?);
+        // listing selects every mail box in turn, so select the wanted one again
+        let mut mail_box = mail_box;
+        mail_box.read_only = read_only;
+        mail_box.mail_box = mail_box.select()?;
+        Ok(mail_box)
+    }
+}
+
+pub struct ClientBuilder<'a> {
+    username: &'a str,
+    password: &'a str,
+    host: &'a str,
+    port: u16,
+    proxy: Option<Proxy>,
+    timeout: Option<Duration>,
+    throttle: Option<Arc<Throttle>>,
+    read_only: bool,
+    compress: bool,
+}
+
+impl<'a> ClientBuilder<'a> {
+    /// The IMAPS server, [`DOMAIN`] on [`PORT`] by default
+    pub fn server(&mut self, host: &'a str, port: u16) -> &mut Self {
+        self.host = host;
+        self.port = port;
+        self
+    }
+
+    pub fn proxy(&mut self, proxy: Option<Proxy>) -> &mut Self {
+        self.proxy = proxy;
+        self
+    }
+
+    /// Read and write timeout of the connection, 60 seconds by default.
+    /// `None` waits forever.
+    pub fn timeout(&mut self, timeout: Option<Duration>) -> &mut Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Pace the commands of the connection, pass the same throttle to every connection
+    /// that should share the limit
+    pub fn throttle(&mut self, throttle: Option<Arc<Throttle>>) -> &mut Self {
+        self.throttle = throttle;
+        self
+    }
+
+    /// See [`Client::set_read_only`], read-only by default
+    pub fn read_only(&mut self, read_only: bool) -> &mut Self {
+        self.read_only = read_only;
+        self
+    }
+
+    /// See [`Client::compress`], off by default
+    pub fn compress(&mut self, compress: bool) -> &mut Self {
+        self.compress = compress;
+        self
+    }
+
+    pub fn connect(&self) -> Result<Client, ClientError> {
+        let started = Instant::now();
+        let stream = match &self.proxy {
+            None => self.connect_direct(),
+            Some(proxy) => proxy.connect((self.host, self.port)),
+        }
+        .map_err(imap::Error::Io)
+        .during(format_args!("CONNECT {}", self.host))?;
+        stream
+            .set_read_timeout(self.timeout)
+            .and_then(|_| stream.set_write_timeout(self.timeout))
+            .map_err(imap::Error::Io)
+            .during("CONNECT")?;
+        let socket = stream
+            .try_clone()
+            .map_err(imap::Error::Io)
+            .during("CONNECT")?;
+
+        let tls = native_tls::TlsConnector::builder().build().unwrap();
+        let stream = tls
+            .connect(self.host, stream)
+            .map_err(imap::Error::TlsHandshake)
+            .during("TLS handshake")?;
+        let connected = started.elapsed();
+        let mut client = match &self.throttle {
+            Some(throttle) => Client::login_stream(
+                Throttled::new(stream, throttle.clone()),
+                self.username,
+                self.password,
+            )?,
+            None => Client::login_stream(stream, self.username, self.password)?,
+        };
+        client.socket = Some(socket);
+        client.timeout = self.timeout;
+        client.read_only = self.read_only;
+        client.started = started;
+        client.timings.get_mut().add(Stage::Connect, connected);
+        if self.compress {
+            client.compress()?;
+        }
+        Ok(client)
+    }
+
+    fn connect_direct(&self) -> io::Result<TcpStream> {
+        let mut last_error = None;
+        for addr in (self.host, self.port).to_socket_addrs()? {
+            let stream = match self.timeout {
+                Some(timeout) => TcpStream::connect_timeout(&addr, timeout),
+                None => TcpStream::connect(addr),
+            };
+            match stream {
+                Ok(stream) => return Ok(stream),
+                Err(e) => last_error = Some(e),
             }
         }
 
-        None
+        Err(last_error.unwrap_or_else(|| io::Error::other("no address resolved")))
     }
 }
 
 pub struct MailBox<'c> {
     client: &'c Client,
     name: String,
+    raw_name: String,
     mail_box: imap::types::Mailbox,
+    read_only: bool,
 }
 
 impl<'c> MailBox<'c> {
+    /// Filter the mails of this mail box, an empty `subject_pattern` matches all subjects
     pub fn filter(
         &'c self,
         subject_pattern: &str,
         start_datetime: chrono::DateTime<FixedOffset>,
     ) -> MailFilter<'c> {
+        self.filter_by(SearchCriteria::new(subject_pattern, start_datetime))
+    }
+
+    /// The mails of this mail box matching `criteria`, see [`MailFilter`]
+    pub fn filter_by(&'c self, criteria: SearchCriteria) -> MailFilter<'c> {
         MailFilter {
             mail_box: self,
-            subject_pattern: subject_pattern.to_string(),
-            start_datetime,
-            end_datetime: "9999-12-01T00:00:00Z"
-                .parse::<chrono::DateTime<FixedOffset>>()
-                .unwrap(),
-            regex: false,
-            reverse: false,
+            criteria,
+            text_client_side: Cell::new(false),
+            progress: false,
+            scanned: Cell::new(0),
+            warnings: RefCell::new(vec![]),
         }
     }
 
+    /// Fetch the mails of this mail box matching `criteria`, see [`MailFilter::fetch`]
+    pub fn search(&'c self, criteria: &SearchCriteria) -> Result<Vec<Mail>, ClientError> {
+        self.filter_by(criteria.clone()).fetch()
+    }
+
     pub fn name(&self) -> &str {
         &self.name
     }
 
-    pub fn download(&self, mail_uid: u32) -> Option<HashMap<String, Vec<u8>>> {
+    /// The name as sent to the server, in modified UTF-7
+    pub fn raw_name(&self) -> &str {
+        &self.raw_name
+    }
+
+    /// Make this the currently selected mail box of the session
+    pub fn select(&self) -> Result<imap::types::Mailbox, ClientError> {
         let mut session = self.client.imap_session.borrow_mut();
-        let messages = session.fetch(mail_uid.to_string(), "BODY[]").unwrap();
-        let message = messages.iter().next().unwrap();
-        let body_parsed = mailparse::parse_mail(message.body().unwrap_or_default()).unwrap();
-        let mut mail_data: HashMap<String, Vec<u8>> = HashMap::new();
+        open(&mut session, self.client, &self.raw_name, self.read_only)
+    }
 
-        for subpart in body_parsed.subparts.iter() {
-            if let Some(content_type) = subpart.get_headers().get_first_value("Content-Disposition")
-            {
-                let filename = content_type
-                    .split(';')
-                    .nth(1)
-                    .unwrap()
-                    .trim()
-                    .replace('"', "")
-                    .replace("filename=", "");
-
-                mail_data.insert(filename, subpart.get_body_raw().unwrap());
-            }
-        }
+    /// Whether the mail box was opened with EXAMINE
+    pub fn is_read_only(&self) -> bool {
+        self.read_only
+    }
 
-        Some(mail_data)
+    /// Number of mails in the mail box, as of the last SELECT
+    pub fn message_count(&self) -> u32 {
+        self.mail_box.exists
     }
-}
 
-pub struct MailFilter<'c> {
-    mail_box: &'c MailBox<'c>,
-    subject_pattern: String,
-    start_datetime: chrono::DateTime<FixedOffset>,
-    end_datetime: chrono::DateTime<FixedOffset>,
-    regex: bool,
-    reverse: bool,
-}
+    /// Same as [`message_count`](Self::message_count), named after the EXISTS response
+    pub fn exists(&self) -> u32 {
+        self.mail_box.exists
+    }
 
-impl<'c> MailFilter<'c> {
-    pub fn end_date(&mut self, end_datetime: chrono::DateTime<FixedOffset>) -> &mut Self {
-        self.end_datetime = end_datetime;
-        self
+    /// Number of mails with the \Recent flag, as of the last SELECT
+    pub fn recent(&self) -> u32 {
+        self.mail_box.recent
     }
 
-    pub fn regex(&mut self, regex: bool) -> &mut Self {
-        self.regex = regex;
-        self
+    /// The sequence number of the first mail not read yet, not a count of them; `None`
+    /// when the server didn't say, e.g. because every mail was read
+    pub fn unseen(&self) -> Option<u32> {
+        self.mail_box.unseen
     }
 
-    pub fn reverse(&mut self, reserve: bool) -> &mut Self {
-        self.reverse = reserve;
-        self
+    /// The flags defined in the mail box
+    pub fn flags(&self) -> &[imap::types::Flag<'static>] {
+        &self.mail_box.flags
     }
 
-    pub fn fetch(&self) -> Vec<Mail> {
-        let mut session = self.mail_box.client.imap_session.borrow_mut();
-        let query = format!(
-            "SINCE {} BEFORE {}",
-            self.start_datetime.format("%d-%b-%Y"),
-            self.end_datetime.format("%d-%b-%Y")
-        );
-        let ret = session.search(query);
-        let mut mails = vec![];
-        let fetch_query =
-            "(INTERNALDATE BODY[HEADER.FIELDS (SUBJECT FROM CC TO)] BODY[TEXT] BODYSTRUCTURE)";
-
-        if let Ok(uids) = ret {
-            for uid in uids.into_iter() {
-                let messages = session.fetch(uid.to_string(), fetch_query).unwrap();
-                let message = if let Some(m) = messages.iter().next() {
-                    m
-                } else {
-                    continue;
-                };
+    /// The flags that can be changed permanently, may contain `\*` when new keywords can
+    /// be created
+    pub fn permanent_flags(&self) -> &[imap::types::Flag<'static>] {
+        &self.mail_box.permanent_flags
+    }
 
-                let date = message.internal_date().unwrap();
-                // imap only can filter by date, so here we need to filter by time
-                if date.timestamp() < self.start_datetime.timestamp()
-                    || date.timestamp() > self.end_datetime.timestamp()
-                {
-                    continue;
-                }
+    /// A snapshot of the state reported by the last SELECT
+    pub fn info(&self) -> MailboxInfo {
+        let names = |flags: &[imap::types::Flag]| flags.iter().map(|f| f.to_string()).collect();
+        MailboxInfo {
+            name: self.name.clone(),
+            flags: names(self.flags()),
+            exists: self.exists(),
+            recent: self.recent(),
+            unseen: self.unseen(),
+            permanent_flags: names(self.permanent_flags()),
+            uid_next: self.uid_next(),
+            uid_validity: self.uid_validity(),
+        }
+    }
 
-                let mut attachments = vec![];
-                let bodystructure = message.bodystructure().unwrap();
-                if let imap_proto::BodyStructure::Multipart {
-                    common: _,
-                    bodies,
-                    extension: _,
-                } = bodystructure
-                {
-                    for body in bodies.iter() {
-                        if let imap_proto::BodyStructure::Basic {
-                            common:
-                                BodyContentCommon {
-                                    ty: _,
-                                    disposition:
-                                        Some(ContentDisposition {
-                                            ty: "attachment",
-                                            params: Some(params),
-                                        }),
-                                    language: _,
-                                    location: _,
-                                },
-                            other: _,
-                            extension: _,
-                        } = body
-                        {
-                            attachments.push(Attachment::new(
-                                params[0].1.to_string(),
-                                params.get(1).map(|v| v.1.parse::<u32>().unwrap()),
-                            ))
-                        }
-                    }
-                }
+    /// The UID the next mail delivered to the mail box will get, as of the last SELECT
+    pub fn uid_next(&self) -> Option<u32> {
+        self.mail_box.uid_next
+    }
 
-                let header = message.header().unwrap();
-                let header_parsed = mailparse::parse_mail(header).unwrap();
-                let body_parsed =
-                    mailparse::parse_mail(message.text().unwrap_or_default()).unwrap();
-
-                let mail = Mail {
-                    uid,
-                    subject: header_parsed
-                        .headers
-                        .get_first_header("Subject")
-                        .map(|h| h.get_value())
-                        .unwrap_or_default(),
-                    from: header_parsed
-                        .headers
-                        .get_first_header("From")
-                        .map(|h| h.get_value())
-                        .unwrap_or_default(),
-                    to: header_parsed
-                        .headers
-                        .get_first_header("To")
-                        .map(|h| h.get_value())
-                        .unwrap_or_default()
-                        .split(',')
-                        .map(|s| s.trim().to_string())
-                        .collect(),
-                    cc: header_parsed
-                        .headers
-                        .get_first_header("CC")
-                        .map(|h| h.get_value())
-                        .unwrap_or_default()
-                        .split(',')
-                        .map(|s| s.trim().to_string())
-                        .collect(),
-                    body: body_parsed
-                        .subparts
-                        .get(0)
-                        .map(|subpart| subpart.get_body().unwrap_or_default())
-                        .unwrap_or_default(),
-                    internal_date: date,
-                    attachments,
-                };
+    /// UIDs are only comparable while this stays the same, a change invalidates cursors
+    pub fn uid_validity(&self) -> Option<u32> {
+        self.mail_box.uid_validity
+    }
 
-                if self.regex {
-                    if !regex::Regex::new(&self.subject_pattern)
-                        .unwrap()
-                        .is_match(&mail.subject)
-                    {
-                        continue;
-                    }
-                } else if !mail.subject.contains(&self.subject_pattern) {
-                    continue;
-                }
+    /// The UIDs of the mails after `uid`, oldest first, as of now rather than the last
+    /// SELECT, e.g. to see what was delivered while idling
+    pub fn uids_after(&self, uid: u32) -> Result<Vec<u32>, ClientError> {
+        let query = format!("UID {}:*", uid + 1);
+        let mut session = self.client.imap_session.borrow_mut();
+        let mut uids = retry("UID SEARCH", || session.uid_search(&query))
+            .during("UID SEARCH")?
+            .into_iter()
+            // `n:*` also matches the last mail when n is past it
+            .filter(|found| *found > uid)
+            .collect::<Vec<_>>();
+        uids.sort_unstable();
+        Ok(uids)
+    }
 
-                mails.push(mail);
-            }
+    /// Find a mail by its Message-ID header, given with or without the angle brackets.
+    /// The oldest copy is returned when there are several.
+    pub fn find_by_message_id(&self, message_id: &str) -> Result<Option<Mail>, ClientError> {
+        let message_id = message_id.trim();
+        let message_id = if message_id.starts_with('<') {
+            message_id.to_string()
+        } else {
+            format!("<{}>", message_id)
+        };
+        let query = format!("HEADER Message-ID {}", quote(&message_id));
+
+        let mut session = self.client.imap_session.borrow_mut();
+        let uid = retry("UID SEARCH", || session.uid_search(&query))
+            .during("UID SEARCH")?
+            .into_iter()
+            .min();
+        let uid = match uid {
+            Some(uid) => uid,
+            None => return Ok(None),
+        };
+        let messages = retry("UID FETCH", || {
+            session.uid_fetch(uid.to_string(), FETCH_QUERY)
+        })
+        .during(format_args!("UID FETCH {}", uid))?;
+
+        // a filter that lets every mail through
+        let filter = self.filter("", chrono::DateTime::<chrono::Utc>::MIN_UTC.into());
+        let found = messages
+            .iter()
+            .find_map(|message| filter.parse(message).transpose())
+            .transpose();
+        Ok(found.unwrap_or_else(|reason| {
+            eprintln!("warning: {}", FetchWarning { uid, reason });
+            None
+        }))
+    }
+
+    /// Download the attachments of a mail, in the order they appear in it, along with
+    /// the headers of the mail. `include_inline` also downloads the unnamed inline
+    /// parts referenced by a `cid:` URL, such as the images of a newsletter.
+    /// `attachment_type` only keeps the parts whose MIME type matches the glob, see
+    /// [`content_type_matches`].
+    ///
+    /// With a bounded `size_limit` the BODYSTRUCTURE is fetched first and only the parts
+    /// within the limit are fetched, the others are returned as skipped.
+    pub fn download(
+        &self,
+        mail_uid: u32,
+        include_inline: bool,
+        attachment_type: Option<&str>,
+        size_limit: SizeLimit,
+    ) -> Result<(MailHeader, AttachmentFiles, Vec<SkippedPart>), ClientError> {
+        if !size_limit.is_unbounded() {
+            return self.download_parts(mail_uid, include_inline, attachment_type, size_limit);
         }
 
-        mails.sort_by_key(|v| -v.internal_date.timestamp());
-        if self.reverse {
-            mails.reverse()
+        let mut session = self.client.imap_session.borrow_mut();
+        let messages = retry("UID FETCH", || {
+            session.uid_fetch(mail_uid.to_string(), "(INTERNALDATE BODY[])")
+        })
+        .during(format_args!("UID FETCH {}", mail_uid))?;
+        let message = match messages.iter().next() {
+            Some(message) => message,
+            None => return Err(ClientError::MailNotFound(mail_uid)),
+        };
+        let body_parsed = mailparse::parse_mail(message.body().unwrap_or_default())
+            .map_err(|err| ClientError::InvalidMail(mail_uid, err.to_string()))?;
+        let header = header_of(mail_uid, &body_parsed, internal_date_of(mail_uid, message)?);
+
+        Ok((
+            header,
+            attachments_of(&body_parsed, include_inline, attachment_type),
+            vec![],
+        ))
+    }
+
+    /// [`download`](Self::download) part by part, so that the parts out of `size_limit`
+    /// never leave the server
+    fn download_parts(
+        &self,
+        mail_uid: u32,
+        include_inline: bool,
+        attachment_type: Option<&str>,
+        size_limit: SizeLimit,
+    ) -> Result<(MailHeader, AttachmentFiles, Vec<SkippedPart>), ClientError> {
+        let mut session = self.client.imap_session.borrow_mut();
+        let messages = retry("UID FETCH", || {
+            session.uid_fetch(
+                mail_uid.to_string(),
+                "(INTERNALDATE BODYSTRUCTURE BODY.PEEK[HEADER])",
+            )
+        })
+        .during(format_args!("UID FETCH {}", mail_uid))?;
+        let message = match messages.iter().next() {
+            Some(message) => message,
+            None => return Err(ClientError::MailNotFound(mail_uid)),
+        };
+        let header_raw = message.header().unwrap_or_default();
+        let header_parsed = mailparse::parse_mail(header_raw).map_err(|err| {
+            ClientError::InvalidMail(mail_uid, format!("invalid header: {}", err))
+        })?;
+        let header = header_of(
+            mail_uid,
+            &header_parsed,
+            internal_date_of(mail_uid, message)?,
+        );
+
+        let bodystructure = message
+            .bodystructure()
+            .ok_or_else(|| ClientError::InvalidMail(mail_uid, "no BODYSTRUCTURE".to_string()))?;
+        // the MIME header of a single part message is the message header
+        let single_part = !matches!(bodystructure, imap_proto::BodyStructure::Multipart { .. });
+        let mut parts = vec![];
+        parts_of(
+            bodystructure,
+            vec![],
+            include_inline,
+            attachment_type,
+            &mut parts,
+        );
+        let mut wanted = vec![];
+        let mut skipped = vec![];
+        for part in parts {
+            match size_limit.check(part.size) {
+                Some(reason) => skipped.push(SkippedPart {
+                    name: part.name,
+                    size: part.size,
+                    skipped: reason,
+                }),
+                None => wanted.push(part),
+            }
         }
+        if wanted.is_empty() {
+            return Ok((header, vec![], skipped));
+        }
+
+        let section = |path: &[u32]| {
+            path.iter()
+                .map(|i| i.to_string())
+                .collect::<Vec<_>>()
+                .join(".")
+        };
+        let query = wanted
+            .iter()
+            .map(|part| match single_part {
+                true => format!("BODY.PEEK[{}]", section(&part.section)),
+                false => format!("BODY.PEEK[{0}.MIME] BODY.PEEK[{0}]", section(&part.section)),
+            })
+            .collect::<Vec<_>>()
+            .join(" ");
+        let messages = retry("UID FETCH", || {
+            session.uid_fetch(mail_uid.to_string(), format!("({})", query))
+        })
+        .during(format_args!("UID FETCH {}", mail_uid))?;
+        let message = match messages.iter().next() {
+            Some(message) => message,
+            None => return Err(ClientError::MailNotFound(mail_uid)),
+        };
 
-        mails
+        let mut attachments = vec![];
+        for wanted in wanted {
+            let mime = match single_part {
+                true => header_raw,
+                false => message
+                    .section(&SectionPath::Part(
+                        wanted.section.clone(),
+                        Some(MessageSection::Mime),
+                    ))
+                    .unwrap_or_default(),
+            };
+            let body = message
+                .section(&SectionPath::Part(wanted.section, None))
+                .unwrap_or_default();
+            let raw = [mime, body].concat();
+            let part = match mailparse::parse_mail(&raw) {
+                Ok(part) => part,
+                Err(_) => {
+                    skipped.push(SkippedPart {
+                        name: wanted.name,
+                        size: wanted.size,
+                        skipped: SkipReason::Invalid,
+                    });
+                    continue;
+                }
+            };
+            let index = attachments.len();
+            if let Some(attachment) = attachment_of(&part, include_inline, attachment_type, index) {
+                attachments.push(attachment);
+            }
+        }
+        Ok((header, attachments, skipped))
     }
 }
 
-impl Display for MailBox<'_> {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(
-            f,
-            "name: {}, flags: {:?}, exists: {}, recent: {}, unseen: {:?}, permanent_flags: {:?},\
-             uid_next: {:?}, uid_validity: {:?}",
-            self.name,
-            self.mail_box.flags,
-            self.mail_box.exists,
-            self.mail_box.recent,
-            self.mail_box.unseen,
-            self.mail_box.permanent_flags,
-            self.mail_box.uid_next,
-            self.mail_box.uid_validity
-        )
+impl MailBox<'_> {
+    /// The HTML part of a mail along with the inline parts it may reference, `None`
+    /// when the mail has no HTML part
+    pub fn html_body(&self, mail_uid: u32) -> Result<Option<HtmlBody>, ClientError> {
+        let raw = self.fetch_raw(mail_uid)?;
+        let mail = mailparse::parse_mail(&raw)
+            .map_err(|err| ClientError::InvalidMail(mail_uid, err.to_string()))?;
+        Ok(html_body_of(&mail))
     }
-}
 
-#[derive(Debug)]
-pub struct Mail {
-    pub subject: String,
-    pub from: String,
-    pub to: Vec<String>,
-    pub cc: Vec<String>,
-    pub uid: u32,
-    pub body: String,
-    pub internal_date: chrono::DateTime<FixedOffset>,
-    pub attachments: Vec<Attachment>,
+    /// The whole message as sent, headers included, for parsing it by other means
+    pub fn fetch_raw(&self, mail_uid: u32) -> Result<Vec<u8>, ClientError> {
+        let mut session = self.client.imap_session.borrow_mut();
+        let messages = retry("UID FETCH", || {
+            session.uid_fetch(mail_uid.to_string(), "BODY[]")
+        })
+        .during(format_args!("UID FETCH {}", mail_uid))?;
+        match messages.iter().next() {
+            Some(message) => Ok(message.body().unwrap_or_default().to_vec()),
+            None => Err(ClientError::MailNotFound(mail_uid)),
+        }
+    }
+
+    /// Mark a mail read or unread. A read-only mail box is selected read-write for the
+    /// change and examined again afterwards.
+    pub fn set_seen(&self, mail_uid: u32, seen: bool) -> Result<(), ClientError> {
+        let mut session = self.client.imap_session.borrow_mut();
+        if self.read_only {
+            open(&mut session, self.client, &self.raw_name, false)?;
+        }
+        let change = if seen {
+            "+FLAGS.SILENT (\\Seen)"
+        } else {
+            "-FLAGS.SILENT (\\Seen)"
+        };
+        let stored = retry("UID STORE", || {
+            session.uid_store(mail_uid.to_string(), change)
+        })
+        .during(format_args!("UID STORE {}", mail_uid));
+        if self.read_only {
+            open(&mut session, self.client, &self.raw_name, true)?;
+        }
+        stored.map(|_| ())
+    }
+
+    /// Move mails to the mail box `destination`, a decoded name, with UID MOVE. Servers
+    /// without the MOVE extension get them copied, flagged \Deleted and expunged: with
+    /// UIDPLUS only these mails, otherwise every mail flagged \Deleted in the mail box.
+    /// A read-only mail box is selected read-write for the move and examined again
+    /// afterwards.
+    ///
+    /// The mails are moved `batch.size` at a time, a failed command doesn't stop the
+    /// next ones unless `batch.fail_fast` is set or the connection was lost.
+    pub fn move_mails(
+        &self,
+        uids: &[u32],
+        destination: &str,
+        batch: Batch,
+    ) -> Result<BatchOutcome, ClientError> {
+        let mut outcome = BatchOutcome {
+            total: uids.len(),
+            chunks: vec![],
+        };
+        if uids.is_empty() {
+            return Ok(outcome);
+        }
+        let has_move = self.client.has_capability("MOVE");
+        let has_uidplus = self.client.has_capability("UIDPLUS");
+        let mut session = self.client.imap_session.borrow_mut();
+        if self.read_only {
+            open(&mut session, self.client, &self.raw_name, false)?;
+        }
+        let raw_destination = encode_utf7_imap(destination);
+        let mut connected = true;
+        for chunk in uids.chunks(batch.size.max(1)) {
+            let uid_set = chunk
+                .iter()
+                .map(|uid| uid.to_string())
+                .collect::<Vec<_>>()
+                .join(",");
+            // a retried move could run twice, so none of these is retried
+            let moved = if has_move {
+                session
+                    .uid_mv(&uid_set, &raw_destination)
+                    .during(format_args!("UID MOVE {}", destination))
+            } else {
+                copy_and_expunge(&mut session, &uid_set, &raw_destination, has_uidplus)
+                    .during(format_args!("UID COPY {}", destination))
+            };
+            let error = moved.err();
+            connected = !error.as_ref().is_some_and(ClientError::is_connection);
+            let stop = error.is_some() && (batch.fail_fast || !connected);
+            outcome.chunks.push(Chunk {
+                uids: chunk.to_vec(),
+                error,
+            });
+            if stop {
+                break;
+            }
+        }
+        if self.read_only && connected {
+            open(&mut session, self.client, &self.raw_name, true)?;
+        }
+        Ok(outcome)
+    }
+}
+
+/// How a batch operation such as [`MailBox::move_mails`] splits its mails into
+/// commands
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Batch {
+    /// Most mails per command
+    pub size: usize,
+    /// Stop at the first failed command instead of going on with the next ones
+    pub fail_fast: bool,
+}
+
+impl Default for Batch {
+    fn default() -> Self {
+        Batch {
+            size: DEFAULT_BATCH_SIZE,
+            fail_fast: false,
+        }
+    }
+}
+
+/// The commands of a batch operation, see [`Batch`]
+#[derive(Debug, Default)]
+pub struct BatchOutcome {
+    /// Number of mails the operation was given
+    pub total: usize,
+    /// The commands sent, in order. The mails after a command stopping the operation
+    /// aren't in any.
+    pub chunks: Vec<Chunk>,
+}
+
+impl BatchOutcome {
+    /// Number of mails of the commands that succeeded
+    pub fn done(&self) -> usize {
+        self.chunks
+            .iter()
+            .filter(|chunk| chunk.error.is_none())
+            .map(|chunk| chunk.uids.len())
+            .sum()
+    }
+
+    pub fn failed(&self) -> impl Iterator<Item = &Chunk> {
+        self.chunks.iter().filter(|chunk| chunk.error.is_some())
+    }
 }
 
+/// One command of a batch operation
 #[derive(Debug)]
-pub struct Attachment {
+pub struct Chunk {
+    pub uids: Vec<u32>,
+    pub error: Option<ClientError>,
+}
+
+impl Chunk {
+    /// The lowest and highest UID, e.g. `8812:9020`
+    pub fn range(&self) -> String {
+        let first = self.uids.iter().min().copied().unwrap_or_default();
+        let last = self.uids.iter().max().copied().unwrap_or_default();
+        match first == last {
+            true => first.to_string(),
+            false => format!("{}:{}", first, last),
+        }
+    }
+}
+
+impl Display for Chunk {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.error {
+            Some(error) => write!(
+                f,
+                "{} failed in range {}: {}",
+                self.uids.len(),
+                self.range(),
+                error
+            ),
+            None => write!(f, "{} done in range {}", self.uids.len(), self.range()),
+        }
+    }
+}
+
+/// UID MOVE for the servers without it
+fn copy_and_expunge(
+    session: &mut Session,
+    uid_set: &str,
+    raw_destination: &str,
+    has_uidplus: bool,
+) -> imap::Result<()> {
+    // unlike uid_mv, uid_copy sends the name without quoting it
+    session.uid_copy(uid_set, quote(raw_destination))?;
+    session.uid_store(uid_set, "+FLAGS.SILENT (\\Deleted)")?;
+    match has_uidplus {
+        true => session.uid_expunge(uid_set).map(|_| ()),
+        false => session.expunge().map(|_| ()),
+    }
+}
+
+/// The HTML body of a mail, see [`MailBox::html_body`]
+#[derive(Debug)]
+pub struct HtmlBody {
+    pub html: String,
+    /// Content-ID without the angle brackets, a file name and the content of every part
+    /// with a Content-ID
+    pub inline: Vec<(String, String, Vec<u8>)>,
+}
+
+fn html_body_of(mail: &ParsedMail) -> Option<HtmlBody> {
+    let parts = leaves(mail);
+    let html = parts
+        .iter()
+        .find(|part| part.ctype.mimetype == "text/html")?
+        .get_body()
+        .ok()?;
+    let inline = parts
+        .iter()
+        .filter_map(|part| {
+            let content_id = part.headers.get_first_value("Content-ID")?;
+            let content_id = content_id
+                .trim()
+                .trim_start_matches('<')
+                .trim_end_matches('>')
+                .to_string();
+            let file_name = content_id_file_name(&content_id, &part.ctype.mimetype);
+            Some((content_id, file_name, part.get_body_raw().ok()?))
+        })
+        .collect();
+    Some(HtmlBody { html, inline })
+}
+
+/// MESSAGES and UNSEEN counts of a mail box, by its raw name
+fn status(
+    session: &mut Session,
+    exists: &Cell<Option<u32>>,
+    raw_name: &str,
+) -> Result<(Option<u32>, Option<u32>), ClientError> {
+    retry("STATUS", || session.status(raw_name, "(MESSAGES UNSEEN)"))
+        .during(format_args!("STATUS {}", raw_name))?;
+    // imap reports the STATUS response as unsolicited
+    let mut counts = (None, None);
+    for response in track(session, exists) {
+        if let UnsolicitedResponse::Status { attributes, .. } = response {
+            for attribute in attributes {
+                match attribute {
+                    StatusAttribute::Messages(n) => counts.0 = Some(n),
+                    StatusAttribute::Unseen(n) => counts.1 = Some(n),
+                    _ => {}
+                }
+            }
+        }
+    }
+    Ok(counts)
+}
+
+/// The position of the mail box `mail_box_name` among the decoded and raw `names` of
+/// the mail boxes, see [`Client::get`]
+pub(crate) fn find_name(
+    mail_box_name: &str,
+    names: &[(String, String)],
+) -> Result<usize, ClientError> {
+    names
+        .iter()
+        .position(|(name, raw_name)| name == mail_box_name || raw_name == mail_box_name)
+        .or_else(|| {
+            names.iter().position(|(name, raw_name)| {
+                name.to_lowercase() == mail_box_name.to_lowercase()
+                    || raw_name.eq_ignore_ascii_case(mail_box_name)
+            })
+        })
+        .ok_or_else(|| {
+            let names = names.iter().map(|(name, _)| name.as_str());
+            ClientError::MailBoxNotFound(
+                mail_box_name.to_string(),
+                closest_names(mail_box_name, names),
+            )
+        })
+}
+
+/// Up to 3 of `names` that look like a misspelling of `name`, closest first
+fn closest_names<'a>(name: &str, names: impl Iterator<Item = &'a str>) -> Vec<String> {
+    let name = name.to_lowercase();
+    let mut candidates = names
+        .filter_map(|candidate| {
+            let lowercase = candidate.to_lowercase();
+            let distance = edit_distance(&name, &lowercase);
+            let close = distance <= (name.chars().count() / 3).max(2)
+                || lowercase.contains(&name)
+                || name.contains(&lowercase);
+            close.then(|| (distance, candidate.to_string()))
+        })
+        .collect::<Vec<_>>();
+    candidates.sort();
+    candidates
+        .into_iter()
+        .take(3)
+        .map(|(_, candidate)| candidate)
+        .collect()
+}
+
+/// Levenshtein distance between two strings, in chars
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b = b.chars().collect::<Vec<_>>();
+    let mut row = (0..=b.len()).collect::<Vec<_>>();
+    for (i, ca) in a.chars().enumerate() {
+        let mut previous = row[0];
+        row[0] = i + 1;
+        for (j, cb) in b.iter().enumerate() {
+            let current = row[j + 1];
+            row[j + 1] = if ca == *cb {
+                previous
+            } else {
+                1 + previous.min(row[j]).min(row[j + 1])
+            };
+            previous = current;
+        }
+    }
+    row[b.len()]
+}
+
+/// Name and content of the attachments of a mail
+pub type AttachmentFiles = Vec<(String, Vec<u8>)>;
+
+/// Bounds on the size of the attachments to download, in bytes
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SizeLimit {
+    pub min: Option<u64>,
+    pub max: Option<u64>,
+}
+
+impl SizeLimit {
+    fn is_unbounded(&self) -> bool {
+        self.min.is_none() && self.max.is_none()
+    }
+
+    /// Why a part of `size` bytes is left out, `None` when it's within the bounds
+    pub fn check(&self, size: u64) -> Option<SkipReason> {
+        if self.max.is_some_and(|max| size > max) {
+            Some(SkipReason::TooLarge)
+        } else if self.min.is_some_and(|min| size < min) {
+            Some(SkipReason::TooSmall)
+        } else {
+            None
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SkipReason {
+    TooLarge,
+    TooSmall,
+    /// The part doesn't parse
+    Invalid,
+}
+
+/// An attachment left on the server by a [`SizeLimit`]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct SkippedPart {
     pub name: String,
-    pub size: Option<u32>,
+    /// The size in the Content-Disposition, or the encoded size when it has none
+    pub size: u64,
+    pub skipped: SkipReason,
 }
 
-impl Attachment {
-    fn new(name: String, size: Option<u32>) -> Self {
-        let name = format!("Subject: {}", name);
-        let (parsed, _) = parse_header(name.as_bytes()).unwrap();
-        let name = parsed.get_value();
+/// An attachment found in a BODYSTRUCTURE
+struct StructurePart {
+    /// The part numbers of the section, e.g. `[2, 1]` for `BODY[2.1]`
+    section: Vec<u32>,
+    name: String,
+    size: u64,
+    content_type: String,
+}
+
+/// The parts of a BODYSTRUCTURE that [`attachments_of`] would collect, along with
+/// their section and size
+fn parts_of(
+    structure: &imap_proto::BodyStructure,
+    section: Vec<u32>,
+    include_inline: bool,
+    attachment_type: Option<&str>,
+    parts: &mut Vec<StructurePart>,
+) {
+    let (common, other) = match structure {
+        imap_proto::BodyStructure::Multipart { bodies, .. } => {
+            for (i, body) in bodies.iter().enumerate() {
+                let mut section = section.clone();
+                section.push(i as u32 + 1);
+                parts_of(body, section, include_inline, attachment_type, parts);
+            }
+            return;
+        }
+        imap_proto::BodyStructure::Basic { common, other, .. }
+        | imap_proto::BodyStructure::Text { common, other, .. }
+        | imap_proto::BodyStructure::Message { common, other, .. } => (common, other),
+    };
+    let mimetype = format!("{}/{}", common.ty.ty, common.ty.subtype).to_lowercase();
+    if attachment_type.is_some_and(|pattern| !content_type_matches(pattern, &mimetype)) {
+        return;
+    }
+
+    let param = |params: &imap_proto::BodyParams, key: &str| {
+        params
+            .iter()
+            .flatten()
+            .find_map(|(name, value)| name.eq_ignore_ascii_case(key).then(|| value.to_string()))
+    };
+    let disposition = common.disposition.as_ref();
+    let filename = disposition
+        .and_then(|disposition| param(&disposition.params, "filename"))
+        .or_else(|| param(&common.ty.params, "name"));
+    let is_attachment =
+        disposition.is_some_and(|disposition| disposition.ty.eq_ignore_ascii_case("attachment"));
+    let name = match (filename, other.id) {
+        (Some(filename), _) => filename,
+        (None, _) if is_attachment => format!("attachment-{}", parts.len() + 1),
+        (None, Some(content_id)) if include_inline => content_id_file_name(content_id, &mimetype),
+        _ => return,
+    };
+    let size = disposition
+        .and_then(|disposition| param(&disposition.params, "size"))
+        .and_then(|size| size.parse().ok())
+        .unwrap_or(other.octets as u64);
+    // a single part message has the section 1
+    let section = match section.is_empty() {
+        true => vec![1],
+        false => section,
+    };
+    parts.push(StructurePart {
+        section,
+        name,
+        size,
+        content_type: mimetype,
+    });
+}
+
+/// The leaf parts of a mail in order, however deeply they are nested in multiparts
+fn leaves<'a>(mail: &'a ParsedMail<'a>) -> Vec<&'a ParsedMail<'a>> {
+    fn walk<'a>(part: &'a ParsedMail<'a>, leaves: &mut Vec<&'a ParsedMail<'a>>) {
+        if part.subparts.is_empty() {
+            leaves.push(part);
+        }
+        for subpart in part.subparts.iter() {
+            walk(subpart, leaves);
+        }
+    }
+
+    let mut parts = vec![];
+    walk(mail, &mut parts);
+    parts
+}
+
+/// Collect the attachments of a mail in order, however deeply they are nested in
+/// multiparts. Parts without a file name are named after their Content-ID when
+/// `include_inline` is set, every part is collected at most once.
+fn attachments_of(
+    mail: &ParsedMail,
+    include_inline: bool,
+    attachment_type: Option<&str>,
+) -> AttachmentFiles {
+    let mut attachments = vec![];
+    for part in leaves(mail) {
+        let index = attachments.len();
+        if let Some(attachment) = attachment_of(part, include_inline, attachment_type, index) {
+            attachments.push(attachment);
+        }
+    }
+    attachments
+}
+
+/// Name and content of a leaf part when it's an attachment, `index` is the number of
+/// attachments before it
+fn attachment_of(
+    part: &ParsedMail,
+    include_inline: bool,
+    attachment_type: Option<&str>,
+    index: usize,
+) -> Option<(String, Vec<u8>)> {
+    if attachment_type.is_some_and(|pattern| !content_type_matches(pattern, &part.ctype.mimetype)) {
+        return None;
+    }
+
+    let disposition = part.get_content_disposition();
+    let filename = disposition
+        .params
+        .get("filename")
+        .or_else(|| part.ctype.params.get("name"))
+        .cloned();
+    let filename = match (filename, disposition.disposition) {
+        (Some(filename), _) => filename,
+        (None, DispositionType::Attachment) => format!("attachment-{}", index + 1),
+        (None, _) if include_inline => {
+            let content_id = part.headers.get_first_value("Content-ID")?;
+            content_id_file_name(&content_id, &part.ctype.mimetype)
+        }
+        _ => return None,
+    };
+    let body = part.get_body_raw().ok()?;
+    Some((filename, body))
+}
+
+/// The headers [`MailBox::download`] names the files with
+/// The INTERNALDATE of a fetched mail, which every FETCH of this module asks for
+fn internal_date_of(
+    uid: u32,
+    message: &imap::types::Fetch,
+) -> Result<chrono::DateTime<FixedOffset>, ClientError> {
+    message
+        .internal_date()
+        .ok_or_else(|| ClientError::InvalidMail(uid, "no INTERNALDATE".to_string()))
+}
+
+fn header_of(
+    uid: u32,
+    parsed: &ParsedMail,
+    internal_date: chrono::DateTime<FixedOffset>,
+) -> MailHeader {
+    MailHeader {
+        uid,
+        subject: collapse_whitespace(
+            &parsed
+                .headers
+                .get_first_value("Subject")
+                .unwrap_or_default(),
+        ),
+        from: collapse_whitespace(&parsed.headers.get_first_value("From").unwrap_or_default()),
+        internal_date,
+    }
+}
+
+/// A file name for an inline part, e.g. `banner.1@example.com.jpg` for the Content-ID
+/// `<banner.1@example.com>` of an `image/jpeg` part
+fn content_id_file_name(content_id: &str, mimetype: &str) -> String {
+    let name = content_id
+        .trim()
+        .trim_start_matches('<')
+        .trim_end_matches('>')
+        .replace(['/', '\\'], "_");
+    match mimetype.split_once('/') {
+        Some(("image", "jpeg")) => format!("{}.jpg", name),
+        Some(("image", subtype)) if !name.ends_with(&format!(".{}", subtype)) => {
+            format!("{}.{}", name, subtype)
+        }
+        _ => name,
+    }
+}
+
+/// What a search matches, independently of any connection: build it once and run it
+/// on any mail box with [`MailBox::search`] or [`MailBox::filter_by`]. It serializes to
+/// json, dates as RFC 3339 and `lag_over` in seconds. A regex subject that doesn't
+/// compile fails the deserialization.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(remote = "Self", deny_unknown_fields)]
+pub struct SearchCriteria {
+    subject_pattern: String,
+    #[serde(with = "rfc3339")]
+    start_datetime: chrono::DateTime<FixedOffset>,
+    #[serde(with = "rfc3339")]
+    end_datetime: chrono::DateTime<FixedOffset>,
+    #[serde(default)]
+    regex: bool,
+    #[serde(default)]
+    reverse: bool,
+    #[serde(default)]
+    raw_query: Option<String>,
+    #[serde(default)]
+    limit: Option<usize>,
+    #[serde(default = "default_window_days")]
+    window_days: u32,
+    #[serde(default)]
+    attachments_only: bool,
+    #[serde(default)]
+    attachment_type: Option<String>,
+    #[serde(default)]
+    since_uid: Option<u32>,
+    #[serde(default)]
+    max_uid: Option<u32>,
+    #[serde(default)]
+    larger: Option<u64>,
+    #[serde(default)]
+    smaller: Option<u64>,
+    #[serde(default)]
+    text: Option<String>,
+    #[serde(default)]
+    body_contains: Option<String>,
+    #[serde(default, with = "seconds_option")]
+    lag_over: Option<chrono::Duration>,
+    #[serde(default)]
+    max_body_bytes: Option<usize>,
+    #[serde(default)]
+    date_basis: DateBasis,
+    #[serde(default)]
+    flags: Vec<FlagKey>,
+    #[serde(skip)]
+    subject_regex: SubjectRegex,
+}
+
+impl Serialize for SearchCriteria {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        SearchCriteria::serialize(self, serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for SearchCriteria {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let criteria = SearchCriteria::deserialize(deserializer)?;
+        criteria.check().map_err(serde::de::Error::custom)?;
+        Ok(criteria)
+    }
+}
+
+fn default_window_days() -> u32 {
+    90
+}
+
+/// The subject regex of a [`SearchCriteria`], compiled on first use. Two criteria are
+/// equal whatever their caches hold.
+#[derive(Debug, Clone, Default)]
+struct SubjectRegex(OnceLock<regex::Regex>);
+
+impl PartialEq for SubjectRegex {
+    fn eq(&self, _: &Self) -> bool {
+        true
+    }
+}
+
+impl Eq for SubjectRegex {}
+
+/// The parts of a FETCH response of [`FETCH_QUERY`] a [`Mail`] is built from, whichever
+/// IMAP crate fetched it
+pub(crate) struct FetchedMail<'a> {
+    pub uid: Option<u32>,
+    pub internal_date: Option<chrono::DateTime<FixedOffset>>,
+    pub header: Option<&'a [u8]>,
+    pub text: Option<&'a [u8]>,
+    /// `None` without a BODYSTRUCTURE
+    pub attachments: Option<Vec<Attachment>>,
+    pub flags: Vec<String>,
+}
+
+/// A [`SearchCriteria`] run on a mail box
+pub struct MailFilter<'c> {
+    mail_box: &'c MailBox<'c>,
+    criteria: SearchCriteria,
+    /// Set once the server refused the TEXT/BODY keys, they are then matched on the
+    /// fetched mails
+    text_client_side: Cell<bool>,
+    progress: bool,
+    scanned: Cell<usize>,
+    warnings: RefCell<Vec<FetchWarning>>,
+}
+
+/// A mail left out of a search because it couldn't be fetched or parsed, the search
+/// goes on with the others
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct FetchWarning {
+    pub uid: u32,
+    pub reason: String,
+}
+
+impl Display for FetchWarning {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "skipped mail {}: {}", self.uid, printable(&self.reason))
+    }
+}
+
+/// The date the time window of a [`MailFilter`] is matched against
+#[derive(clap::ArgEnum, Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DateBasis {
+    /// INTERNALDATE, when the server received the mail
+    #[default]
+    Received,
+    /// The Date header, when the mail claims it was sent
+    Sent,
+}
+
+/// A flag search key, the mails have to match all those of a [`MailFilter`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FlagKey {
+    Seen,
+    Unseen,
+    Answered,
+    Unanswered,
+    Draft,
+    Undraft,
+    Flagged,
+    Unflagged,
+    /// Arrived since the previous session that selected the mail box
+    Recent,
+    /// Not recent
+    Old,
+}
+
+impl FlagKey {
+    /// The key of IMAP SEARCH
+    pub fn keyword(self) -> &'static str {
+        match self {
+            FlagKey::Seen => "SEEN",
+            FlagKey::Unseen => "UNSEEN",
+            FlagKey::Answered => "ANSWERED",
+            FlagKey::Unanswered => "UNANSWERED",
+            FlagKey::Draft => "DRAFT",
+            FlagKey::Undraft => "UNDRAFT",
+            FlagKey::Flagged => "FLAGGED",
+            FlagKey::Unflagged => "UNFLAGGED",
+            FlagKey::Recent => "RECENT",
+            FlagKey::Old => "OLD",
+        }
+    }
+}
+
+impl SearchCriteria {
+    /// The mails whose subject contains `subject_pattern` ignoring case, or matches it
+    /// as a regex with [`regex`](Self::regex), received from `start_datetime` on
+    pub fn new(subject_pattern: &str, start_datetime: chrono::DateTime<FixedOffset>) -> Self {
+        SearchCriteria {
+            subject_pattern: subject_pattern.to_string(),
+            start_datetime,
+            end_datetime: "9999-12-01T00:00:00Z"
+                .parse::<chrono::DateTime<FixedOffset>>()
+                .unwrap(),
+            regex: false,
+            reverse: false,
+            raw_query: None,
+            limit: None,
+            window_days: default_window_days(),
+            attachments_only: false,
+            attachment_type: None,
+            since_uid: None,
+            max_uid: None,
+            larger: None,
+            smaller: None,
+            text: None,
+            body_contains: None,
+            lag_over: None,
+            max_body_bytes: None,
+            date_basis: DateBasis::Received,
+            flags: vec![],
+            subject_regex: SubjectRegex::default(),
+        }
+    }
+
+    pub fn start_date(&mut self, start_datetime: chrono::DateTime<FixedOffset>) -> &mut Self {
+        self.start_datetime = start_datetime;
+        self
+    }
+
+    pub fn end_date(&mut self, end_datetime: chrono::DateTime<FixedOffset>) -> &mut Self {
+        self.end_datetime = end_datetime;
+        self
+    }
+
+    /// Match the subject pattern as a regex, see [`check`](Self::check)
+    pub fn regex(&mut self, regex: bool) -> &mut Self {
+        self.regex = regex;
+        self
+    }
+
+    /// Reject a regex subject pattern that doesn't compile, which would otherwise match
+    /// no mail
+    pub fn check(&self) -> Result<(), regex::Error> {
+        self.subject_regex().map(|_| ())
+    }
+
+    /// The compiled subject pattern, `None` unless it's a regex
+    fn subject_regex(&self) -> Result<Option<&regex::Regex>, regex::Error> {
+        if !self.regex || self.subject_pattern.is_empty() {
+            return Ok(None);
+        }
+        if let Some(regex) = self.subject_regex.0.get() {
+            return Ok(Some(regex));
+        }
+        let regex = regex::Regex::new(&self.subject_pattern)?;
+        Ok(Some(self.subject_regex.0.get_or_init(|| regex)))
+    }
+
+    pub fn reverse(&mut self, reserve: bool) -> &mut Self {
+        self.reverse = reserve;
+        self
+    }
+
+    pub fn limit(&mut self, limit: Option<usize>) -> &mut Self {
+        self.limit = limit;
+        self
+    }
+
+    /// Split date ranges longer than this many days into several SEARCH commands, which
+    /// keeps the responses small on big mail boxes. 90 by default, 0 never splits.
+    pub fn window_days(&mut self, window_days: u32) -> &mut Self {
+        self.window_days = window_days;
+        self
+    }
+
+    /// Append raw IMAP SEARCH criteria, e.g. `LARGER 5000000`, to the query as is.
+    /// The criteria are not validated.
+    pub fn raw_query(&mut self, raw_query: Option<String>) -> &mut Self {
+        self.raw_query = raw_query;
+        self
+    }
+
+    /// Drop mails without attachments. This is applied before the limit, so a limit
+    /// counts mails with attachments only.
+    pub fn attachments_only(&mut self, attachments_only: bool) -> &mut Self {
+        self.attachments_only = attachments_only;
+        self
+    }
+
+    /// Only match mails with at least one attachment whose MIME type matches the glob,
+    /// see [`content_type_matches`]. Applied before the limit like
+    /// [`attachments_only`](Self::attachments_only).
+    pub fn attachment_type(&mut self, attachment_type: Option<String>) -> &mut Self {
+        self.attachment_type = attachment_type;
+        self
+    }
+
+    /// Only match mails with a UID greater than `since_uid`, a cursor returned by
+    /// [`fetch_with_cursor`](MailFilter::fetch_with_cursor)
+    pub fn since_uid(&mut self, since_uid: Option<u32>) -> &mut Self {
+        self.since_uid = since_uid;
+        self
+    }
+
+    /// Only match mails with a UID up to `max_uid`
+    pub fn max_uid(&mut self, max_uid: Option<u32>) -> &mut Self {
+        self.max_uid = max_uid;
+        self
+    }
+
+    /// Only match mails larger than `larger` bytes, searched with `LARGER`
+    pub fn larger(&mut self, larger: Option<u64>) -> &mut Self {
+        self.larger = larger;
+        self
+    }
+
+    /// Only match mails smaller than `smaller` bytes, searched with `SMALLER`
+    pub fn smaller(&mut self, smaller: Option<u64>) -> &mut Self {
+        self.smaller = smaller;
+        self
+    }
+
+    /// Only match mails with all these flags set or unset, searched by the server
+    pub fn flags(&mut self, flags: Vec<FlagKey>) -> &mut Self {
+        self.flags = flags;
+        self
+    }
+
+    /// Only match mails containing `text` in their headers or body, searched with `TEXT`.
+    ///
+    /// If the server refuses the search with `BADCHARSET`, e.g. for Chinese terms, the
+    /// mails of the date range are fetched and matched case-insensitively instead,
+    /// which is much slower.
+    pub fn text(&mut self, text: Option<String>) -> &mut Self {
+        self.text = text;
+        self
+    }
+
+    /// Only match mails containing `body_contains` in their body, searched with `BODY`.
+    /// Falls back to matching client-side like [`text`](Self::text).
+    pub fn body_contains(&mut self, body_contains: Option<String>) -> &mut Self {
+        self.body_contains = body_contains;
+        self
+    }
+
+    /// Only match mails delivered more than `lag_over` after they were sent, see
+    /// [`Mail::lag`]. Applied client-side before the limit.
+    pub fn lag_over(&mut self, lag_over: Option<chrono::Duration>) -> &mut Self {
+        self.lag_over = lag_over;
+        self
+    }
+
+    /// Match the precise time window against the sent date instead of the delivery
+    /// date. The server still searches the days by delivery date, it can't do
+    /// otherwise, so the sent date only narrows that down. Mails without a valid Date
+    /// header are matched by their delivery date.
+    pub fn date_basis(&mut self, date_basis: DateBasis) -> &mut Self {
+        self.date_basis = date_basis;
+        self
+    }
+
+    /// Fetch at most `max_body_bytes` of the text of every mail, and cut
+    /// [`Mail::body`] down to that many bytes. The snippet and the client-side text
+    /// search only see that part.
+    pub fn max_body_bytes(&mut self, max_body_bytes: Option<usize>) -> &mut Self {
+        self.max_body_bytes = max_body_bytes;
+        self
+    }
+}
+
+impl<'c> MailFilter<'c> {
+    /// The criteria of the search
+    pub fn criteria(&self) -> &SearchCriteria {
+        &self.criteria
+    }
+
+    /// See [`SearchCriteria::start_date`]
+    pub fn start_date(&mut self, start_datetime: chrono::DateTime<FixedOffset>) -> &mut Self {
+        self.criteria.start_date(start_datetime);
+        self
+    }
+
+    /// See [`SearchCriteria::end_date`]
+    pub fn end_date(&mut self, end_datetime: chrono::DateTime<FixedOffset>) -> &mut Self {
+        self.criteria.end_date(end_datetime);
+        self
+    }
+
+    /// See [`SearchCriteria::regex`]
+    pub fn regex(&mut self, regex: bool) -> &mut Self {
+        self.criteria.regex(regex);
+        self
+    }
+
+    /// See [`SearchCriteria::reverse`]
+    pub fn reverse(&mut self, reserve: bool) -> &mut Self {
+        self.criteria.reverse(reserve);
+        self
+    }
+
+    /// See [`SearchCriteria::limit`]
+    pub fn limit(&mut self, limit: Option<usize>) -> &mut Self {
+        self.criteria.limit(limit);
+        self
+    }
+
+    /// See [`SearchCriteria::window_days`]
+    pub fn window_days(&mut self, window_days: u32) -> &mut Self {
+        self.criteria.window_days(window_days);
+        self
+    }
+
+    /// See [`SearchCriteria::raw_query`]
+    pub fn raw_query(&mut self, raw_query: Option<String>) -> &mut Self {
+        self.criteria.raw_query(raw_query);
+        self
+    }
+
+    /// See [`SearchCriteria::attachments_only`]
+    pub fn attachments_only(&mut self, attachments_only: bool) -> &mut Self {
+        self.criteria.attachments_only(attachments_only);
+        self
+    }
+
+    /// See [`SearchCriteria::attachment_type`]
+    pub fn attachment_type(&mut self, attachment_type: Option<String>) -> &mut Self {
+        self.criteria.attachment_type(attachment_type);
+        self
+    }
+
+    /// See [`SearchCriteria::since_uid`]
+    pub fn since_uid(&mut self, since_uid: Option<u32>) -> &mut Self {
+        self.criteria.since_uid(since_uid);
+        self
+    }
+
+    /// See [`SearchCriteria::max_uid`]
+    pub fn max_uid(&mut self, max_uid: Option<u32>) -> &mut Self {
+        self.criteria.max_uid(max_uid);
+        self
+    }
+
+    /// See [`SearchCriteria::larger`]
+    pub fn larger(&mut self, larger: Option<u64>) -> &mut Self {
+        self.criteria.larger(larger);
+        self
+    }
+
+    /// See [`SearchCriteria::smaller`]
+    pub fn smaller(&mut self, smaller: Option<u64>) -> &mut Self {
+        self.criteria.smaller(smaller);
+        self
+    }
+
+    /// See [`SearchCriteria::flags`]
+    pub fn flags(&mut self, flags: Vec<FlagKey>) -> &mut Self {
+        self.criteria.flags(flags);
+        self
+    }
+
+    /// See [`SearchCriteria::text`]
+    pub fn text(&mut self, text: Option<String>) -> &mut Self {
+        self.criteria.text(text);
+        self
+    }
+
+    /// See [`SearchCriteria::body_contains`]
+    pub fn body_contains(&mut self, body_contains: Option<String>) -> &mut Self {
+        self.criteria.body_contains(body_contains);
+        self
+    }
+
+    /// See [`SearchCriteria::lag_over`]
+    pub fn lag_over(&mut self, lag_over: Option<chrono::Duration>) -> &mut Self {
+        self.criteria.lag_over(lag_over);
+        self
+    }
+
+    /// See [`SearchCriteria::date_basis`]
+    pub fn date_basis(&mut self, date_basis: DateBasis) -> &mut Self {
+        self.criteria.date_basis(date_basis);
+        self
+    }
+
+    /// See [`SearchCriteria::max_body_bytes`]
+    pub fn max_body_bytes(&mut self, max_body_bytes: Option<usize>) -> &mut Self {
+        self.criteria.max_body_bytes(max_body_bytes);
+        self
+    }
+
+    /// Report every searched window on stderr when the range is split into several
+    pub fn progress(&mut self, progress: bool) -> &mut Self {
+        self.progress = progress;
+        self
+    }
+
+    /// Same as [`fetch`](Self::fetch), along with the cursor to pass to
+    /// [`since_uid`](Self::since_uid) next time to only get newer mails.
+    ///
+    /// The cursor is the last UID of the mail box at the time it was selected, capped
+    /// at [`max_uid`](Self::max_uid). A mail delivered in between the SELECT and the
+    /// search may be returned twice, but none is missed. Cursors are only valid as long
+    /// as [`MailBox::uid_validity`] doesn't change.
+    pub fn fetch_with_cursor(&self) -> Result<(Vec<Mail>, u32), ClientError> {
+        let mails = self.fetch()?;
+        let last_uid = self.mail_box.uid_next().unwrap_or(1).saturating_sub(1);
+        let cursor = match self.criteria.max_uid {
+            Some(max_uid) => last_uid.min(max_uid),
+            None => last_uid,
+        };
+        Ok((mails, cursor.max(self.criteria.since_uid.unwrap_or(0))))
+    }
+
+    /// Number of mails fetched so far, including those dropped by the client side
+    /// filters
+    pub fn scanned(&self) -> usize {
+        self.scanned.get()
+    }
+
+    /// The mails skipped so far because they couldn't be fetched or parsed
+    pub fn warnings(&self) -> Vec<FetchWarning> {
+        self.warnings.borrow().clone()
+    }
+
+    fn warn(&self, uid: u32, reason: String) {
+        self.warnings
+            .borrow_mut()
+            .push(FetchWarning { uid, reason });
+    }
+
+    /// Same as [`fetch`](Self::fetch), along with the mails it skipped, see
+    /// [`warnings`](Self::warnings)
+    pub fn fetch_with_warnings(&self) -> Result<(Vec<Mail>, Vec<FetchWarning>), ClientError> {
+        let mails = self.fetch()?;
+        Ok((mails, self.warnings()))
+    }
+
+    /// Fetch the matching mails, newest first unless reversed.
+    ///
+    /// This collects [`fetch_iter`](Self::fetch_iter) and sorts the result.
+    pub fn fetch(&self) -> Result<Vec<Mail>, ClientError> {
+        let mut mails = self.fetch_iter().collect::<Result<Vec<_>, _>>()?;
+        self.criteria.sort(&mut mails);
+
+        Ok(mails)
+    }
+
+    /// Lazily fetch the matching mails.
+    ///
+    /// Long date ranges are searched window by window (see
+    /// [`window_days`](Self::window_days)), and the SEARCH of a window is issued when the
+    /// iterator reaches it. The mails of a window are then fetched in chunks of
+    /// [`FETCH_CHUNK_SIZE`] and yielded as soon as a chunk has been parsed.
+    ///
+    /// Ordering guarantees:
+    /// - windows are visited in result order, newest first unless reversed, so every
+    ///   mail of a window comes before the mails of the next one;
+    /// - the mails of a chunk are sorted, but chunks of the same window are not sorted
+    ///   relative to each other unless the server sorted the window (see below);
+    /// - a globally sorted result is only available after collecting, see
+    ///   [`fetch`](Self::fetch).
+    ///
+    /// With a [`limit`](Self::limit) the iterator stops after that many mails, which
+    /// are exactly the first ones in result order: if the server advertises the SORT
+    /// extension, it sorts each window so that the chunks come in order and fetching
+    /// stops early, otherwise a whole window is fetched before any of its mails is
+    /// yielded.
+    ///
+    /// The session is only borrowed while a chunk is fetched, so the client can be used
+    /// in between, as long as the mail box stays selected.
+    pub fn fetch_iter(&self) -> MailIter<'_, 'c> {
+        let server_sort =
+            self.criteria.limit.is_some() && self.mail_box.client.has_capability("SORT");
+        let mut windows = self.criteria.windows();
+        if !self.criteria.has_uids_in_range(self.mail_box.uid_next()) {
+            windows.clear();
+        }
+
+        MailIter {
+            filter: self,
+            server_sort,
+            window_count: windows.len(),
+            windows: windows.into(),
+            uids: VecDeque::new(),
+            pending: vec![],
+            ready: VecDeque::new(),
+            remaining: self.criteria.limit.unwrap_or(usize::MAX),
+            done: false,
+        }
+    }
+
+    /// Build a [`Mail`] from a fetched message, see [`SearchCriteria::mail_of`]
+    fn parse(&self, message: &imap::types::Fetch) -> Result<Option<Mail>, String> {
+        let fetched = FetchedMail {
+            uid: message.uid,
+            internal_date: message.internal_date(),
+            header: message.header(),
+            text: message.text(),
+            attachments: message.bodystructure().map(attachments_of_structure),
+            flags: message
+                .flags()
+                .iter()
+                .map(|flag| flag.to_string())
+                .collect(),
+        };
+        self.criteria.mail_of(
+            fetched,
+            self.mail_box.name(),
+            self.mail_box.uid_validity(),
+            self.text_client_side.get(),
+        )
+    }
+
+    /// Count the matching mails.
+    ///
+    /// Unlike [`fetch`](Self::fetch) this leaves as much as possible to the server: a plain
+    /// ASCII subject is sent as a SUBJECT search key, and nothing is fetched when the date
+    /// window covers whole days of the server's timezone. Otherwise only the headers are fetched to apply the
+    /// remaining filters, with the BODYSTRUCTURE when the attachments are filtered on.
+    pub fn count(&self) -> Result<usize, ClientError> {
+        if !self.criteria.has_uids_in_range(self.mail_box.uid_next()) {
+            return Ok(0);
+        }
+        let mut session = self.mail_box.client.imap_session.borrow_mut();
+        let mut server_side_subject = false;
+        let uids = self.uid_search(&mut session, || {
+            let (query, server_side) = self
+                .criteria
+                .server_search_query(self.text_client_side.get());
+            server_side_subject = server_side;
+            query
+        })?;
+        if self.text_client_side.get() {
+            // the headers aren't enough to match the text
+            drop(session);
+            return self
+                .fetch_iter()
+                .try_fold(0, |count, mail| mail.map(|_| count + 1));
+        }
+        if uids.is_empty() || self.criteria.counted_by_search(server_side_subject) {
+            return Ok(uids.len());
+        }
+
+        let uids = uids.into_iter().collect::<Vec<_>>();
+        Ok(self.fetch_headers_of(&mut session, &uids)?.len())
+    }
+
+    /// Fetch only the Subject/From headers and INTERNALDATE of the matching mails,
+    /// which is much cheaper than [`fetch`](Self::fetch) when bodies are not needed.
+    pub fn fetch_headers(&self) -> Result<Vec<MailHeader>, ClientError> {
+        let mut session = self.mail_box.client.imap_session.borrow_mut();
+        let uids = self
+            .uid_search(&mut session, || {
+                let text_client_side = self.text_client_side.get();
+                self.criteria.server_search_query(text_client_side).0
+            })?
+            .into_iter()
+            .collect::<Vec<_>>();
+        if self.text_client_side.get() {
+            drop(session);
+            return self
+                .fetch_iter()
+                .map(|mail| mail.map(|mail| MailHeader::from(&mail)))
+                .collect();
+        }
+        self.fetch_headers_of(&mut session, &uids)
+    }
+
+    fn fetch_headers_of(
+        &self,
+        session: &mut Session,
+        uids: &[u32],
+    ) -> Result<Vec<MailHeader>, ClientError> {
+        if uids.is_empty() {
+            return Ok(vec![]);
+        }
+
+        let uids = uids
+            .iter()
+            .map(|s| s.to_string())
+            .collect::<Vec<_>>()
+            .join(",");
+        let messages = self
+            .mail_box
+            .client
+            .time(Stage::Fetch, || {
+                let query = self.criteria.header_fetch_query();
+                retry("UID FETCH", || session.uid_fetch(&uids, query))
+            })
+            .during("UID FETCH")?;
+
+        Ok(messages
+            .iter()
+            .filter_map(|message| {
+                let attachments = message
+                    .bodystructure()
+                    .map(attachments_of_structure)
+                    .unwrap_or_default();
+                self.criteria.header_of(
+                    message.uid?,
+                    message.internal_date()?,
+                    message.header(),
+                    &attachments,
+                )
+            })
+            .collect())
+    }
+
+    /// UID SEARCH the query built by `query`, declaring UTF-8 when it isn't plain ASCII.
+    ///
+    /// When the server refuses a UTF-8 search, e.g. with `NO [BADCHARSET]`, the search
+    /// is sent again without the TEXT/BODY keys, which are then matched client-side.
+    /// The imap crate drops the response code, so any NO counts as a refused charset.
+    fn uid_search(
+        &self,
+        session: &mut Session,
+        mut query: impl FnMut() -> String,
+    ) -> Result<HashSet<u32>, ClientError> {
+        let mut search = |query: String| {
+            let query = match query.is_ascii() {
+                true => query,
+                false => format!("CHARSET UTF-8 {}", query),
+            };
+            let client = self.mail_box.client;
+            client.time(Stage::Search, || {
+                retry("UID SEARCH", || session.uid_search(&query))
+            })
+        };
+        let first = query();
+        let query_utf8 = !first.is_ascii();
+        match search(first) {
+            Err(imap::Error::No(message))
+                if query_utf8 && self.criteria.searches_text() && !self.text_client_side.get() =>
+            {
+                warn_text_client_side(&message);
+                self.text_client_side.set(true);
+                search(query()).during("UID SEARCH")
+            }
+            result => result.during("UID SEARCH"),
+        }
+    }
+}
+
+/// The search of [`MailFilter`] and of its async mirror, which only differ in how they
+/// talk to the server
+impl SearchCriteria {
+    /// Build a [`Mail`] of the mail box `folder` from a fetched message, `None` when it
+    /// doesn't pass the filters. Fails with the reason when the message is malformed.
+    pub(crate) fn mail_of(
+        &self,
+        message: FetchedMail,
+        folder: &str,
+        uid_validity: Option<u32>,
+        text_client_side: bool,
+    ) -> Result<Option<Mail>, String> {
+        // UID FETCH responses always carry the UID
+        let uid = match message.uid {
+            Some(uid) => uid,
+            None => return Ok(None),
+        };
+        let date = message
+            .internal_date
+            .ok_or_else(|| "no INTERNALDATE".to_string())?;
+        // imap only can filter by date, so here we need to filter by time
+        if self.date_basis == DateBasis::Received && !self.in_time_range(&date) {
+            return Ok(None);
+        }
+        if self.since_uid.is_some_and(|since_uid| uid <= since_uid)
+            || self.max_uid.is_some_and(|max_uid| uid > max_uid)
+        {
+            return Ok(None);
+        }
+        let attachments = message
+            .attachments
+            .ok_or_else(|| "no BODYSTRUCTURE".to_string())?;
+
+        let header = message.header.ok_or_else(|| "no header".to_string())?;
+        let header_parsed =
+            mailparse::parse_mail(header).map_err(|err| format!("invalid header: {}", err))?;
+        let text = message.text.unwrap_or_default();
+        let raw = [header, text].concat();
+        let parsed =
+            mailparse::parse_mail(&raw).map_err(|err| format!("invalid message: {}", err))?;
+
+        let (sent_date, date_parse_error) = match header_parsed.headers.get_first_value("Date") {
+            Some(value) => match parse_sent_date(&value) {
+                Some(date) => (Some(date), None),
+                None => (
+                    None,
+                    Some(format!("invalid Date header `{}`", value.trim())),
+                ),
+            },
+            None => (None, None),
+        };
+        let body_part = body_part_of(&parsed);
+        let decoded = body_part
+            .map(|(part, _)| part.get_body().unwrap_or_default())
+            .unwrap_or_default();
+        let (mut body, mut raw_body) = match body_part.and_then(|(part, _)| flowed(part)) {
+            Some(delsp) => (unflow(&decoded, delsp), Some(decoded)),
+            None => (decoded, None),
+        };
+        let mut body_bytes = body_part
+            .map(|(part, _)| part.get_body_raw().unwrap_or_default())
+            .unwrap_or_default();
+        let body_truncated = match self.max_body_bytes {
+            Some(max_body_bytes) => {
+                let body_cut = truncate(&mut body, max_body_bytes);
+                if let Some(raw_body) = &mut raw_body {
+                    truncate(raw_body, max_body_bytes);
+                }
+                body_bytes.truncate(max_body_bytes);
+                text.len() > max_body_bytes || body_cut
+            }
+            None => false,
+        };
+        let mail = Mail {
+            uid,
+            uid_validity,
+            folder: Some(folder.to_string()),
+            folders: vec![],
+            also_in: vec![],
+            thread_id: None,
+            subject: header_parsed
+                .headers
+                .get_first_header("Subject")
+                .map(|h| collapse_whitespace(&h.get_value()))
+                .unwrap_or_default(),
+            from: header_parsed
+                .headers
+                .get_first_header("From")
+                .map(|h| collapse_whitespace(&h.get_value()))
+                .unwrap_or_default(),
+            from_address: header_parsed
+                .headers
+                .get_first_header("From")
+                .and_then(first_address),
+            sender: header_parsed
+                .headers
+                .get_first_header("Sender")
+                .map(|h| collapse_whitespace(&h.get_value())),
+            sender_address: header_parsed
+                .headers
+                .get_first_header("Sender")
+                .and_then(first_address),
+            reply_to: header_parsed
+                .headers
+                .get_first_header("Reply-To")
+                .map(|h| split_addresses(&h.get_value())),
+            to: split_addresses(
+                &header_parsed
+                    .headers
+                    .get_first_header("To")
+                    .map(|h| h.get_value())
+                    .unwrap_or_default(),
+            ),
+            cc: split_addresses(
+                &header_parsed
+                    .headers
+                    .get_first_header("Cc")
+                    .map(|h| h.get_value())
+                    .unwrap_or_default(),
+            ),
+            message_id: header_parsed
+                .headers
+                .get_first_header("Message-ID")
+                .map(|h| h.get_value().trim().to_string()),
+            in_reply_to: header_parsed
+                .headers
+                .get_first_header("In-Reply-To")
+                .map(|h| h.get_value().trim().to_string()),
+            references: header_parsed
+                .headers
+                .get_first_header("References")
+                .map(|h| {
+                    h.get_value()
+                        .split_whitespace()
+                        .map(|s| s.to_string())
+                        .collect()
+                })
+                .unwrap_or_default(),
+            snippet: snippet_of(&parsed),
+            body,
+            raw_body,
+            body_bytes,
+            body_kind: body_part.map(|(_, kind)| kind),
+            body_truncated,
+            internal_date: date,
+            sent_date,
+            date_parse_error,
+            flags: message.flags,
+            attachments,
+        };
+
+        if self.date_basis == DateBasis::Sent
+            && !self.in_time_range(&mail.sent_date.unwrap_or(mail.internal_date))
+        {
+            return Ok(None);
+        }
+        if !self.subject_matches(&mail.subject)
+            || !self.text_matches(&mail, &parsed, text_client_side)
+        {
+            return Ok(None);
+        }
+        if self.lag_over.is_some_and(|lag_over| mail.lag() <= lag_over)
+            || !self.attachments_match(&mail.attachments)
+        {
+            return Ok(None);
+        }
+
+        Ok(Some(mail))
+    }
+
+    /// Whether `attachments` pass [`attachments_only`](Self::attachments_only) and
+    /// [`attachment_type`](Self::attachment_type)
+    fn attachments_match(&self, attachments: &[Attachment]) -> bool {
+        if self.attachments_only && attachments.is_empty() {
+            return false;
+        }
+        match &self.attachment_type {
+            Some(pattern) => attachments
+                .iter()
+                .any(|attachment| content_type_matches(pattern, &attachment.content_type)),
+            None => true,
+        }
+    }
+
+    /// Whether the attachments are filtered on, which takes the BODYSTRUCTURE
+    fn filters_attachments(&self) -> bool {
+        self.attachments_only || self.attachment_type.is_some()
+    }
+
+    /// The SINCE/BEFORE windows to search in result order, see [`MailFilter::fetch_iter`]
+    pub(crate) fn windows(&self) -> Vec<(NaiveDate, NaiveDate)> {
+        let mut windows = date_windows(
+            self.start_datetime.date_naive(),
+            self.end_datetime.date_naive(),
+            chrono::Local::now().date_naive(),
+            self.window_days,
+        );
+        if !self.reverse {
+            windows.reverse();
+        }
+        windows
+    }
+
+    /// Sort `mails` newest first unless reversed
+    pub(crate) fn sort(&self, mails: &mut [Mail]) {
+        mails.sort_by_key(|v| -v.internal_date.timestamp());
+        if self.reverse {
+            mails.reverse()
+        }
+    }
+
+    pub(crate) fn result_limit(&self) -> Option<usize> {
+        self.limit
+    }
+
+    /// Whether the search has TEXT/BODY keys, which the server may refuse
+    pub(crate) fn searches_text(&self) -> bool {
+        self.text.is_some() || self.body_contains.is_some()
+    }
+
+    /// Whether the UID SEARCH of [`server_search_query`](Self::server_search_query)
+    /// found exactly the matching mails, given whether it searched the subject
+    pub(crate) fn counted_by_search(&self, server_side_subject: bool) -> bool {
+        // only whole days of the server line up with the SINCE and BEFORE it evaluated
+        let whole_days = [self.start_datetime, self.end_datetime].iter().all(|d| {
+            d.time() == chrono::NaiveTime::MIN && d.offset().local_minus_utc() == SERVER_UTC_OFFSET
+        }) && self.date_basis == DateBasis::Received;
+        // the attachments and the delivery lag aren't search keys
+        server_side_subject && whole_days && !self.filters_attachments() && self.lag_over.is_none()
+    }
+
+    /// What to fetch of the mails for [`header_of`](Self::header_of)
+    pub(crate) fn header_fetch_query(&self) -> &'static str {
+        match self.filters_attachments() {
+            true => HEADER_STRUCTURE_FETCH_QUERY,
+            false => HEADER_FETCH_QUERY,
+        }
+    }
+
+    /// The [`MailHeader`] of a mail fetched with [`header_fetch_query`](Self::header_fetch_query),
+    /// `None` when it doesn't pass the filters. `attachments` are those of the
+    /// BODYSTRUCTURE, when it was fetched.
+    pub(crate) fn header_of(
+        &self,
+        uid: u32,
+        internal_date: chrono::DateTime<FixedOffset>,
+        header: Option<&[u8]>,
+        attachments: &[Attachment],
+    ) -> Option<MailHeader> {
+        let headers = header.and_then(|header| mailparse::parse_mail(header).ok());
+        let header = |name| {
+            headers
+                .as_ref()
+                .and_then(|parsed| parsed.headers.get_first_value(name))
+                .unwrap_or_default()
+        };
+
+        let sent_date = parse_sent_date(&header("Date"));
+        let matched_date = match self.date_basis {
+            DateBasis::Received => internal_date,
+            DateBasis::Sent => sent_date.unwrap_or(internal_date),
+        };
+        if !self.in_time_range(&matched_date) {
+            return None;
+        }
+        let lag = sent_date.map_or(chrono::Duration::zero(), |sent_date| {
+            internal_date.signed_duration_since(sent_date)
+        });
+        if self.lag_over.is_some_and(|lag_over| lag <= lag_over)
+            || !self.attachments_match(attachments)
+        {
+            return None;
+        }
+
+        let mail = MailHeader {
+            uid,
+            subject: collapse_whitespace(&header("Subject")),
+            from: collapse_whitespace(&header("From")),
+            internal_date,
+        };
+        self.subject_matches(&mail.subject).then_some(mail)
+    }
+
+    /// The search query with the subject pushed to the server when the server can
+    /// evaluate it, returning whether it did
+    pub(crate) fn server_search_query(&self, text_client_side: bool) -> (String, bool) {
+        let server_side_subject = !self.regex && self.subject_pattern.is_ascii();
+        let mut query = self.search_query(text_client_side);
+        if server_side_subject && !self.subject_pattern.is_empty() {
+            query = format!("{} SUBJECT {}", query, quote(&self.subject_pattern));
+        }
+        (query, server_side_subject)
+    }
+
+    fn search_query(&self, text_client_side: bool) -> String {
+        let today = chrono::Local::now().date_naive();
+        self.search_query_between(
+            self.start_datetime.date_naive(),
+            self.end_datetime.date_naive().min(latest_before(today)),
+            text_client_side,
+        )
+    }
+
+    pub(crate) fn search_query_between(
+        &self,
+        since: NaiveDate,
+        before: NaiveDate,
+        text_client_side: bool,
+    ) -> String {
+        let mut query = format!(
+            "SINCE {} BEFORE {}",
+            since.format("%d-%b-%Y"),
+            before.format("%d-%b-%Y")
+        );
+        if self.since_uid.is_some() || self.max_uid.is_some() {
+            let first = self.since_uid.map_or(1, |since_uid| since_uid + 1);
+            match self.max_uid {
+                Some(max_uid) => query = format!("{} UID {}:{}", query, first, max_uid),
+                None => query = format!("{} UID {}:*", query, first),
+            }
+        }
+        if let Some(larger) = self.larger {
+            query = format!("{} LARGER {}", query, larger);
+        }
+        if let Some(smaller) = self.smaller {
+            query = format!("{} SMALLER {}", query, smaller);
+        }
+        for flag in self.flags.iter() {
+            query = format!("{} {}", query, flag.keyword());
+        }
+        if !text_client_side {
+            if let Some(text) = &self.text {
+                query = format!("{} TEXT {}", query, quote(text));
+            }
+            if let Some(body_contains) = &self.body_contains {
+                query = format!("{} BODY {}", query, quote(body_contains));
+            }
+        }
+        if let Some(raw_query) = &self.raw_query {
+            query = format!("{} {}", query, raw_query);
+        }
+        query
+    }
+
+    pub(crate) fn fetch_query(&self) -> String {
+        match self.max_body_bytes {
+            // a byte more tells whether the text was cut
+            Some(max_body_bytes) => FETCH_QUERY.replace(
+                "BODY[TEXT]",
+                &format!("BODY.PEEK[TEXT]<0.{}>", max_body_bytes + 1),
+            ),
+            None => FETCH_QUERY.to_string(),
+        }
+    }
+
+    /// Whether any UID can fall into the since/max UID range. `UID n:*` matches the last
+    /// mail even when n is past it, so an empty range must not be searched at all. A
+    /// range up to `max_uid` may go past the UIDNEXT of the last SELECT, for the mails
+    /// delivered since, `uid_next` is that of the mail box.
+    pub(crate) fn has_uids_in_range(&self, uid_next: Option<u32>) -> bool {
+        let first = self.since_uid.map_or(1, |since_uid| since_uid + 1);
+        let beyond_last =
+            self.max_uid.is_none() && uid_next.is_some_and(|uid_next| first >= uid_next);
+        let empty = self.max_uid.is_some_and(|max_uid| first > max_uid);
+        !(beyond_last || empty)
+    }
+
+    fn in_time_range(&self, date: &chrono::DateTime<FixedOffset>) -> bool {
+        date.timestamp() >= self.start_datetime.timestamp()
+            && date.timestamp() <= self.end_datetime.timestamp()
+    }
+
+    /// Whether the text and body terms are found in `mail`, parsed as `parsed`, only
+    /// checked once the server couldn't search them
+    fn text_matches(&self, mail: &Mail, parsed: &ParsedMail, text_client_side: bool) -> bool {
+        if !text_client_side {
+            return true;
+        }
+        let body = text_of(parsed);
+        let contains =
+            |haystack: &str, needle: &str| haystack.to_lowercase().contains(&needle.to_lowercase());
+        let body_matches = self
+            .body_contains
+            .as_ref()
+            .is_none_or(|term| contains(&body, term));
+        let text_matches = self.text.as_ref().is_none_or(|term| {
+            [&mail.subject, &mail.from, &body]
+                .into_iter()
+                .chain(mail.to.iter())
+                .chain(mail.cc.iter())
+                .any(|field| contains(field, term))
+        });
+        body_matches && text_matches
+    }
+
+    fn subject_matches(&self, subject: &str) -> bool {
+        if self.subject_pattern.is_empty() {
+            // no subject filter
+            return true;
+        }
+        if self.regex {
+            self.subject_regex()
+                .is_ok_and(|regex| regex.is_some_and(|regex| regex.is_match(subject)))
+        } else {
+            // case-insensitive like the SUBJECT key of the server
+            subject
+                .to_lowercase()
+                .contains(&self.subject_pattern.to_lowercase())
+        }
+    }
+}
+
+/// Tell that the server refused to search the text with `message`, see
+/// [`MailFilter::uid_search`]
+pub(crate) fn warn_text_client_side(message: &str) {
+    eprintln!(
+        "warning: the server can't search the text ({}), filtering the mails client-side, \
+         which is slower",
+        message.trim()
+    );
+}
+
+/// The attachments listed by a BODYSTRUCTURE of the imap crate, at any depth, as
+/// [`parts_of`] finds them
+fn attachments_of_structure(bodystructure: &imap_proto::BodyStructure) -> Vec<Attachment> {
+    let mut parts = vec![];
+    parts_of(bodystructure, vec![], false, None, &mut parts);
+    parts
+        .into_iter()
+        .map(|part| Attachment::new(part.name, part.size.try_into().ok(), part.content_type))
+        .collect()
+}
+
+/// Number of mails fetched by a single FETCH command of [`MailFilter::fetch_iter`]
+pub const FETCH_CHUNK_SIZE: usize = 50;
+
+/// What [`MailFilter::count`] and [`MailFilter::fetch_headers`] fetch of a mail
+const HEADER_FETCH_QUERY: &str = "(INTERNALDATE BODY.PEEK[HEADER.FIELDS (SUBJECT FROM DATE)])";
+
+/// [`HEADER_FETCH_QUERY`] with the attachments
+const HEADER_STRUCTURE_FETCH_QUERY: &str =
+    "(INTERNALDATE BODY.PEEK[HEADER.FIELDS (SUBJECT FROM DATE)] BODYSTRUCTURE)";
+
+const FETCH_QUERY: &str = "(UID INTERNALDATE FLAGS BODY[HEADER.FIELDS (SUBJECT FROM DATE SENDER REPLY-TO CC TO \
+                           MESSAGE-ID IN-REPLY-TO REFERENCES CONTENT-TYPE)] BODY[TEXT] BODYSTRUCTURE)";
+
+/// Iterator returned by [`MailFilter::fetch_iter`]
+pub struct MailIter<'f, 'c> {
+    filter: &'f MailFilter<'c>,
+    server_sort: bool,
+    window_count: usize,
+    windows: VecDeque<(NaiveDate, NaiveDate)>,
+    // searched but not fetched yet
+    uids: VecDeque<u32>,
+    // fetched, but held back until the whole window is known
+    pending: Vec<Mail>,
+    ready: VecDeque<Mail>,
+    remaining: usize,
+    done: bool,
+}
+
+impl MailIter<'_, '_> {
+    /// Fill `ready` with the next sorted batch of mails, returns false once exhausted
+    fn fill(&mut self) -> Result<bool, ClientError> {
+        // without the server sorting, a window has to be fetched completely to know
+        // which of its mails make it into the limit
+        let whole_window = self.filter.criteria.limit.is_some() && !self.server_sort;
+        let mut session = self.filter.mail_box.client.imap_session.borrow_mut();
+
+        loop {
+            if self.uids.is_empty() {
+                if !self.pending.is_empty() {
+                    break;
+                }
+                let (since, before) = match self.windows.pop_front() {
+                    Some(window) => window,
+                    None => return Ok(false),
+                };
+                let text_client_side = self.filter.text_client_side.get();
+                let query =
+                    self.filter
+                        .criteria
+                        .search_query_between(since, before, text_client_side);
+                if self.server_sort {
+                    let client = self.filter.mail_box.client;
+                    self.uids = client
+                        .time(Stage::Search, || {
+                            retry("UID SORT", || {
+                                sort(&mut session, self.filter.criteria.reverse, &query)
+                            })
+                        })
+                        .during("UID SORT")?
+                        .into();
+                } else {
+                    self.uids = self
+                        .filter
+                        .uid_search(&mut session, || {
+                            let text_client_side = self.filter.text_client_side.get();
+                            self.filter.criteria.search_query_between(
+                                since,
+                                before,
+                                text_client_side,
+                            )
+                        })?
+                        .into_iter()
+                        .collect();
+                }
+                if self.filter.progress && self.window_count > 1 {
+                    eprintln!(
+                        "search: window {}/{} from {} to {}, {} mails to fetch",
+                        self.window_count - self.windows.len(),
+                        self.window_count,
+                        since,
+                        before.pred_opt().unwrap_or(before),
+                        self.uids.len()
+                    );
+                }
+                continue;
+            }
+
+            let chunk = self
+                .uids
+                .drain(..self.uids.len().min(FETCH_CHUNK_SIZE))
+                .collect::<Vec<_>>();
+            let query = self.filter.criteria.fetch_query();
+            let mut batches = vec![chunk];
+            while let Some(uids) = batches.pop() {
+                let set = uids
+                    .iter()
+                    .map(|uid| uid.to_string())
+                    .collect::<Vec<_>>()
+                    .join(",");
+                let client = self.filter.mail_box.client;
+                let messages = match client
+                    .time(Stage::Fetch, || {
+                        retry("UID FETCH", || session.uid_fetch(&set, &query))
+                    })
+                    .during(format_args!("UID FETCH {}", set))
+                {
+                    Ok(messages) => messages,
+                    // a single mail the server refuses fails the whole chunk, fetch them
+                    // one by one to skip only that one
+                    Err(ClientError::Imap(_, err))
+                        if matches!(err, imap::Error::No(_)) && !is_throttled(&err) =>
+                    {
+                        match uids.len() {
+                            1 => self.filter.warn(uids[0], err.to_string()),
+                            _ => batches.extend(uids.into_iter().rev().map(|uid| vec![uid])),
+                        }
+                        continue;
+                    }
+                    Err(err) => return Err(err),
+                };
+                self.filter
+                    .scanned
+                    .set(self.filter.scanned.get() + messages.len());
+                for message in messages.iter() {
+                    match client.time(Stage::Parse, || self.filter.parse(message)) {
+                        Ok(mail) => self.pending.extend(mail),
+                        Err(reason) => self.filter.warn(message.uid.unwrap_or_default(), reason),
+                    }
+                }
+            }
+
+            if !whole_window || self.uids.is_empty() {
+                break;
+            }
+        }
+
+        let mut mails = std::mem::take(&mut self.pending);
+        self.filter.criteria.sort(&mut mails);
+        self.ready.extend(mails);
+        Ok(true)
+    }
+}
+
+impl Iterator for MailIter<'_, '_> {
+    type Item = Result<Mail, ClientError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while !self.done && self.remaining > 0 {
+            if let Some(mail) = self.ready.pop_front() {
+                self.remaining -= 1;
+                return Some(Ok(mail));
+            }
+
+            match self.fill() {
+                Ok(true) => {}
+                Ok(false) => self.done = true,
+                Err(err) => {
+                    self.done = true;
+                    return Some(Err(err));
+                }
+            }
+        }
+
+        None
+    }
+}
+
+/// The latest BEFORE date sent to the server when searching up to `before`: nothing
+/// was delivered after `today`, give or take a day for timezones. Some servers reject
+/// far future dates such as the `9999-12-31` default end date, the end of the time
+/// window is still matched client-side.
+fn latest_before(today: NaiveDate) -> NaiveDate {
+    today + chrono::Duration::days(2)
+}
+
+/// Split the SINCE/BEFORE date range `[since, before)` into consecutive windows of at
+/// most `days` days, oldest first.
+///
+/// The range ends at [`latest_before`] at the latest, so the far future default end
+/// date neither creates thousands of empty windows nor reaches the server.
+fn date_windows(
+    since: NaiveDate,
+    before: NaiveDate,
+    today: NaiveDate,
+    days: u32,
+) -> Vec<(NaiveDate, NaiveDate)> {
+    let before = before.min(latest_before(today));
+    let mut windows = vec![];
+    let mut from = since;
+    while days > 0 && from < before {
+        let to = (from + chrono::Duration::days(days as i64)).min(before);
+        windows.push((from, to));
+        from = to;
+    }
+
+    if windows.is_empty() {
+        windows.push((since, before));
+    }
+    windows
+}
+
+/// Run a UID SORT command by arrival date, newest first unless reversed
+fn sort(session: &mut Session, reverse: bool, query: &str) -> Result<Vec<u32>, imap::Error> {
+    let keys = if reverse {
+        "ARRIVAL"
+    } else {
+        "REVERSE ARRIVAL"
+    };
+    let response =
+        session.run_command_and_read_response(format!("UID SORT ({}) UTF-8 {}", keys, query))?;
+
+    Ok(String::from_utf8_lossy(&response)
+        .lines()
+        .filter_map(|line| line.strip_prefix("* SORT"))
+        .flat_map(|ids| {
+            ids.split_whitespace()
+                .filter_map(|id| id.parse::<u32>().ok())
+                .collect::<Vec<_>>()
+        })
+        .collect())
+}
+
+/// SELECT the mail box `raw_name` over the session of `client`, or EXAMINE it when
+/// `read_only`, and start counting its mails in [`Client::exists`]
+fn open(
+    session: &mut Session,
+    client: &Client,
+    raw_name: &str,
+    read_only: bool,
+) -> Result<imap::types::Mailbox, ClientError> {
+    let mail_box = client.time(Stage::Select, || {
+        if read_only {
+            retry("EXAMINE", || session.examine(raw_name))
+                .during(format_args!("EXAMINE {}", raw_name))
+        } else {
+            retry("SELECT", || session.select(raw_name)).during(format_args!("SELECT {}", raw_name))
+        }
+    })?;
+    // whatever came before is about the previous mail box
+    track(session, &client.exists);
+    client.exists.set(Some(mail_box.exists));
+    Ok(mail_box)
+}
+
+/// Follow the EXISTS and EXPUNGE responses received so far in `exists`, returns the
+/// other unsolicited responses
+fn track(session: &mut Session, exists: &Cell<Option<u32>>) -> Vec<UnsolicitedResponse> {
+    let mut others = vec![];
+    for response in session.unsolicited_responses.try_iter() {
+        match response {
+            UnsolicitedResponse::Exists(n) => exists.set(Some(n)),
+            UnsolicitedResponse::Expunge(_) => {
+                exists.set(exists.get().map(|n| n.saturating_sub(1)))
+            }
+            other => others.push(other),
+        }
+    }
+    others
+}
+
+/// Quote a string for use in an IMAP command
+fn quote(value: &str) -> String {
+    format!("\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+impl Drop for Client {
+    fn drop(&mut self) {
+        if self.logged_out {
+            return;
+        }
+        // best effort, a server that doesn't answer doesn't hold up the exit
+        if let Some(socket) = &self.socket {
+            let _ = socket.set_read_timeout(Some(LOGOUT_TIMEOUT));
+            let _ = socket.set_write_timeout(Some(LOGOUT_TIMEOUT));
+        }
+        let _ = self.close();
+    }
+}
+
+impl Display for MailBox<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.info().fmt(f)
+    }
+}
+
+/// The state of a mail box as reported by SELECT, see [`MailBox::info`]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct MailboxInfo {
+    /// The decoded name
+    pub name: String,
+    pub flags: Vec<String>,
+    pub exists: u32,
+    pub recent: u32,
+    /// The sequence number of the first mail not read yet, see [`MailBox::unseen`]
+    pub unseen: Option<u32>,
+    pub permanent_flags: Vec<String>,
+    pub uid_next: Option<u32>,
+    pub uid_validity: Option<u32>,
+}
+
+impl Display for MailboxInfo {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "name: {}, flags: {:?}, exists: {}, recent: {}, unseen: {:?}, permanent_flags: {:?}, \
+             uid_next: {:?}, uid_validity: {:?}",
+            self.name,
+            self.flags,
+            self.exists,
+            self.recent,
+            self.unseen,
+            self.permanent_flags,
+            self.uid_next,
+            self.uid_validity
+        )
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Mail {
+    pub subject: String,
+    /// The raw From header, see `from_address` for its parts
+    pub from: String,
+    /// The sender parsed out of the From header, `None` when it has no address or is
+    /// malformed
+    #[serde(default)]
+    pub from_address: Option<Address>,
+    /// The raw Sender header, see `sender_address` for its parts
+    pub sender: Option<String>,
+    /// The mailbox parsed out of the Sender header, like `from_address`
+    #[serde(default)]
+    pub sender_address: Option<Address>,
+    pub reply_to: Option<Vec<String>>,
+    pub to: Vec<String>,
+    pub cc: Vec<String>,
+    pub message_id: Option<String>,
+    pub in_reply_to: Option<String>,
+    pub references: Vec<String>,
+    #[serde(rename = "id")]
+    pub uid: u32,
+    /// UIDVALIDITY of the mail box, `uid` is only a stable id while this doesn't change
+    #[serde(default)]
+    pub uid_validity: Option<u32>,
+    /// The text of the body part, decoded from its transfer encoding and charset, with
+    /// its lines joined when it's `format=flowed`
+    pub body: String,
+    /// `body` before its flowed lines were joined, `None` when it wasn't flowed, see
+    /// [`Mail::body_raw`]
+    #[serde(skip)]
+    pub(crate) raw_body: Option<String>,
+    /// The undecoded bytes of the part `body` was decoded from, in its original
+    /// charset, cut to the same [`MailFilter::max_body_bytes`]. Left out of the json
+    /// output, which has `body`.
+    #[serde(skip)]
+    pub body_bytes: Vec<u8>,
+    /// The kind of part `body` was taken from, `None` when the mail has no text part
+    /// and `body` is empty
+    #[serde(default)]
+    pub body_kind: Option<BodyKind>,
+    /// Whether `body` was cut by [`MailFilter::max_body_bytes`]. The whole text counts,
+    /// so a mail with attachments may be marked even though its body is complete.
+    #[serde(default)]
+    pub body_truncated: bool,
+    /// The first [`SNIPPET_LENGTH`] characters of the plain text body
+    pub snippet: String,
+    #[serde(rename = "date", with = "rfc3339")]
+    pub internal_date: chrono::DateTime<FixedOffset>,
+    /// When the mail claims it was sent, from the Date header
+    #[serde(default, with = "rfc3339_option")]
+    pub sent_date: Option<chrono::DateTime<FixedOffset>>,
+    /// Why the Date header couldn't be parsed into `sent_date`
+    #[serde(default)]
+    pub date_parse_error: Option<String>,
+    /// Flags such as `\\Seen`, as of the fetch
+    #[serde(default)]
+    pub flags: Vec<String>,
+    /// The decoded name of the mail box the mail was found in
+    #[serde(default)]
+    pub folder: Option<String>,
+    /// Every mail box a copy of the mail was found in when copies are collapsed, see
+    /// [`crate::search::dedup`], empty otherwise
+    #[serde(default)]
+    pub folders: Vec<String>,
+    /// The mail boxes of the copies collapsed into this one, `folders` without `folder`
+    #[serde(default)]
+    pub also_in: Vec<String>,
+    /// The conversation thread of the mail among the mails of the search, see
+    /// [`crate::thread::assign_ids`]; `None` when the mails were streamed
+    #[serde(default)]
+    pub thread_id: Option<String>,
+    pub attachments: Vec<Attachment>,
+}
+
+impl Mail {
+    /// The body as sent, with the line breaks of a `format=flowed` body kept. See
+    /// `body_bytes` for the bytes before any decoding.
+    pub fn body_raw(&self) -> &str {
+        self.raw_body.as_deref().unwrap_or(&self.body)
+    }
+
+    /// How long after it was sent the server received the mail, zero without a valid
+    /// Date header
+    pub fn lag(&self) -> chrono::Duration {
+        match self.sent_date {
+            Some(sent_date) => self.internal_date.signed_duration_since(sent_date),
+            None => chrono::Duration::zero(),
+        }
+    }
+
+    pub fn is_seen(&self) -> bool {
+        self.flags.iter().any(|flag| flag == "\\Seen")
+    }
+
+    /// Record a [`MailBox::set_seen`] without fetching the mail again
+    pub fn set_seen(&mut self, seen: bool) {
+        self.flags.retain(|flag| flag != "\\Seen");
+        if seen {
+            self.flags.push("\\Seen".to_string());
+        }
+    }
+}
+
+/// The decoded headers followed by the body
+impl Display for Mail {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        // the headers are made printable, the body is kept as is for the pipes
+        writeln!(f, "Subject: {}", printable(&self.subject))?;
+        writeln!(f, "From: {}", printable(&self.from))?;
+        if !self.to.is_empty() {
+            writeln!(f, "To: {}", printable(&self.to.join(", ")))?;
+        }
+        if !self.cc.is_empty() {
+            writeln!(f, "Cc: {}", printable(&self.cc.join(", ")))?;
+        }
+        writeln!(f, "Date: {}", self.internal_date.to_rfc3339())?;
+        writeln!(f, "UID: {}", self.uid)?;
+        for attachment in self.attachments.iter() {
+            writeln!(f, "Attachment: {}", printable(&attachment.name))?;
+        }
+        writeln!(f)?;
+        write!(f, "{}", self.body)
+    }
+}
+
+/// (De)serialize dates as RFC 3339 strings
+pub(crate) mod rfc3339 {
+    use chrono::{DateTime, FixedOffset};
+    use serde::{de::Error, Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(
+        date: &DateTime<FixedOffset>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&date.to_rfc3339())
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<DateTime<FixedOffset>, D::Error> {
+        let date = String::deserialize(deserializer)?;
+        DateTime::parse_from_rfc3339(&date).map_err(D::Error::custom)
+    }
+}
+
+mod rfc3339_option {
+    use chrono::{DateTime, FixedOffset};
+    use serde::{de::Error, Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(
+        date: &Option<DateTime<FixedOffset>>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        match date {
+            Some(date) => serializer.serialize_str(&date.to_rfc3339()),
+            None => serializer.serialize_none(),
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Option<DateTime<FixedOffset>>, D::Error> {
+        Option::<String>::deserialize(deserializer)?
+            .map(|date| DateTime::parse_from_rfc3339(&date).map_err(D::Error::custom))
+            .transpose()
+    }
+}
+
+mod seconds_option {
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(
+        duration: &Option<chrono::Duration>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        match duration {
+            Some(duration) => serializer.serialize_i64(duration.num_seconds()),
+            None => serializer.serialize_none(),
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Option<chrono::Duration>, D::Error> {
+        Ok(Option::<i64>::deserialize(deserializer)?.map(chrono::Duration::seconds))
+    }
+}
+
+/// The date of a Date header, in its own timezone when it's valid RFC 2822, otherwise
+/// in UTC as far as mailparse makes sense of it
+fn parse_sent_date(value: &str) -> Option<chrono::DateTime<FixedOffset>> {
+    let value = value.trim();
+    if let Ok(date) = chrono::DateTime::parse_from_rfc2822(value) {
+        return Some(date);
+    }
+    // mailparse skips the words it doesn't know, down to the epoch for no date at all
+    let timestamp = mailparse::dateparse(value).ok().filter(|t| *t > 0)?;
+    chrono::DateTime::from_timestamp(timestamp, 0).map(|date| date.fixed_offset())
+}
+
+/// Cut `text` down to at most `max_bytes` bytes on a character boundary, returns
+/// whether anything was cut
+fn truncate(text: &mut String, max_bytes: usize) -> bool {
+    if text.len() <= max_bytes {
+        return false;
+    }
+    let mut end = max_bytes;
+    while !text.is_char_boundary(end) {
+        end -= 1;
+    }
+    text.truncate(end);
+    true
+}
+
+/// Number of characters kept in [`Mail::snippet`]
+pub const SNIPPET_LENGTH: usize = 200;
+
+/// Build a short preview of a mail with whitespace collapsed. The text/plain part is
+/// preferred, an HTML part has its tags stripped.
+fn snippet_of(mail: &ParsedMail) -> String {
+    text_of(mail)
+        .split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ")
+        .chars()
+        .take(SNIPPET_LENGTH)
+        .collect()
+}
+
+/// The plain text body of a mail, or its HTML body without the tags
+fn text_of(mail: &ParsedMail) -> String {
+    match body_part_of(mail) {
+        Some((part, BodyKind::Plain)) => {
+            let body = part.get_body().unwrap_or_default();
+            match flowed(part) {
+                Some(delsp) => unflow(&body, delsp),
+                None => body,
+            }
+        }
+        Some((part, BodyKind::Html)) => strip_tags(&part.get_body().unwrap_or_default()),
+        None => String::new(),
+    }
+}
+
+/// Whether a text part is `format=flowed`, and if so whether its flowed lines end
+/// with a space to delete, `delsp=yes`
+fn flowed(part: &ParsedMail) -> Option<bool> {
+    let is = |param: &str, value: &str| {
+        part.ctype
+            .params
+            .get(param)
+            .is_some_and(|param| param.eq_ignore_ascii_case(value))
+    };
+    is("format", "flowed").then(|| is("delsp", "yes"))
+}
+
+/// The kind of part the body of a mail is taken from
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum BodyKind {
+    Plain,
+    Html,
+}
+
+/// The part holding the body of a mail: the best scored leaf, see [`body_score`], the
+/// first one of equal scores
+fn body_part_of<'a>(mail: &'a ParsedMail<'a>) -> Option<(&'a ParsedMail<'a>, BodyKind)> {
+    let mut best: Option<(&ParsedMail, BodyKind, u8)> = None;
+    for part in leaves(mail) {
+        if let Some((kind, score)) = body_score(part) {
+            if best.is_none_or(|(_, _, best)| score > best) {
+                best = Some((part, kind, score));
+            }
+        }
+    }
+    best.map(|(part, kind, _)| (part, kind))
+}
+
+/// How fit a leaf part is to hold the body, `None` when it isn't text. Plain text
+/// beats HTML, and any inline part beats one attached or carrying a file name, such
+/// as a forwarded `.txt`.
+fn body_score(part: &ParsedMail) -> Option<(BodyKind, u8)> {
+    let (kind, score) = match part.ctype.mimetype.as_str() {
+        "text/plain" => (BodyKind::Plain, 2),
+        "text/html" => (BodyKind::Html, 1),
+        _ => return None,
+    };
+    let disposition = part.get_content_disposition();
+    let attached = disposition.disposition != DispositionType::Inline
+        || disposition.params.contains_key("filename")
+        || part.ctype.params.contains_key("name");
+    Some((kind, if attached { score } else { score + 2 }))
+}
+
+/// Ends a body cut by [`preview`]
+pub const PREVIEW_MARKER: &str = "…";
+
+/// Remove the quoted reply history from a mail body: lines starting with `>` and the
+/// "On ... wrote:" lines introducing them, which may be wrapped over two lines
+pub fn strip_quotes(body: &str) -> String {
+    static ATTRIBUTION: OnceLock<regex::Regex> = OnceLock::new();
+    let attribution = ATTRIBUTION
+        .get_or_init(|| regex::Regex::new(r"(?i)^(on\b.*)?\bwrote:$|^在.*写道[:：]$").unwrap());
+    let mut lines: Vec<&str> = vec![];
+    for line in body.lines() {
+        let trimmed = line.trim();
+        if trimmed.starts_with('>') {
+            continue;
+        }
+        if attribution.is_match(trimmed) {
+            if !trimmed.to_lowercase().starts_with("on ") && !trimmed.starts_with('在') {
+                // the first half of a wrapped "On ..., Alice <alice@qq.com>\nwrote:"
+                if let Some(previous) = lines.last() {
+                    if previous.trim().to_lowercase().starts_with("on ") {
+                        lines.pop();
+                    }
+                }
+            }
+            continue;
+        }
+        lines.push(line);
+    }
+
+    while lines.last().is_some_and(|line| line.trim().is_empty()) {
+        lines.pop();
+    }
+    lines.join("\n")
+}
+
+/// The first `lines` non-empty lines of a mail body, with a [`PREVIEW_MARKER`] line
+/// when more text was cut
+pub fn preview(body: &str, lines: usize) -> String {
+    let mut kept = 0;
+    let mut end = 0;
+    for line in body.split_inclusive('\n') {
+        if !line.trim().is_empty() {
+            if kept == lines {
+                return format!("{}\n{}", body[..end].trim_end(), PREVIEW_MARKER);
+            }
+            kept += 1;
+        }
+        end += line.len();
+    }
+    body.to_string()
+}
+
+fn strip_tags(html: &str) -> String {
+    static INVISIBLE: OnceLock<regex::Regex> = OnceLock::new();
+    static TAG: OnceLock<regex::Regex> = OnceLock::new();
+    let invisible = INVISIBLE.get_or_init(|| {
+        regex::Regex::new(r"(?is)<(style|script|head)\b.*?</(style|script|head)>").unwrap()
+    });
+    let tag = TAG.get_or_init(|| regex::Regex::new(r"(?s)<[^>]*>").unwrap());
+    let text = invisible.replace_all(html, " ");
+    tag.replace_all(&text, " ")
+        .replace("&nbsp;", " ")
+        .replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&amp;", "&")
+}
+
+/// Whether the greeting advertises the LOGINDISABLED capability, e.g.
+/// `* OK [CAPABILITY IMAP4rev1 LOGINDISABLED] ready`
+pub(crate) fn login_disabled(greeting: &[u8]) -> bool {
+    let greeting = String::from_utf8_lossy(greeting).to_uppercase();
+    greeting
+        .split_once("[CAPABILITY ")
+        .and_then(|(_, rest)| rest.split_once(']'))
+        .is_some_and(|(capabilities, _)| {
+            capabilities
+                .split_whitespace()
+                .any(|capability| capability == "LOGINDISABLED")
+        })
+}
+
+/// Turn a folded or multi-line header value into a single trimmed line
+fn collapse_whitespace(value: &str) -> String {
+    value.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+/// Split an address list header, skipping empty entries such as those of a blank
+/// header or a trailing comma
+fn split_addresses(value: &str) -> Vec<String> {
+    value
+        .split(',')
+        .map(|s| s.trim())
+        .filter(|s| !s.is_empty())
+        .map(|s| s.to_string())
+        .collect()
+}
+
+/// A mailbox of an address header, e.g. `"Foo Bar" <foo@bar.com>`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Address {
+    /// The display name, decoded
+    pub name: Option<String>,
+    pub email: String,
+}
+
+impl Address {
+    /// The display name, or the email when there is none
+    pub fn display_name(&self) -> &str {
+        self.name.as_deref().unwrap_or(&self.email)
+    }
+}
+
+/// The first mailbox of an address header, the first member of a group
+fn first_address(header: &mailparse::MailHeader) -> Option<Address> {
+    let addresses = mailparse::addrparse_header(header).ok()?;
+    let first = addresses.iter().find_map(|address| match address {
+        mailparse::MailAddr::Single(single) => Some(single.clone()),
+        mailparse::MailAddr::Group(group) => group.addrs.first().cloned(),
+    })?;
+    Some(Address {
+        name: first
+            .display_name
+            .map(|name| collapse_whitespace(&name))
+            .filter(|name| !name.is_empty()),
+        email: first.addr,
+    })
+}
+
+#[derive(Debug)]
+pub struct MailHeader {
+    pub uid: u32,
+    pub subject: String,
+    pub from: String,
+    pub internal_date: chrono::DateTime<FixedOffset>,
+}
+
+impl From<&Mail> for MailHeader {
+    fn from(mail: &Mail) -> Self {
+        MailHeader {
+            uid: mail.uid,
+            subject: mail.subject.clone(),
+            from: mail.from.clone(),
+            internal_date: mail.internal_date,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Attachment {
+    pub name: String,
+    pub size: Option<u32>,
+    /// Lowercase MIME type without parameters, e.g. `application/pdf`
+    #[serde(default)]
+    pub content_type: String,
+}
+
+impl Attachment {
+    pub(crate) fn new(name: String, size: Option<u32>, content_type: String) -> Self {
+        let name = format!("Subject: {}", name);
+        let (parsed, _) = parse_header(name.as_bytes()).unwrap();
+        let name = parsed.get_value();
+
+        Self {
+            name,
+            size,
+            content_type,
+        }
+    }
+}
+
+/// Whether `content_type` matches the glob `pattern`, e.g. `image/*`. Both are compared
+/// case-insensitively and without their parameters.
+pub fn content_type_matches(pattern: &str, content_type: &str) -> bool {
+    fn essence(value: &str) -> String {
+        value
+            .split(';')
+            .next()
+            .unwrap_or_default()
+            .trim()
+            .to_lowercase()
+    }
+
+    fn glob(pattern: &[char], text: &[char]) -> bool {
+        match pattern.split_first() {
+            None => text.is_empty(),
+            Some(('*', rest)) => (0..=text.len()).any(|skip| glob(rest, &text[skip..])),
+            Some(('?', rest)) => !text.is_empty() && glob(rest, &text[1..]),
+            Some((c, rest)) => text.first() == Some(c) && glob(rest, &text[1..]),
+        }
+    }
+
+    let pattern = essence(pattern).chars().collect::<Vec<_>>();
+    let content_type = essence(content_type).chars().collect::<Vec<_>>();
+    glob(&pattern, &content_type)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::{
+        fetch_body_response, fetch_response, fetch_response_with_structure, MockServer,
+    };
+
+    #[test]
+    fn attachments_of_nested_multipart() {
+        let mail = mailparse::parse_mail(include_bytes!("../tests/fixtures/nested_multipart.eml"))
+            .unwrap();
+        let attachments = attachments_of(&mail, false, None);
+
+        let names = attachments
+            .iter()
+            .map(|(name, _)| name.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(names, vec!["logo.png", "report.csv"]);
+        assert_eq!(attachments[1].1, b"a,b\n1,2\n");
+    }
+
+    #[test]
+    fn attachments_of_a_type() {
+        let mail = mailparse::parse_mail(include_bytes!("../tests/fixtures/nested_multipart.eml"))
+            .unwrap();
+        let names = |pattern| {
+            attachments_of(&mail, false, pattern)
+                .into_iter()
+                .map(|(name, _)| name)
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(names(Some("IMAGE/*")), vec!["logo.png"]);
+        assert_eq!(names(Some("text/csv; charset=utf-8")), vec!["report.csv"]);
+        assert!(names(Some("application/pdf")).is_empty());
+    }
+
+    #[test]
+    fn match_content_types() {
+        assert!(content_type_matches("image/*", "image/png"));
+        assert!(content_type_matches("Image/PNG", "image/png; name=a.png"));
+        assert!(content_type_matches("*/pdf", "application/pdf"));
+        assert!(content_type_matches(
+            "application/vnd.*",
+            "application/vnd.ms-excel"
+        ));
+        assert!(!content_type_matches("image/*", "application/pdf"));
+        assert!(!content_type_matches("image/png", "image/pngx"));
+    }
+
+    #[test]
+    fn fetch_by_attachment_type() {
+        let bodystructure = include_str!("../tests/fixtures/mixed_attachments.bodystructure");
+        let server = MockServer::new();
+        server.on("UID SEARCH", "* SEARCH 1 2\r\n").on(
+            "UID FETCH",
+            [
+                fetch_response_with_structure(
+                    1,
+                    "02-May-2022 09:30:00 +0800",
+                    "Subject: invoice\r\nFrom: alice@qq.com\r\n\r\n",
+                    "first\r\n",
+                    bodystructure,
+                ),
+                fetch_response(
+                    2,
+                    "03-May-2022 09:30:00 +0800",
+                    "Subject: lunch\r\nFrom: bob@qq.com\r\n\r\n",
+                    "second\r\n",
+                ),
+            ]
+            .concat(),
+        );
+        let client = server.client();
+        let mail_box = client.get("INBOX").unwrap();
+        let mut filter = mail_box.filter("", datetime("2022-05-01T00:00:00+08:00"));
+        filter.end_date(datetime("2022-06-01T00:00:00+08:00"));
+
+        let mails = filter.fetch().unwrap();
+        let invoice = mails.iter().find(|m| m.uid == 1).unwrap();
+        let attachments = invoice
+            .attachments
+            .iter()
+            .map(|a| (a.name.as_str(), a.content_type.as_str()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            attachments,
+            vec![
+                ("invoice.pdf", "application/pdf"),
+                ("chart.png", "image/png")
+            ]
+        );
+
+        filter.attachment_type(Some("image/*".to_string()));
+        let uids = filter
+            .fetch()
+            .unwrap()
+            .iter()
+            .map(|m| m.uid)
+            .collect::<Vec<_>>();
+        assert_eq!(uids, vec![1]);
+
+        filter.attachment_type(Some("audio/*".to_string()));
+        assert!(filter.fetch().unwrap().is_empty());
+    }
+
+    #[test]
+    fn list_nested_attachments() {
+        // a forwarded invoice nested in a multipart/mixed, with the parameters of its
+        // disposition in another order and in upper case
+        let bodystructure = include_str!("../tests/fixtures/nested_attachments.bodystructure");
+        let server = MockServer::new();
+        server.on("UID SEARCH", "* SEARCH 1\r\n").on(
+            "UID FETCH",
+            fetch_response_with_structure(
+                1,
+                "02-May-2022 09:30:00 +0800",
+                "Subject: Fwd: invoice\r\n\r\n",
+                "first\r\n",
+                bodystructure.trim(),
+            ),
+        );
+        let client = server.client();
+        let mail_box = client.get("INBOX").unwrap();
+        let mut filter = mail_box.filter("", datetime("2022-05-01T00:00:00+08:00"));
+        filter.end_date(datetime("2022-06-01T00:00:00+08:00"));
+
+        let mails = filter.fetch().unwrap();
+
+        let attachment = &mails[0].attachments[0];
+        assert_eq!(mails[0].attachments.len(), 1);
+        assert_eq!(attachment.name, "invoice.pdf");
+        assert_eq!(attachment.size, Some(1480));
+        assert_eq!(attachment.content_type, "application/pdf");
+    }
+
+    #[test]
+    fn html_body_with_inline_parts() {
+        let mail =
+            mailparse::parse_mail(include_bytes!("../tests/fixtures/inline_images.eml")).unwrap();
+        let body = html_body_of(&mail).unwrap();
+
+        assert!(body.html.contains("cid:chart"));
+        assert_eq!(
+            body.inline
+                .iter()
+                .map(|(id, name, _)| (id.as_str(), name.as_str()))
+                .collect::<Vec<_>>(),
+            vec![
+                ("banner.1@example.com", "banner.1@example.com.jpg"),
+                ("logo", "logo.png"),
+                ("chart", "chart.png")
+            ]
+        );
+
+        let plain = mailparse::parse_mail(b"Subject: a\r\n\r\nplain text").unwrap();
+        assert!(html_body_of(&plain).is_none());
+    }
+
+    #[test]
+    fn fail_on_an_html_body_that_doesnt_parse() {
+        let server = MockServer::new();
+        server.on(
+            "UID FETCH 7",
+            fetch_body_response(
+                7,
+                "20-Nov-2023 09:30:00 +0800",
+                b" Subject: a\r\n\r\n<p>a</p>",
+            ),
+        );
+        let client = server.client();
+        let mail_box = client.get("INBOX").unwrap();
+
+        let err = mail_box.html_body(7).unwrap_err();
+
+        assert!(matches!(err, ClientError::InvalidMail(7, _)), "{}", err);
+    }
+
+    #[test]
+    fn pick_the_body_part() {
+        let body = |message: &[u8]| {
+            let mail = mailparse::parse_mail(message).unwrap();
+            body_part_of(&mail)
+                .map(|(part, kind)| (part.get_body().unwrap().trim_end().to_string(), kind))
+        };
+
+        // the text after the inline logo, rather than the attached text or the signature
+        assert_eq!(
+            body(include_bytes!("../tests/fixtures/signed.eml")),
+            Some(("The figures are attached.".to_string(), BodyKind::Plain))
+        );
+        assert_eq!(
+            body(include_bytes!("../tests/fixtures/image_first.eml")),
+            Some(("<p>Hello <b>Bob</b></p>".to_string(), BodyKind::Html))
+        );
+        assert_eq!(body(b"Content-Type: image/png\r\n\r\npng"), None);
+        assert_eq!(
+            body(b"Subject: a\r\n\r\nplain text"),
+            Some(("plain text".to_string(), BodyKind::Plain))
+        );
+    }
+
+    #[test]
+    fn fetch_the_body_of_an_image_first_mail() {
+        let message = include_str!("../tests/fixtures/image_first.eml").replace('\n', "\r\n");
+        let (header, text) = message.split_once("\r\n\r\n").unwrap();
+        let server = MockServer::new();
+        server.on("UID SEARCH", "* SEARCH 1 2\r\n").on(
+            "UID FETCH",
+            [
+                fetch_response(
+                    1,
+                    "02-May-2022 09:30:00 +0800",
+                    &format!("{}\r\n\r\n", header),
+                    text,
+                ),
+                fetch_response(
+                    2,
+                    "03-May-2022 09:30:00 +0800",
+                    "Subject: chart\r\nContent-Type: image/png\r\n\r\n",
+                    "cG5n\r\n",
+                ),
+            ]
+            .concat(),
+        );
+        let client = server.client();
+        let mail_box = client.get("INBOX").unwrap();
+        let mut filter = mail_box.filter("", datetime("2022-05-01T00:00:00+08:00"));
+        filter.end_date(datetime("2022-06-01T00:00:00+08:00"));
+
+        let mails = filter.fetch().unwrap();
+
+        assert_eq!(mails[1].body.trim_end(), "<p>Hello <b>Bob</b></p>");
+        assert_eq!(mails[1].body_kind, Some(BodyKind::Html));
+        assert_eq!(mails[1].snippet, "Hello Bob");
+        // nothing to show rather than the image bytes
+        assert_eq!(mails[0].body, "");
+        assert_eq!(mails[0].body_kind, None);
+    }
+
+    #[test]
+    fn inline_images_by_content_id() {
+        let mail =
+            mailparse::parse_mail(include_bytes!("../tests/fixtures/inline_images.eml")).unwrap();
+        let names = |include_inline| {
+            attachments_of(&mail, include_inline, None)
+                .into_iter()
+                .map(|(name, _)| name)
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(names(false), vec!["logo.png"]);
+        // the named inline image only once, under its file name
+        assert_eq!(
+            names(true),
+            vec!["banner.1@example.com.jpg", "logo.png", "chart.png"]
+        );
+        assert_eq!(attachments_of(&mail, true, None)[0].1, b"banner");
+    }
+
+    fn date(s: &str) -> NaiveDate {
+        s.parse().unwrap()
+    }
+
+    fn assert_partition(windows: &[(NaiveDate, NaiveDate)], since: NaiveDate, before: NaiveDate) {
+        assert_eq!(windows.first().unwrap().0, since);
+        assert_eq!(windows.last().unwrap().1, before);
+        for pair in windows.windows(2) {
+            // BEFORE is exclusive and SINCE inclusive, so sharing the boundary day
+            // neither skips nor double counts it
+            assert_eq!(pair[0].1, pair[1].0);
+        }
+    }
+
+    #[test]
+    fn date_windows_partition_long_ranges() {
+        let (since, before) = (date("2023-01-01"), date("2023-12-01"));
+        let windows = date_windows(since, before, date("2024-06-01"), 90);
+
+        assert_eq!(windows.len(), 4);
+        assert_partition(&windows, since, before);
+        assert!(windows
+            .iter()
+            .all(|(from, to)| (*to - *from).num_days() <= 90));
+    }
+
+    #[test]
+    fn date_windows_stop_at_today() {
+        let (since, before) = (date("2024-01-01"), date("9999-12-31"));
+        let windows = date_windows(since, before, date("2024-03-01"), 30);
+
+        assert_eq!(windows.len(), 3);
+        assert_partition(&windows, since, date("2024-03-03"));
+        assert_eq!(windows[2].0, date("2024-03-01"));
+        assert_eq!(
+            date_windows(since, before, date("2024-03-01"), 0),
+            vec![(since, date("2024-03-03"))]
+        );
+    }
+
+    #[test]
+    fn date_windows_short_or_disabled() {
+        let (since, before) = (date("2024-01-01"), date("2024-02-01"));
+
+        assert_eq!(
+            date_windows(since, before, date("2024-06-01"), 90),
+            vec![(since, before)]
+        );
+        assert_eq!(
+            date_windows(since, before, date("2024-06-01"), 0),
+            vec![(since, before)]
+        );
+        assert_eq!(
+            date_windows(before, since, date("2024-06-01"), 90),
+            vec![(before, since)]
+        );
+    }
+
+    #[test]
+    fn snippet_of_html_only_mail() {
+        let raw = b"Content-Type: multipart/alternative; boundary=b\r\n\r\n\
+--b\r\nContent-Type: text/html\r\n\r\n\
+<html><head><style>p { color: red; }</style></head>\r\n\
+<body><p>Hello&nbsp;<b>world</b>,</p>\r\n\r\n<p>see   you</p></body></html>\r\n--b--\r\n";
+        let mail = mailparse::parse_mail(raw).unwrap();
+
+        assert_eq!(snippet_of(&mail), "Hello world , see you");
+    }
+
+    #[test]
+    fn snippet_of_truncates_plain_text() {
+        let raw = format!(
+            "Content-Type: multipart/alternative; boundary=b\r\n\r\n\
+--b\r\nContent-Type: text/plain\r\n\r\n{}\r\n\
+--b\r\nContent-Type: text/html\r\n\r\n<p>html</p>\r\n--b--\r\n",
+            "word\r\n".repeat(100)
+        );
+        let mail = mailparse::parse_mail(raw.as_bytes()).unwrap();
+        let snippet = snippet_of(&mail);
+
+        assert_eq!(snippet.chars().count(), SNIPPET_LENGTH);
+        assert!(snippet.starts_with("word word"));
+    }
+
+    #[test]
+    fn strip_quoted_history() {
+        let body = "Sounds good, see you then.\n\
+                    \n\
+                    On Mon, May 2, 2022 at 9:30 AM Alice <alice@qq.com> wrote:\n\
+                    > Lunch at noon?\n\
+                    >> earlier\n\
+                    \n";
+        assert_eq!(strip_quotes(body), "Sounds good, see you then.");
+
+        let wrapped = "Done.\nOn 2022-05-02, Alice <alice@qq.com>\nwrote:\n> Please review";
+        assert_eq!(strip_quotes(wrapped), "Done.");
+
+        let chinese = "收到\n在 2022年5月2日 09:30，Alice 写道：\n> 请查收";
+        assert_eq!(strip_quotes(chinese), "收到");
+
+        let unquoted = "a > b\nOn call this week";
+        assert_eq!(strip_quotes(unquoted), unquoted);
+    }
+
+    #[test]
+    fn preview_the_first_lines() {
+        let body = "Hi Bob,\r\n\r\n会议改到周五，\r\n地点不变。\r\n\r\nAlice\r\n";
+
+        assert_eq!(preview(body, 2), "Hi Bob,\r\n\r\n会议改到周五，\n…");
+        assert_eq!(preview(body, 4), body);
+        assert_eq!(preview(body, 10), body);
+        assert_eq!(preview("\n\nHi\n", 1), "\n\nHi\n");
+    }
+
+    #[test]
+    fn parse_from_address() {
+        let from = |value: &str| {
+            let header = format!("From: {}\r\n", value);
+            let (header, _) = mailparse::parse_header(header.as_bytes()).unwrap();
+            first_address(&header)
+        };
+        let address = |name: Option<&str>, email: &str| {
+            Some(Address {
+                name: name.map(|name| name.to_string()),
+                email: email.to_string(),
+            })
+        };
+
+        assert_eq!(
+            from("\"Foo Bar\" <foo@bar.com>"),
+            address(Some("Foo Bar"), "foo@bar.com")
+        );
+        assert_eq!(from("foo@bar.com"), address(None, "foo@bar.com"));
+        assert_eq!(
+            from("=?UTF-8?B?5byg5LiJ?= <zhangsan@qq.com>"),
+            address(Some("张三"), "zhangsan@qq.com")
+        );
+        assert_eq!(
+            from("Team: alice@qq.com, bob@qq.com;"),
+            address(None, "alice@qq.com")
+        );
+        assert_eq!(from("undisclosed-recipients:;"), None);
+        assert_eq!(from(""), None);
+        assert_eq!(from("\"unterminated <foo@bar.com>"), None);
+    }
+
+    #[test]
+    fn refuse_to_login_when_disabled() {
+        let server = MockServer::new();
+        server.greet("* OK [CAPABILITY IMAP4rev1 STARTTLS LOGINDISABLED] ready\r\n");
+
+        assert!(matches!(server.connect(), Err(ClientError::LoginDisabled)));
+        assert!(server.commands().is_empty(), "LOGIN isn't sent");
+
+        server.greet("* OK [CAPABILITY IMAP4rev1 AUTH=PLAIN] ready\r\n");
+        assert!(server.connect().is_ok());
+    }
+
+    #[test]
+    fn split_addresses_skips_empty_entries() {
+        assert!(split_addresses("").is_empty());
+        assert!(split_addresses("  ").is_empty());
+        assert_eq!(
+            split_addresses("a@qq.com, ,b@qq.com,"),
+            vec!["a@qq.com", "b@qq.com"]
+        );
+    }
+
+    fn mail() -> Mail {
+        Mail {
+            subject: "Weekly report".to_string(),
+            from: "Alice <alice@qq.com>".to_string(),
+            from_address: Some(Address {
+                name: Some("Alice".to_string()),
+                email: "alice@qq.com".to_string(),
+            }),
+            sender: None,
+            sender_address: None,
+            reply_to: Some(vec!["team@qq.com".to_string()]),
+            to: vec!["bob@qq.com".to_string(), "carol@qq.com".to_string()],
+            cc: vec![],
+            message_id: Some("<1@qq.com>".to_string()),
+            in_reply_to: None,
+            references: vec![],
+            uid: 42,
+            uid_validity: Some(1700000000),
+            body: "see attached".to_string(),
+            raw_body: None,
+            body_bytes: b"see attached".to_vec(),
+            body_kind: Some(BodyKind::Plain),
+            body_truncated: false,
+            snippet: "see attached".to_string(),
+            internal_date: "2022-05-20T09:30:00+08:00".parse().unwrap(),
+            sent_date: Some("2022-05-20T09:29:58+08:00".parse().unwrap()),
+            date_parse_error: None,
+            flags: vec!["\\Seen".to_string()],
+            folder: Some("INBOX".to_string()),
+            folders: vec![],
+            also_in: vec![],
+            thread_id: None,
+            attachments: vec![Attachment {
+                name: "report.csv".to_string(),
+                size: Some(8),
+                content_type: "text/csv".to_string(),
+            }],
+        }
+    }
+
+    #[test]
+    fn mail_as_text() {
+        assert_eq!(
+            mail().to_string(),
+            "Subject: Weekly report\n\
+             From: Alice <alice@qq.com>\n\
+             To: bob@qq.com, carol@qq.com\n\
+             Date: 2022-05-20T09:30:00+08:00\n\
+             UID: 42\n\
+             Attachment: report.csv\n\
+             \n\
+             see attached"
+        );
+    }
+
+    #[test]
+    fn mail_json_shape() {
+        assert_eq!(
+            serde_json::to_value(mail()).unwrap(),
+            serde_json::json!({
+                "subject": "Weekly report",
+                "from": "Alice <alice@qq.com>",
+                "from_address": {"name": "Alice", "email": "alice@qq.com"},
+                "sender": null,
+                "sender_address": null,
+                "reply_to": ["team@qq.com"],
+                "to": ["bob@qq.com", "carol@qq.com"],
+                "cc": [],
+                "message_id": "<1@qq.com>",
+                "in_reply_to": null,
+                "references": [],
+                "id": 42,
+                "uid_validity": 1700000000,
+                "body": "see attached",
+                "body_kind": "plain",
+                "body_truncated": false,
+                "snippet": "see attached",
+                "date": "2022-05-20T09:30:00+08:00",
+                "sent_date": "2022-05-20T09:29:58+08:00",
+                "date_parse_error": null,
+                "flags": ["\\Seen"],
+                "folder": "INBOX",
+                "folders": [],
+                "also_in": [],
+                "thread_id": null,
+                "attachments": [{"name": "report.csv", "size": 8, "content_type": "text/csv"}],
+            })
+        );
+    }
+
+    #[test]
+    fn mail_json_round_trip() {
+        let json = serde_json::to_string(&mail()).unwrap();
+        let parsed: Mail = serde_json::from_str(&json).unwrap();
+
+        assert_eq!(serde_json::to_string(&parsed).unwrap(), json);
+    }
+
+    fn datetime(s: &str) -> chrono::DateTime<FixedOffset> {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn get_unknown_mail_box() {
+        let server = MockServer::new();
+        let client = server.client();
+
+        assert!(matches!(
+            client.get("Archive"),
+            Err(ClientError::MailBoxNotFound(name, _)) if name == "Archive"
+        ));
+    }
+
+    #[test]
+    fn select_a_mail_box_by_its_decoded_name() {
+        let server = MockServer::new();
+        server
+            .on(
+                "LIST",
+                "* LIST () \"/\" \"INBOX\"\r\n* LIST () \"/\" \"&U9F5aA-/2023\"\r\n",
+            )
+            .on("STATUS", "* STATUS \"&U9F5aA-/2023\" (MESSAGES 3)\r\n");
+        let client = server.client();
+
+        let mail_box = client.get("发票/2023").unwrap();
+        assert_eq!(client.message_count("发票/2023").unwrap(), 3);
+
+        assert_eq!(mail_box.name(), "发票/2023");
+        assert_eq!(mail_box.raw_name(), "&U9F5aA-/2023");
+        let commands = server.commands();
+        assert_eq!(
+            commands.iter().filter(|c| !c.is_ascii()).count(),
+            0,
+            "sent a name not encoded: {:?}",
+            commands
+        );
+        assert_eq!(
+            commands.last().map(String::as_str),
+            Some("STATUS \"&U9F5aA-/2023\" (MESSAGES UNSEEN)")
+        );
+    }
+
+    #[test]
+    fn fetch_from_mock_server() {
+        let server = MockServer::new();
+        server
+            .on(
+                "EXAMINE",
+                "* 3 EXISTS\r\n* 0 RECENT\r\n* OK [UIDVALIDITY 1700000000] UIDs valid\r\n",
+            )
+            .on("UID SEARCH", "* SEARCH 1 2 3\r\n")
+            .on(
+                "UID FETCH",
+                [
+                    fetch_response(
+                        1,
+                        "02-May-2022 09:30:00 +0800",
+                        "Subject: weekly report\r\nFrom: alice@qq.com\r\nTo: bob@qq.com\r\nCc: \r\n\r\n",
+                        "first\r\n",
+                    ),
+                    fetch_response(
+                        2,
+                        "03-May-2022 09:30:00 +0800",
+                        "Subject: lunch\r\nFrom: carol@qq.com\r\n\r\n",
+                        "second\r\n",
+                    ),
+                    fetch_response(
+                        3,
+                        "04-May-2022 09:30:00 +0800",
+                        "Subject: Re: weekly report\r\nFrom: bob@qq.com\r\n\r\n",
+                        "third\r\n",
+                    ),
+                ]
+                .concat(),
+            );
+        let client = server.client();
+        let mail_box = client.get("INBOX").unwrap();
+        let mut filter = mail_box.filter("report", datetime("2022-05-01T00:00:00+08:00"));
+        filter.end_date(datetime("2022-06-01T00:00:00+08:00"));
+
+        let mails = filter.fetch().unwrap();
+
+        assert_eq!(
+            mails.iter().map(|m| m.uid).collect::<Vec<_>>(),
+            vec![3, 1],
+            "newest first, without the lunch mail"
+        );
+        assert_eq!(filter.scanned(), 3);
+        assert_eq!(mails[1].to, vec!["bob@qq.com"]);
+        assert!(mails[1].cc.is_empty());
+        assert_eq!(mails[1].snippet, "first");
+        assert_eq!(mails[1].uid_validity, Some(1700000000));
+        let commands = server.commands();
+        assert!(commands.contains(&"UID SEARCH SINCE 01-May-2022 BEFORE 01-Jun-2022".to_string()));
+        let fetches = commands
+            .iter()
+            .filter(|c| c.starts_with("UID FETCH "))
+            .collect::<Vec<_>>();
+        assert_eq!(fetches.len(), 1, "a single FETCH for the whole chunk");
+        let mut seqs = fetches[0]
+            .split(' ')
+            .nth(2)
+            .unwrap()
+            .split(',')
+            .collect::<Vec<_>>();
+        seqs.sort();
+        assert_eq!(seqs, vec!["1", "2", "3"]);
+    }
+
+    #[test]
+    fn never_search_before_the_far_future() {
+        let server = MockServer::new();
+        server.on(
+            "EXAMINE",
+            "* 3 EXISTS\r\n* 0 RECENT\r\n* OK [UIDVALIDITY 1700000000] UIDs valid\r\n",
+        );
+        let client = server.client();
+        let mail_box = client.get("INBOX").unwrap();
+        // without an end date the filter ends in the year 9999
+        let filter = mail_box.filter("", datetime("2022-05-01T00:00:00+08:00"));
+
+        filter.fetch().unwrap();
+        filter.count().unwrap();
+
+        let latest = latest_before(chrono::Local::now().date_naive());
+        let searches = server
+            .commands()
+            .into_iter()
+            .filter(|c| c.starts_with("UID SEARCH"))
+            .collect::<Vec<_>>();
+        // one search per window, then the count
+        assert!(searches.len() > 2);
+        for search in searches {
+            let before = search.split(' ').skip_while(|w| *w != "BEFORE").nth(1);
+            let before = NaiveDate::parse_from_str(before.unwrap(), "%d-%b-%Y").unwrap();
+            assert!(before <= latest, "{}", search);
+        }
+    }
+
+    #[test]
+    fn skip_malformed_mails() {
+        let server = MockServer::new();
+        server.on("UID SEARCH", "* SEARCH 1 2 3\r\n").on(
+            "UID FETCH",
+            [
+                fetch_response(
+                    1,
+                    "02-May-2022 09:30:00 +0800",
+                    "Subject: weekly report\r\n\r\n",
+                    "first\r\n",
+                ),
+                // a continuation line without a header to continue
+                fetch_response(
+                    2,
+                    "03-May-2022 09:30:00 +0800",
+                    " folded: nothing\r\nSubject: broken\r\n\r\n",
+                    "second\r\n",
+                ),
+                "* 3 FETCH (UID 3 INTERNALDATE \"04-May-2022 09:30:00 +0800\" \
+                 BODY[HEADER.FIELDS (SUBJECT FROM TO CC)] {18}\r\nSubject: third\r\n\r\n \
+                 BODY[TEXT] {0}\r\n)\r\n"
+                    .to_string(),
+            ]
+            .concat(),
+        );
+        let client = server.client();
+        let mail_box = client.get("INBOX").unwrap();
+        let mut filter = mail_box.filter("", datetime("2022-05-01T00:00:00+08:00"));
+        filter.end_date(datetime("2022-06-01T00:00:00+08:00"));
+
+        let (mails, mut warnings) = filter.fetch_with_warnings().unwrap();
+
+        assert_eq!(mails.iter().map(|m| m.uid).collect::<Vec<_>>(), vec![1]);
+        assert_eq!(filter.scanned(), 3);
+        warnings.sort_by_key(|warning| warning.uid);
+        assert_eq!(
+            warnings.iter().map(|w| w.uid).collect::<Vec<_>>(),
+            vec![2, 3]
+        );
+        assert!(warnings[0].reason.starts_with("invalid header"));
+        assert_eq!(warnings[1].to_string(), "skipped mail 3: no BODYSTRUCTURE");
+    }
+
+    #[test]
+    fn skip_mails_the_server_refuses() {
+        let server = MockServer::new();
+        server
+            .on("UID SEARCH", "* SEARCH 1 2\r\n")
+            .fail("UID FETCH", "some messages could not be fetched")
+            .on(
+                "UID FETCH 1 ",
+                fetch_response(1, "02-May-2022 09:30:00 +0800", "Subject: a\r\n\r\n", ""),
+            )
+            .fail("UID FETCH 2 ", "message is gone");
+        let client = server.client();
+        let mail_box = client.get("INBOX").unwrap();
+        let mut filter = mail_box.filter("", datetime("2022-05-01T00:00:00+08:00"));
+        filter.end_date(datetime("2022-06-01T00:00:00+08:00"));
+
+        let (mails, warnings) = filter.fetch_with_warnings().unwrap();
+
+        assert_eq!(mails.iter().map(|m| m.uid).collect::<Vec<_>>(), vec![1]);
+        assert_eq!(warnings.len(), 1);
+        assert_eq!(warnings[0].uid, 2);
+        assert!(warnings[0].reason.contains("message is gone"));
+        let fetches = server
+            .commands()
+            .iter()
+            .filter(|c| c.starts_with("UID FETCH "))
+            .count();
+        assert_eq!(fetches, 3, "the chunk, then every mail on its own");
+    }
+
+    #[test]
+    fn run_the_same_criteria_on_several_mail_boxes() {
+        let server = MockServer::new();
+        let subject = "Subject: report\r\n\r\n";
+        server
+            .on(
+                "LIST",
+                "* LIST () \"/\" \"INBOX\"\r\n* LIST () \"/\" \"Archive\"\r\n",
+            )
+            .on("UID SEARCH", "* SEARCH 1\r\n")
+            .on(
+                "UID FETCH",
+                fetch_response(1, "02-May-2022 09:30:00 +0800", subject, ""),
+            );
+        let client = server.client();
+        let mut criteria = SearchCriteria::new("report", datetime("2022-05-01T00:00:00+08:00"));
+        criteria
+            .end_date(datetime("2022-06-01T00:00:00+08:00"))
+            .flags(vec![FlagKey::Unseen]);
+
+        for name in ["INBOX", "Archive"] {
+            let mails = client.get(name).unwrap().search(&criteria).unwrap();
+            assert_eq!(mails.len(), 1);
+        }
+        let searches = server
+            .commands()
+            .into_iter()
+            .filter(|c| c.starts_with("UID SEARCH"))
+            .collect::<Vec<_>>();
+        assert_eq!(searches.len(), 2);
+        assert_eq!(searches[0], searches[1]);
+        assert_eq!(
+            searches[0],
+            "UID SEARCH SINCE 01-May-2022 BEFORE 01-Jun-2022 UNSEEN"
+        );
+    }
+
+    #[test]
+    fn criteria_round_trip_through_json() {
+        let mut criteria = SearchCriteria::new("周报", datetime("2022-05-01T00:00:00+08:00"));
+        criteria
+            .regex(true)
+            .lag_over(Some(chrono::Duration::hours(2)))
+            .date_basis(DateBasis::Sent)
+            .flags(vec![FlagKey::Flagged]);
+
+        let json = serde_json::to_value(&criteria).unwrap();
+        assert_eq!(json["start_datetime"], "2022-05-01T00:00:00+08:00");
+        assert_eq!(json["lag_over"], 7200);
+        assert_eq!(json["date_basis"], "sent");
+        assert_eq!(json["flags"], serde_json::json!(["flagged"]));
+        assert_eq!(
+            serde_json::from_value::<SearchCriteria>(json).unwrap(),
+            criteria
+        );
+
+        // left out fields take their defaults
+        let criteria = serde_json::from_str::<SearchCriteria>(
+            r#"{"subject_pattern": "", "start_datetime": "2022-05-01T00:00:00+08:00",
+                "end_datetime": "2022-06-01T00:00:00+08:00"}"#,
+        )
+        .unwrap();
+        assert_eq!(criteria.window_days, 90);
+    }
+
+    #[test]
+    fn reject_an_invalid_subject_regex() {
+        let mut criteria = SearchCriteria::new("[report", datetime("2022-05-01T00:00:00+08:00"));
+        assert!(criteria.check().is_ok(), "only a regex is compiled");
+        criteria.regex(true);
+        assert!(criteria.check().is_err());
+        assert!(!criteria.subject_matches("[report"));
+
+        let err = serde_json::from_str::<SearchCriteria>(
+            r#"{"subject_pattern": "[report", "regex": true,
+                "start_datetime": "2022-05-01T00:00:00+08:00",
+                "end_datetime": "2022-06-01T00:00:00+08:00"}"#,
+        )
+        .unwrap_err();
+        assert!(err.to_string().contains("regex parse error"), "{}", err);
+
+        let mut criteria = SearchCriteria::new("^周报", datetime("2022-05-01T00:00:00+08:00"));
+        criteria.regex(true);
+        assert!(criteria.subject_matches("周报 5月"));
+        assert!(criteria.subject_regex.0.get().is_some(), "compiled once");
+        assert!(!criteria.subject_matches("Re: 周报"));
+    }
+
+    #[test]
+    fn time_the_stages() {
+        let server = MockServer::new();
+        server.on("UID SEARCH", "* SEARCH 1 2\r\n").on(
+            "UID FETCH",
+            [
+                fetch_response(1, "02-May-2022 09:30:00 +0800", "Subject: a\r\n\r\n", ""),
+                fetch_response(2, "03-May-2022 09:30:00 +0800", "Subject: b\r\n\r\n", ""),
+            ]
+            .concat(),
+        );
+        let client = server.client();
+        let mail_box = client.get("INBOX").unwrap();
+        let mut filter = mail_box.filter("", datetime("2022-05-01T00:00:00+08:00"));
+        filter.end_date(datetime("2022-06-01T00:00:00+08:00"));
+        filter.fetch().unwrap();
+
+        let timings = client.timings();
+        assert_eq!(timings.fetches, 1);
+        assert!(timings.select > Duration::ZERO);
+        assert!(timings.search > Duration::ZERO);
+        assert!(timings.parse > Duration::ZERO);
+        assert!(timings.total >= timings.login + timings.select + timings.search + timings.fetch);
+    }
+
+    #[test]
+    fn download_from_mock_server() {
+        let server = MockServer::new();
+        server.on(
+            "UID FETCH 7",
+            fetch_body_response(
+                7,
+                "20-Nov-2023 09:30:00 +0800",
+                include_bytes!("../tests/fixtures/nested_multipart.eml"),
+            ),
+        );
+        let client = server.client();
+        let mail_box = client.get("INBOX").unwrap();
+
+        let (header, attachments, skipped) = mail_box
+            .download(7, false, None, SizeLimit::default())
+            .unwrap();
+
+        assert_eq!(header.uid, 7);
+        assert_eq!(header.internal_date, datetime("2023-11-20T09:30:00+08:00"));
+        assert_eq!(
+            attachments[1],
+            ("report.csv".to_string(), b"a,b\n1,2\n".to_vec())
+        );
+        assert!(skipped.is_empty());
+    }
+
+    #[test]
+    fn skip_large_attachments_before_fetching_them() {
+        let bodystructure = include_str!("../tests/fixtures/mixed_attachments.bodystructure");
+        let header = "Subject: invoice\r\nFrom: alice@qq.com\r\n\r\n";
+        let mime = "Content-Type: image/png; name=\"chart.png\"\r\n\
+                    Content-Transfer-Encoding: base64\r\n\
+                    Content-Disposition: attachment; filename=\"chart.png\"\r\n\r\n";
+        let server = MockServer::new();
+        server
+            .on(
+                "UID FETCH 7 (INTERNALDATE BODYSTRUCTURE",
+                format!(
+                    "* 7 FETCH (UID 7 INTERNALDATE \"20-Nov-2023 09:30:00 +0800\" \
+                     BODYSTRUCTURE {} BODY[HEADER] {{{}}}\r\n{})\r\n",
+                    bodystructure.trim(),
+                    header.len(),
+                    header
+                ),
+            )
+            .on(
+                "UID FETCH 7 (BODY.PEEK[3.MIME]",
+                format!(
+                    "* 7 FETCH (UID 7 BODY[3.MIME] {{{}}}\r\n{} BODY[3] {{8}}\r\ncG5nZGF0)\r\n",
+                    mime.len(),
+                    mime
+                ),
+            );
+        let client = server.client();
+        let mail_box = client.get("INBOX").unwrap();
+        let size_limit = SizeLimit {
+            min: None,
+            max: Some(1000),
+        };
+
+        let (header, attachments, skipped) = mail_box.download(7, false, None, size_limit).unwrap();
+
+        assert_eq!(header.subject, "invoice");
+        assert_eq!(
+            attachments,
+            vec![("chart.png".to_string(), b"pngdat".to_vec())]
+        );
+        assert_eq!(
+            skipped,
+            vec![SkippedPart {
+                name: "invoice.pdf".to_string(),
+                size: 1480,
+                skipped: SkipReason::TooLarge,
+            }]
+        );
+        // the skipped part never left the server
+        assert!(!server
+            .commands()
+            .iter()
+            .any(|c| c.contains("BODY.PEEK[2]") || c.contains("BODY[]")));
+    }
+
+    #[test]
+    fn fail_on_a_mail_without_structure() {
+        let header = "Subject: invoice\r\n\r\n";
+        let server = MockServer::new();
+        server.on(
+            "UID FETCH 7",
+            format!(
+                "* 7 FETCH (UID 7 INTERNALDATE \"20-Nov-2023 09:30:00 +0800\" \
+                 BODY[HEADER] {{{}}}\r\n{})\r\n",
+                header.len(),
+                header
+            ),
+        );
+        let client = server.client();
+        let mail_box = client.get("INBOX").unwrap();
+        let size_limit = SizeLimit {
+            min: None,
+            max: Some(1000),
+        };
+
+        let err = mail_box.download(7, false, None, size_limit).unwrap_err();
+
+        assert_eq!(err.to_string(), "mail 7 is invalid: no BODYSTRUCTURE");
+    }
+
+    #[test]
+    fn check_size_limits() {
+        let size_limit = SizeLimit {
+            min: Some(100),
+            max: Some(1000),
+        };
+        assert_eq!(size_limit.check(99), Some(SkipReason::TooSmall));
+        assert_eq!(size_limit.check(100), None);
+        assert_eq!(size_limit.check(1000), None);
+        assert_eq!(size_limit.check(1001), Some(SkipReason::TooLarge));
+    }
+
+    #[test]
+    fn fetch_raw_from_mock_server() {
+        let message = include_bytes!("../tests/fixtures/nested_multipart.eml");
+        let server = MockServer::new();
+        server.on(
+            "UID FETCH 7",
+            fetch_body_response(7, "20-Nov-2023 09:30:00 +0800", message),
+        );
+        let client = server.client();
+        let mail_box = client.get("INBOX").unwrap();
+
+        assert_eq!(mail_box.fetch_raw(7).unwrap(), message.to_vec());
+        assert!(matches!(
+            mail_box.fetch_raw(8),
+            Err(ClientError::MailNotFound(8))
+        ));
+    }
+
+    fn server_with_folders() -> MockServer {
+        let server = MockServer::new();
+        server.on(
+            "LIST",
+            "* LIST (\\HasNoChildren) \"/\" \"INBOX\"\r\n\
+             * LIST (\\HasNoChildren) \"/\" \"&U9F5aA-/2023\"\r\n\
+             * LIST (\\HasNoChildren) \"/\" \"Archive\"\r\n",
+        );
+        server
+    }
+
+    #[test]
+    fn get_by_decoded_or_raw_name() {
+        let server = server_with_folders();
+        let client = server.client();
+
+        assert_eq!(client.get("发票/2023").unwrap().name(), "发票/2023");
+        assert_eq!(client.get("&U9F5aA-/2023").unwrap().name(), "发票/2023");
+        assert_eq!(client.get("archive").unwrap().name(), "Archive");
+        assert_eq!(server.commands().last().unwrap(), "EXAMINE \"Archive\"");
+    }
+
+    #[test]
+    fn compress_when_the_server_supports_it() {
+        let server = MockServer::new();
+        let mut client = server.client();
+        assert!(!client.compress().unwrap());
+        assert!(!server.commands().contains(&"COMPRESS DEFLATE".to_string()));
+
+        server.on("CAPABILITY", "* CAPABILITY IMAP4rev1 COMPRESS=DEFLATE\r\n");
+        let mut client = server.client();
+        assert!(client.compress().unwrap());
+        // the session goes on over the compressed stream
+        let mail_box = client.get("INBOX").unwrap();
+        assert_eq!(mail_box.name(), "INBOX");
+        client.logout().unwrap();
+
+        let commands = server.commands();
+        let compress = commands
+            .iter()
+            .position(|command| command == "COMPRESS DEFLATE")
+            .unwrap();
+        assert!(commands[compress..]
+            .iter()
+            .any(|command| command == "LOGOUT"));
+    }
+
+    #[test]
+    fn a_rejected_login_is_an_auth_error() {
+        let server = MockServer::new();
+        server.fail("LOGIN", "[AUTHENTICATIONFAILED] Invalid credentials");
+
+        let err = server.connect().err().unwrap();
+
+        assert!(err.is_auth());
+        assert!(!err.is_connection());
+        assert!(!ClientError::MailNotFound(1).is_auth());
+    }
+
+    #[test]
+    fn bye_is_a_connection_error_with_its_reason() {
+        let server = MockServer::new();
+        server.bye("UID SEARCH", "Autologout; idle for too long");
+        let client = server.client();
+        let mail_box = client.get("INBOX").unwrap();
+
+        let err = mail_box
+            .filter("", datetime("2022-05-01T00:00:00+08:00"))
+            .fetch()
+            .unwrap_err();
+
+        assert!(err.is_connection());
+        assert_eq!(
+            err.to_string(),
+            "UID SEARCH failed: the server closed the connection: Autologout; idle for too long"
+        );
+    }
+
+    #[test]
+    fn follow_exists_and_expunge_responses() {
+        let server = MockServer::new();
+        server
+            .on("EXAMINE", "* 3 EXISTS\r\n* 0 RECENT\r\n")
+            .on("UID SEARCH", "* 2 EXPUNGE\r\n* SEARCH\r\n")
+            .on("NOOP", "* 4 EXISTS\r\n");
+        let client = server.client();
+        assert_eq!(client.exists(), None);
+        let mail_box = client.get("INBOX").unwrap();
+        assert_eq!(client.exists(), Some(3));
+
+        mail_box
+            .filter("", datetime("2022-05-01T00:00:00+08:00"))
+            .end_date(datetime("2022-06-01T00:00:00+08:00"))
+            .fetch()
+            .unwrap();
+        assert_eq!(client.exists(), Some(2));
+
+        client.noop().unwrap();
+        assert_eq!(client.exists(), Some(4));
+    }
+
+    #[test]
+    fn set_seen_upgrades_read_only_mail_box() {
+        let server = MockServer::new();
+        let client = server.client();
+        let mail_box = client.get("INBOX").unwrap();
+
+        mail_box.set_seen(5, true).unwrap();
+        mail_box.set_seen(5, false).unwrap();
+
+        let commands = server.commands();
+        assert_eq!(
+            commands[commands.len() - 6..],
+            [
+                "SELECT \"INBOX\"",
+                "UID STORE 5 +FLAGS.SILENT (\\Seen)",
+                "EXAMINE \"INBOX\"",
+                "SELECT \"INBOX\"",
+                "UID STORE 5 -FLAGS.SILENT (\\Seen)",
+                "EXAMINE \"INBOX\"",
+            ]
+        );
+    }
+
+    #[test]
+    fn select_read_write_only_when_asked() {
+        let server = server_with_folders();
+        let mut client = server.client();
+
+        assert!(client.get("INBOX").unwrap().is_read_only());
+        let mail_box = client.get_writable("Archive").unwrap();
+        assert!(!mail_box.is_read_only());
+        assert_eq!(server.commands().last().unwrap(), "SELECT \"Archive\"");
+        assert!(!server
+            .commands()
+            .iter()
+            .any(|c| c.starts_with("SELECT \"INBOX\"")));
+        drop(mail_box);
+
+        client.set_read_only(false);
+        client.get("INBOX").unwrap();
+        assert_eq!(server.commands().last().unwrap(), "SELECT \"INBOX\"");
+    }
+
+    #[test]
+    fn get_suggests_close_names() {
+        let server = server_with_folders();
+        let client = server.client();
+
+        match client.get("发票/2022") {
+            Err(err @ ClientError::MailBoxNotFound(..)) => {
+                assert_eq!(
+                    err.to_string(),
+                    "mail box 发票/2022 not found, did you mean 发票/2023?"
+                )
+            }
+            _ => panic!("expected MailBoxNotFound"),
+        }
+    }
+
+    #[test]
+    fn mailbox_info_snapshot() {
+        let server = MockServer::new();
+        server.on(
+            "EXAMINE",
+            "* FLAGS (\\Answered \\Seen)\r\n\
+             * 12 EXISTS\r\n\
+             * 2 RECENT\r\n\
+             * OK [UNSEEN 9] first unseen\r\n\
+             * OK [PERMANENTFLAGS ()] none\r\n\
+             * OK [UIDNEXT 40] next\r\n\
+             * OK [UIDVALIDITY 7] valid\r\n",
+        );
+        let client = server.client();
+        let mail_box = client.get("INBOX").unwrap();
+
+        assert_eq!(
+            serde_json::to_value(mail_box.info()).unwrap(),
+            serde_json::json!({
+                "name": "INBOX",
+                "flags": ["\\Answered", "\\Seen"],
+                "exists": 12,
+                "recent": 2,
+                "unseen": 9,
+                "permanent_flags": [],
+                "uid_next": 40,
+                "uid_validity": 7,
+            })
+        );
+        assert_eq!(
+            mail_box.to_string(),
+            "name: INBOX, flags: [\"\\\\Answered\", \"\\\\Seen\"], exists: 12, recent: 2, \
+             unseen: Some(9), permanent_flags: [], uid_next: Some(40), uid_validity: Some(7)"
+        );
+    }
+
+    #[test]
+    fn closest_names_ranks_by_distance() {
+        let names = ["INBOX", "Archive", "Archives", "Sent"];
+
+        assert_eq!(
+            closest_names("archiv", names.iter().copied()),
+            vec!["Archive", "Archives"]
+        );
+        assert!(closest_names("Drafts", names.iter().copied()).is_empty());
+    }
+
+    #[test]
+    fn logout_explicitly_or_on_drop() {
+        let server = MockServer::new();
+        let client = server.client();
+        client.logout().unwrap();
+        assert_eq!(server.commands().last().unwrap(), "LOGOUT");
+
+        let client = server.client();
+        client.get("INBOX").unwrap();
+        drop(client);
+        let commands = server.commands();
+        assert_eq!(
+            commands.iter().filter(|c| *c == "LOGOUT").count(),
+            2,
+            "{:?}",
+            commands
+        );
+    }
+
+    #[test]
+    fn since_uid_past_the_last_mail() {
+        let server = MockServer::new();
+        server
+            .on("EXAMINE", "* 3 EXISTS\r\n* OK [UIDNEXT 11] next\r\n")
+            .on("UID SEARCH", "* SEARCH 3\r\n");
+        let client = server.client();
+        let mail_box = client.get("INBOX").unwrap();
+        assert_eq!(mail_box.uid_next(), Some(11));
+        let mut filter = mail_box.filter("", datetime("2022-05-01T00:00:00+08:00"));
+        filter
+            .end_date(datetime("2022-06-01T00:00:00+08:00"))
+            .since_uid(Some(10));
+
+        let (mails, cursor) = filter.fetch_with_cursor().unwrap();
+
+        assert!(mails.is_empty());
+        assert_eq!(cursor, 10);
+        assert!(
+            !server
+                .commands()
+                .iter()
+                .any(|c| c.starts_with("UID SEARCH")),
+            "UID 11:* would match the last mail"
+        );
+    }
+
+    #[test]
+    fn since_uid_searches_newer_uids() {
+        let server = MockServer::new();
+        server.on("EXAMINE", "* 3 EXISTS\r\n* OK [UIDNEXT 11] next\r\n");
+        let client = server.client();
+        let mail_box = client.get("INBOX").unwrap();
+        let mut filter = mail_box.filter("", datetime("2022-05-01T00:00:00+08:00"));
+        filter
+            .end_date(datetime("2022-06-01T00:00:00+08:00"))
+            .since_uid(Some(7))
+            .max_uid(Some(9));
+
+        let (_, cursor) = filter.fetch_with_cursor().unwrap();
+
+        assert_eq!(cursor, 9);
+        assert!(server
+            .commands()
+            .contains(&"UID SEARCH SINCE 01-May-2022 BEFORE 01-Jun-2022 UID 8:9".to_string()));
+    }
+
+    #[test]
+    fn search_by_size() {
+        let server = MockServer::new();
+        let client = server.client();
+        let mail_box = client.get("INBOX").unwrap();
+        let mut filter = mail_box.filter("", datetime("2022-05-01T00:00:00+08:00"));
+        filter
+            .end_date(datetime("2022-06-01T00:00:00+08:00"))
+            .larger(Some(5 << 20))
+            .smaller(Some(100 << 20))
+            .raw_query(Some("UNSEEN".to_string()));
+
+        filter.fetch().unwrap();
+
+        assert!(server.commands().contains(
+            &"UID SEARCH SINCE 01-May-2022 BEFORE 01-Jun-2022 LARGER 5242880 SMALLER 104857600 UNSEEN"
+                .to_string()
+        ));
+    }
+
+    #[test]
+    fn search_by_flags() {
+        let server = MockServer::new();
+        let client = server.client();
+        let mail_box = client.get("INBOX").unwrap();
+        let mut filter = mail_box.filter("", datetime("2022-05-01T00:00:00+08:00"));
+        filter
+            .end_date(datetime("2022-06-01T00:00:00+08:00"))
+            .flags(vec![FlagKey::Unanswered, FlagKey::Draft, FlagKey::Old])
+            .raw_query(Some("FROM \"a\"".to_string()));
+
+        filter.count().unwrap();
+
+        assert!(server.commands().contains(
+            &"UID SEARCH SINCE 01-May-2022 BEFORE 01-Jun-2022 UNANSWERED DRAFT OLD FROM \"a\""
+                .to_string()
+        ));
+    }
+
+    #[test]
+    fn filter_by_delivery_lag() {
+        let server = MockServer::new();
+        server.on("UID SEARCH", "* SEARCH 1 2 3\r\n").on(
+            "UID FETCH",
+            [
+                fetch_response(
+                    1,
+                    "02-May-2022 09:30:00 +0800",
+                    "Subject: on time\r\nDate: Mon, 2 May 2022 09:29:30 +0800\r\n\r\n",
+                    "body\r\n",
+                ),
+                fetch_response(
+                    2,
+                    "02-May-2022 10:30:00 +0800",
+                    "Subject: delayed\r\nDate: Mon, 2 May 2022 01:00:00 +0000\r\n\r\n",
+                    "body\r\n",
+                ),
+                fetch_response(
+                    3,
+                    "02-May-2022 11:30:00 +0800",
+                    "Subject: bad date\r\nDate: yesterday\r\n\r\n",
+                    "body\r\n",
+                ),
+            ]
+            .concat(),
+        );
+        let client = server.client();
+        let mail_box = client.get("INBOX").unwrap();
+        let mut filter = mail_box.filter("", datetime("2022-05-01T00:00:00+08:00"));
+        filter.end_date(datetime("2022-06-01T00:00:00+08:00"));
+
+        let mails = filter.fetch().unwrap();
+        let bad_date = mails.iter().find(|m| m.uid == 3).unwrap();
+        assert_eq!(bad_date.sent_date, None);
+        assert_eq!(
+            bad_date.date_parse_error.as_deref(),
+            Some("invalid Date header `yesterday`")
+        );
+        assert_eq!(bad_date.lag(), chrono::Duration::zero());
+
+        filter.lag_over(Some(chrono::Duration::minutes(10)));
+        let mails = filter.fetch().unwrap();
+        assert_eq!(mails.iter().map(|m| m.uid).collect::<Vec<_>>(), vec![2]);
+        assert_eq!(mails[0].lag(), chrono::Duration::minutes(90));
+        assert_eq!(
+            mails[0].sent_date,
+            Some(datetime("2022-05-02T01:00:00+00:00"))
+        );
+    }
+
+    #[test]
+    fn filter_by_sent_date() {
+        let server = MockServer::new();
+        server.on("UID SEARCH", "* SEARCH 1 2 3\r\n").on(
+            "UID FETCH",
+            [
+                // sent the evening before, delivered in the window
+                fetch_response(
+                    1,
+                    "02-May-2022 00:30:00 +0800",
+                    "Subject: late\r\nDate: Sun, 1 May 2022 23:50:00 +0800\r\n\r\n",
+                    "body\r\n",
+                ),
+                fetch_response(
+                    2,
+                    "02-May-2022 09:30:00 +0800",
+                    "Subject: on time\r\nDate: Mon, 2 May 2022 09:29:30 +0800\r\n\r\n",
+                    "body\r\n",
+                ),
+                // no usable Date header, the received date stands in
+                fetch_response(
+                    3,
+                    "02-May-2022 11:30:00 +0800",
+                    "Subject: bad date\r\nDate: yesterday\r\n\r\n",
+                    "body\r\n",
+                ),
+            ]
+            .concat(),
+        );
+        let client = server.client();
+        let mail_box = client.get("INBOX").unwrap();
+        let mut filter = mail_box.filter("", datetime("2022-05-02T00:00:00+08:00"));
+        filter.end_date(datetime("2022-05-03T00:00:00+08:00"));
+
+        let uids = |filter: &MailFilter| {
+            let mails = filter.fetch().unwrap();
+            mails.iter().map(|m| m.uid).collect::<Vec<_>>()
+        };
+        assert_eq!(uids(&filter).len(), 3);
+
+        filter.date_basis(DateBasis::Sent);
+        let mut sent = uids(&filter);
+        sent.sort();
+        assert_eq!(sent, vec![2, 3]);
+        assert_eq!(filter.count().unwrap(), 2);
+        // the server still searched the delivery days
+        assert!(server
+            .commands()
+            .iter()
+            .any(|command| command == "UID SEARCH SINCE 02-May-2022 BEFORE 03-May-2022"));
+    }
+
+    #[test]
+    fn count_what_search_finds() {
+        let server = MockServer::new();
+        server
+            .on(
+                "EXAMINE",
+                "* 3 EXISTS\r\n* 0 RECENT\r\n* OK [UIDVALIDITY 1700000000] UIDs valid\r\n",
+            )
+            // the server matches the subject ignoring case, and on its own days
+            .on("UID SEARCH", "* SEARCH 1 2 3\r\n")
+            .on(
+                "UID FETCH",
+                [
+                    fetch_response(
+                        1,
+                        "02-May-2022 07:30:00 +0800",
+                        "Subject: REPORT\r\n\r\n",
+                        "",
+                    ),
+                    fetch_response(
+                        2,
+                        "02-May-2022 09:30:00 +0800",
+                        "Subject: Report\r\n\r\n",
+                        "",
+                    ),
+                    fetch_response(
+                        3,
+                        "02-May-2022 10:30:00 +0800",
+                        "Subject: report\r\n\r\n",
+                        "",
+                    ),
+                ]
+                .concat(),
+            );
+        let client = server.client();
+        let mail_box = client.get("INBOX").unwrap();
+        let mut filter = mail_box.filter("Report", datetime("2022-05-02T00:00:00+08:00"));
+        filter.end_date(datetime("2022-05-03T00:00:00+08:00"));
+
+        assert_eq!(filter.fetch().unwrap().len(), 3);
+        assert_eq!(filter.count().unwrap(), 3);
+
+        // midnight in UTC is 08:00 for the server, the mail of 07:30 is left out
+        filter
+            .start_date(datetime("2022-05-02T00:00:00+00:00"))
+            .end_date(datetime("2022-05-03T00:00:00+00:00"));
+        assert_eq!(filter.fetch().unwrap().len(), 2);
+        assert_eq!(filter.count().unwrap(), 2);
+    }
+
+    #[test]
+    fn count_only_the_mails_with_attachments() {
+        let bodystructure = include_str!("../tests/fixtures/mixed_attachments.bodystructure");
+        let server = MockServer::new();
+        server.on("UID SEARCH", "* SEARCH 1 2\r\n").on(
+            "UID FETCH",
+            [
+                fetch_response_with_structure(
+                    1,
+                    "02-May-2022 09:30:00 +0800",
+                    "Subject: invoice\r\n\r\n",
+                    "",
+                    bodystructure.trim(),
+                ),
+                fetch_response(
+                    2,
+                    "03-May-2022 09:30:00 +0800",
+                    "Subject: lunch\r\n\r\n",
+                    "",
+                ),
+            ]
+            .concat(),
+        );
+        let client = server.client();
+        let mail_box = client.get("INBOX").unwrap();
+        // `has:attachment` of --query, over whole days the search alone would count
+        let mut filter = mail_box.filter("", datetime("2022-05-01T00:00:00+08:00"));
+        filter
+            .end_date(datetime("2022-06-01T00:00:00+08:00"))
+            .attachments_only(true);
+
+        assert_eq!(filter.count().unwrap(), 1);
+        assert_eq!(filter.fetch_headers().unwrap()[0].uid, 1);
+        assert!(server
+            .commands()
+            .iter()
+            .any(|c| c.starts_with("UID FETCH") && c.ends_with(" BODYSTRUCTURE)")));
+
+        filter
+            .attachments_only(false)
+            .attachment_type(Some("audio/*".to_string()));
+        assert_eq!(filter.count().unwrap(), 0);
+    }
+
+    #[test]
+    fn fetch_part_of_the_bodies() {
+        let server = MockServer::new();
+        server.on("UID SEARCH", "* SEARCH 1 2\r\n").on(
+            "UID FETCH",
+            [
+                fetch_response(
+                    1,
+                    "02-May-2022 09:30:00 +0800",
+                    "Subject: short\r\n\r\n",
+                    "body\r\n",
+                ),
+                fetch_response(
+                    2,
+                    "03-May-2022 09:30:00 +0800",
+                    "Subject: log dump\r\n\r\n",
+                    "0123456789a",
+                ),
+            ]
+            .concat(),
+        );
+        let client = server.client();
+        let mail_box = client.get("INBOX").unwrap();
+        let mut filter = mail_box.filter("", datetime("2022-05-01T00:00:00+08:00"));
+        filter
+            .end_date(datetime("2022-06-01T00:00:00+08:00"))
+            .max_body_bytes(Some(10));
+
+        let mails = filter.fetch().unwrap();
+
+        let truncated = |uid| mails.iter().find(|m| m.uid == uid).unwrap().body_truncated;
+        assert!(!truncated(1));
+        assert!(truncated(2));
+        assert!(server
+            .commands()
+            .iter()
+            .any(|c| c.starts_with("UID FETCH") && c.contains(" BODY.PEEK[TEXT]<0.11> ")));
+    }
+
+    fn fetch_fixture(fixture: &str) -> Mail {
+        let message = fixture.replace('\n', "\r\n");
+        let (header, text) = message.split_once("\r\n\r\n").unwrap();
+        let server = MockServer::new();
+        server.on("UID SEARCH", "* SEARCH 1\r\n").on(
+            "UID FETCH",
+            fetch_response(
+                1,
+                "02-May-2022 09:30:00 +0800",
+                &format!("{}\r\n\r\n", header),
+                text,
+            ),
+        );
+        let client = server.client();
+        let mail_box = client.get("INBOX").unwrap();
+        let mut filter = mail_box.filter("", datetime("2022-05-01T00:00:00+08:00"));
+        filter.end_date(datetime("2022-06-01T00:00:00+08:00"));
+        filter.fetch().unwrap().remove(0)
+    }
+
+    #[test]
+    fn decode_quoted_printable_soft_breaks() {
+        let mail = fetch_fixture(include_str!("../tests/fixtures/quoted_printable.eml"));
+
+        assert_eq!(
+            mail.body,
+            "This line is longer than the seventy six characters quoted-printable allows, so it was cut with a soft break.\r\n\
+             中文也一样，切在字节之间。\r\n\
+             a=b\r\n"
+        );
+        assert_eq!(mail.body_raw(), mail.body);
+    }
+
+    #[test]
+    fn join_the_lines_of_a_flowed_body() {
+        let mail = fetch_fixture(include_str!("../tests/fixtures/flowed.eml"));
+
+        assert_eq!(mail.body_kind, Some(BodyKind::Plain));
+        assert_eq!(
+            mail.body,
+            "Hi Alice, the report is ready and I think you will like how it turned out.\n\
+             \n\
+             > Could you send me the report before Friday?\n\
+             \n\
+             中文段落不加空格。\n\
+             -- \n\
+             Bob\n"
+        );
+        assert!(mail
+            .body_raw()
+            .starts_with("Hi Alice, the report is ready and I think you  \r\nwill like"));
+        assert!(mail.snippet.starts_with("Hi Alice, the report is ready"));
+    }
+
+    #[test]
+    fn keep_the_body_bytes_in_their_charset() {
+        let server = MockServer::new();
+        server.on("UID SEARCH", "* SEARCH 1\r\n").on(
+            "UID FETCH",
+            fetch_response(
+                1,
+                "02-May-2022 09:30:00 +0800",
+                "Subject: invoice\r\nContent-Type: multipart/alternative; boundary=b\r\n\r\n",
+                "--b\r\n\
+                 Content-Type: text/plain; charset=gbk\r\n\
+                 Content-Transfer-Encoding: quoted-printable\r\n\r\n\
+                 =B7=A2=C6=B1\r\n\
+                 --b--\r\n",
+            ),
+        );
+        let client = server.client();
+        let mail_box = client.get("INBOX").unwrap();
+        let mut filter = mail_box.filter("", datetime("2022-05-01T00:00:00+08:00"));
+        filter.end_date(datetime("2022-06-01T00:00:00+08:00"));
+
+        let mails = filter.fetch().unwrap();
+
+        assert_eq!(mails[0].body.trim_end(), "发票");
+        assert_eq!(mails[0].body_bytes, b"\xb7\xa2\xc6\xb1\r\n");
+    }
+
+    #[test]
+    fn truncate_on_char_boundary() {
+        let mut text = "发票 2022".to_string();
+        assert!(truncate(&mut text, 4));
+        assert_eq!(text, "发");
+        assert!(!truncate(&mut text, 3));
+        assert_eq!(text, "发");
+        assert!(truncate(&mut text, 0));
+        assert_eq!(text, "");
+    }
+
+    #[test]
+    fn search_text_in_utf8() {
+        let server = MockServer::new();
+        let client = server.client();
+        let mail_box = client.get("INBOX").unwrap();
+        let mut filter = mail_box.filter("", datetime("2022-05-01T00:00:00+08:00"));
+        filter
+            .end_date(datetime("2022-06-01T00:00:00+08:00"))
+            .text(Some("中文".to_string()))
+            .body_contains(Some("say \"hi\"".to_string()));
+
+        filter.fetch().unwrap();
+
+        assert!(server.commands().contains(
+            &"UID SEARCH CHARSET UTF-8 SINCE 01-May-2022 BEFORE 01-Jun-2022 \
+              TEXT \"中文\" BODY \"say \\\"hi\\\"\""
+                .to_string()
+        ));
+    }
+
+    #[test]
+    fn search_text_client_side_on_bad_charset() {
+        let server = MockServer::new();
+        server
+            .fail(
+                "UID SEARCH CHARSET",
+                "[BADCHARSET (US-ASCII)] unsupported charset",
+            )
+            .on("UID SEARCH SINCE", "* SEARCH 1 2\r\n")
+            .on(
+                "UID FETCH",
+                [
+                    fetch_response(
+                        1,
+                        "02-May-2022 09:30:00 +0800",
+                        "Subject: weekly report\r\n\r\n",
+                        "nothing to see\r\n",
+                    ),
+                    fetch_response(
+                        2,
+                        "03-May-2022 09:30:00 +0800",
+                        "Subject: =?UTF-8?B?5Lit5paH?=\r\n\r\n",
+                        "hello\r\n",
+                    ),
+                ]
+                .concat(),
+            );
+        let client = server.client();
+        let mail_box = client.get("INBOX").unwrap();
+        let mut filter = mail_box.filter("", datetime("2022-05-01T00:00:00+08:00"));
+        filter
+            .end_date(datetime("2022-06-01T00:00:00+08:00"))
+            .text(Some("中文".to_string()));
+
+        let mails = filter.fetch().unwrap();
+
+        assert_eq!(mails.iter().map(|m| m.uid).collect::<Vec<_>>(), vec![2]);
+        assert!(server
+            .commands()
+            .contains(&"UID SEARCH SINCE 01-May-2022 BEFORE 01-Jun-2022".to_string()));
+
+        filter.text(None).body_contains(Some("NOTHING".to_string()));
+        let mails = filter.fetch().unwrap();
+        assert_eq!(mails.iter().map(|m| m.uid).collect::<Vec<_>>(), vec![1]);
+    }
+
+    #[test]
+    fn folders_from_mock_server() {
+        let server = MockServer::new();
+        server
+            .on(
+                "LIST",
+                "* LIST (\\HasNoChildren) \"/\" \"INBOX\"\r\n\
+                 * LIST (\\Noselect \\HasChildren) \"/\" \"Projects\"\r\n\
+                 * LIST (\\HasNoChildren) \"/\" \"Projects/&U9F5aA-\"\r\n",
+            )
+            .on(
+                "STATUS \"INBOX\"",
+                "* STATUS INBOX (MESSAGES 12 UNSEEN 3)\r\n",
+            )
+            .on(
+                "STATUS \"Projects/&U9F5aA-\"",
+                "* STATUS \"Projects/&U9F5aA-\" (MESSAGES 2 UNSEEN 0)\r\n",
+            );
+        let client = server.client();
+
+        let folders = client.folders().unwrap();
+
+        assert_eq!(
+            folder::render(&folders, None),
+            "INBOX  12 mails, 3 unseen\nProjects/\n  发票  2 mails"
+        );
+        assert!(!server.commands().iter().any(|c| c.contains("\"Projects\"")));
+        assert_eq!(
+            client
+                .mail_boxes()
+                .unwrap()
+                .iter()
+                .map(|b| b.name())
+                .collect::<Vec<_>>(),
+            vec!["INBOX", "Projects/发票"]
+        );
+    }
+
+    #[test]
+    fn mailbox_management_encodes_names() {
+        let server = MockServer::new();
+        server
+            .fail("DELETE", "[CANNOT] Mailbox has children")
+            .on("STATUS", "* STATUS \"&U9F5aA-\" (MESSAGES 2 UNSEEN 0)\r\n");
+        let client = server.client();
+
+        client.create_mailbox("发票").unwrap();
+        client.rename_mailbox("发票", "发票/2023").unwrap();
+        assert_eq!(client.message_count("发票").unwrap(), 2);
+        assert_eq!(
+            client.delete_mailbox("发票").unwrap_err().to_string(),
+            "DELETE 发票 failed: No Response: [CANNOT] Mailbox has children"
+        );
+
+        let commands = server.commands();
+        assert!(commands.contains(&"CREATE \"&U9F5aA-\"".to_string()));
+        assert!(commands.contains(&"RENAME \"&U9F5aA-\" \"&U9F5aA-/2023\"".to_string()));
+        assert!(commands.contains(&"STATUS \"&U9F5aA-\" (MESSAGES UNSEEN)".to_string()));
+    }
+
+    #[test]
+    fn folded_subject_is_one_line() {
+        let server = MockServer::new();
+        server.on("UID SEARCH", "* SEARCH 1\r\n").on(
+            "UID FETCH",
+            fetch_response(
+                1,
+                "02-May-2022 09:30:00 +0800",
+                "Subject:  weekly   report\r\n\tfor\r\n  May \r\n\
+                     From: Alice\r\n <alice@qq.com>\r\n\r\n",
+                "body\r\n",
+            ),
+        );
+        let client = server.client();
+        let mail_box = client.get("INBOX").unwrap();
+        let mut filter = mail_box.filter("", datetime("2022-05-01T00:00:00+08:00"));
+        filter.end_date(datetime("2022-06-01T00:00:00+08:00"));
+
+        let mails = filter.fetch().unwrap();
+
+        assert_eq!(mails[0].subject, "weekly report for May");
+        assert_eq!(mails[0].from, "Alice <alice@qq.com>");
+    }
+
+    #[test]
+    fn folded_sender_is_parsed() {
+        let server = MockServer::new();
+        server.on("UID SEARCH", "* SEARCH 1\r\n").on(
+            "UID FETCH",
+            fetch_response(
+                1,
+                "02-May-2022 09:30:00 +0800",
+                "Subject: weekly report\r\nFrom: Alice <alice@qq.com>\r\n\
+                     Sender: \"Mail   Robot\"\r\n\t<robot@qq.com>\r\n\r\n",
+                "body\r\n",
+            ),
+        );
+        let client = server.client();
+        let mail_box = client.get("INBOX").unwrap();
+        let mut filter = mail_box.filter("", datetime("2022-05-01T00:00:00+08:00"));
+        filter.end_date(datetime("2022-06-01T00:00:00+08:00"));
+
+        let mails = filter.fetch().unwrap();
+
+        assert_eq!(
+            mails[0].sender.as_deref(),
+            Some("\"Mail Robot\" <robot@qq.com>")
+        );
+        assert_eq!(
+            mails[0].sender_address,
+            Some(Address {
+                name: Some("Mail Robot".to_string()),
+                email: "robot@qq.com".to_string(),
+            })
+        );
+    }
+
+    #[test]
+    fn find_by_message_id() {
+        let server = MockServer::new();
+        server.on("UID SEARCH", "* SEARCH 9 7\r\n").on(
+            "UID FETCH",
+            fetch_response(
+                7,
+                "02-May-2022 09:30:00 +0800",
+                "Subject: weekly report\r\nMessage-ID: <abc@qq.com>\r\n\r\n",
+                "body\r\n",
+            ),
+        );
+        let client = server.client();
+        let mail_box = client.get("INBOX").unwrap();
+
+        let mail = mail_box.find_by_message_id("abc@qq.com").unwrap().unwrap();
+
+        assert_eq!(mail.uid, 7);
+        assert_eq!(mail.message_id.as_deref(), Some("<abc@qq.com>"));
+        let commands = server.commands();
+        assert!(commands.contains(&"UID SEARCH HEADER Message-ID \"<abc@qq.com>\"".to_string()));
+        assert!(commands.iter().any(|c| c.starts_with("UID FETCH 7 ")));
+    }
+
+    #[test]
+    fn find_by_unknown_message_id() {
+        let server = MockServer::new();
+        let client = server.client();
+        let mail_box = client.get("INBOX").unwrap();
+
+        assert!(mail_box
+            .find_by_message_id("<nope@qq.com>")
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    fn empty_subject_matches_all() {
+        let server = MockServer::new();
+        server.on("UID SEARCH", "* SEARCH 1\r\n").on(
+            "UID FETCH",
+            fetch_response(
+                1,
+                "02-May-2022 09:30:00 +0800",
+                "From: alice@qq.com\r\n\r\n",
+                "body\r\n",
+            ),
+        );
+        let client = server.client();
+        let mail_box = client.get("INBOX").unwrap();
+        let mut filter = mail_box.filter("", datetime("2022-05-01T00:00:00+08:00"));
+        filter
+            .end_date(datetime("2022-06-01T00:00:00+08:00"))
+            .regex(true);
+
+        assert_eq!(
+            filter.fetch().unwrap().len(),
+            1,
+            "even without a subject header"
+        );
+        assert!(!server
+            .commands()
+            .iter()
+            .any(|c| c.starts_with("UID SEARCH") && c.contains("SUBJECT")));
+    }
+
+    #[test]
+    fn subscriptions() {
+        let server = MockServer::new();
+        server
+            .on(
+                "LSUB",
+                "* LSUB (\\Noselect) \"/\" \"Projects\"\r\n\
+                 * LSUB () \"/\" \"Projects/&U9F5aA-\"\r\n",
+            )
+            .on(
+                "STATUS",
+                "* STATUS \"Projects/&U9F5aA-\" (MESSAGES 2 UNSEEN 0)\r\n",
+            );
+        let client = server.client();
+
+        client.subscribe("发票").unwrap();
+        client.unsubscribe("Archive").unwrap();
+        let folders = client.subscribed_folders().unwrap();
 
-        Self { name, size }
+        assert_eq!(folder::render(&folders, None), "Projects/\n  发票  2 mails");
+        let commands = server.commands();
+        assert!(commands.contains(&"SUBSCRIBE \"&U9F5aA-\"".to_string()));
+        assert!(commands.contains(&"UNSUBSCRIBE \"Archive\"".to_string()));
     }
 }
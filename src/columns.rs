@@ -0,0 +1,443 @@
+use std::str::FromStr;
+
+use tui::layout::Constraint;
+use unicode_width::{UnicodeWidthChar, UnicodeWidthStr};
+
+use crate::client::Mail;
+use crate::find;
+use crate::printable::printable;
+
+/// A column of the mail list
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Column {
+    Id,
+    /// The mail box the mail was found in, with the number of other copies collapsed
+    /// into it
+    Mailbox,
+    Subject,
+    Snippet,
+    From,
+    To,
+    Cc,
+    Date,
+    Sent,
+    Attachments,
+    /// The id of the conversation thread, see [`Mail::thread_id`]
+    Thread,
+}
+
+impl Column {
+    /// Every column, in the order they are displayed
+    pub const ALL: [Column; 11] = [
+        Column::Id,
+        Column::Mailbox,
+        Column::Subject,
+        Column::Snippet,
+        Column::From,
+        Column::To,
+        Column::Cc,
+        Column::Date,
+        Column::Sent,
+        Column::Attachments,
+        Column::Thread,
+    ];
+
+    /// The columns shown unless others are asked for, the snippet has its own switch
+    pub const DEFAULT: [Column; 7] = [
+        Column::Id,
+        Column::Subject,
+        Column::From,
+        Column::To,
+        Column::Cc,
+        Column::Date,
+        Column::Attachments,
+    ];
+
+    /// The name given to `--columns`
+    pub fn name(self) -> &'static str {
+        match self {
+            Column::Id => "id",
+            Column::Mailbox => "mailbox",
+            Column::Subject => "subject",
+            Column::Snippet => "snippet",
+            Column::From => "from",
+            Column::To => "to",
+            Column::Cc => "cc",
+            Column::Date => "date",
+            Column::Sent => "sent",
+            Column::Attachments => "attachments",
+            Column::Thread => "thread",
+        }
+    }
+
+    pub fn title(self) -> &'static str {
+        match self {
+            Column::Id => "id",
+            Column::Mailbox => "Mailbox",
+            Column::Subject => "Subject",
+            Column::Snippet => "Snippet",
+            Column::From => "From",
+            Column::To => "To",
+            Column::Cc => "CC",
+            Column::Date => "Date",
+            Column::Sent => "Sent",
+            Column::Attachments => "Attachments",
+            Column::Thread => "Thread",
+        }
+    }
+
+    /// Width until it's resized, the snippet takes its share from the other columns
+    /// when shown
+    pub fn default_width(self, with_snippet: bool) -> Constraint {
+        match (self, with_snippet) {
+            (Column::Id, _) => Constraint::Length(5),
+            (Column::Thread, _) => Constraint::Length(8),
+            (Column::Mailbox, _) => Constraint::Length(16),
+            (Column::Date | Column::Sent, _) => Constraint::Length(20),
+            (Column::Snippet, _) => Constraint::Percentage(25),
+            (Column::Subject | Column::From | Column::To, true) => Constraint::Percentage(15),
+            (Column::Subject | Column::From | Column::To, false) => Constraint::Percentage(20),
+            (Column::Cc, true) => Constraint::Percentage(5),
+            (Column::Cc, false) => Constraint::Percentage(10),
+            (Column::Attachments, true) => Constraint::Percentage(10),
+            (Column::Attachments, false) => Constraint::Percentage(20),
+        }
+    }
+}
+
+impl Column {
+    /// The content of the column for `mail`, the values of the list columns are joined
+    /// by `separator`. The values are [`printable`].
+    pub fn text(self, mail: &Mail, separator: &str) -> String {
+        let join = |values: &[String]| {
+            values
+                .iter()
+                .map(|value| printable(value))
+                .collect::<Vec<_>>()
+                .join(separator)
+        };
+        match self {
+            Column::Id => mail.uid.to_string(),
+            Column::Mailbox => {
+                let folder = printable(mail.folder.as_deref().unwrap_or_default());
+                match mail.also_in.len() {
+                    0 => folder,
+                    copies => format!("{} +{}", folder, copies),
+                }
+            }
+            Column::Subject => printable(&mail.subject),
+            Column::Snippet => printable(&mail.snippet.chars().take(60).collect::<String>()),
+            Column::From => printable(match &mail.from_address {
+                Some(address) => address.display_name(),
+                None => &mail.from,
+            }),
+            Column::To => join(&mail.to),
+            Column::Cc => join(&mail.cc),
+            Column::Date => mail.internal_date.format("%Y-%m-%dT%H:%M:%S").to_string(),
+            Column::Sent => match mail.sent_date {
+                Some(sent_date) => sent_date.format("%Y-%m-%dT%H:%M:%S").to_string(),
+                None => "?".to_string(),
+            },
+            Column::Attachments => mail
+                .attachments
+                .iter()
+                .map(|a| printable(&a.name))
+                .collect::<Vec<String>>()
+                .join(separator),
+            Column::Thread => mail.thread_id.clone().unwrap_or_default(),
+        }
+    }
+
+    /// Most characters of the column in a plain text table, wider values are cut
+    fn max_table_width(self) -> usize {
+        match self {
+            Column::Subject | Column::Snippet => 60,
+            Column::From | Column::Mailbox => 30,
+            Column::To | Column::Cc | Column::Attachments => 40,
+            Column::Id | Column::Date | Column::Sent | Column::Thread => usize::MAX,
+        }
+    }
+}
+
+/// The mails as a plain text table with a header line, one line per mail. Columns are
+/// aligned with spaces and cut at a fixed width, whatever the width of the terminal.
+pub fn table(mails: &[Mail], columns: &[Column]) -> String {
+    let rows = std::iter::once(
+        columns
+            .iter()
+            .map(|column| column.title().to_string())
+            .collect::<Vec<_>>(),
+    )
+    .chain(mails.iter().map(|mail| {
+        columns
+            .iter()
+            .map(|column| cut(&column.text(mail, ", "), column.max_table_width()))
+            .collect()
+    }))
+    .collect::<Vec<_>>();
+    let widths = (0..columns.len())
+        .map(|i| rows.iter().map(|row| row[i].width()).max().unwrap_or(0))
+        .collect::<Vec<_>>();
+
+    let mut table = String::new();
+    for row in rows {
+        let mut line = String::new();
+        for (cell, width) in row.iter().zip(widths.iter()) {
+            line.push_str(cell);
+            line.push_str(&" ".repeat(width - cell.width() + 2));
+        }
+        table.push_str(line.trim_end());
+        table.push('\n');
+    }
+    table
+}
+
+/// `text` wrapped to `width` columns and cut to its first `height` lines, the last one
+/// ends with `… +N more` when N lines are left out
+pub fn cap_lines(text: &str, width: usize, height: Option<usize>) -> String {
+    let mut lines = find::wrap(text, width);
+    let height = height.unwrap_or(usize::MAX).max(1);
+    if lines.len() > height {
+        let more = format!("… +{} more", lines.len() - height);
+        lines.truncate(height);
+        let last = lines.pop().unwrap_or_default();
+        let last = take_width(&last, width.saturating_sub(more.width() + 1));
+        lines.push(match last.is_empty() {
+            true => more,
+            false => format!("{} {}", last, more),
+        });
+    }
+    lines.join("\n")
+}
+
+/// The full values of the `columns` of `mail` that take more than `height` lines in the
+/// mail list once wrapped to the `widths` of the columns, one `<title>: <values>` line
+/// each followed by a blank line, or nothing
+pub fn cut_cells(mail: &Mail, columns: &[Column], widths: &[usize], height: usize) -> String {
+    let cut = columns
+        .iter()
+        .enumerate()
+        .filter(|(i, column)| {
+            let width = widths.get(*i).copied().unwrap_or(usize::MAX);
+            find::wrap(&column.text(mail, "\n"), width).len() > height
+        })
+        .map(|(_, column)| format!("{}: {}\n", column.title(), column.text(mail, ", ")))
+        .collect::<String>();
+    match cut.is_empty() {
+        true => cut,
+        false => format!("{}\n", cut),
+    }
+}
+
+/// `text` on a single line, cut to `width` columns with an ellipsis
+fn cut(text: &str, width: usize) -> String {
+    let text = text.replace(['\r', '\n', '\t'], " ");
+    if text.width() <= width {
+        return text;
+    }
+    let mut cut = take_width(&text, width.saturating_sub(1));
+    cut.push('…');
+    cut
+}
+
+/// The start of `text` that fits in `width` columns
+fn take_width(text: &str, width: usize) -> String {
+    let mut taken = String::new();
+    let mut used = 0;
+    for c in text.chars() {
+        let char_width = c.width().unwrap_or(0);
+        if used + char_width > width {
+            break;
+        }
+        taken.push(c);
+        used += char_width;
+    }
+    taken
+}
+
+impl FromStr for Column {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Column::ALL
+            .into_iter()
+            .find(|column| column.name().eq_ignore_ascii_case(s.trim()))
+            .ok_or_else(|| {
+                let names = Column::ALL.map(Column::name).join(", ");
+                format!("unknown column `{}`, expected one of {}", s, names)
+            })
+    }
+}
+
+/// `width` one step wider or narrower, fixed widths by 2 characters and relative ones
+/// by 5%
+pub fn resize(width: Constraint, wider: bool) -> Constraint {
+    match width {
+        Constraint::Length(n) if wider => Constraint::Length((n + 2).min(200)),
+        Constraint::Length(n) => Constraint::Length(n.saturating_sub(2).max(1)),
+        Constraint::Percentage(p) if wider => Constraint::Percentage((p + 5).min(100)),
+        Constraint::Percentage(p) => Constraint::Percentage(p.saturating_sub(5).max(5)),
+        other => other,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_columns() {
+        assert_eq!("subject".parse::<Column>().unwrap(), Column::Subject);
+        assert_eq!(" CC".parse::<Column>().unwrap(), Column::Cc);
+        assert_eq!(
+            "body".parse::<Column>().unwrap_err(),
+            "unknown column `body`, expected one of \
+             id, mailbox, subject, snippet, from, to, cc, date, sent, attachments, thread"
+        );
+    }
+
+    #[test]
+    fn plain_table() {
+        let mail = |uid: u32, subject: &str, from: &str| -> Mail {
+            serde_json::from_value(serde_json::json!({
+                "subject": subject,
+                "from": from,
+                "sender": null,
+                "reply_to": null,
+                "to": [],
+                "cc": [],
+                "message_id": null,
+                "in_reply_to": null,
+                "references": [],
+                "id": uid,
+                "body": "",
+                "snippet": "",
+                "date": "2022-05-20T09:30:00+08:00",
+                "attachments": [],
+            }))
+            .unwrap()
+        };
+        let mails = [
+            mail(7, "发票", "alice@qq.com"),
+            mail(12, &"long ".repeat(20), "bob@qq.com"),
+        ];
+
+        assert_eq!(
+            table(&mails, &[Column::Id, Column::Subject, Column::From]),
+            format!(
+                "id  Subject{}From\n\
+                 7   发票{}alice@qq.com\n\
+                 12  {}long…  bob@qq.com\n",
+                " ".repeat(55),
+                // CJK characters are two columns wide
+                " ".repeat(58),
+                "long ".repeat(11)
+            )
+        );
+        // a title sequence in a spam subject is shown rather than run
+        assert_eq!(
+            table(
+                &[mail(8, "\x1b]0;pwned\x07Win\r\n", "eve@qq.com")],
+                &[Column::Subject]
+            ),
+            "Subject\n␛]0;pwned␇Win␍␊\n"
+        );
+    }
+
+    #[test]
+    fn badge_the_collapsed_copies() {
+        let mut mail: Mail = serde_json::from_value(serde_json::json!({
+            "subject": "Weekly report",
+            "from": "alice@qq.com",
+            "to": [],
+            "cc": [],
+            "references": [],
+            "id": 7,
+            "body": "",
+            "snippet": "",
+            "date": "2022-05-20T09:30:00+08:00",
+            "attachments": [],
+            "folder": "INBOX",
+        }))
+        .unwrap();
+        assert_eq!(Column::Mailbox.text(&mail, ", "), "INBOX");
+        mail.also_in = vec!["Archive".to_string(), "Reports".to_string()];
+        assert_eq!(Column::Mailbox.text(&mail, ", "), "INBOX +2");
+    }
+
+    #[test]
+    fn cap_cells() {
+        let recipients = (0..40)
+            .map(|i| format!("user{}@qq.com", i))
+            .collect::<Vec<_>>()
+            .join("\n");
+        assert_eq!(
+            cap_lines(&recipients, 30, Some(4)),
+            "user0@qq.com\nuser1@qq.com\nuser2@qq.com\nuser3@qq.com … +36 more"
+        );
+        // the last line makes room for the summary
+        assert_eq!(
+            cap_lines(&recipients, 16, Some(2)),
+            "user0@qq.com\nuser1 … +38 more"
+        );
+        assert_eq!(cap_lines(&recipients, 13, Some(1)), "us … +39 more");
+        assert_eq!(
+            cap_lines("a@qq.com\nb@qq.com", 30, Some(2)),
+            "a@qq.com\nb@qq.com"
+        );
+        // the height counts the wrapped lines
+        assert_eq!(
+            cap_lines("a@qq.com, b@qq.com", 9, None),
+            "a@qq.com,\n b@qq.com"
+        );
+        assert_eq!(cap_lines("a@qq.com, b@qq.com", 9, Some(1)), "… +1 more");
+        assert_eq!(cap_lines("发票发票", 4, None), "发票\n发票");
+
+        let mail: Mail = serde_json::from_value(serde_json::json!({
+            "subject": "Weekly report",
+            "from": "alice@qq.com",
+            "sender": null,
+            "reply_to": null,
+            "to": ["bob@qq.com", "carol@qq.com"],
+            "cc": ["dave@qq.com"],
+            "message_id": null,
+            "in_reply_to": null,
+            "references": [],
+            "id": 7,
+            "body": "",
+            "snippet": "",
+            "date": "2022-05-20T09:30:00+08:00",
+            "attachments": [],
+        }))
+        .unwrap();
+        let columns = [Column::Subject, Column::To, Column::Cc];
+        assert_eq!(
+            cut_cells(&mail, &columns, &[], 1),
+            "To: bob@qq.com, carol@qq.com\n\n"
+        );
+        assert_eq!(cut_cells(&mail, &columns, &[], 2), "");
+        // a narrow column wraps the subject
+        assert_eq!(
+            cut_cells(&mail, &columns, &[5, 20, 20], 2),
+            "Subject: Weekly report\n\n"
+        );
+    }
+
+    #[test]
+    fn resize_within_bounds() {
+        assert_eq!(
+            resize(Constraint::Percentage(20), true),
+            Constraint::Percentage(25)
+        );
+        assert_eq!(
+            resize(Constraint::Percentage(5), false),
+            Constraint::Percentage(5)
+        );
+        assert_eq!(
+            resize(Constraint::Percentage(100), true),
+            Constraint::Percentage(100)
+        );
+        assert_eq!(resize(Constraint::Length(5), false), Constraint::Length(3));
+        assert_eq!(resize(Constraint::Length(2), false), Constraint::Length(1));
+    }
+}
@@ -0,0 +1,158 @@
+use std::{
+    io::{self, Read, Write},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use flate2::{Compress, Compression, Decompress, FlushCompress, FlushDecompress};
+use imap::extensions::idle::SetReadTimeout;
+
+const BUFFER_SIZE: usize = 8 * 1024;
+
+/// A stream that switches to the raw DEFLATE of COMPRESS=DEFLATE (RFC 4978) once
+/// `enabled` is set, the session can't swap its stream once logged in. Every flush
+/// ends a command, and sends it whole with a sync flush.
+pub struct Deflate<S> {
+    stream: S,
+    enabled: Arc<AtomicBool>,
+    compress: Compress,
+    decompress: Decompress,
+    /// Compressed bytes read but not inflated yet, from `start` on
+    input: Vec<u8>,
+    start: usize,
+    output: Vec<u8>,
+    /// Whether bytes were compressed since the last sync flush
+    unflushed: bool,
+}
+
+impl<S> Deflate<S> {
+    pub fn new(stream: S, enabled: Arc<AtomicBool>) -> Self {
+        Deflate {
+            stream,
+            enabled,
+            compress: Compress::new(Compression::default(), false),
+            decompress: Decompress::new(false),
+            input: vec![],
+            start: 0,
+            output: Vec::with_capacity(BUFFER_SIZE),
+            unflushed: false,
+        }
+    }
+
+    fn enabled(&self) -> bool {
+        self.enabled.load(Ordering::Relaxed)
+    }
+}
+
+impl<S: Write> Deflate<S> {
+    /// Compress `input` and write what came out of it
+    fn deflate(&mut self, mut input: &[u8], flush: FlushCompress) -> io::Result<()> {
+        loop {
+            self.output.clear();
+            let consumed = self.compress.total_in();
+            self.compress
+                .compress_vec(input, &mut self.output, flush)
+                .map_err(io::Error::other)?;
+            input = &input[(self.compress.total_in() - consumed) as usize..];
+            self.stream.write_all(&self.output)?;
+            // a full buffer may have left output behind
+            if input.is_empty() && self.output.len() < self.output.capacity() {
+                return Ok(());
+            }
+        }
+    }
+}
+
+impl<S: Read> Read for Deflate<S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if !self.enabled() || buf.is_empty() {
+            return self.stream.read(buf);
+        }
+        loop {
+            // even without input left, the output that didn't fit `buf` last time may be
+            // pending
+            let (consumed, produced) = (self.decompress.total_in(), self.decompress.total_out());
+            self.decompress
+                .decompress(&self.input[self.start..], buf, FlushDecompress::None)
+                .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+            self.start += (self.decompress.total_in() - consumed) as usize;
+            let produced = (self.decompress.total_out() - produced) as usize;
+            if produced > 0 {
+                return Ok(produced);
+            }
+            self.input.drain(..self.start);
+            self.start = 0;
+            let mut chunk = [0; BUFFER_SIZE];
+            let read = self.stream.read(&mut chunk)?;
+            if read == 0 {
+                return Ok(0);
+            }
+            self.input.extend_from_slice(&chunk[..read]);
+        }
+    }
+}
+
+impl<S: Write> Write for Deflate<S> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if !self.enabled() {
+            return self.stream.write(buf);
+        }
+        self.deflate(buf, FlushCompress::None)?;
+        self.unflushed = true;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        // an empty sync flush would still send a block, which the peer may read
+        // before it switched to DEFLATE itself
+        if self.enabled() && self.unflushed {
+            self.deflate(&[], FlushCompress::Sync)?;
+            self.unflushed = false;
+        }
+        self.stream.flush()
+    }
+}
+
+impl<S: SetReadTimeout> SetReadTimeout for Deflate<S> {
+    fn set_read_timeout(&mut self, timeout: Option<Duration>) -> imap::Result<()> {
+        self.stream.set_read_timeout(timeout)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pass_through_until_enabled() {
+        let enabled = Arc::new(AtomicBool::new(false));
+        let mut writer = Deflate::new(vec![], enabled.clone());
+        writer.write_all(b"a1 COMPRESS DEFLATE\r\n").unwrap();
+        writer.flush().unwrap();
+        enabled.store(true, Ordering::Relaxed);
+        writer.flush().unwrap();
+        let command = "a2 UID FETCH 1:* (FLAGS)\r\n".repeat(1000);
+        writer.write_all(command.as_bytes()).unwrap();
+        writer.flush().unwrap();
+        writer.write_all(b"a3 LOGOUT\r\n").unwrap();
+        writer.flush().unwrap();
+
+        let written = writer.stream;
+        let compressed = written
+            .strip_prefix(&b"a1 COMPRESS DEFLATE\r\n"[..])
+            .unwrap();
+        assert!(compressed.len() < command.len() / 10);
+
+        // each flushed command can be read in full before the next one was sent
+        let mut reader = Deflate::new(compressed, Arc::new(AtomicBool::new(true)));
+        let mut read = vec![0; command.len()];
+        reader.read_exact(&mut read).unwrap();
+        assert_eq!(read, command.as_bytes());
+        let mut rest = vec![];
+        reader.read_to_end(&mut rest).unwrap();
+        assert_eq!(rest, b"a3 LOGOUT\r\n");
+    }
+}
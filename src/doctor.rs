@@ -0,0 +1,405 @@
+use std::{
+    fmt::Display,
+    io,
+    net::{SocketAddr, TcpStream, ToSocketAddrs},
+    path::Path,
+    time::{Duration, Instant},
+};
+
+use native_tls::TlsStream;
+use serde::Serialize;
+
+use crate::client::{Client, ClientError};
+use crate::proxy::Proxy;
+
+/// Outcome of one step of the diagnosis
+#[derive(Debug, Serialize)]
+pub struct Check {
+    pub name: &'static str,
+    pub ok: bool,
+    pub elapsed_ms: u128,
+    /// What was found, or the error
+    pub detail: String,
+    /// The likely cause of a failure and how to fix it
+    pub hint: Option<String>,
+}
+
+impl Display for Check {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "[{}] {:<16} {:>6}ms  {}",
+            if self.ok { " ok " } else { "FAIL" },
+            self.name,
+            self.elapsed_ms,
+            self.detail
+        )?;
+        if let Some(hint) = &self.hint {
+            write!(f, "\n       {}", hint)?;
+        }
+        Ok(())
+    }
+}
+
+/// Where the credentials were taken from
+#[derive(Debug, Clone, Copy)]
+pub enum Source {
+    CommandLine,
+    PasswordFile,
+    Environment,
+    ConfigFile,
+}
+
+pub struct Doctor<'a> {
+    /// The IMAPS host and port
+    pub server: (&'a str, u16),
+    pub credentials: Option<(&'a str, &'a str)>,
+    /// Where the password was taken from
+    pub source: Source,
+    /// The config file, whether it exists or not
+    pub config_path: &'a Path,
+    pub proxy: Option<&'a Proxy>,
+    pub timeout: Option<Duration>,
+}
+
+impl Doctor<'_> {
+    /// What the connection is going to use, the password masked
+    fn configuration(&self) -> String {
+        let config = match self.config_path.exists() {
+            true => format!("config {}", self.config_path.display()),
+            false => format!("no config at {}", self.config_path.display()),
+        };
+        let credentials = match (self.credentials, self.source) {
+            (None, _) => "no credentials".to_string(),
+            (Some((username, password)), source) => format!(
+                "{} with password {} from {}",
+                username,
+                mask(password),
+                match source {
+                    Source::CommandLine => "the command line".to_string(),
+                    Source::PasswordFile => "--password-file".to_string(),
+                    Source::Environment => "QMAIL_PASSWORD".to_string(),
+                    Source::ConfigFile => self.config_path.display().to_string(),
+                }
+            ),
+        };
+        let proxy = match self.proxy {
+            Some(proxy) => format!(" through {}", proxy),
+            None => String::new(),
+        };
+        let (host, port) = self.server;
+        format!("{}:{}{}, {}, {}", host, port, proxy, credentials, config)
+    }
+
+    /// Check every step of a connection in turn, up to the first one that fails
+    pub fn run(&self) -> Vec<Check> {
+        let mut checks = vec![];
+        let mut step = |name, run: &mut dyn FnMut() -> Result<String, (String, String)>| {
+            let started = Instant::now();
+            let result = run();
+            let elapsed_ms = started.elapsed().as_millis();
+            let ok = result.is_ok();
+            checks.push(match result {
+                Ok(detail) => Check {
+                    name,
+                    ok,
+                    elapsed_ms,
+                    detail,
+                    hint: None,
+                },
+                Err((detail, hint)) => Check {
+                    name,
+                    ok,
+                    elapsed_ms,
+                    detail,
+                    hint: Some(hint),
+                },
+            });
+            ok
+        };
+
+        // only reports, the missing credentials fail the LOGIN step
+        step("configuration", &mut || Ok(self.configuration()));
+
+        let (host, port) = self.server;
+        let mut addrs = vec![];
+        let resolved = step("DNS resolution", &mut || match self.proxy {
+            Some(proxy) => Ok(format!("{} is resolved by the proxy {}", host, proxy)),
+            None => {
+                addrs = self
+                    .server
+                    .to_socket_addrs()
+                    .map_err(|err| {
+                        (
+                            format!("{}: {}", host, err),
+                            "check the network and DNS settings, or connect through --proxy"
+                                .to_string(),
+                        )
+                    })?
+                    .collect();
+                Ok(format!("{} -> {}", host, join(&addrs)))
+            }
+        });
+        if !resolved {
+            return checks;
+        }
+
+        let mut tcp = None;
+        let connected = step("TCP connect", &mut || {
+            let stream = self.connect_tcp(&addrs).map_err(|err| {
+                let hint = match self.proxy {
+                    Some(_) => {
+                        "check that the --proxy address is right and the proxy is up".to_string()
+                    }
+                    None => format!("port {} may be blocked by a firewall, try --proxy", port),
+                };
+                (err.to_string(), hint)
+            })?;
+            let peer = stream.peer_addr().map(|addr| addr.to_string());
+            tcp = Some(stream);
+            Ok(format!("connected to {}", peer.unwrap_or_default()))
+        });
+        let tcp = match tcp {
+            Some(tcp) if connected => tcp,
+            _ => return checks,
+        };
+
+        let mut tls = None;
+        let mut tcp = Some(tcp);
+        let handshaken = step("TLS handshake", &mut || {
+            let connector = native_tls::TlsConnector::new().map_err(|err| {
+                (
+                    err.to_string(),
+                    "the system TLS library could not be set up".to_string(),
+                )
+            })?;
+            let stream = connector
+                .connect(host, tcp.take().unwrap())
+                .map_err(|err| {
+                    (
+                        err.to_string(),
+                        "the certificate could not be verified: the system CA certificates may \
+                         be missing or outdated, or a proxy or firewall intercepts TLS"
+                            .to_string(),
+                    )
+                })?;
+            let detail = certificate(&stream);
+            tls = Some(stream);
+            Ok(detail)
+        });
+        let tls = match tls {
+            Some(tls) if handshaken => tls,
+            _ => return checks,
+        };
+
+        let mut client = None;
+        let mut tls = Some(tls);
+        let logged_in = step("LOGIN", &mut || {
+            let (username, password) = self.credentials.ok_or_else(|| {
+                (
+                    "no credentials".to_string(),
+                    "run `qmail login` or pass --username and --password".to_string(),
+                )
+            })?;
+            client = Some(
+                Client::login_stream(tls.take().unwrap(), username, password).map_err(|err| {
+                    let hint = if err.is_connection() {
+                        "the server closed the connection, retry later or through --proxy"
+                    } else if matches!(err, ClientError::LoginDisabled) {
+                        "generate an authorization code in the mail settings and run \
+                         `qmail login` with it"
+                    } else {
+                        "check the username and password, run `qmail login` again; with \
+                         secure login enabled exmail needs a client-specific password"
+                    };
+                    (err.to_string(), hint.to_string())
+                })?,
+            );
+            Ok(format!("logged in as {}", username))
+        });
+        let client = match client {
+            Some(client) if logged_in => client,
+            _ => return checks,
+        };
+
+        let listed = step("LIST", &mut || {
+            let names = client.mail_box_names().map_err(|err| {
+                (
+                    err.to_string(),
+                    "the account may be restricted, check it in the web mail".to_string(),
+                )
+            })?;
+            Ok(format!("{} folders", names.len()))
+        });
+        if !listed {
+            return checks;
+        }
+
+        step("SELECT INBOX", &mut || {
+            let mail_box = client.select("INBOX").map_err(|err| {
+                (
+                    err.to_string(),
+                    "the INBOX could not be opened, check the account in the web mail".to_string(),
+                )
+            })?;
+            Ok(format!("{} mails", mail_box.message_count()))
+        });
+
+        checks
+    }
+
+    fn connect_tcp(&self, addrs: &[SocketAddr]) -> io::Result<TcpStream> {
+        let stream = match self.proxy {
+            Some(proxy) => proxy.connect(self.server)?,
+            None => {
+                let mut last_error = None;
+                let mut stream = None;
+                for addr in addrs {
+                    let connected = match self.timeout {
+                        Some(timeout) => TcpStream::connect_timeout(addr, timeout),
+                        None => TcpStream::connect(addr),
+                    };
+                    match connected {
+                        Ok(connected) => {
+                            stream = Some(connected);
+                            break;
+                        }
+                        Err(err) => last_error = Some(err),
+                    }
+                }
+                match stream {
+                    Some(stream) => stream,
+                    None => {
+                        return Err(
+                            last_error.unwrap_or_else(|| io::Error::other("no address resolved"))
+                        )
+                    }
+                }
+            }
+        };
+        stream.set_read_timeout(self.timeout)?;
+        stream.set_write_timeout(self.timeout)?;
+        Ok(stream)
+    }
+}
+
+/// The subject and expiry of the server certificate
+fn certificate(stream: &TlsStream<TcpStream>) -> String {
+    let certificate = stream
+        .peer_certificate()
+        .ok()
+        .flatten()
+        .and_then(|certificate| certificate.to_der().ok())
+        .and_then(|der| openssl::x509::X509::from_der(&der).ok());
+    let certificate = match certificate {
+        Some(certificate) => certificate,
+        None => return "no certificate details".to_string(),
+    };
+
+    let subject = certificate
+        .subject_name()
+        .entries()
+        .filter_map(|entry| {
+            let value = entry.data().to_string().ok()?;
+            let key = entry.object().nid().short_name().ok()?;
+            Some(format!("{}={}", key, value))
+        })
+        .collect::<Vec<_>>()
+        .join(", ");
+    format!(
+        "certificate {}, expires {}",
+        subject,
+        certificate.not_after()
+    )
+}
+
+/// `password` hidden but for its length
+fn mask(password: &str) -> String {
+    match password.chars().count() {
+        0 => "(empty)".to_string(),
+        length => "*".repeat(length.min(8)),
+    }
+}
+
+fn join(addrs: &[SocketAddr]) -> String {
+    addrs
+        .iter()
+        .map(|addr| addr.ip().to_string())
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn render_check() {
+        let check = Check {
+            name: "TCP connect",
+            ok: false,
+            elapsed_ms: 3012,
+            detail: "connection timed out".to_string(),
+            hint: Some("port 993 may be blocked by a firewall, try --proxy".to_string()),
+        };
+
+        assert_eq!(
+            check.to_string(),
+            "[FAIL] TCP connect        3012ms  connection timed out\n       \
+             port 993 may be blocked by a firewall, try --proxy"
+        );
+    }
+
+    #[test]
+    fn stops_at_the_first_failure() {
+        let proxy = "socks5://127.0.0.1:1".parse::<Proxy>().unwrap();
+        let checks = Doctor {
+            server: ("imap.exmail.qq.com", 993),
+            credentials: None,
+            source: Source::CommandLine,
+            config_path: Path::new("/nonexistent/.qmail_pass"),
+            proxy: Some(&proxy),
+            timeout: Some(Duration::from_secs(1)),
+        }
+        .run();
+
+        assert_eq!(
+            checks.iter().map(|c| (c.name, c.ok)).collect::<Vec<_>>(),
+            vec![
+                ("configuration", true),
+                ("DNS resolution", true),
+                ("TCP connect", false)
+            ]
+        );
+        assert!(checks[2].hint.as_deref().unwrap().contains("--proxy"));
+    }
+
+    #[test]
+    fn report_the_configuration_without_the_password() {
+        let doctor = Doctor {
+            server: ("imap.exmail.qq.com", 993),
+            credentials: Some(("alice@qq.com", "s3cret")),
+            source: Source::ConfigFile,
+            config_path: Path::new("/home/alice/.qmail_pass"),
+            proxy: None,
+            timeout: None,
+        };
+
+        assert_eq!(
+            doctor.configuration(),
+            "imap.exmail.qq.com:993, alice@qq.com with password ****** from \
+             /home/alice/.qmail_pass, no config at /home/alice/.qmail_pass"
+        );
+
+        let doctor = Doctor {
+            server: ("127.0.0.1", 1993),
+            source: Source::Environment,
+            ..doctor
+        };
+        assert_eq!(
+            doctor.configuration(),
+            "127.0.0.1:1993, alice@qq.com with password ****** from QMAIL_PASSWORD, no \
+             config at /home/alice/.qmail_pass"
+        );
+    }
+}
@@ -0,0 +1,103 @@
+use unicode_width::UnicodeWidthChar;
+
+/// A match of the body search, in chars of a wrapped line
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Match {
+    pub line: usize,
+    pub start: usize,
+    pub end: usize,
+}
+
+/// `text` broken into lines of at most `width` columns, the way the body pane shows it
+pub fn wrap(text: &str, width: usize) -> Vec<String> {
+    let width = width.max(1);
+    let mut lines = vec![];
+    for line in text.lines() {
+        let mut wrapped = String::new();
+        let mut used = 0;
+        for c in line.chars() {
+            let c = if c == '\t' { ' ' } else { c };
+            let char_width = c.width().unwrap_or(0);
+            if used + char_width > width && !wrapped.is_empty() {
+                lines.push(std::mem::take(&mut wrapped));
+                used = 0;
+            }
+            wrapped.push(c);
+            used += char_width;
+        }
+        lines.push(wrapped);
+    }
+    lines
+}
+
+/// Every match of `term` in `lines`. Smart case: the search ignores the case unless the
+/// term has an uppercase letter.
+pub fn find(lines: &[String], term: &str) -> Vec<Match> {
+    let ignore_case = !term.chars().any(char::is_uppercase);
+    let fold = |c: char| match ignore_case {
+        true => c.to_lowercase().next().unwrap_or(c),
+        false => c,
+    };
+    let term = term.chars().map(fold).collect::<Vec<_>>();
+    if term.is_empty() {
+        return vec![];
+    }
+
+    let mut matches = vec![];
+    for (i, line) in lines.iter().enumerate() {
+        let chars = line.chars().map(fold).collect::<Vec<_>>();
+        let mut start = 0;
+        while start + term.len() <= chars.len() {
+            if chars[start..start + term.len()] == term[..] {
+                matches.push(Match {
+                    line: i,
+                    start,
+                    end: start + term.len(),
+                });
+                start += term.len();
+            } else {
+                start += 1;
+            }
+        }
+    }
+    matches
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn wrap_by_display_width() {
+        assert_eq!(wrap("abcdef\n\ngh", 4), vec!["abcd", "ef", "", "gh"]);
+        // CJK characters take two columns
+        assert_eq!(wrap("发票发票", 5), vec!["发票", "发票"]);
+    }
+
+    #[test]
+    fn find_with_smart_case() {
+        let lines = wrap("Invoice attached, see the invoice\nINVOICE", 20);
+        assert_eq!(
+            find(&lines, "invoice"),
+            vec![
+                Match {
+                    line: 0,
+                    start: 0,
+                    end: 7
+                },
+                Match {
+                    line: 1,
+                    start: 6,
+                    end: 13
+                },
+                Match {
+                    line: 2,
+                    start: 0,
+                    end: 7
+                },
+            ]
+        );
+        assert_eq!(find(&lines, "INVOICE").len(), 1);
+        assert!(find(&lines, "").is_empty());
+    }
+}
@@ -0,0 +1,65 @@
+//! Decoding of `format=flowed` text (RFC 3676): lines ending in a space are soft
+//! breaks, and are joined with the next line into the paragraph they were cut from.
+
+/// The signature separator, which ends in a space but is never flowed
+const SIGNATURE_SEPARATOR: &str = "-- ";
+
+/// `text` with its flowed lines joined. With `delsp` the space ending a flowed line
+/// was added by the sender and is removed, otherwise it's part of the text. Quoted
+/// lines are only joined with lines of the same quote depth, and come out with a
+/// `> ` prefix per level.
+pub fn unflow(text: &str, delsp: bool) -> String {
+    let mut unflowed = String::with_capacity(text.len());
+    // the quote depth of the paragraph being joined, `None` between paragraphs
+    let mut paragraph: Option<usize> = None;
+    for line in text.split_terminator('\n') {
+        let line = line.strip_suffix('\r').unwrap_or(line);
+        let content = line.trim_start_matches('>');
+        let depth = line.len() - content.len();
+        let content = content.strip_prefix(' ').unwrap_or(content);
+        if paragraph.is_some_and(|quoted| quoted != depth) {
+            unflowed.push('\n');
+            paragraph = None;
+        }
+        if paragraph.is_none() && depth > 0 {
+            unflowed.push_str(&">".repeat(depth));
+            unflowed.push(' ');
+        }
+        match content.strip_suffix(' ') {
+            Some(joined) if content != SIGNATURE_SEPARATOR => {
+                unflowed.push_str(if delsp { joined } else { content });
+                paragraph = Some(depth);
+            }
+            _ => {
+                unflowed.push_str(content);
+                unflowed.push('\n');
+                paragraph = None;
+            }
+        }
+    }
+    if paragraph.is_some() {
+        unflowed.push('\n');
+    }
+    unflowed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn join_flowed_lines() {
+        let text = "Hello Bob, this is a \r\nlong line.\r\n\r\n> quoted and \r\n> flowed\r\n>> deeper \r\nthen back\r\n -- stuffed\r\n-- \r\nAlice\r\n";
+
+        assert_eq!(
+            unflow(text, false),
+            "Hello Bob, this is a long line.\n\n> quoted and flowed\n>> deeper \nthen back\n-- stuffed\n-- \nAlice\n"
+        );
+    }
+
+    #[test]
+    fn delete_the_flowed_space() {
+        assert_eq!(unflow("中文没有 \r\n空格 \r\n", true), "中文没有空格\n");
+        assert_eq!(unflow("中文没有 \r\n空格\r\n", false), "中文没有 空格\n");
+    }
+}
@@ -0,0 +1,235 @@
+use serde::Serialize;
+
+/// A mail box in the folder hierarchy
+#[derive(Debug, Clone, Serialize)]
+pub struct Folder {
+    /// Last segment of the path
+    pub name: String,
+    /// Decoded full name, segments separated by `delimiter`
+    pub path: String,
+    pub delimiter: Option<String>,
+    /// `false` for \Noselect folders, which only contain other folders
+    pub selectable: bool,
+    pub messages: Option<u32>,
+    pub unseen: Option<u32>,
+    pub children: Vec<Folder>,
+}
+
+/// Arrange a flat LIST result into a tree. Parents missing from the list are added as
+/// non-selectable folders.
+pub fn tree(folders: Vec<Folder>) -> Vec<Folder> {
+    let mut roots: Vec<Folder> = vec![];
+    for folder in folders {
+        let segments = match &folder.delimiter {
+            Some(delimiter) => folder
+                .path
+                .split(delimiter.as_str())
+                .map(|s| s.to_string())
+                .collect::<Vec<_>>(),
+            None => vec![folder.path.clone()],
+        };
+        insert(&mut roots, &segments, 0, folder);
+    }
+    roots
+}
+
+fn insert(siblings: &mut Vec<Folder>, segments: &[String], level: usize, folder: Folder) {
+    let name = &segments[level];
+    let position = match siblings.iter().position(|sibling| &sibling.name == name) {
+        Some(position) => position,
+        None => {
+            let delimiter = folder.delimiter.clone().unwrap_or_default();
+            siblings.push(Folder {
+                name: name.clone(),
+                path: segments[..=level].join(&delimiter),
+                delimiter: folder.delimiter.clone(),
+                selectable: false,
+                messages: None,
+                unseen: None,
+                children: vec![],
+            });
+            siblings.len() - 1
+        }
+    };
+
+    if level + 1 == segments.len() {
+        let children = std::mem::take(&mut siblings[position].children);
+        siblings[position] = Folder {
+            name: name.clone(),
+            children,
+            ..folder
+        };
+    } else {
+        insert(
+            &mut siblings[position].children,
+            segments,
+            level + 1,
+            folder,
+        );
+    }
+}
+
+/// Drop the folders deeper than `depth` levels, 1 keeps the top level only
+pub fn truncate(folders: &mut [Folder], depth: usize) {
+    for folder in folders.iter_mut() {
+        if depth <= 1 {
+            folder.children.clear();
+        } else {
+            truncate(&mut folder.children, depth - 1);
+        }
+    }
+}
+
+/// Render the tree indented by level, with the message counts of every folder.
+/// Branches deeper than `depth` are collapsed into a count of their subfolders.
+pub fn render(folders: &[Folder], depth: Option<usize>) -> String {
+    fn walk(folders: &[Folder], level: usize, depth: Option<usize>, lines: &mut Vec<String>) {
+        for folder in folders {
+            let mut line = format!("{}{}", "  ".repeat(level), folder.name);
+            if !folder.selectable {
+                line.push_str(folder.delimiter.as_deref().unwrap_or("/"));
+            }
+            match (folder.messages, folder.unseen) {
+                (Some(messages), Some(unseen)) if unseen > 0 => {
+                    line.push_str(&format!("  {} mails, {} unseen", messages, unseen))
+                }
+                (Some(messages), _) => line.push_str(&format!("  {} mails", messages)),
+                _ => {}
+            }
+
+            if depth.is_some_and(|depth| level + 1 >= depth) && !folder.children.is_empty() {
+                line.push_str(&format!("  (+{} subfolders)", count(&folder.children)));
+                lines.push(line);
+            } else {
+                lines.push(line);
+                walk(&folder.children, level + 1, depth, lines);
+            }
+        }
+    }
+
+    let mut lines = vec![];
+    walk(folders, 0, depth, &mut lines);
+    lines.join("\n")
+}
+
+/// Whether a mail box name given on the command line is a pattern for [`matches`]
+pub fn is_pattern(name: &str) -> bool {
+    name.contains(['*', '?'])
+}
+
+/// Whether the mail box `path` matches the glob `pattern`, e.g. `Archive/*`. `*` and `?`
+/// stay within one level of the hierarchy, `**` also spans the `delimiter`.
+pub fn matches(pattern: &str, path: &str, delimiter: Option<&str>) -> bool {
+    fn glob(pattern: &[char], path: &[char], delimiter: Option<char>) -> bool {
+        let crosses = |skipped: &[char]| delimiter.is_some_and(|d| skipped.contains(&d));
+        match pattern {
+            [] => path.is_empty(),
+            ['*', '*', rest @ ..] => {
+                (0..=path.len()).any(|skip| glob(rest, &path[skip..], delimiter))
+            }
+            ['*', rest @ ..] => (0..=path.len())
+                .take_while(|&skip| !crosses(&path[..skip]))
+                .any(|skip| glob(rest, &path[skip..], delimiter)),
+            ['?', rest @ ..] => {
+                !path.is_empty() && !crosses(&path[..1]) && glob(rest, &path[1..], delimiter)
+            }
+            [c, rest @ ..] => path.first() == Some(c) && glob(rest, &path[1..], delimiter),
+        }
+    }
+
+    let pattern = pattern.chars().collect::<Vec<_>>();
+    let path = path.chars().collect::<Vec<_>>();
+    // the hierarchy delimiter is a single character in practice
+    let delimiter = delimiter.and_then(|delimiter| delimiter.chars().next());
+    glob(&pattern, &path, delimiter)
+}
+
+fn count(folders: &[Folder]) -> usize {
+    folders
+        .iter()
+        .map(|folder| 1 + count(&folder.children))
+        .sum()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn folder(path: &str, selectable: bool, messages: u32, unseen: u32) -> Folder {
+        Folder {
+            name: path.to_string(),
+            path: path.to_string(),
+            delimiter: Some("/".to_string()),
+            selectable,
+            messages: selectable.then_some(messages),
+            unseen: selectable.then_some(unseen),
+            children: vec![],
+        }
+    }
+
+    fn folders() -> Vec<Folder> {
+        tree(vec![
+            folder("INBOX", true, 12, 3),
+            folder("Projects", false, 0, 0),
+            folder("Projects/Alpha", true, 4, 0),
+            folder("Projects/Alpha/Invoices", true, 2, 1),
+            folder("Archive/2022", true, 7, 0),
+        ])
+    }
+
+    #[test]
+    fn tree_nests_by_delimiter() {
+        let folders = folders();
+
+        assert_eq!(
+            folders.iter().map(|f| f.name.as_str()).collect::<Vec<_>>(),
+            vec!["INBOX", "Projects", "Archive"]
+        );
+        let invoices = &folders[1].children[0].children[0];
+        assert_eq!(invoices.name, "Invoices");
+        assert_eq!(invoices.path, "Projects/Alpha/Invoices");
+        // not listed itself, only implied by its child
+        assert_eq!(folders[2].path, "Archive");
+        assert!(!folders[2].selectable);
+    }
+
+    #[test]
+    fn render_tree() {
+        assert_eq!(
+            render(&folders(), None),
+            "INBOX  12 mails, 3 unseen\n\
+             Projects/\n  Alpha  4 mails\n    Invoices  2 mails, 1 unseen\n\
+             Archive/\n  2022  7 mails"
+        );
+        assert_eq!(
+            render(&folders(), Some(1)),
+            "INBOX  12 mails, 3 unseen\n\
+             Projects/  (+2 subfolders)\n\
+             Archive/  (+1 subfolders)"
+        );
+    }
+
+    #[test]
+    fn truncate_drops_deep_folders() {
+        let mut folders = folders();
+        truncate(&mut folders, 2);
+
+        assert_eq!(folders[1].children.len(), 1);
+        assert!(folders[1].children[0].children.is_empty());
+    }
+
+    #[test]
+    fn match_patterns_by_level() {
+        assert!(is_pattern("Archive/*"));
+        assert!(!is_pattern("Archive/2023"));
+
+        assert!(matches("Archive/*", "Archive/2023", Some("/")));
+        assert!(!matches("Archive/*", "Archive/2023/Q1", Some("/")));
+        assert!(!matches("Archive/*", "Archive", Some("/")));
+        assert!(matches("Archive/**", "Archive/2023/Q1", Some("/")));
+        assert!(matches("Archive.20??", "Archive.2024", Some(".")));
+        assert!(!matches("Archive?2024", "Archive.2024", Some(".")));
+        // without a hierarchy every name is a single level
+        assert!(matches("Archive*", "Archive/2023/Q1", None));
+    }
+}
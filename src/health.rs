@@ -0,0 +1,154 @@
+use std::{
+    io::{self, Read, Write},
+    time::Duration,
+};
+
+use imap::extensions::idle::SetReadTimeout;
+
+/// A stream that watches the server responses for an untagged BYE. The imap crate
+/// skips it and only notices the connection is gone once the server closes it, with a
+/// bare "connection lost". Once a BYE went by, reads fail right away with its reason.
+///
+/// The BYE answering a LOGOUT is expected and left alone.
+pub struct ByeWatch<S> {
+    stream: S,
+    /// The response line read so far
+    line: Vec<u8>,
+    /// Bytes left of the literal being read, which can't hold a response
+    literal: usize,
+    /// The command line written so far
+    command: Vec<u8>,
+    logging_out: bool,
+    bye: Option<String>,
+}
+
+impl<S> ByeWatch<S> {
+    pub fn new(stream: S) -> Self {
+        ByeWatch {
+            stream,
+            line: vec![],
+            literal: 0,
+            command: vec![],
+            logging_out: false,
+            bye: None,
+        }
+    }
+
+    fn scan(&mut self, read: &[u8]) {
+        for &byte in read {
+            if self.literal > 0 {
+                self.literal -= 1;
+                continue;
+            }
+            self.line.push(byte);
+            if byte != b'\n' {
+                continue;
+            }
+
+            let line = String::from_utf8_lossy(&self.line);
+            let line = line.trim_end();
+            if let Some(reason) = line.strip_prefix("* BYE") {
+                if !self.logging_out {
+                    self.bye = Some(reason.trim().to_string());
+                }
+            } else if let Some(size) = line
+                .strip_suffix('}')
+                .and_then(|line| line.rsplit_once('{'))
+                .and_then(|(_, size)| size.parse().ok())
+            {
+                self.literal = size;
+            }
+            self.line.clear();
+        }
+    }
+}
+
+impl<S: Read> Read for ByeWatch<S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if let Some(reason) = &self.bye {
+            let message = match reason.is_empty() {
+                true => "the server closed the connection".to_string(),
+                false => format!("the server closed the connection: {}", reason),
+            };
+            return Err(io::Error::new(io::ErrorKind::ConnectionAborted, message));
+        }
+        let read = self.stream.read(buf)?;
+        self.scan(&buf[..read]);
+        Ok(read)
+    }
+}
+
+impl<S: Write> Write for ByeWatch<S> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.stream.write(buf)?;
+        for &byte in &buf[..written] {
+            if byte == b'\n' {
+                let command = String::from_utf8_lossy(&self.command);
+                // a tag then the command
+                self.logging_out = command
+                    .split_whitespace()
+                    .nth(1)
+                    .is_some_and(|name| name.eq_ignore_ascii_case("LOGOUT"));
+                self.command.clear();
+            } else {
+                self.command.push(byte);
+            }
+        }
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.stream.flush()
+    }
+}
+
+impl<S: SetReadTimeout> SetReadTimeout for ByeWatch<S> {
+    fn set_read_timeout(&mut self, timeout: Option<Duration>) -> imap::Result<()> {
+        self.stream.set_read_timeout(timeout)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn read_all(watch: &mut ByeWatch<&[u8]>) -> io::Result<Vec<u8>> {
+        let mut read = vec![];
+        let mut buf = [0; 7];
+        loop {
+            match watch.read(&mut buf)? {
+                0 => return Ok(read),
+                n => read.extend_from_slice(&buf[..n]),
+            }
+        }
+    }
+
+    #[test]
+    fn bye_fails_the_next_read() {
+        let mut watch =
+            ByeWatch::new(&b"* 3 EXISTS\r\n* BYE Autologout; idle for too long\r\n"[..]);
+
+        let err = read_all(&mut watch).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::ConnectionAborted);
+        assert_eq!(
+            err.to_string(),
+            "the server closed the connection: Autologout; idle for too long"
+        );
+    }
+
+    #[test]
+    fn bye_in_a_literal_is_data() {
+        let response = b"* 1 FETCH (BODY[TEXT] {14}\r\n* BYE quoted\r\n)\r\na1 OK done\r\n";
+        let mut watch = ByeWatch::new(&response[..]);
+
+        assert_eq!(read_all(&mut watch).unwrap(), response.to_vec());
+    }
+
+    #[test]
+    fn bye_after_logout_is_expected() {
+        let mut watch = ByeWatch::new(&b"* BYE logging out\r\na2 OK done\r\n"[..]);
+        watch.logging_out = true;
+
+        assert!(read_all(&mut watch).is_ok());
+    }
+}
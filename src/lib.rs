@@ -0,0 +1,39 @@
+//! The mail client behind the `qmail` command, to search Tencent exmail accounts from
+//! other programs. Build with the `async` feature for [`asynchronous::AsyncClient`].
+
+pub mod addresses;
+pub mod archive;
+#[cfg(feature = "async")]
+pub mod asynchronous;
+pub mod browser;
+pub mod client;
+pub mod columns;
+pub mod compress;
+pub mod doctor;
+pub mod find;
+pub mod flowed;
+pub mod folder;
+pub mod health;
+pub mod manifest;
+#[cfg(test)]
+mod mock;
+pub mod paths;
+pub mod pool;
+pub mod printable;
+pub mod profile;
+pub mod proxy;
+pub mod query;
+pub mod safety;
+pub mod saved;
+pub mod search;
+#[cfg(feature = "smtp")]
+pub mod smtp;
+pub mod stats;
+pub mod template;
+pub mod theme;
+pub mod thread;
+pub mod throttle;
+pub mod timezone;
+pub mod timings;
+pub mod utf7;
+pub mod watch;
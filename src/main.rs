@@ -1,61 +1,930 @@
-mod client;
-mod search;
-
-use std::fs::{self, File};
-use std::io::Write;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, IsTerminal, Write};
+use std::num::NonZeroUsize;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 use std::{fmt::Display, str::FromStr};
 
 use chrono::format::{parse, ParseError, Parsed, StrftimeItems};
-use chrono::{Datelike, FixedOffset, NaiveDate, TimeZone};
-use clap::{Parser, Subcommand};
+use chrono::{FixedOffset, NaiveDate};
+use clap::{Args, Parser, Subcommand};
+use columns::Column;
+use profile::Profile;
+#[cfg(feature = "smtp")]
+use qmail::smtp;
+use qmail::{
+    addresses, archive, client, columns, doctor, folder, manifest, paths, printable, profile,
+    proxy, query, safety, saved, search, stats, template, theme, thread, throttle, timezone,
+    timings, watch,
+};
+use query::Query;
 use serde::Serialize;
 use serde_json::Value;
+use template::NameTemplate;
+use theme::{ColorChoice, Theme};
+use timezone::local_midnight;
+use timings::Timings;
 
 #[derive(Parser, Debug)]
-#[clap(author, version)]
+#[clap(
+    author,
+    version,
+    after_help = "EXAMPLES:
+    qmail init                                    set up the config file step by step
+    qmail login                                   save the credentials to the config file
+    qmail doctor                                  check the configuration and the connection
+    qmail search 发票 --start-datetime 2024-01-01  browse the matching mails
+    qmail search -m 'Archive/*' --json report     search several folders, print json
+    qmail download 1234 --max-attachment-size 25M
+    qmail export -m Archive -o backup             back up a folder as .eml files"
+)]
 struct Cli {
     #[clap(subcommand)]
     command: Commands,
-    #[clap(long, help = "The username for login [default: from ~/.qmail_pass]")]
+    #[clap(
+        long,
+        global = true,
+        help = "The username for login [default: $QMAIL_USERNAME, or from the config file]"
+    )]
     username: Option<String>,
-    #[clap(long, help = "The password for login [default: from ~/.qmail_pass]")]
+    #[clap(
+        long,
+        global = true,
+        help = "Use the credentials and server of this named profile of the config file, see `qmail init` [default: $QMAIL_PROFILE, or the top-level settings]"
+    )]
+    profile: Option<String>,
+    #[clap(
+        long,
+        help = "The password for login, shown in the process list: prefer --password-file or $QMAIL_PASSWORD [default: from --password-file, $QMAIL_PASSWORD, or the config file]"
+    )]
     password: Option<String>,
+    #[clap(
+        long,
+        conflicts_with = "password",
+        help = "Read the password from this file, e.g. a mounted secret, a trailing newline is ignored"
+    )]
+    password_file: Option<PathBuf>,
+    #[clap(
+        long,
+        help = "The config file [default: $QMAIL_CONFIG, or config.json in $XDG_CONFIG_HOME/qmail-client, or ~/.qmail_pass of older versions]"
+    )]
+    config: Option<PathBuf>,
+    #[clap(
+        long,
+        help = "The cache directory [default: $QMAIL_CACHE_DIR or $XDG_CACHE_HOME/qmail-client]"
+    )]
+    cache_dir: Option<PathBuf>,
+    #[clap(
+        long,
+        help = "Connect through a proxy, e.g. socks5://127.0.0.1:1080 or http://127.0.0.1:8080 [default: from ALL_PROXY/HTTPS_PROXY, unless NO_PROXY lists the server]"
+    )]
+    proxy: Option<proxy::Proxy>,
+    #[clap(
+        long,
+        help = "Network timeout in seconds, 0 to wait forever [default: from the config file or 60]"
+    )]
+    timeout: Option<u64>,
+    #[clap(
+        long,
+        help = "Number of connections for work spread over several mail boxes [default: up to 4]"
+    )]
+    concurrency: Option<usize>,
+    #[clap(
+        long,
+        help = "Don't print the summary of searches and downloads to stderr"
+    )]
+    quiet: bool,
+    #[clap(
+        long,
+        default_value_t = throttle::DEFAULT_MAX_RPS,
+        help = "Maximum IMAP commands per second over all connections, 0 for no limit"
+    )]
+    max_rps: f64,
+    #[clap(
+        long,
+        default_value = "auto",
+        possible_values = &["auto", "always", "never"],
+        help = "When to use colors, auto uses them on a terminal unless NO_COLOR is set"
+    )]
+    color: ColorChoice,
+    #[clap(
+        long,
+        default_value_t = true,
+        parse(try_from_str),
+        value_name = "BOOL",
+        help = "Open mail boxes read-only with EXAMINE, so that searching doesn't mark mails as read; false opens them read-write with SELECT"
+    )]
+    select_readonly: bool,
+    #[clap(
+        long,
+        global = true,
+        help = "Only print what the commands changing the server would change"
+    )]
+    dry_run: bool,
+    #[clap(
+        long,
+        global = true,
+        help = "Don't ask before changing the server; without it changes of more mails than confirm_above in the config file, 10 by default, are confirmed on a terminal"
+    )]
+    yes: bool,
+    #[clap(
+        long,
+        global = true,
+        help = "Print where the time went to stderr once done: connect and TLS, login, select, search, FETCH commands, parsing and total; with --json, count, archive, search and stats also add it as _timings, as does export to mails.json, and a json list is then put under mails, threads, contacts or attachments"
+    )]
+    timings: bool,
+    #[clap(
+        long,
+        global = true,
+        help = "Compress the connection with COMPRESS=DEFLATE when the server supports it, which saves bandwidth on slow links"
+    )]
+    compress: bool,
 }
 
+// parsed once per run, the size of the search variant doesn't matter
+#[allow(clippy::large_enum_variant)]
 #[derive(Subcommand, Debug)]
 enum Commands {
+    #[clap(
+        about = "Set up the config file step by step: the credentials, the server and a login to check them"
+    )]
+    Init {
+        #[clap(
+            long,
+            help = "Read the password from the first line of stdin instead of asking, for scripts; nothing is asked then"
+        )]
+        password_stdin: bool,
+        #[clap(long, help = "The IMAPS server [default: imap.exmail.qq.com]")]
+        host: Option<String>,
+        #[clap(long, help = "The port of the IMAPS server [default: 993]")]
+        port: Option<u16>,
+        #[clap(long, help = "Save the profile without trying to log in first")]
+        no_verify: bool,
+    },
+    #[clap(about = "Save the login credentials to the config file")]
+    Login {
+        #[clap(long, help = "Save the credentials without trying to log in first")]
+        no_verify: bool,
+    },
+    #[clap(about = "Show where the config and the other files are")]
+    Config {
+        #[clap(subcommand)]
+        command: ConfigCommand,
+    },
+    #[clap(about = "Check the connection to the server and the login step by step")]
+    Doctor {
+        #[clap(long, help = "Format the output as json")]
+        json: bool,
+    },
+    #[clap(about = "Manage mail boxes")]
+    Mailbox {
+        #[clap(subcommand)]
+        command: MailboxCommand,
+    },
     #[clap(about = "List boxes")]
-    Boxes,
+    Boxes {
+        #[clap(long, help = "Collapse the folders nested deeper than this")]
+        depth: Option<usize>,
+        #[clap(long, help = "Only list the subscribed folders")]
+        subscribed: bool,
+        #[clap(long, help = "Format the output as json")]
+        json: bool,
+    },
     #[clap(about = "Search emails")]
+    #[clap(group = clap::ArgGroup::new("pick").args(&["newest", "oldest"]))]
     Search {
-        subject_query: String,
-
-        #[clap(long, help="Start datetime", default_value_t = {
-        let now = chrono::Local::now();
-        let start_datetime = NaiveDate::from_ymd(now.year(), now.month(), now.day()).and_hms(0, 0, 0);
-        DateTime(now.offset().from_local_datetime(&start_datetime).unwrap())
-        })]
-        start_datetime: DateTime,
-
-        #[clap(long, help="End datetime", default_value_t = {
-        let now = chrono::Local::now();
-        let start_datetime = NaiveDate::from_ymd(9999, 12, 31).and_hms(0, 0, 0);
-        DateTime(now.offset().from_local_datetime(&start_datetime).unwrap())
-        })]
-        end_datetime: DateTime,
-
-        #[clap(long, help = "Search query can be regex")]
-        regex: bool,
+        #[clap(flatten)]
+        filter: FilterArgs,
+
         #[clap(long, help = "Reverse the order of search result")]
         reserve: bool,
-        #[clap(short, long, help = "Specify the mail box", default_value_t = String::from("INBOX"))]
+        #[clap(long, help = "Only show the first N mails of the search result")]
+        limit: Option<usize>,
+        #[clap(
+            long,
+            visible_alias = "fetch-window-days",
+            default_value_t = 90,
+            help = "Search date ranges longer than this many days window by window, one SEARCH and FETCH each, 0 to disable"
+        )]
+        window_days: u32,
+
+        #[clap(long, help = "Only keep the newest matching mail")]
+        newest: bool,
+        #[clap(long, help = "Only keep the oldest matching mail")]
+        oldest: bool,
+        #[clap(
+            long,
+            help = "Download attachments of the selected mail",
+            requires = "pick"
+        )]
+        download: bool,
+        #[clap(
+            long,
+            default_value = "{name}",
+            requires = "download",
+            help = "File name of the downloaded attachments, see the download command"
+        )]
+        name_template: NameTemplate,
+        #[clap(
+            long,
+            requires = "download",
+            help = "Also download inline parts such as images, see the download command"
+        )]
+        include_inline: bool,
+        #[clap(
+            long,
+            requires = "download",
+            help = "Leave the attachments larger than this on the server, see the download command"
+        )]
+        max_attachment_size: Option<Size>,
+        #[clap(
+            long,
+            requires = "download",
+            help = "Leave the attachments smaller than this on the server, see the download command"
+        )]
+        min_attachment_size: Option<Size>,
+        #[clap(long, help = "Group the search result into conversation threads")]
+        threads: bool,
+        #[clap(
+            long,
+            help = "Show a mail found in several mail boxes once, by its Message-ID or else its sender, date and subject: the copy of INBOX, or of the first mail box alphabetically, is kept; the json output lists every mail box under `folders` and the others under `also_in`"
+        )]
+        dedup: bool,
+        #[clap(
+            long,
+            alias = "has-attachment",
+            conflicts_with = "query",
+            help = "Only show mails with attachments"
+        )]
+        attachments_only: bool,
+        #[clap(
+            long,
+            help = "Only show mails with at least one attachment of this MIME type, e.g. 'image/*' or application/pdf"
+        )]
+        attachment_type: Option<String>,
+        #[clap(
+            long,
+            help = "Only show mails containing this text in their headers or body, searched by the server"
+        )]
+        text: Option<String>,
+        #[clap(
+            long,
+            help = "Only show mails containing this text in their body, searched by the server"
+        )]
+        body_contains: Option<String>,
+        #[clap(
+            long,
+            help = "Only show mails received longer than this after their Date header, with an s, m, h or d suffix, e.g. 10m"
+        )]
+        lag_over: Option<Span>,
+        #[clap(flatten)]
+        body: BodyArgs,
+        #[clap(long, help = "Show a preview of the mail body in the mail list")]
+        show_snippet: bool,
+        #[clap(
+            long,
+            use_value_delimiter = true,
+            help = "Columns of the mail list, out of id, mailbox, subject, snippet, from, to, cc, date, sent, attachments and thread, the id of the conversation thread [default: all but mailbox, snippet, sent and thread; with mailbox when searching several mail boxes]"
+        )]
+        columns: Option<Vec<Column>>,
+        #[clap(
+            long,
+            help = "Keep every row of the mail list on one line, the recipients and attachments that don't fit end with how many more there are and are listed in full above the body"
+        )]
+        compact: bool,
+        #[clap(
+            long,
+            help = "Most lines of a row of the mail list once wrapped to the column widths, the cells cut short are listed in full above the body; overrides --compact [default: 4]"
+        )]
+        row_height: Option<NonZeroUsize>,
+        #[clap(
+            long,
+            help = "Hide the quoted reply history in the mail body pane and the json output"
+        )]
+        no_quotes: bool,
+        #[clap(
+            long,
+            help = "Command to open the HTML body of a mail with (`o` in the mail list), the system default when not given"
+        )]
+        browser: Option<String>,
+        #[clap(
+            long,
+            help = "Run a search saved under \"searches\" in the config file, the filters given here override its fields"
+        )]
+        saved: Option<String>,
+        #[clap(long, conflicts_with = "saved", help = "List the saved searches")]
+        list_saved: bool,
+
+        #[clap(long, help = "Format the output as json")]
+        json: bool,
+        #[clap(
+            long,
+            conflicts_with_all = &["json", "threads", "pick"],
+            help = "Print every email as a json line as soon as it is fetched"
+        )]
+        ndjson: bool,
+        #[clap(
+            long,
+            possible_values = &["tui", "table"],
+            conflicts_with_all = &["json", "ndjson"],
+            help = "Show the mails in the interactive list or as a plain text table with the --columns [default: tui on a terminal, table otherwise]"
+        )]
+        format: Option<ListFormat>,
+        #[clap(
+            long,
+            arg_enum,
+            conflicts_with_all = &["download", "threads", "ndjson", "format"],
+            help = "Print the distinct addresses of these headers of the matching emails as CSV, or json with --json, with how many emails they are in and when they were first and last seen"
+        )]
+        collect_addresses: Option<addresses::AddressField>,
+        #[clap(
+            long,
+            use_value_delimiter = true,
+            requires = "collect-addresses",
+            help = "Leave the addresses of these domains and their subdomains out of --collect-addresses, e.g. our own"
+        )]
+        exclude_domain: Vec<String>,
+    },
+    #[clap(
+        about = "Save the filters given under a name, to run them again with run-search; the dates are saved as they resolve now"
+    )]
+    SaveSearch {
+        name: String,
+        #[clap(flatten)]
+        filter: FilterArgs,
+    },
+    #[clap(about = "Run a search saved with save-search and list the matching mails")]
+    RunSearch {
+        name: String,
+        #[clap(long, help = "Format the output as json")]
+        json: bool,
+    },
+    #[clap(about = "List the searches saved with save-search")]
+    ListSearches,
+    #[clap(about = "Count the matching emails")]
+    Count {
+        #[clap(flatten)]
+        filter: FilterArgs,
+
+        #[clap(long, help = "Format the output as json")]
+        json: bool,
+    },
+    #[clap(about = "Count the matching emails per sender, day or mail box")]
+    Stats {
+        #[clap(flatten)]
+        filter: FilterArgs,
+
+        #[clap(
+            long,
+            arg_enum,
+            default_value = "from",
+            help = "Group the matching emails by"
+        )]
+        group_by: stats::GroupBy,
+        #[clap(long, help = "Format the output as json")]
+        json: bool,
+    },
+    #[clap(about = "Watch for new emails and print them as json lines")]
+    Watch {
+        #[clap(flatten)]
+        filter: FilterArgs,
+
+        #[clap(
+            long,
+            help = "Run a shell command for every new email, with QMAIL_UID, QMAIL_SUBJECT, QMAIL_FROM, QMAIL_DATE and QMAIL_ATTACHMENTS set and the json record on stdin"
+        )]
+        exec: Option<String>,
+        #[clap(
+            long,
+            default_value_t = 30,
+            help = "Kill the --exec command after this many seconds"
+        )]
+        exec_timeout: u64,
+    },
+    #[clap(about = "Print the email with the given Message-ID")]
+    Get {
+        message_id: String,
+        #[clap(
+            short,
+            long,
+            alias = "folder",
+            default_value_t = String::from("INBOX"),
+            help = "Specify the mail box"
+        )]
         mail_box: String,
+        #[clap(long, help = "Format the output as json")]
+        json: bool,
+        #[clap(
+            long,
+            help = "Cut the mail body to its first N non-empty lines, followed by a … line [default: unlimited]"
+        )]
+        preview_lines: Option<NonZeroUsize>,
+    },
+    #[clap(
+        about = "Write the matching emails to a directory as .eml files, json or their attachments"
+    )]
+    Export {
+        #[clap(flatten)]
+        filter: FilterArgs,
 
+        #[clap(
+            long,
+            default_value = "eml",
+            possible_values = &["eml", "json", "attachments"],
+            help = "eml writes every mail as sent, json an array of all mails in mails.json \
+                    and attachments the attachments of every mail"
+        )]
+        output_format: ExportFormat,
+        #[clap(
+            short,
+            long,
+            default_value = ".",
+            help = "Directory to write the files to"
+        )]
+        output_dir: PathBuf,
+        #[clap(
+            long,
+            default_value = "{name}",
+            help = "File name of the .eml files and attachments, with the placeholders {uid}, \
+                    {date}, {date:<strftime format>}, {subject}, {from}, {name} and {index}; \
+                    {name} is <uid>.eml for the .eml files, which are listed in manifest.jsonl \
+                    so that a new run skips the ones already exported"
+        )]
+        name_template: NameTemplate,
+        #[clap(
+            long,
+            help = "Also write the inline parts such as embedded images with the attachments"
+        )]
+        include_inline: bool,
+        #[clap(
+            long,
+            help = "Only export mails with at least one attachment of this MIME type, and only \
+                    such attachments with --output-format attachments, e.g. 'image/*'"
+        )]
+        attachment_type: Option<String>,
+        #[clap(flatten)]
+        body: BodyArgs,
+    },
+    #[clap(
+        about = "Move the matching emails to another mail box, e.g. those older than 30 days",
+        after_help = "EXAMPLES:
+    qmail archive --end-datetime -30d --dest 'Archive/%Y'
+    qmail archive --end-datetime 2024-01-01 --query 'from:alerts@corp.com' --dest Alerts --dry-run"
+    )]
+    Archive {
+        #[clap(flatten)]
+        filter: FilterArgs,
+
+        #[clap(
+            long,
+            help = "Mail box to move the emails to, created when missing; strftime placeholders such as %Y or %m are filled from the date each email was received"
+        )]
+        dest: archive::Destination,
+        #[clap(
+            long,
+            default_value_t = client::DEFAULT_BATCH_SIZE,
+            help = "Most emails moved by a single command"
+        )]
+        batch_size: usize,
+        #[clap(
+            long,
+            help = "Stop at the first failed command instead of going on with the next batches"
+        )]
+        fail_fast: bool,
         #[clap(long, help = "Format the output as json")]
         json: bool,
     },
     #[clap(about = "Download email attachments")]
-    Download { mail_uid: u32 },
+    Download {
+        mail_uid: u32,
+        #[clap(
+            long,
+            default_value = "{name}",
+            help = "File name of the attachments, with the placeholders {uid}, {date}, \
+                    {date:<strftime format>}, {subject}, {from}, {name} and {index}; a name \
+                    already taken gets a \" (2)\" suffix"
+        )]
+        name_template: NameTemplate,
+        #[clap(
+            long,
+            help = "Also download the inline parts such as embedded images, named after their \
+                    Content-ID when they have no file name"
+        )]
+        include_inline: bool,
+        #[clap(
+            long,
+            help = "Only download the attachments of this MIME type, e.g. 'image/*'"
+        )]
+        attachment_type: Option<String>,
+        #[clap(
+            long,
+            help = "Leave the attachments larger than this on the server, in bytes or with a K, M or G suffix, e.g. 25M; checked before they are fetched"
+        )]
+        max_attachment_size: Option<Size>,
+        #[clap(
+            long,
+            help = "Leave the attachments smaller than this on the server, e.g. 2K to skip tracking pixels"
+        )]
+        min_attachment_size: Option<Size>,
+        #[clap(
+            long,
+            help = "Print a json manifest of the saved and skipped attachments instead of the file paths"
+        )]
+        json: bool,
+    },
+    #[cfg(feature = "smtp")]
+    #[clap(
+        about = "Send an email over SMTP, e.g. to pass downloaded attachments on",
+        after_help = "The server is smtp.exmail.qq.com:465 with the login credentials, unless \
+                      overridden by an \"smtp\" object in the config file with host, port, \
+                      username and password. Port 465 is encrypted from the start, other \
+                      ports such as 587 and 25 are upgraded with STARTTLS."
+    )]
+    Send {
+        #[clap(long, required = true, help = "Recipient address, can be repeated")]
+        to: Vec<String>,
+        #[clap(long, help = "Carbon copy address, can be repeated")]
+        cc: Vec<String>,
+        #[clap(
+            long,
+            required_unless_present = "reply-to-uid",
+            help = "Subject [default: Re: and the subject of the --reply-to-uid mail]"
+        )]
+        subject: Option<String>,
+        #[clap(long, help = "File holding the plain text body [default: empty body]")]
+        body_file: Option<PathBuf>,
+        #[clap(long, help = "Attach this file, can be repeated")]
+        attach: Vec<PathBuf>,
+        #[clap(
+            long,
+            help = "Answer the mail with this UID, threading the reply to it with In-Reply-To and References"
+        )]
+        reply_to_uid: Option<u32>,
+        #[clap(
+            short,
+            long,
+            alias = "folder",
+            default_value_t = String::from("INBOX"),
+            help = "Mail box of the --reply-to-uid mail"
+        )]
+        mail_box: String,
+    },
+}
+
+impl Commands {
+    /// Whether the command was asked for json output
+    fn json(&self) -> bool {
+        match self {
+            Commands::Search { json, ndjson, .. } => *json || *ndjson,
+            Commands::Doctor { json }
+            | Commands::Boxes { json, .. }
+            | Commands::RunSearch { json, .. }
+            | Commands::Count { json, .. }
+            | Commands::Stats { json, .. }
+            | Commands::Get { json, .. }
+            | Commands::Archive { json, .. }
+            | Commands::Download { json, .. }
+            | Commands::Config {
+                command: ConfigCommand::Path { json },
+            }
+            | Commands::Mailbox {
+                command: MailboxCommand::Info { json, .. },
+            } => *json,
+            // prints json lines only
+            Commands::Watch { .. } => true,
+            _ => false,
+        }
+    }
+
+    /// The filters of the commands searching mails
+    fn filter(&self) -> Option<&FilterArgs> {
+        match self {
+            Commands::Search { filter, .. }
+            | Commands::Count { filter, .. }
+            | Commands::Stats { filter, .. }
+            | Commands::Watch { filter, .. }
+            | Commands::Export { filter, .. }
+            | Commands::Archive { filter, .. }
+            | Commands::SaveSearch { filter, .. } => Some(filter),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Subcommand, Debug)]
+enum ConfigCommand {
+    #[clap(about = "Print the resolved locations of the config file, the cache and the state")]
+    Path {
+        #[clap(long, help = "Format the output as json")]
+        json: bool,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum MailboxCommand {
+    #[clap(about = "Show the state of a mail box: its flags, number of mails and UIDs")]
+    Info {
+        name: String,
+        #[clap(long, help = "Format the output as json")]
+        json: bool,
+    },
+    #[clap(about = "Create a mail box")]
+    Create { name: String },
+    #[clap(about = "Rename a mail box")]
+    Rename { old_name: String, new_name: String },
+    #[clap(about = "Subscribe to a mail box")]
+    Subscribe { name: String },
+    #[clap(about = "Unsubscribe from a mail box")]
+    Unsubscribe { name: String },
+    #[clap(about = "Delete a mail box")]
+    Delete {
+        name: String,
+        #[clap(long, help = "Delete the mail box even if it still contains mails")]
+        force: bool,
+    },
+}
+
+#[derive(Args, Debug)]
+struct FilterArgs {
+    #[clap(
+        help = "Only match subjects containing this text, ignoring case [default: match all subjects]"
+    )]
+    subject_query: Option<String>,
+    #[clap(
+        long = "subject",
+        conflicts_with = "subject-query",
+        help = "Same as the positional subject query"
+    )]
+    subject: Option<String>,
+
+    #[clap(
+        long,
+        allow_hyphen_values = true,
+        help = "Start datetime, e.g. 2024-01-01, 2024-01-01T09:00:00 or -7d for 7 days ago [default: today]"
+    )]
+    start_datetime: Option<DateTime>,
+
+    #[clap(
+        long,
+        allow_hyphen_values = true,
+        help = "End datetime, in the formats of --start-datetime [default: 9999-12-31T00:00:00]"
+    )]
+    end_datetime: Option<DateTime>,
+
+    #[clap(long, help = "Search query can be regex")]
+    regex: bool,
+    #[clap(
+        short,
+        long,
+        alias = "folder",
+        help = "Specify the mail box [default: INBOX], or a pattern such as 'Archive/*' to search every matching one; `*` stays within a level of the hierarchy, `**` spans levels"
+    )]
+    mail_box: Option<String>,
+    #[clap(
+        long,
+        help = "Advanced: raw IMAP SEARCH criteria appended verbatim to the query, e.g. 'LARGER 5000000' (not validated)"
+    )]
+    raw_query: Option<String>,
+    #[clap(long, help = "Only match mails with a UID greater than this one")]
+    since_uid: Option<u32>,
+    #[clap(long, help = "Only match mails with a UID up to this one")]
+    max_uid: Option<u32>,
+    #[clap(
+        long,
+        help = "Only match mails larger than this size, in bytes or with a K, M or G suffix, e.g. 5M"
+    )]
+    larger: Option<Size>,
+    #[clap(
+        long,
+        help = "Only match mails smaller than this size, in bytes or with a K, M or G suffix, e.g. 100K"
+    )]
+    smaller: Option<Size>,
+    #[clap(
+        long,
+        arg_enum,
+        default_value = "received",
+        help = "Match --start-datetime and --end-datetime against the received date or the sent date of the Date header; the server still narrows the days down by received date"
+    )]
+    date_basis: client::DateBasis,
+    #[clap(
+        long,
+        conflicts_with = "unseen",
+        help = "Only match mails read already"
+    )]
+    seen: bool,
+    #[clap(long, help = "Only match mails not read yet")]
+    unseen: bool,
+    #[clap(
+        long,
+        conflicts_with = "unanswered",
+        help = "Only match mails replied to"
+    )]
+    answered: bool,
+    #[clap(long, help = "Only match mails not replied to")]
+    unanswered: bool,
+    #[clap(long, conflicts_with = "undraft", help = "Only match drafts")]
+    draft: bool,
+    #[clap(long, help = "Only match mails that are not drafts")]
+    undraft: bool,
+    #[clap(long, conflicts_with = "unflagged", help = "Only match flagged mails")]
+    flagged: bool,
+    #[clap(long, help = "Only match mails not flagged")]
+    unflagged: bool,
+    #[clap(
+        long,
+        conflicts_with = "old",
+        help = "Only match mails arrived since the mail box was last opened"
+    )]
+    recent: bool,
+    #[clap(long, help = "Only match mails that are not recent")]
+    old: bool,
+    #[clap(
+        long,
+        conflicts_with_all = &["subject-query", "subject", "start-datetime", "end-datetime", "regex", "raw-query"],
+        help = "Search expression instead of the individual filters, e.g. \
+                'from:boss@corp.com subject:\"weekly report\" has:attachment after:2023-11-01 \
+                -is:read (to:me OR cc:me)'"
+    )]
+    query: Option<Query>,
+}
+
+#[derive(Args, Debug)]
+struct BodyArgs {
+    #[clap(
+        long,
+        help = "Fetch and keep at most this much of every mail body, in bytes or with a K, M or G suffix, e.g. 64K [default: unlimited]"
+    )]
+    max_body_bytes: Option<Size>,
+    #[clap(
+        long,
+        conflicts_with = "max-body-bytes",
+        help = "Leave the mail bodies out, same as a --max-body-bytes of 0"
+    )]
+    no_body: bool,
+    #[clap(
+        long,
+        help = "Cut every mail body of the json output to its first N non-empty lines, followed by a … line, after --no-quotes [default: unlimited]"
+    )]
+    preview_lines: Option<NonZeroUsize>,
+}
+
+impl BodyArgs {
+    fn max_body_bytes(&self) -> Option<usize> {
+        match self.no_body {
+            true => Some(0),
+            false => self.max_body_bytes.map(|size| size.0 as usize),
+        }
+    }
+
+    /// `body` cut to `--preview-lines`
+    fn preview(&self, body: String) -> String {
+        preview(body, self.preview_lines)
+    }
+}
+
+impl FilterArgs {
+    /// Fill the filters not given on the command line from a saved search
+    fn apply_saved(&mut self, saved: saved::SavedSearch) -> Result<(), String> {
+        let has_subject =
+            self.subject_query.is_some() || self.subject.is_some() || self.query.is_some();
+        if !has_subject {
+            self.subject = saved.subject;
+        }
+        if self.query.is_none() {
+            if let Some(query) = saved.query {
+                self.query = Some(query.parse().map_err(|err| format!("{}", err))?);
+            }
+        }
+        if self.start_datetime.is_none() {
+            let today = chrono::Local::now().date_naive();
+            self.start_datetime = saved
+                .window
+                .map(|window| DateTime(local_midnight(window.start(today))));
+        }
+        self.mail_box = self.mail_box.take().or(saved.mail_box);
+        self.regex |= saved.regex;
+        self.raw_query = self.raw_query.take().or(saved.raw_query);
+        if saved.unseen {
+            self.raw_query = Some(match self.raw_query.take() {
+                Some(raw_query) => format!("{} UNSEEN", raw_query),
+                None => "UNSEEN".to_string(),
+            });
+        }
+        Ok(())
+    }
+
+    /// The time window searched: the dates given, then those of the query, from today
+    /// on by default
+    fn date_range(&self) -> (chrono::DateTime<FixedOffset>, chrono::DateTime<FixedOffset>) {
+        let (after, before) = match &self.query {
+            Some(query) => (query.after, query.before),
+            None => (None, None),
+        };
+        let start = match (&self.start_datetime, after) {
+            (Some(start_datetime), _) => start_datetime.0,
+            (None, Some(after)) => local_midnight(after),
+            (None, None) => local_midnight(chrono::Local::now().date_naive()),
+        };
+        let end = match (&self.end_datetime, before) {
+            (Some(end_datetime), _) => end_datetime.0,
+            (None, Some(before)) => local_midnight(before),
+            (None, None) => local_midnight(NaiveDate::from_ymd_opt(9999, 12, 31).unwrap()),
+        };
+        (start, end)
+    }
+
+    /// Reject a time window ending before it starts, which would only ever match
+    /// nothing
+    fn check_dates(&self) -> Result<(), String> {
+        let (start, end) = self.date_range();
+        match start <= end {
+            true => Ok(()),
+            false => Err(format!(
+                "start date must be before end date, {} is after {}",
+                start.to_rfc3339(),
+                end.to_rfc3339()
+            )),
+        }
+    }
+
+    /// Reject a `--regex` subject that doesn't compile, which would only ever match
+    /// nothing
+    fn check_regex(&self) -> Result<(), String> {
+        let subject = self
+            .subject_query
+            .as_ref()
+            .or(self.subject.as_ref())
+            .or(self.query.as_ref().and_then(|query| query.subject.as_ref()));
+        match subject {
+            Some(subject) if self.regex => regex::Regex::new(subject)
+                .map(|_| ())
+                .map_err(|err| format!("invalid regex {}: {}", subject, err)),
+            _ => Ok(()),
+        }
+    }
+
+    fn into_options(self) -> search::SearchOptions {
+        let (start_datetime, end_datetime) = self.date_range();
+        let query = self.query.unwrap_or_default();
+        search::SearchOptions {
+            // the filters only meet here when a saved search is overridden
+            subject_query: self
+                .subject_query
+                .or(self.subject)
+                .or(query.subject)
+                .unwrap_or_default(),
+            start_datetime,
+            end_datetime,
+            regex: self.regex,
+            reserve: false,
+            mail_box: self.mail_box.unwrap_or_else(|| "INBOX".to_string()),
+            raw_query: match (self.raw_query, query.criteria) {
+                (Some(raw_query), Some(criteria)) => Some(format!("{} {}", criteria, raw_query)),
+                (raw_query, criteria) => criteria.or(raw_query),
+            },
+            since_uid: self.since_uid,
+            max_uid: self.max_uid,
+            larger: self.larger.map(|size| size.0),
+            smaller: self.smaller.map(|size| size.0),
+            limit: None,
+            window_days: 90,
+            progress: false,
+            newest: false,
+            oldest: false,
+            threads: false,
+            attachments_only: query.has_attachment,
+            show_snippet: false,
+            columns: Column::DEFAULT.to_vec(),
+            row_height: None,
+            no_quotes: false,
+            browser: None,
+            attachment_type: None,
+            text: None,
+            body_contains: None,
+            lag_over: None,
+            max_body_bytes: None,
+            date_basis: self.date_basis,
+            dedup: false,
+            flags: [
+                (self.seen, client::FlagKey::Seen),
+                (self.unseen, client::FlagKey::Unseen),
+                (self.answered, client::FlagKey::Answered),
+                (self.unanswered, client::FlagKey::Unanswered),
+                (self.draft, client::FlagKey::Draft),
+                (self.undraft, client::FlagKey::Undraft),
+                (self.flagged, client::FlagKey::Flagged),
+                (self.unflagged, client::FlagKey::Unflagged),
+                (self.recent, client::FlagKey::Recent),
+                (self.old, client::FlagKey::Old),
+            ]
+            .into_iter()
+            .filter(|(set, _)| *set)
+            .map(|(_, flag)| flag)
+            .collect(),
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -66,6 +935,11 @@ impl FromStr for DateTime {
     type Err = ParseError;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
+        // a duration before now, e.g. -30d
+        if let Some(Ok(Span(ago))) = s.strip_prefix('-').map(str::parse::<Span>) {
+            return Ok(DateTime((chrono::Local::now() - ago).fixed_offset()));
+        }
+
         let mut parsed = Parsed::new();
 
         if parse(&mut parsed, s, StrftimeItems::new("%Y-%m-%dT%H:%M:%S")).is_err() {
@@ -80,10 +954,9 @@ impl FromStr for DateTime {
         parsed.second = parsed.second.or(Some(0));
         parsed.nanosecond = parsed.nanosecond.or(Some(0));
 
-        let now = chrono::Local::now();
-        parsed.offset = parsed.offset.or(Some(now.offset().local_minus_utc()));
-
-        Ok(DateTime(parsed.to_datetime()?))
+        // in the offset of that date, which differs from today's across DST changes
+        let local = parsed.to_naive_datetime_with_offset(0)?;
+        Ok(DateTime(timezone::local(local)))
     }
 }
 
@@ -93,109 +966,1673 @@ impl Display for DateTime {
     }
 }
 
+/// A mail size in bytes, given as a number with an optional K, M or G suffix (powers
+/// of 1024)
+#[derive(Debug, Clone, Copy)]
+struct Size(u64);
+
+impl FromStr for Size {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let (number, unit) = match s.char_indices().last() {
+            Some((i, c)) if c.is_ascii_alphabetic() => (&s[..i], c.to_ascii_uppercase()),
+            _ => (s, 'B'),
+        };
+        let multiplier = match unit {
+            'B' => 1,
+            'K' => 1 << 10,
+            'M' => 1 << 20,
+            'G' => 1 << 30,
+            _ => return Err(format!("unknown size unit `{}`, use K, M or G", unit)),
+        };
+        let number = number
+            .trim()
+            .parse::<f64>()
+            .map_err(|_| format!("invalid size `{}`", s))?;
+        if !number.is_finite() || number <= 0.0 {
+            return Err(format!("the size must be greater than 0, got `{}`", s));
+        }
+        Ok(Size((number * multiplier as f64).round() as u64))
+    }
+}
+
+/// A duration given as a number with an s, m, h or d suffix, e.g. 90s or 2h
+#[derive(Debug, Clone, Copy)]
+struct Span(chrono::Duration);
+
+impl FromStr for Span {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let (number, unit) = match s.char_indices().last() {
+            Some((i, c)) if c.is_ascii_alphabetic() => (&s[..i], c.to_ascii_lowercase()),
+            _ => return Err(format!("missing unit in `{}`, use s, m, h or d", s)),
+        };
+        let seconds = match unit {
+            's' => 1,
+            'm' => 60,
+            'h' => 60 * 60,
+            'd' => 24 * 60 * 60,
+            _ => {
+                return Err(format!(
+                    "unknown duration unit `{}`, use s, m, h or d",
+                    unit
+                ))
+            }
+        };
+        let number = number
+            .trim()
+            .parse::<u32>()
+            .map_err(|_| format!("invalid duration `{}`", s))?;
+        Ok(Span(chrono::Duration::seconds(number as i64 * seconds)))
+    }
+}
+
 #[derive(Serialize)]
-struct SearchResult {
-    id: u32,
-    subject: String,
-    from: String,
-    to: String,
-    cc: String,
-    date: String,
-    body: String,
-    attachments: String,
-}
-
-impl SearchResult {
-    fn from_mail(mail: client::Mail) -> Self {
-        SearchResult {
-            id: mail.uid,
-            subject: mail.subject,
-            from: mail.from,
-            to: mail.to.join("\n"),
-            cc: mail.cc.join("\n"),
-            date: mail.internal_date.to_rfc3339(),
-            body: mail.body,
-            attachments: mail
-                .attachments
-                .iter()
-                .map(|a| a.name.clone())
-                .collect::<Vec<String>>()
-                .join("\n"),
+struct ThreadResult {
+    thread_subject: String,
+    mails: Vec<client::Mail>,
+}
+
+impl ThreadResult {
+    fn from_thread(thread: thread::Thread) -> Self {
+        ThreadResult {
+            thread_subject: thread.subject,
+            mails: thread.mails,
         }
     }
 }
 
+const EXIT_FAILURE: i32 = 1;
+const EXIT_NO_MATCH: i32 = 2;
+const EXIT_CONNECTION_FAILURE: i32 = 3;
+
+/// Whether the command prints json, its errors then go to stdout as json too, see
+/// [`report_error`]
+static JSON_ERRORS: AtomicBool = AtomicBool::new(false);
+
 fn main() {
     let cli = Cli::parse();
-    let (username, password) = if cli.username.is_none() || cli.password.is_none() {
-        let qmail_passwd = dirs::home_dir().unwrap().join(".qmail_pass");
-        let value: Value =
-            serde_json::from_str(fs::read_to_string(qmail_passwd).unwrap().as_str()).unwrap();
-        (
-            value["username"].as_str().unwrap().to_string(),
-            value["password"].as_str().unwrap().to_string(),
-        )
-    } else {
-        (cli.username.unwrap(), cli.password.unwrap())
+    if cli.command.json() {
+        JSON_ERRORS.store(true, Ordering::Relaxed);
+        let default_hook = std::panic::take_hook();
+        std::panic::set_hook(Box::new(move |info| {
+            let message = match info.payload().downcast_ref::<&str>() {
+                Some(message) => message.to_string(),
+                None => match info.payload().downcast_ref::<String>() {
+                    Some(message) => message.clone(),
+                    None => "panicked".to_string(),
+                },
+            };
+            report_error(ErrorKind::Internal, &message);
+            default_hook(info);
+        }));
+    }
+    if let Err(err) = run(cli) {
+        report_error(ErrorKind::of(&err), &err.to_string());
+        std::process::exit(if err.is_connection() {
+            EXIT_CONNECTION_FAILURE
+        } else {
+            EXIT_FAILURE
+        });
+    }
+}
+
+fn run(mut cli: Cli) -> Result<(), client::ClientError> {
+    let paths = paths::Paths::resolve(cli.config.take(), cli.cache_dir.take());
+    if let Commands::Config {
+        command: ConfigCommand::Path { json },
+    } = cli.command
+    {
+        if json {
+            println!("{}", serde_json::to_string(&paths).unwrap());
+        } else {
+            println!("config    {}", paths.config);
+            println!("cache     {}", paths.cache_dir);
+            println!("searches  {}", paths.searches);
+            println!("state     {}", paths.state_dir);
+        }
+        return Ok(());
+    }
+
+    let config = read_config(&paths);
+    let profile = cli.profile.clone().or_else(|| env_var("QMAIL_PROFILE"));
+    if let Commands::Init {
+        password_stdin,
+        host,
+        port,
+        no_verify,
+    } = &cli.command
+    {
+        let given = Init {
+            profile,
+            username: cli.username.clone(),
+            password_stdin: *password_stdin,
+            host: host.clone(),
+            port: *port,
+            no_verify: *no_verify,
+            proxy: cli.proxy.clone(),
+        };
+        return init(given, config, &paths);
+    }
+    if let Commands::Login { no_verify } = cli.command {
+        // the credentials saved already are replaced, not reused
+        let given = credentials(&cli, &Value::Null);
+        let timeout = cli
+            .timeout
+            .or_else(|| config["timeout"].as_u64())
+            .unwrap_or(60);
+        let connection = Connection {
+            proxy: cli.proxy.clone(),
+            timeout: match timeout {
+                0 => None,
+                secs => Some(Duration::from_secs(secs)),
+            },
+        };
+        return login(
+            given.username,
+            given.password,
+            no_verify,
+            profile.as_deref(),
+            connection,
+            config,
+            &paths,
+        );
+    }
+    let config = match &profile {
+        Some(name) => profile::select(&config, name).unwrap_or_else(|err| fail(&err)),
+        None => config,
+    };
+    let credentials = credentials(&cli, &config);
+    let host = env_var("QMAIL_HOST")
+        .or_else(|| config["host"].as_str().map(str::to_string))
+        .unwrap_or_else(|| client::DOMAIN.to_string());
+    let port = match env_var("QMAIL_PORT") {
+        Some(port) => port
+            .parse::<u16>()
+            .unwrap_or_else(|_| fail(&format!("invalid QMAIL_PORT `{}`", port))),
+        None => config["port"]
+            .as_u64()
+            .and_then(|port| u16::try_from(port).ok())
+            .unwrap_or(client::PORT),
     };
 
-    let client = client::Client::new(&username, &password).unwrap();
+    if let Commands::Search {
+        list_saved: true, ..
+    } = cli.command
+    {
+        for (name, search) in saved::saved_searches(&config).unwrap_or_else(|err| fail(&err)) {
+            println!("{}  {}", name, search);
+        }
+        return Ok(());
+    }
+    if let Commands::Search {
+        filter,
+        attachments_only,
+        saved: Some(name),
+        ..
+    } = &mut cli.command
+    {
+        let saved = saved::saved_searches(&config)
+            .unwrap_or_else(|err| fail(&err))
+            .remove(name.as_str())
+            .unwrap_or_else(|| fail(&format!("no saved search named {}", name)));
+        *attachments_only |= saved.attachments_only;
+        filter
+            .apply_saved(saved)
+            .unwrap_or_else(|err| fail(&format!("saved search {}: {}", name, err)));
+    }
+    if let Some(filter) = cli.command.filter() {
+        filter.check_dates().unwrap_or_else(|err| fail(&err));
+        filter.check_regex().unwrap_or_else(|err| fail(&err));
+    }
+    let searches = saved::SearchStore::new(&paths.searches.path);
+    match cli.command {
+        Commands::SaveSearch { name, filter } => {
+            let options = filter.into_options();
+            let search = saved::StoredSearch {
+                criteria: options.criteria(),
+                mail_box: options.mail_box,
+            };
+            let replaced = searches
+                .save(&name, &search)
+                .unwrap_or_else(|err| fail(&err));
+            println!(
+                "{} {} in {}: {}",
+                if replaced { "Replaced" } else { "Saved" },
+                name,
+                paths.searches.path.display(),
+                search
+            );
+            return Ok(());
+        }
+        Commands::ListSearches => {
+            let mut invalid = false;
+            for (name, search) in searches.load().unwrap_or_else(|err| fail(&err)) {
+                match search {
+                    Ok(search) => println!("{}  {}", name, search),
+                    Err(err) => {
+                        eprintln!("error: {}", err);
+                        invalid = true;
+                    }
+                }
+            }
+            if invalid {
+                std::process::exit(EXIT_FAILURE);
+            }
+            return Ok(());
+        }
+        _ => {}
+    }
+
+    let timeout = cli
+        .timeout
+        .or_else(|| config["timeout"].as_u64())
+        .unwrap_or(60);
+    let proxy = cli.proxy.or_else(|| proxy::Proxy::from_env(&host));
+
+    if let Commands::Doctor { json } = cli.command {
+        let config_path = paths.config_path();
+        let checks = doctor::Doctor {
+            server: (&host, port),
+            credentials: credentials
+                .username
+                .as_deref()
+                .zip(credentials.password.as_deref()),
+            source: credentials.source,
+            config_path,
+            proxy: proxy.as_ref(),
+            timeout: match timeout {
+                0 => None,
+                secs => Some(Duration::from_secs(secs)),
+            },
+        }
+        .run();
+        if json {
+            println!("{}", serde_json::to_string(&checks).unwrap());
+        } else {
+            for check in checks.iter() {
+                println!("{}", check);
+            }
+        }
+        if checks.iter().any(|check| !check.ok) {
+            std::process::exit(EXIT_FAILURE);
+        }
+        return Ok(());
+    }
+
+    let (username, password) = match credentials.username.zip(credentials.password) {
+        Some(credentials) => credentials,
+        None => fail(&format!(
+            "no credentials in {}, run `qmail init`, pass --username and --password, or set \
+             QMAIL_USERNAME and QMAIL_PASSWORD",
+            paths.config_path().display()
+        )),
+    };
+    let throttle = Arc::new(throttle::Throttle::new(cli.max_rps));
+    let connect = || {
+        client::Client::builder(&username, &password)
+            .server(&host, port)
+            .proxy(proxy.clone())
+            .throttle(Some(throttle.clone()))
+            .read_only(cli.select_readonly)
+            .compress(cli.compress)
+            .timeout(match timeout {
+                0 => None,
+                secs => Some(Duration::from_secs(secs)),
+            })
+            .connect()
+    };
+    #[cfg(feature = "smtp")]
+    if let Commands::Send {
+        to,
+        cc,
+        subject,
+        body_file,
+        attach,
+        reply_to_uid,
+        mail_box,
+    } = cli.command
+    {
+        let config =
+            smtp::SmtpConfig::from_config(&config, &username, &password).unwrap_or_else(|err| {
+                fail(&format!(
+                    "invalid smtp in {}: {}",
+                    paths.config_path().display(),
+                    err
+                ))
+            });
+        let mut message = smtp::Message {
+            from: config.username.clone(),
+            to,
+            cc,
+            subject: subject.unwrap_or_default(),
+            body: match body_file {
+                Some(path) => fs::read_to_string(&path).unwrap_or_else(|err| {
+                    fail(&format!("failed to read {}: {}", path.display(), err))
+                }),
+                None => String::new(),
+            },
+            ..smtp::Message::default()
+        };
+        for path in attach {
+            let content = fs::read(&path)
+                .unwrap_or_else(|err| fail(&format!("failed to read {}: {}", path.display(), err)));
+            let name = path
+                .file_name()
+                .map(|name| name.to_string_lossy().to_string())
+                .unwrap_or_else(|| fail(&format!("{} is not a file", path.display())));
+            message.attachments.push(smtp::Attachment { name, content });
+        }
+        if let Some(uid) = reply_to_uid {
+            let client = connect()?;
+            let original = client.get(&mail_box)?.fetch_raw(uid)?;
+            message
+                .reply_to(&original)
+                .unwrap_or_else(|err| fail(&format!("can't reply to mail {}: {}", uid, err)));
+        }
+        let timeout = match timeout {
+            0 => None,
+            secs => Some(Duration::from_secs(secs)),
+        };
+        smtp::send(&config, proxy.as_ref(), timeout, &message)
+            .unwrap_or_else(|err| fail(&format!("send: {}", err)));
+        if !cli.quiet {
+            eprintln!(
+                "send: sent to {} recipients with {} attachments",
+                message.to.len() + message.cc.len(),
+                message.attachments.len()
+            );
+        }
+        return Ok(());
+    }
+
+    let safety = safety::Safety::new(
+        cli.dry_run,
+        cli.yes,
+        config["confirm_above"]
+            .as_u64()
+            .map_or(safety::DEFAULT_CONFIRM_ABOVE, |above| above as usize),
+    );
+    let mut client = connect()?;
+    client.set_report_timings(cli.timings);
     match cli.command {
         Commands::Search {
-            subject_query,
-            start_datetime,
-            end_datetime,
-            regex,
+            filter,
             reserve,
-            mail_box,
+            limit,
+            window_days,
+            newest,
+            oldest,
+            download,
+            name_template,
+            include_inline,
+            max_attachment_size,
+            min_attachment_size,
+            threads,
+            dedup,
+            attachments_only,
+            attachment_type,
+            text,
+            body_contains,
+            lag_over,
+            body,
+            show_snippet,
+            columns,
+            compact,
+            row_height,
+            no_quotes,
+            browser,
+            saved: _,
+            list_saved: _,
             json,
+            ndjson,
+            format,
+            collect_addresses,
+            exclude_domain,
         } => {
+            let list_format = format.unwrap_or_else(ListFormat::detect);
+            let options = search::SearchOptions {
+                reserve,
+                limit,
+                window_days,
+                // the TUI owns the terminal
+                progress: !cli.quiet
+                    && (download
+                        || ndjson
+                        || json
+                        || collect_addresses.is_some()
+                        || list_format == ListFormat::Table),
+                newest,
+                oldest,
+                threads,
+                dedup,
+                show_snippet,
+                columns: columns.unwrap_or_else(|| {
+                    let mut columns = Column::DEFAULT.to_vec();
+                    if filter.mail_box.as_deref().is_some_and(folder::is_pattern) {
+                        columns.insert(1, Column::Mailbox);
+                    }
+                    columns
+                }),
+                row_height: Some(match (row_height, compact) {
+                    (Some(row_height), _) => row_height.get(),
+                    (None, true) => 1,
+                    (None, false) => search::DEFAULT_ROW_HEIGHT,
+                }),
+                no_quotes,
+                browser,
+                attachment_type,
+                text,
+                body_contains,
+                lag_over: lag_over.map(|span| span.0),
+                max_body_bytes: body.max_body_bytes(),
+                ..filter.into_options()
+            };
+            let options = search::SearchOptions {
+                attachments_only: attachments_only || options.attachments_only,
+                ..options
+            };
+            let started = Instant::now();
+            // the json output shows the body the way the TUI does
+            let export = |mut mail: client::Mail| {
+                if no_quotes {
+                    mail.body = client::strip_quotes(&mail.body);
+                }
+                mail.body = body.preview(mail.body);
+                mail
+            };
+            if let Some(field) = collect_addresses {
+                // only the addresses are kept, not the mails
+                let mut book = addresses::AddressBook::new(field, &exclude_domain);
+                let mut matched = 0;
+                let scan = options.fetch_each(&client, |mail| {
+                    book.add(&mail);
+                    matched += 1;
+                })?;
+                print_fetch_warnings(&scan.warnings);
+                let contacts = book.contacts();
+                if json {
+                    let contacts = serde_json::to_value(&contacts).unwrap();
+                    println!(
+                        "{}",
+                        list_with_timings("contacts", contacts, &client, cli.timings)
+                    );
+                } else {
+                    print!("{}", addresses::to_csv(&contacts));
+                }
+                if !cli.quiet {
+                    print_search_summary(matched, &scan, started);
+                }
+            } else if download {
+                let size_limit = size_limit(min_attachment_size, max_attachment_size);
+                let mut mail_box: Option<client::MailBox> = None;
+                let mut downloaded = Downloaded::with_manifest(json);
+                let (mails, scan) = options.fetch_scanned(&client)?;
+                print_fetch_warnings(&scan.warnings);
+                for mail in mails {
+                    // a pattern interleaves the mails of several mail boxes
+                    let mail_box = match mail_box {
+                        Some(ref mail_box) if Some(mail_box.name()) == mail.folder.as_deref() => {
+                            mail_box
+                        }
+                        _ => mail_box.insert(options.mail_box_of(&client, &mail)?),
+                    };
+                    downloaded.add_attachments(download_attachments(
+                        mail_box,
+                        mail.uid,
+                        Path::new(""),
+                        &name_template,
+                        include_inline,
+                        options.attachment_type.as_deref(),
+                        size_limit,
+                    )?);
+                }
+                downloaded.print_manifest(&client, cli.timings);
+                if !cli.quiet {
+                    eprintln!("download: {}", downloaded);
+                }
+            } else if ndjson {
+                let stdout = io::stdout();
+                let mut matched = 0;
+                let scan = options.fetch_each(&client, |mail| {
+                    let mail = export(mail);
+                    let mut stdout = stdout.lock();
+                    serde_json::to_writer(&mut stdout, &mail).unwrap();
+                    writeln!(stdout).unwrap();
+                    stdout.flush().unwrap();
+                    matched += 1;
+                })?;
+                // the timings come last, on a line of their own
+                if cli.timings {
+                    println!("{}", with_timings(serde_json::json!({}), &client, true));
+                }
+                print_fetch_warnings(&scan.warnings);
+                if !cli.quiet {
+                    print_search_summary(matched, &scan, started);
+                }
+            } else if json {
+                let (mails, scan) = options.fetch_scanned(&client)?;
+                print_fetch_warnings(&scan.warnings);
+                let mails = mails.into_iter().map(export).collect::<Vec<_>>();
+                let matched = mails.len();
+                if threads {
+                    let mut threads = thread::threads(mails)
+                        .into_iter()
+                        .map(ThreadResult::from_thread)
+                        .collect::<Vec<_>>();
+                    if options.reserve {
+                        threads.reverse();
+                    }
+                    let threads = serde_json::to_value(&threads).unwrap();
+                    println!(
+                        "{}",
+                        list_with_timings("threads", threads, &client, cli.timings)
+                    );
+                } else {
+                    let mails = serde_json::to_value(&mails).unwrap();
+                    println!(
+                        "{}",
+                        list_with_timings("mails", mails, &client, cli.timings)
+                    );
+                }
+                if !cli.quiet {
+                    print_search_summary(matched, &scan, started);
+                }
+            } else if list_format == ListFormat::Table {
+                let (mails, scan) = options.fetch_scanned(&client)?;
+                print_fetch_warnings(&scan.warnings);
+                print!("{}", columns::table(&mails, &options.shown_columns()));
+                if !cli.quiet {
+                    print_search_summary(mails.len(), &scan, started);
+                }
+            } else {
+                let theme = if cli.color.enabled() {
+                    Theme::from_config(&config["theme"]).unwrap_or_else(|err| {
+                        fail(&format!(
+                            "invalid theme in {}: {}",
+                            paths.config_path().display(),
+                            err
+                        ))
+                    })
+                } else {
+                    Theme::monochrome()
+                };
+                // logs the client out on exit
+                search::run(client, &connect, options, theme, safety).unwrap();
+                return Ok(());
+            }
+        }
+        Commands::RunSearch { name, json } => {
+            let search = searches.get(&name).unwrap_or_else(|err| fail(&err));
+            let started = Instant::now();
+            let (mails, warnings) = search.fetch(&client)?;
+            print_fetch_warnings(&warnings);
             if json {
-                let mail_box = client.get(&mail_box).unwrap();
-                let mails = mail_box
-                    .filter(&subject_query, start_datetime.0)
-                    .end_date(end_datetime.0)
-                    .regex(regex)
-                    .reverse(reserve)
-                    .fetch();
-                let mails = mails
-                    .into_iter()
-                    .map(SearchResult::from_mail)
-                    .collect::<Vec<_>>();
                 println!("{}", serde_json::to_string(&mails).unwrap());
             } else {
-                search::run(
-                    client,
-                    subject_query,
-                    start_datetime.0,
-                    end_datetime.0,
-                    regex,
-                    reserve,
-                    mail_box,
-                )
-                .unwrap();
-            }
-        }
-        Commands::Download { mail_uid } => {
-            let mail_box = client.get("INBOX").unwrap();
-            let attachments = mail_box.download(mail_uid).unwrap_or_default();
-            for (attachment_name, attachment_data) in attachments {
-                let mut file = File::create(&attachment_name).unwrap();
-                println!("{}", attachment_name);
-                file.write_all(&attachment_data[..]).unwrap();
-            }
-        }
-        Commands::Boxes => {
+                print!("{}", columns::table(&mails, &Column::DEFAULT));
+            }
+            if !cli.quiet {
+                eprintln!(
+                    "run-search: {} matched in {:.1}s",
+                    mails.len(),
+                    started.elapsed().as_secs_f64()
+                );
+            }
+            if mails.is_empty() {
+                exit(client, EXIT_NO_MATCH);
+            }
+        }
+        Commands::Count { filter, json } => {
+            let options = filter.into_options();
+            let count = options.count(&client)?;
+            if json {
+                let output = serde_json::json!({
+                    "count": count,
+                    "mailbox": options.mail_box,
+                    "query": {
+                        "subject": options.subject_query,
+                        "start_datetime": options.start_datetime.to_rfc3339(),
+                        "end_datetime": options.end_datetime.to_rfc3339(),
+                        "regex": options.regex,
+                        "raw_query": options.raw_query,
+                    },
+                });
+                println!("{}", with_timings(output, &client, cli.timings));
+            } else {
+                println!("{}", count);
+            }
+            if count == 0 {
+                exit(client, EXIT_NO_MATCH);
+            }
+        }
+        Commands::Archive {
+            mut filter,
+            dest,
+            batch_size,
+            fail_fast,
+            json,
+        } => {
+            let query = filter.query.as_ref();
+            if filter.end_datetime.is_none() && query.and_then(|q| q.before).is_none() {
+                fail("archive needs a cutoff, pass --end-datetime, e.g. -30d");
+            }
+            if filter.start_datetime.is_none() && query.and_then(|q| q.after).is_none() {
+                // everything up to the cutoff rather than the mails of today
+                let epoch = NaiveDate::from_ymd_opt(1970, 1, 1).unwrap();
+                filter.start_datetime = Some(DateTime(local_midnight(epoch)));
+            }
+            let options = filter.into_options();
+            if folder::is_pattern(&options.mail_box) {
+                fail("archive moves the emails of a single mail box, not of a pattern");
+            }
+            let batch = client::Batch {
+                size: batch_size,
+                fail_fast,
+            };
+            let moved = match archive::run(&client, &options, &dest, batch, &safety)? {
+                Some(moved) => moved,
+                None => {
+                    eprintln!("archive: cancelled, nothing was moved");
+                    return Ok(());
+                }
+            };
+            let dry_run = safety.dry_run;
+            // a dry run counts the mails it would move
+            let count = |outcome: &client::BatchOutcome| match dry_run {
+                true => outcome.total,
+                false => outcome.done(),
+            };
+            let total = moved
+                .iter()
+                .map(|(_, outcome)| count(outcome))
+                .sum::<usize>();
+            let requested = moved
+                .iter()
+                .map(|(_, outcome)| outcome.total)
+                .sum::<usize>();
+            let failed = moved
+                .iter()
+                .flat_map(|(name, outcome)| outcome.failed().map(move |chunk| (name, chunk)))
+                .collect::<Vec<_>>();
+            if json {
+                let destinations = moved
+                    .iter()
+                    .map(|(name, outcome)| (name.clone(), Value::from(count(outcome))))
+                    .collect::<serde_json::Map<_, _>>();
+                let failures = failed
+                    .iter()
+                    .map(|(name, chunk)| {
+                        serde_json::json!({
+                            "destination": name,
+                            "count": chunk.uids.len(),
+                            "range": chunk.range(),
+                            "error": chunk.error.as_ref().map(|err| err.to_string()),
+                        })
+                    })
+                    .collect::<Vec<_>>();
+                let output = serde_json::json!({
+                    "dry_run": dry_run,
+                    "mailbox": options.mail_box,
+                    "destinations": destinations,
+                    "total": total,
+                    "failed": failures,
+                });
+                println!("{}", with_timings(output, &client, cli.timings));
+            } else {
+                for (name, outcome) in moved.iter() {
+                    println!("{:>6}  {}", count(outcome), name);
+                }
+            }
+            // printed even with --quiet, like the skipped mails of a search
+            for (name, chunk) in failed.iter() {
+                eprintln!("error: moving to {}: {}", name, chunk);
+            }
+            if !cli.quiet {
+                let verb = if dry_run { "would move" } else { "moved" };
+                let of = match total == requested {
+                    true => String::new(),
+                    false => format!(" of {}", requested),
+                };
+                eprintln!(
+                    "archive: {} {}{} mails to {} mail boxes",
+                    verb,
+                    total,
+                    of,
+                    moved.len()
+                );
+            }
+            if !failed.is_empty() {
+                exit(client, EXIT_FAILURE);
+            }
+        }
+        Commands::Stats {
+            filter,
+            group_by,
+            json,
+        } => {
+            let options = filter.into_options();
+            let groups = stats::run(&client, &options, group_by, cli.concurrency, connect)?;
+            if json {
+                let groups = groups
+                    .into_iter()
+                    .map(|(key, count)| (key, Value::from(count)))
+                    .collect::<serde_json::Map<_, _>>();
+                println!(
+                    "{}",
+                    with_timings(Value::Object(groups), &client, cli.timings)
+                );
+            } else {
+                for (key, count) in groups {
+                    println!("{:>6}  {}", count, printable::printable(&key));
+                }
+            }
+        }
+        Commands::Watch {
+            filter,
+            exec,
+            exec_timeout,
+        } => {
+            let options = filter.into_options();
+            let hook = exec.map(|command| watch::Hook {
+                command,
+                timeout: Duration::from_secs(exec_timeout),
+            });
+            watch::run(&client, &options, |mail| {
+                let json = serde_json::to_string(&mail).unwrap();
+                println!("{}", json);
+                if let Some(hook) = &hook {
+                    match hook.run(&mail, &json) {
+                        Ok(status) if !status.success() => {
+                            eprintln!("hook failed for mail {}: {}", mail.uid, status)
+                        }
+                        Err(err) => eprintln!("hook failed for mail {}: {}", mail.uid, err),
+                        _ => {}
+                    }
+                }
+            })?;
+        }
+        Commands::Get {
+            message_id,
+            mail_box,
+            json,
+            preview_lines,
+        } => {
+            let found = client.get(&mail_box)?.find_by_message_id(&message_id)?;
+            let mut mail = match found {
+                Some(mail) => mail,
+                None => {
+                    report_error(
+                        ErrorKind::MailNotFound,
+                        &format!("no mail with Message-ID {} in {}", message_id, mail_box),
+                    );
+                    exit(client, EXIT_NO_MATCH);
+                }
+            };
+            mail.body = preview(mail.body, preview_lines);
+            if json {
+                println!("{}", serde_json::to_string(&mail).unwrap());
+            } else {
+                print_mail(&mail);
+            }
+        }
+        Commands::Export {
+            filter,
+            output_format,
+            output_dir,
+            name_template,
+            include_inline,
+            attachment_type,
+            body,
+        } => {
+            let options = search::SearchOptions {
+                attachment_type,
+                max_body_bytes: body.max_body_bytes(),
+                ..filter.into_options()
+            };
+            fs::create_dir_all(&output_dir).unwrap_or_else(|err| {
+                fail(&format!(
+                    "failed to create {}: {}",
+                    output_dir.display(),
+                    err
+                ))
+            });
+            let mail_box = client.get(&options.mail_box)?;
+            let filter = options.filter(&mail_box);
+            let mut written = Downloaded::default();
+            let mut json = match output_format {
+                ExportFormat::Json => {
+                    let path = output_dir.join("mails.json");
+                    println!("{}", path.display());
+                    Some(JsonArray::create(&path, cli.timings).unwrap_or_else(|err| {
+                        fail(&format!("failed to write {}: {}", path.display(), err))
+                    }))
+                }
+                _ => None,
+            };
+            // the .eml files already exported by an earlier run are skipped
+            let mut manifest = match output_format {
+                ExportFormat::Eml => {
+                    Some(manifest::Manifest::open(&output_dir).unwrap_or_else(|err| {
+                        fail(&format!(
+                            "failed to open {}: {}",
+                            manifest::MANIFEST_NAME,
+                            err
+                        ))
+                    }))
+                }
+                _ => None,
+            };
+            let mut unchanged = 0;
+            if let Some(manifest) = manifest.as_mut() {
+                // only the headers are fetched until the manifest is checked
+                let uid_validity = mail_box.uid_validity();
+                for header in filter.fetch_headers()? {
+                    if manifest.is_exported(mail_box.name(), header.uid, uid_validity) {
+                        unchanged += 1;
+                        continue;
+                    }
+                    let raw = mail_box.fetch_raw(header.uid)?;
+                    let name = name_template.render(&header, &format!("{}.eml", header.uid), 1);
+                    let path = template::create_unique(&output_dir, &name)
+                        .and_then(|(path, mut file)| file.write_all(&raw).map(|_| path))
+                        .unwrap_or_else(|err| {
+                            fail(&format!(
+                                "failed to write {}: {}",
+                                output_dir.join(&name).display(),
+                                err
+                            ))
+                        });
+                    println!("{}", path.display());
+                    manifest
+                        .record(manifest::Entry {
+                            mail_box: Some(mail_box.name().to_string()),
+                            uid: header.uid,
+                            uid_validity,
+                            file: path.file_name().unwrap().to_string_lossy().into_owned(),
+                            subject: header.subject.clone(),
+                            date: header.internal_date.to_rfc3339(),
+                            sha256: manifest::sha256_hex(&raw),
+                        })
+                        .unwrap_or_else(|err| {
+                            fail(&format!(
+                                "failed to write {}: {}",
+                                manifest::MANIFEST_NAME,
+                                err
+                            ))
+                        });
+                    written.add((1, raw.len()));
+                }
+            } else {
+                // mails are written as they are fetched rather than all at the end
+                for mail in filter.fetch_iter() {
+                    let mut mail = mail?;
+                    match output_format {
+                        ExportFormat::Eml => unreachable!("exported from the headers"),
+                        ExportFormat::Json => {
+                            mail.body = body.preview(mail.body);
+                            let json = json.as_mut().unwrap();
+                            let bytes = json.push(&mail).unwrap_or_else(|err| {
+                                fail(&format!("failed to write mails.json: {}", err))
+                            });
+                            written.add((0, bytes));
+                        }
+                        ExportFormat::Attachments => written.add_attachments(download_attachments(
+                            &mail_box,
+                            mail.uid,
+                            &output_dir,
+                            &name_template,
+                            include_inline,
+                            options.attachment_type.as_deref(),
+                            client::SizeLimit::default(),
+                        )?),
+                    }
+                }
+            }
+            if let Some(json) = json {
+                json.finish(cli.timings.then(|| client.timings()))
+                    .unwrap_or_else(|err| fail(&format!("failed to write mails.json: {}", err)));
+                written.files += 1;
+            }
+            print_fetch_warnings(&filter.warnings());
+            if !cli.quiet {
+                match unchanged {
+                    0 => eprintln!("export: {}", written),
+                    _ => eprintln!("export: {}, {} already exported", written, unchanged),
+                }
+            }
+        }
+        Commands::Download {
+            mail_uid,
+            name_template,
+            include_inline,
+            attachment_type,
+            max_attachment_size,
+            min_attachment_size,
+            json,
+        } => {
+            let mail_box = client.get("INBOX")?;
+            let mut downloaded = Downloaded::with_manifest(json);
+            downloaded.add_attachments(download_attachments(
+                &mail_box,
+                mail_uid,
+                Path::new(""),
+                &name_template,
+                include_inline,
+                attachment_type.as_deref(),
+                size_limit(min_attachment_size, max_attachment_size),
+            )?);
+            downloaded.print_manifest(&client, cli.timings);
+            if !cli.quiet {
+                eprintln!("download: {}", downloaded);
+            }
+        }
+        Commands::Mailbox { command } => match command {
+            MailboxCommand::Info { name, json } => {
+                let info = client.get(&name)?.info();
+                if json {
+                    println!("{}", serde_json::to_string(&info).unwrap());
+                } else {
+                    println!("{}", info);
+                }
+            }
+            MailboxCommand::Create { name } => {
+                if safety.allow(&format!("create mail box {}", name)) {
+                    client.create_mailbox(&name)?
+                }
+            }
+            MailboxCommand::Rename { old_name, new_name } => {
+                if safety.allow(&format!("rename mail box {} to {}", old_name, new_name)) {
+                    client.rename_mailbox(&old_name, &new_name)?
+                }
+            }
+            MailboxCommand::Subscribe { name } => {
+                if safety.allow(&format!("subscribe to mail box {}", name)) {
+                    client.subscribe(&name)?
+                }
+            }
+            MailboxCommand::Unsubscribe { name } => {
+                if safety.allow(&format!("unsubscribe from mail box {}", name)) {
+                    client.unsubscribe(&name)?
+                }
+            }
+            MailboxCommand::Delete { name, force } => {
+                let messages = client.message_count(&name)?;
+                if messages > 0 && !force {
+                    eprintln!(
+                        "error: mail box {} still contains {} mails, use --force to delete it anyway",
+                        name, messages
+                    );
+                    exit(client, EXIT_FAILURE);
+                }
+                if !safety.allow(&format!("delete mail box {}", name)) {
+                    return Ok(());
+                }
+                // `ask` is unset by --yes, and off a terminal where nobody can answer
+                match safety.ask {
+                    Some(ask) if !ask(&format!("Delete mail box {}?", name)) => return Ok(()),
+                    None if !cli.yes => {
+                        eprintln!(
+                            "error: not deleting mail box {} without a terminal to confirm on, pass --yes",
+                            name
+                        );
+                        exit(client, EXIT_FAILURE);
+                    }
+                    _ => {}
+                }
+                client.delete_mailbox(&name)?;
+            }
+        },
+        Commands::Init { .. }
+        | Commands::Login { .. }
+        | Commands::Doctor { .. }
+        | Commands::Config { .. }
+        | Commands::SaveSearch { .. }
+        | Commands::ListSearches => unreachable!(),
+        #[cfg(feature = "smtp")]
+        Commands::Send { .. } => unreachable!(),
+        Commands::Boxes {
+            depth,
+            subscribed,
+            json,
+        } => {
+            let mut folders = if subscribed {
+                client.subscribed_folders()?
+            } else {
+                client.folders()?
+            };
+            if json {
+                if let Some(depth) = depth {
+                    folder::truncate(&mut folders, depth);
+                }
+                println!("{}", serde_json::to_string(&folders).unwrap());
+            } else {
+                println!("{}", folder::render(&folders, depth));
+            }
+        }
+    }
+
+    // the work is done, a failed LOGOUT doesn't change the outcome
+    let _ = client.logout();
+    Ok(())
+}
+
+/// Log out and exit with `code`, as exiting skips the destructor that would log out
+fn exit(client: client::Client, code: i32) -> ! {
+    let _ = client.logout();
+    std::process::exit(code);
+}
+
+/// Add the timings of `client` so far to the json object `output` as `_timings` when
+/// `timings` is set
+fn with_timings(mut output: Value, client: &client::Client, timings: bool) -> Value {
+    if timings {
+        output["_timings"] = serde_json::to_value(client.timings()).unwrap();
+    }
+    output
+}
+
+/// `list` as is, or under `name` next to the timings of `client` so far when `timings`
+/// is set, as a json array has no room for `_timings`
+fn list_with_timings(name: &str, list: Value, client: &client::Client, timings: bool) -> Value {
+    match timings {
+        true => with_timings(serde_json::json!({ name: list }), client, true),
+        false => list,
+    }
+}
+
+/// Print a mail as headers followed by its body
+fn print_mail(mail: &client::Mail) {
+    println!("{}", mail);
+}
+
+/// `body` cut to its first `lines` non-empty lines, whole when not given
+fn preview(body: String, lines: Option<NonZeroUsize>) -> String {
+    match lines {
+        Some(lines) => client::preview(&body, lines.get()),
+        None => body,
+    }
+}
+
+/// What went wrong, the `kind` of the json errors
+#[derive(Serialize, Clone, Copy, Debug)]
+#[serde(rename_all = "snake_case")]
+enum ErrorKind {
+    /// The server couldn't be reached or closed the connection
+    Connection,
+    Timeout,
+    /// The server turned the credentials down
+    Auth,
+    MailBoxNotFound,
+    MailNotFound,
+    /// The server refused a command
+    Server,
+    /// Anything else, such as an invalid config file
+    Other,
+    /// A bug
+    Internal,
+}
+
+impl ErrorKind {
+    fn of(err: &client::ClientError) -> Self {
+        match err {
+            client::ClientError::Timeout(_) => ErrorKind::Timeout,
+            client::ClientError::MailBoxNotFound(..) => ErrorKind::MailBoxNotFound,
+            client::ClientError::MailNotFound(_) => ErrorKind::MailNotFound,
+            err if err.is_connection() => ErrorKind::Connection,
+            err if err.is_auth() => ErrorKind::Auth,
+            _ => ErrorKind::Server,
+        }
+    }
+}
+
+/// Print an error to stderr, or as `{"error": {"kind": ..., "message": ...}}` to
+/// stdout when the command prints json so that stdout always parses
+fn report_error(kind: ErrorKind, message: &str) {
+    if JSON_ERRORS.load(Ordering::Relaxed) {
+        println!(
+            "{}",
+            serde_json::json!({"error": {"kind": kind, "message": message}})
+        );
+    } else {
+        eprintln!("error: {}", message);
+    }
+}
+
+/// Print the error and exit
+fn fail(message: &str) -> ! {
+    report_error(ErrorKind::Other, message);
+    std::process::exit(EXIT_FAILURE);
+}
+
+/// The login credentials, each taken from the first of the command line, the
+/// `QMAIL_USERNAME` and `QMAIL_PASSWORD` variables and `config` that has it. The
+/// password of `--password-file` comes right after `--password`.
+struct Credentials {
+    username: Option<String>,
+    password: Option<String>,
+    /// Where the password was taken from
+    source: doctor::Source,
+}
+
+fn credentials(cli: &Cli, config: &Value) -> Credentials {
+    let username = cli
+        .username
+        .clone()
+        .or_else(|| env_var("QMAIL_USERNAME"))
+        .or_else(|| config["username"].as_str().map(str::to_string));
+    let (password, source) = if let Some(password) = &cli.password {
+        (Some(password.clone()), doctor::Source::CommandLine)
+    } else if let Some(path) = &cli.password_file {
+        (Some(read_password_file(path)), doctor::Source::PasswordFile)
+    } else if let Some(password) = env_var("QMAIL_PASSWORD") {
+        (Some(password), doctor::Source::Environment)
+    } else {
+        (
+            config["password"].as_str().map(str::to_string),
+            doctor::Source::ConfigFile,
+        )
+    };
+    Credentials {
+        username,
+        password,
+        source,
+    }
+}
+
+/// The secret in `path` without its trailing newline. The errors never show the content.
+fn read_password_file(path: &Path) -> String {
+    let mut password = fs::read_to_string(path).unwrap_or_else(|err| {
+        fail(&format!(
+            "can't read the password file {}: {}",
+            path.display(),
+            err
+        ))
+    });
+    let length = password.trim_end_matches(['\n', '\r']).len();
+    password.truncate(length);
+    password
+}
+
+/// An environment variable, unset when empty
+fn env_var(name: &str) -> Option<String> {
+    std::env::var(name).ok().filter(|value| !value.is_empty())
+}
+
+/// Read the config file, or `null` when there is none
+fn read_config(paths: &paths::Paths) -> Value {
+    let path = paths.config_path();
+    match fs::read_to_string(path) {
+        Ok(content) => {
+            paths.notice_legacy_config();
+            serde_json::from_str(&content)
+                .unwrap_or_else(|err| fail(&format!("invalid config {}: {}", path.display(), err)))
+        }
+        Err(_) => Value::Null,
+    }
+}
+
+/// How `login` reaches the server to check the credentials
+struct Connection {
+    /// The `--proxy`, the one of the environment otherwise
+    proxy: Option<proxy::Proxy>,
+    timeout: Option<Duration>,
+}
+
+/// Prompt for the credentials missing from the command line, check them against the
+/// server of the `profile` and save them to the config file as the `profile` or the
+/// default one, keeping the other settings in there. A legacy `~/.qmail_pass` is left
+/// behind for the config at the new location.
+fn login(
+    username: Option<String>,
+    password: Option<String>,
+    no_verify: bool,
+    profile: Option<&str>,
+    connection: Connection,
+    config: Value,
+    paths: &paths::Paths,
+) -> Result<(), client::ClientError> {
+    let username = username.unwrap_or_else(|| prompt("Username: "));
+    let password = password.unwrap_or_else(|| rpassword::prompt_password("Password: ").unwrap());
+
+    let previous = profile::Profile::read(&config, profile);
+    if !no_verify {
+        let server = previous.as_ref();
+        let host = server
+            .and_then(|previous| previous.host.as_deref())
+            .unwrap_or(client::DOMAIN);
+        client::Client::builder(&username, &password)
+            .server(
+                host,
+                server
+                    .and_then(|previous| previous.port)
+                    .unwrap_or(client::PORT),
+            )
+            .proxy(connection.proxy.or_else(|| proxy::Proxy::from_env(host)))
+            .timeout(connection.timeout)
+            .connect()?
+            .logout()?;
+    }
+
+    let config = profile::save(
+        config,
+        profile,
+        &profile::Profile {
+            username,
+            password,
+            host: previous.as_ref().and_then(|previous| previous.host.clone()),
+            port: previous.and_then(|previous| previous.port),
+        },
+    );
+    write_config(&paths.config_target, &config);
+    println!("Saved credentials to {}", paths.config_target.display());
+    Ok(())
+}
+
+/// What `init` was given on the command line
+struct Init {
+    profile: Option<String>,
+    username: Option<String>,
+    password_stdin: bool,
+    host: Option<String>,
+    port: Option<u16>,
+    no_verify: bool,
+    /// The `--proxy`, the one of the environment otherwise
+    proxy: Option<proxy::Proxy>,
+}
+
+/// Ask for the profile to set up, its credentials and server, log in with them and save
+/// them to the config file. With `--password-stdin` nothing is asked: the username
+/// comes from `--username` and the server from `--host` and `--port`.
+fn init(given: Init, config: Value, paths: &paths::Paths) -> Result<(), client::ClientError> {
+    let interactive = !given.password_stdin && io::stdin().is_terminal();
+    if !interactive && given.username.is_none() {
+        fail("--username is required to run init without a terminal");
+    }
+
+    let profile = match given.profile {
+        Some(profile) => Some(profile),
+        None if interactive && Profile::read(&config, None).is_some() => {
+            let names = profile::names(&config);
+            println!(
+                "The config {} already has a default profile{}.",
+                paths.config_path().display(),
+                match names.is_empty() {
+                    true => String::new(),
+                    false => format!(" and the profiles {}", names.join(", ")),
+                }
+            );
+            prompt_or("Profile to update, or the name of a new one", "default")
+                .filter(|name| name != "default")
+        }
+        None => None,
+    };
+    let previous = Profile::read(&config, profile.as_deref());
+
+    let username = match (given.username, &previous) {
+        (Some(username), _) => username,
+        (None, Some(previous)) => {
+            prompt_or("Username", &previous.username).unwrap_or(previous.username.clone())
+        }
+        (None, None) => prompt("Username: "),
+    };
+    let password = match given.password_stdin {
+        true => {
+            let mut password = String::new();
+            io::stdin().read_line(&mut password).unwrap();
+            password.trim_end_matches(['\n', '\r']).to_string()
+        }
+        false => rpassword::prompt_password("Password (hidden): ").unwrap(),
+    };
+    let previous_host = previous.as_ref().and_then(|previous| previous.host.clone());
+    let host = match given.host {
+        Some(host) => Some(host),
+        None if interactive => {
+            prompt_or("Host", previous_host.as_deref().unwrap_or(client::DOMAIN)).or(previous_host)
+        }
+        None => previous_host,
+    };
+    let previous_port = previous.and_then(|previous| previous.port);
+    let port = match given.port {
+        Some(port) => Some(port),
+        None if interactive => loop {
+            let answer = prompt_or("Port", &previous_port.unwrap_or(client::PORT).to_string());
+            match answer.map(|answer| answer.parse::<u16>().map_err(|_| answer)) {
+                None => break previous_port,
+                Some(Ok(port)) => break Some(port),
+                Some(Err(answer)) => eprintln!("invalid port `{}`", answer),
+            }
+        },
+        None => previous_port,
+    };
+    if password.is_empty() {
+        fail("the password is empty");
+    }
+
+    if !given.no_verify {
+        let server = host.as_deref().unwrap_or(client::DOMAIN);
+        client::Client::builder(&username, &password)
+            .server(server, port.unwrap_or(client::PORT))
+            .proxy(given.proxy.or_else(|| proxy::Proxy::from_env(server)))
+            .connect()?
+            .logout()?;
+        println!("Logged in as {}", username);
+    }
+
+    let config = profile::save(
+        config,
+        profile.as_deref(),
+        &Profile {
+            username,
+            password,
+            host,
+            port,
+        },
+    );
+    write_config(&paths.config_target, &config);
+    println!(
+        "Wrote the {} to {}",
+        match &profile {
+            Some(name) => format!("profile {}", name),
+            None => "default profile".to_string(),
+        },
+        paths.config_target.display()
+    );
+    Ok(())
+}
+
+/// Ask `question` on the terminal, the answer is trimmed
+fn prompt(question: &str) -> String {
+    print!("{}", question);
+    io::stdout().flush().unwrap();
+    let mut answer = String::new();
+    io::stdin().read_line(&mut answer).unwrap();
+    answer.trim().to_string()
+}
+
+/// Ask for `what` showing the `current` value, `None` keeps it
+fn prompt_or(what: &str, current: &str) -> Option<String> {
+    Some(prompt(&format!("{} [{}]: ", what, current))).filter(|answer| !answer.is_empty())
+}
+
+/// Write `config` to `path`, only readable by the user as it holds the password
+fn write_config(path: &Path, config: &Value) {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)
+            .unwrap_or_else(|err| fail(&format!("failed to create {}: {}", dir.display(), err)));
+    }
+    let mut options = OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    let mut file = options.open(path).unwrap();
+    // the mode only applies to new files
+    #[cfg(unix)]
+    fs::set_permissions(path, std::os::unix::fs::PermissionsExt::from_mode(0o600)).unwrap();
+    file.write_all(serde_json::to_string_pretty(config).unwrap().as_bytes())
+        .unwrap();
+}
+
+fn size_limit(min: Option<Size>, max: Option<Size>) -> client::SizeLimit {
+    client::SizeLimit {
+        min: min.map(|size| size.0),
+        max: max.map(|size| size.0),
+    }
+}
+
+/// Save the attachments of a mail into `dir`, returns the saved and the skipped ones
+fn download_attachments(
+    mail_box: &client::MailBox,
+    mail_uid: u32,
+    dir: &Path,
+    name_template: &NameTemplate,
+    include_inline: bool,
+    attachment_type: Option<&str>,
+    size_limit: client::SizeLimit,
+) -> Result<Vec<ManifestEntry>, client::ClientError> {
+    let (mail, attachments, skipped) =
+        mail_box.download(mail_uid, include_inline, attachment_type, size_limit)?;
+    let mut entries = vec![];
+    for (index, (attachment_name, attachment_data)) in attachments.into_iter().enumerate() {
+        let name = name_template.render(&mail, &attachment_name, index + 1);
+        let file_name = template::create_unique(dir, &name)
+            .and_then(|(path, mut file)| file.write_all(&attachment_data).map(|_| path))
+            .unwrap_or_else(|err| {
+                fail(&format!(
+                    "failed to write {}: {}",
+                    dir.join(&name).display(),
+                    err
+                ))
+            });
+        entries.push(ManifestEntry {
+            uid: mail_uid,
+            name: attachment_name,
+            size: attachment_data.len() as u64,
+            path: Some(file_name),
+            skipped: None,
+        });
+    }
+    entries.extend(skipped.into_iter().map(|part| ManifestEntry {
+        uid: mail_uid,
+        name: part.name,
+        size: part.size,
+        path: None,
+        skipped: Some(part.skipped),
+    }));
+    Ok(entries)
+}
+
+/// An attachment of the download manifest, saved to `path` or left on the server
+#[derive(Debug, Serialize)]
+struct ManifestEntry {
+    uid: u32,
+    name: String,
+    size: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    path: Option<PathBuf>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    skipped: Option<client::SkipReason>,
+}
+
+/// How `search` shows the mails when they aren't printed as json
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ListFormat {
+    Tui,
+    Table,
+}
+
+impl ListFormat {
+    /// The TUI would send its escape sequences down a pipe
+    fn detect() -> ListFormat {
+        match io::stdout().is_terminal() {
+            true => ListFormat::Tui,
+            false => ListFormat::Table,
+        }
+    }
+}
+
+impl FromStr for ListFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "tui" => Ok(ListFormat::Tui),
+            "table" => Ok(ListFormat::Table),
+            _ => Err(format!("unknown format `{}`", s)),
+        }
+    }
+}
+
+/// What `export` writes
+#[derive(Debug, Clone, Copy)]
+enum ExportFormat {
+    Eml,
+    Json,
+    Attachments,
+}
+
+impl FromStr for ExportFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "eml" => Ok(ExportFormat::Eml),
+            "json" => Ok(ExportFormat::Json),
+            "attachments" => Ok(ExportFormat::Attachments),
+            _ => Err(format!("unknown output format `{}`", s)),
+        }
+    }
+}
+
+/// A json array written one element at a time, or the `mails` of an object that also
+/// gets `_timings` once finished
+struct JsonArray {
+    file: io::BufWriter<File>,
+    empty: bool,
+    in_object: bool,
+}
+
+impl JsonArray {
+    fn create(path: &Path, in_object: bool) -> io::Result<JsonArray> {
+        let mut file = io::BufWriter::new(File::create(path)?);
+        match in_object {
+            true => file.write_all(b"{\"mails\":[")?,
+            false => file.write_all(b"[")?,
+        }
+        Ok(JsonArray {
+            file,
+            empty: true,
+            in_object,
+        })
+    }
+
+    /// Append `value`, returns the number of bytes written
+    fn push<T: Serialize>(&mut self, value: &T) -> io::Result<usize> {
+        let json = serde_json::to_vec(value)?;
+        if !self.empty {
+            self.file.write_all(b",")?;
+        }
+        self.empty = false;
+        self.file.write_all(&json)?;
+        Ok(json.len())
+    }
+
+    /// Close the array, with `timings` when it's in an object
+    fn finish(mut self, timings: Option<Timings>) -> io::Result<()> {
+        self.file.write_all(b"]")?;
+        if self.in_object {
+            self.file.write_all(b",\"_timings\":")?;
+            serde_json::to_writer(&mut self.file, &timings.unwrap_or_default())?;
+            self.file.write_all(b"}")?;
+        }
+        self.file.write_all(b"\n")?;
+        self.file.flush()
+    }
+}
+
+/// Tally of the attachments saved from one or more mails
+#[derive(Default)]
+struct Downloaded {
+    files: usize,
+    bytes: usize,
+    mails: usize,
+    too_large: usize,
+    too_small: usize,
+    invalid: usize,
+    /// Collects the attachments for [`print_manifest`](Self::print_manifest) rather than
+    /// printing the paths of the saved files as they come
+    manifest: Option<Vec<ManifestEntry>>,
+}
+
+impl Downloaded {
+    fn with_manifest(manifest: bool) -> Downloaded {
+        Downloaded {
+            manifest: manifest.then(Vec::new),
+            ..Default::default()
+        }
+    }
+
+    fn add(&mut self, (files, bytes): (usize, usize)) {
+        self.files += files;
+        self.bytes += bytes;
+        self.mails += 1;
+    }
+
+    /// Count the attachments of a mail from [`download_attachments`]
+    fn add_attachments(&mut self, entries: Vec<ManifestEntry>) {
+        let mut written = (0, 0);
+        for entry in entries.iter() {
+            match entry.skipped {
+                Some(client::SkipReason::TooLarge) => self.too_large += 1,
+                Some(client::SkipReason::TooSmall) => self.too_small += 1,
+                Some(client::SkipReason::Invalid) => self.invalid += 1,
+                None => written = (written.0 + 1, written.1 + entry.size as usize),
+            }
+            if let (None, Some(path)) = (&self.manifest, &entry.path) {
+                println!("{}", path.display());
+            }
+        }
+        self.add(written);
+        if let Some(manifest) = &mut self.manifest {
+            manifest.extend(entries);
+        }
+    }
+
+    fn print_manifest(&self, client: &client::Client, timings: bool) {
+        if let Some(manifest) = &self.manifest {
+            let manifest = serde_json::to_value(manifest).unwrap();
             println!(
                 "{}",
-                client
-                    .mail_boxes()
-                    .unwrap_or_default()
-                    .iter()
-                    .map(|b| b.name())
-                    .collect::<Vec<&str>>()
-                    .join("\n")
+                list_with_timings("attachments", manifest, client, timings)
             );
         }
     }
 }
+
+impl Display for Downloaded {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "wrote {} files ({:.1} MB) from {} messages",
+            self.files,
+            self.bytes as f64 / 1_000_000.0,
+            self.mails
+        )?;
+        if self.too_large > 0 {
+            write!(f, ", skipped {} too large", self.too_large)?;
+        }
+        if self.too_small > 0 {
+            write!(f, ", skipped {} too small", self.too_small)?;
+        }
+        if self.invalid > 0 {
+            write!(f, ", skipped {} invalid", self.invalid)?;
+        }
+        Ok(())
+    }
+}
+
+fn print_search_summary(matched: usize, scan: &search::Scan, started: Instant) {
+    let skipped = match scan.warnings.len() {
+        0 => String::new(),
+        n => format!(" / {} skipped", n),
+    };
+    eprintln!(
+        "search: {} matched / {} scanned{} in {:.1}s",
+        matched,
+        scan.scanned,
+        skipped,
+        started.elapsed().as_secs_f64()
+    );
+}
+
+/// The mails a search skipped, printed even with `--quiet` since they are missing
+/// from the output
+fn print_fetch_warnings(warnings: &[client::FetchWarning]) {
+    for warning in warnings {
+        eprintln!("warning: {}", warning);
+    }
+}
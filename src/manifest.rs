@@ -0,0 +1,204 @@
+use std::{
+    collections::HashMap,
+    fs::{self, File, OpenOptions},
+    io::{self, Write},
+    path::{Path, PathBuf},
+};
+
+use serde::{Deserialize, Serialize};
+
+/// Name of the manifest in the export directory
+pub const MANIFEST_NAME: &str = "manifest.jsonl";
+
+/// A line of the manifest, one per exported `.eml` file
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Entry {
+    /// The mail box of the mail, missing in the manifests of older versions
+    #[serde(default)]
+    pub mail_box: Option<String>,
+    pub uid: u32,
+    /// UIDVALIDITY of the mail box, a change invalidates the entry
+    pub uid_validity: Option<u32>,
+    /// The file name, relative to the export directory
+    pub file: String,
+    pub subject: String,
+    pub date: String,
+    /// Hex SHA-256 of the file
+    pub sha256: String,
+}
+
+/// Identifies a mail across mail boxes and UID resets
+type Key = (Option<String>, Option<u32>, u32);
+
+/// The `manifest.jsonl` of an `.eml` export. Entries are appended once their file is
+/// completely written, so an interrupted export picks up where it stopped.
+pub struct Manifest {
+    dir: PathBuf,
+    entries: HashMap<Key, Entry>,
+    file: File,
+}
+
+impl Manifest {
+    /// Open the manifest of `dir`, created when missing. A line cut by an interruption
+    /// is ignored, its mail is exported again.
+    pub fn open(dir: &Path) -> io::Result<Manifest> {
+        let path = dir.join(MANIFEST_NAME);
+        let content = match fs::read_to_string(&path) {
+            Ok(content) => content,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => String::new(),
+            Err(err) => return Err(err),
+        };
+        let entries = content
+            .lines()
+            .filter_map(|line| serde_json::from_str::<Entry>(line).ok())
+            .map(|entry| (key_of(&entry), entry))
+            .collect();
+        let mut file = OpenOptions::new().create(true).append(true).open(&path)?;
+        if !content.is_empty() && !content.ends_with('\n') {
+            // start the next entry on a line of its own
+            file.write_all(b"\n")?;
+        }
+        Ok(Manifest {
+            dir: dir.to_path_buf(),
+            entries,
+            file,
+        })
+    }
+
+    /// Whether mail `uid` of `mail_box` was exported before and its file is still
+    /// intact. The entries without a mail box match any.
+    pub fn is_exported(&self, mail_box: &str, uid: u32, uid_validity: Option<u32>) -> bool {
+        let entry = match self
+            .entries
+            .get(&(Some(mail_box.to_string()), uid_validity, uid))
+            .or_else(|| self.entries.get(&(None, uid_validity, uid)))
+        {
+            Some(entry) => entry,
+            None => return false,
+        };
+        fs::read(self.dir.join(&entry.file))
+            .is_ok_and(|content| sha256_hex(&content) == entry.sha256)
+    }
+
+    /// Append `entry`, its file has to be written already
+    pub fn record(&mut self, entry: Entry) -> io::Result<()> {
+        let mut line = serde_json::to_vec(&entry)?;
+        line.push(b'\n');
+        self.file.write_all(&line)?;
+        self.file.flush()?;
+        self.entries.insert(key_of(&entry), entry);
+        Ok(())
+    }
+}
+
+fn key_of(entry: &Entry) -> Key {
+    (entry.mail_box.clone(), entry.uid_validity, entry.uid)
+}
+
+pub fn sha256_hex(content: &[u8]) -> String {
+    openssl::sha::sha256(content)
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(uid: u32, content: &[u8]) -> Entry {
+        Entry {
+            mail_box: Some("INBOX".to_string()),
+            uid,
+            uid_validity: Some(7),
+            file: format!("{}.eml", uid),
+            subject: "report".to_string(),
+            date: "2022-05-20T09:30:00+08:00".to_string(),
+            sha256: sha256_hex(content),
+        }
+    }
+
+    #[test]
+    fn resume_from_the_manifest() {
+        let dir = std::env::temp_dir().join(format!("qmail-manifest-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+
+        let mut manifest = Manifest::open(&dir).unwrap();
+        fs::write(dir.join("1.eml"), b"first").unwrap();
+        manifest.record(entry(1, b"first")).unwrap();
+        fs::write(dir.join("2.eml"), b"second").unwrap();
+        manifest.record(entry(2, b"second")).unwrap();
+        drop(manifest);
+        // interrupted while appending the next entry
+        let mut file = OpenOptions::new()
+            .append(true)
+            .open(dir.join(MANIFEST_NAME))
+            .unwrap();
+        file.write_all(br#"{"uid":3,"uid_va"#).unwrap();
+        // and the second file was damaged since
+        fs::write(dir.join("2.eml"), b"sec").unwrap();
+
+        let mut manifest = Manifest::open(&dir).unwrap();
+        assert!(manifest.is_exported("INBOX", 1, Some(7)));
+        assert!(
+            !manifest.is_exported("INBOX", 1, Some(8)),
+            "the UIDs were reset"
+        );
+        assert!(!manifest.is_exported("INBOX", 2, Some(7)));
+        assert!(!manifest.is_exported("INBOX", 3, Some(7)));
+
+        fs::write(dir.join("3.eml"), b"third").unwrap();
+        manifest.record(entry(3, b"third")).unwrap();
+        drop(manifest);
+        assert!(Manifest::open(&dir)
+            .unwrap()
+            .is_exported("INBOX", 3, Some(7)));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn scope_the_entries_per_mail_box() {
+        let dir = std::env::temp_dir().join(format!("qmail-manifest-box-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+
+        let mut manifest = Manifest::open(&dir).unwrap();
+        fs::write(dir.join("1.eml"), b"first").unwrap();
+        manifest.record(entry(1, b"first")).unwrap();
+        fs::write(dir.join("1 (1).eml"), b"sent").unwrap();
+        manifest
+            .record(Entry {
+                mail_box: Some("Sent".to_string()),
+                uid_validity: Some(9),
+                file: "1 (1).eml".to_string(),
+                ..entry(1, b"sent")
+            })
+            .unwrap();
+        // written before the mail box was recorded
+        fs::write(dir.join("2.eml"), b"second").unwrap();
+        let mut file = OpenOptions::new()
+            .append(true)
+            .open(dir.join(MANIFEST_NAME))
+            .unwrap();
+        let old = r#"{"uid":2,"uid_validity":7,"file":"2.eml","subject":"","date":"","sha256":"#;
+        writeln!(file, "{}\"{}\"}}", old, sha256_hex(b"second")).unwrap();
+        drop(manifest);
+
+        let manifest = Manifest::open(&dir).unwrap();
+        assert!(manifest.is_exported("INBOX", 1, Some(7)));
+        assert!(manifest.is_exported("Sent", 1, Some(9)));
+        assert!(!manifest.is_exported("Sent", 1, Some(7)));
+        assert!(!manifest.is_exported("Drafts", 1, Some(9)));
+        assert!(manifest.is_exported("INBOX", 2, Some(7)));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn hash_as_hex() {
+        assert_eq!(
+            sha256_hex(b"abc"),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+    }
+}
@@ -0,0 +1,236 @@
+//! A scripted, in-process IMAP server to test the client without a network.
+
+use std::{
+    io::{BufRead, BufReader, Write},
+    net::{SocketAddr, TcpListener, TcpStream},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    thread,
+};
+
+use crate::client::{Client, ClientError};
+use crate::compress::Deflate;
+
+/// Answers every command with the untagged lines registered for its longest matching
+/// prefix, followed by a tagged `OK`, and records the commands it received. Of two
+/// responses registered for the same prefix, the last one wins.
+///
+/// Prefixes are matched case-insensitively against the command without its tag, e.g.
+/// `SEARCH SINCE 1-May-2022` or just `SEARCH`.
+pub struct MockServer {
+    listener: TcpListener,
+    greeting: Arc<Mutex<String>>,
+    responses: Arc<Mutex<Vec<(String, Reply)>>>,
+    commands: Arc<Mutex<Vec<String>>>,
+}
+
+impl MockServer {
+    /// A server with a single empty INBOX
+    pub fn new() -> Self {
+        let server = MockServer {
+            listener: TcpListener::bind("127.0.0.1:0").unwrap(),
+            greeting: Arc::new(Mutex::new("* OK mock server ready\r\n".to_string())),
+            responses: Arc::default(),
+            commands: Arc::default(),
+        };
+        server
+            .on("CAPABILITY", "* CAPABILITY IMAP4rev1 IDLE\r\n")
+            .on("LIST", "* LIST (\\HasNoChildren) \"/\" \"INBOX\"\r\n")
+            .on("SELECT", "* 0 EXISTS\r\n* 0 RECENT\r\n* FLAGS (\\Seen)\r\n")
+            .on(
+                "EXAMINE",
+                "* 0 EXISTS\r\n* 0 RECENT\r\n* FLAGS (\\Seen)\r\n",
+            )
+            .on("UID SEARCH", "* SEARCH\r\n");
+        server
+    }
+
+    /// Answer the commands starting with `prefix` with `response`, which is sent as is
+    /// and so has to end with CRLF
+    pub fn on(&self, prefix: &str, response: impl Into<String>) -> &Self {
+        self.responses
+            .lock()
+            .unwrap()
+            .push((prefix.to_uppercase(), Reply::Ok(response.into())));
+        self
+    }
+
+    /// Reject the commands starting with `prefix` with a tagged `NO message`
+    pub fn fail(&self, prefix: &str, message: &str) -> &Self {
+        self.responses
+            .lock()
+            .unwrap()
+            .push((prefix.to_uppercase(), Reply::No(message.to_string())));
+        self
+    }
+
+    /// Answer the commands starting with `prefix` with an untagged `BYE message`, then
+    /// close the connection
+    pub fn bye(&self, prefix: &str, message: &str) -> &Self {
+        self.responses
+            .lock()
+            .unwrap()
+            .push((prefix.to_uppercase(), Reply::Bye(message.to_string())));
+        self
+    }
+
+    /// Greet the next clients with `greeting`, which has to end with CRLF
+    pub fn greet(&self, greeting: &str) -> &Self {
+        *self.greeting.lock().unwrap() = greeting.to_string();
+        self
+    }
+
+    /// Connect and log in a client, the server answers it on a background thread
+    pub fn client(&self) -> Client {
+        self.connect().unwrap()
+    }
+
+    /// [`client`](Self::client), failing with the error of the login
+    pub fn connect(&self) -> Result<Client, ClientError> {
+        let stream = TcpStream::connect(self.accept()).unwrap();
+        stream.set_nodelay(true).unwrap();
+        Client::login_stream(stream, "user", "password")
+    }
+
+    /// Answer the next connection on a background thread, returns the address to
+    /// connect to, e.g. for a client of another IMAP crate
+    pub fn accept(&self) -> SocketAddr {
+        let listener = self.listener.try_clone().unwrap();
+        let greeting = self.greeting.lock().unwrap().clone();
+        let responses = self.responses.clone();
+        let commands = self.commands.clone();
+        thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            stream.set_nodelay(true).unwrap();
+            serve(stream, &greeting, &responses, &commands);
+        });
+        self.listener.local_addr().unwrap()
+    }
+
+    /// The commands received so far, without their tags
+    pub fn commands(&self) -> Vec<String> {
+        self.commands.lock().unwrap().clone()
+    }
+}
+
+#[derive(Clone)]
+enum Reply {
+    Ok(String),
+    No(String),
+    Bye(String),
+}
+
+fn serve(
+    stream: TcpStream,
+    greeting: &str,
+    responses: &Mutex<Vec<(String, Reply)>>,
+    commands: &Mutex<Vec<String>>,
+) {
+    // both directions are deflated once COMPRESS DEFLATE completed
+    let compressed = Arc::new(AtomicBool::new(false));
+    let mut writer = Deflate::new(stream.try_clone().unwrap(), compressed.clone());
+    let mut reader = BufReader::new(Deflate::new(stream, compressed.clone()));
+    writer.write_all(greeting.as_bytes()).unwrap();
+
+    let mut idling = None;
+    loop {
+        writer.flush().unwrap();
+        let mut line = String::new();
+        if reader.read_line(&mut line).unwrap_or(0) == 0 {
+            return;
+        }
+        let line = line.trim_end();
+        if line == "DONE" {
+            if let Some(tag) = idling.take() {
+                write!(writer, "{} OK IDLE terminated\r\n", tag).unwrap();
+            }
+            continue;
+        }
+
+        let (tag, command) = line.split_once(' ').unwrap_or((line, ""));
+        commands.lock().unwrap().push(command.to_string());
+        let upper = command.to_uppercase();
+        let reply = responses
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(prefix, _)| upper.starts_with(prefix.as_str()))
+            .max_by_key(|(prefix, _)| prefix.len())
+            .map(|(_, reply)| reply.clone())
+            .unwrap_or_else(|| Reply::Ok(String::new()));
+        let response = match reply {
+            Reply::Ok(response) => response,
+            Reply::No(message) => {
+                write!(writer, "{} NO {}\r\n", tag, message).unwrap();
+                continue;
+            }
+            Reply::Bye(message) => {
+                write!(writer, "* BYE {}\r\n", message).unwrap();
+                return;
+            }
+        };
+
+        if upper == "IDLE" {
+            // the changes are reported while idling, the command completes on DONE
+            write!(writer, "+ idling\r\n{}", response).unwrap();
+            idling = Some(tag.to_string());
+            continue;
+        }
+        if upper == "LOGOUT" {
+            writer.write_all(b"* BYE logging out\r\n").unwrap();
+        }
+        write!(writer, "{}{} OK {} completed\r\n", response, tag, upper).unwrap();
+        if upper == "COMPRESS DEFLATE" {
+            writer.flush().unwrap();
+            compressed.store(true, Ordering::Relaxed);
+        }
+    }
+}
+
+/// An untagged FETCH response as requested by [`MailFilter::fetch`](crate::client::MailFilter::fetch),
+/// for a single part text/plain mail. The sequence number doubles as UID.
+pub fn fetch_response(seq: u32, internal_date: &str, header: &str, text: &str) -> String {
+    let bodystructure = format!(
+        "(\"TEXT\" \"PLAIN\" (\"CHARSET\" \"utf-8\") NIL NIL \"7BIT\" {} 1)",
+        text.len()
+    );
+    fetch_response_with_structure(seq, internal_date, header, text, &bodystructure)
+}
+
+/// [`fetch_response`] with the given BODYSTRUCTURE, e.g. that of a multipart mail
+pub fn fetch_response_with_structure(
+    seq: u32,
+    internal_date: &str,
+    header: &str,
+    text: &str,
+    bodystructure: &str,
+) -> String {
+    format!(
+        "* {seq} FETCH (UID {seq} INTERNALDATE \"{date}\" \
+         BODY[HEADER.FIELDS (SUBJECT FROM TO CC)] {{{header_len}}}\r\n{header} \
+         BODY[TEXT] {{{text_len}}}\r\n{text} \
+         BODYSTRUCTURE {bodystructure})\r\n",
+        seq = seq,
+        date = internal_date,
+        header_len = header.len(),
+        header = header,
+        text_len = text.len(),
+        text = text,
+        bodystructure = bodystructure,
+    )
+}
+
+/// An untagged FETCH response carrying the full message, as requested by
+/// [`MailBox::download`](crate::client::MailBox::download). The sequence number
+/// doubles as UID.
+pub fn fetch_body_response(seq: u32, internal_date: &str, message: &[u8]) -> String {
+    format!(
+        "* {0} FETCH (UID {0} INTERNALDATE \"{1}\" BODY[] {{{2}}}\r\n{3})\r\n",
+        seq,
+        internal_date,
+        message.len(),
+        String::from_utf8_lossy(message)
+    )
+}
@@ -0,0 +1,247 @@
+use std::{
+    ffi::OsString,
+    fmt::Display,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use serde::Serialize;
+
+/// The directory of qmail under the XDG base directories
+const APP_DIR: &str = "qmail-client";
+const CONFIG_NAME: &str = "config.json";
+/// The searches of `save-search`, next to the config
+const SEARCHES_NAME: &str = "searches.json";
+/// The config file of the older versions, read when there is no config at the XDG
+/// location
+const LEGACY_CONFIG: &str = ".qmail_pass";
+/// Created in the state directory once the legacy config notice was shown
+const LEGACY_NOTICE: &str = "legacy-config-notice";
+
+/// A resolved file or directory, along with what decided it
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Location {
+    pub path: PathBuf,
+    /// The flag or variable it was taken from, `default` or `legacy`
+    pub origin: &'static str,
+}
+
+impl Display for Location {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} ({})", self.path.display(), self.origin)
+    }
+}
+
+/// Where the files of qmail live
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Paths {
+    /// The config file read, whether it exists or not
+    pub config: Location,
+    pub cache_dir: Location,
+    /// The searches saved with `save-search`
+    pub searches: Location,
+    /// Where the state between runs is kept
+    pub state_dir: Location,
+    /// Where `login` saves the config: the XDG location in place of the legacy file
+    #[serde(skip)]
+    pub config_target: PathBuf,
+}
+
+impl Paths {
+    /// The locations from the `--config` and `--cache-dir` flags, then the
+    /// `QMAIL_CONFIG` and `QMAIL_CACHE_DIR` variables, then the XDG base directories
+    pub fn resolve(config: Option<PathBuf>, cache_dir: Option<PathBuf>) -> Paths {
+        Paths::resolve_with(
+            config,
+            cache_dir,
+            |name| std::env::var_os(name),
+            dirs::home_dir(),
+        )
+    }
+
+    fn resolve_with<V>(
+        config: Option<PathBuf>,
+        cache_dir: Option<PathBuf>,
+        var: V,
+        home: Option<PathBuf>,
+    ) -> Paths
+    where
+        V: Fn(&str) -> Option<OsString>,
+    {
+        let home = home.unwrap_or_default();
+        let env = |name: &str| {
+            var(name)
+                .filter(|value| !value.is_empty())
+                .map(PathBuf::from)
+        };
+        // relative XDG variables are invalid and ignored
+        let base = |name: &'static str, default: &str| match env(name) {
+            Some(dir) if dir.is_absolute() => Location {
+                path: dir.join(APP_DIR),
+                origin: name,
+            },
+            _ => Location {
+                path: home.join(default).join(APP_DIR),
+                origin: "default",
+            },
+        };
+        let given = |flag: Option<PathBuf>, flag_name: &'static str, var_name: &'static str| {
+            flag.map(|path| Location {
+                path,
+                origin: flag_name,
+            })
+            .or_else(|| {
+                env(var_name).map(|path| Location {
+                    path,
+                    origin: var_name,
+                })
+            })
+        };
+
+        let xdg_config = base("XDG_CONFIG_HOME", ".config");
+        let xdg_config = Location {
+            path: xdg_config.path.join(CONFIG_NAME),
+            ..xdg_config
+        };
+        let searches = Location {
+            path: xdg_config.path.with_file_name(SEARCHES_NAME),
+            ..xdg_config.clone()
+        };
+        let legacy = home.join(LEGACY_CONFIG);
+        let (config, config_target) = match given(config, "--config", "QMAIL_CONFIG") {
+            Some(config) => (config.clone(), config.path),
+            None if !xdg_config.path.exists() && legacy.exists() => (
+                Location {
+                    path: legacy,
+                    origin: "legacy",
+                },
+                xdg_config.path,
+            ),
+            None => (xdg_config.clone(), xdg_config.path),
+        };
+        Paths {
+            config,
+            cache_dir: given(cache_dir, "--cache-dir", "QMAIL_CACHE_DIR")
+                .unwrap_or_else(|| base("XDG_CACHE_HOME", ".cache")),
+            searches,
+            state_dir: base("XDG_STATE_HOME", ".local/state"),
+            config_target,
+        }
+    }
+
+    /// Point out the new config location on stderr when the legacy config is read, once
+    pub fn notice_legacy_config(&self) {
+        if self.config.origin != "legacy" {
+            return;
+        }
+        let marker = self.state_dir.path.join(LEGACY_NOTICE);
+        if marker.exists() {
+            return;
+        }
+        eprintln!(
+            "note: reading the config from {}, the config now lives in {}; `qmail login` \
+             moves it there",
+            self.config.path.display(),
+            self.config_target.display()
+        );
+        // without a state directory the notice is shown again next time
+        let _ = fs::create_dir_all(&self.state_dir.path).and_then(|_| fs::write(&marker, ""));
+    }
+
+    pub fn config_path(&self) -> &Path {
+        &self.config.path
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn resolve(config: Option<&str>, vars: &[(&str, &str)], home: &Path) -> Paths {
+        let vars = vars
+            .iter()
+            .map(|(name, value)| (name.to_string(), OsString::from(value)))
+            .collect::<Vec<_>>();
+        Paths::resolve_with(
+            config.map(PathBuf::from),
+            None,
+            |name| {
+                vars.iter()
+                    .find(|(var, _)| var == name)
+                    .map(|(_, value)| value.clone())
+            },
+            Some(home.to_path_buf()),
+        )
+    }
+
+    #[test]
+    fn resolve_the_locations() {
+        let home = Path::new("/nonexistent/alice");
+        let paths = resolve(None, &[], home);
+        assert_eq!(
+            paths.config,
+            Location {
+                path: home.join(".config/qmail-client/config.json"),
+                origin: "default",
+            }
+        );
+        assert_eq!(paths.cache_dir.path, home.join(".cache/qmail-client"));
+        assert_eq!(paths.state_dir.path, home.join(".local/state/qmail-client"));
+        assert_eq!(
+            paths.searches.path,
+            home.join(".config/qmail-client/searches.json")
+        );
+
+        let paths = resolve(
+            None,
+            &[
+                ("XDG_CONFIG_HOME", "/xdg/config"),
+                ("XDG_CACHE_HOME", "relative"),
+                ("QMAIL_CACHE_DIR", "/tmp/qmail-cache"),
+            ],
+            home,
+        );
+        assert_eq!(
+            paths.config.path,
+            Path::new("/xdg/config/qmail-client/config.json")
+        );
+        assert_eq!(paths.config.origin, "XDG_CONFIG_HOME");
+        assert_eq!(
+            paths.cache_dir,
+            Location {
+                path: PathBuf::from("/tmp/qmail-cache"),
+                origin: "QMAIL_CACHE_DIR",
+            }
+        );
+
+        let paths = resolve(
+            Some("/etc/qmail.json"),
+            &[("QMAIL_CONFIG", "/other.json")],
+            home,
+        );
+        assert_eq!(paths.config.origin, "--config");
+        assert_eq!(paths.config_target, Path::new("/etc/qmail.json"));
+    }
+
+    #[test]
+    fn fall_back_to_the_legacy_config() {
+        let home = std::env::temp_dir().join(format!("qmail-paths-{}", std::process::id()));
+        fs::create_dir_all(&home).unwrap();
+        fs::write(home.join(".qmail_pass"), "{}").unwrap();
+
+        let paths = resolve(None, &[], &home);
+        assert_eq!(paths.config.path, home.join(".qmail_pass"));
+        assert_eq!(paths.config.origin, "legacy");
+        assert_eq!(
+            paths.config_target,
+            home.join(".config/qmail-client/config.json")
+        );
+
+        // the XDG config wins once it exists
+        fs::create_dir_all(home.join(".config/qmail-client")).unwrap();
+        fs::write(home.join(".config/qmail-client/config.json"), "{}").unwrap();
+        assert_eq!(resolve(None, &[], &home).config.origin, "default");
+
+        fs::remove_dir_all(&home).unwrap();
+    }
+}
@@ -0,0 +1,150 @@
+use std::{
+    collections::VecDeque,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex,
+    },
+    thread,
+};
+
+use crate::client::{Client, ClientError};
+
+/// Upper bound of the connections opened by default, servers limit the connections
+/// per account
+pub const MAX_DEFAULT_CONCURRENCY: usize = 4;
+
+/// Number of connections to use for `items` pieces of work when none was requested
+pub fn default_concurrency(items: usize) -> usize {
+    items.clamp(1, MAX_DEFAULT_CONCURRENCY)
+}
+
+/// Run `work` on every item over `concurrency` connections opened by `connect`, as IMAP
+/// sessions can't be shared between threads. Results are returned in the order of
+/// `items`.
+///
+/// A connection that fails to open is reported on stderr and its share of the work is
+/// taken over by the others, the error is only returned when no connection could be
+/// opened. The first failing item stops all connections and its error is returned.
+pub fn run<T, R, C, W>(
+    items: Vec<T>,
+    concurrency: usize,
+    connect: C,
+    work: W,
+) -> Result<Vec<R>, ClientError>
+where
+    T: Send,
+    R: Send,
+    C: Fn() -> Result<Client, ClientError> + Sync,
+    W: Fn(&Client, T) -> Result<R, ClientError> + Sync,
+{
+    let total = items.len();
+    let queue = Mutex::new(items.into_iter().enumerate().collect::<VecDeque<_>>());
+    let results = Mutex::new((0..total).map(|_| None).collect::<Vec<Option<R>>>());
+    let failed = AtomicBool::new(false);
+    let work_error = Mutex::new(None);
+    let connect_errors = Mutex::new(vec![]);
+
+    thread::scope(|scope| {
+        for connection in 1..=concurrency.clamp(1, total.max(1)) {
+            let (queue, results, failed) = (&queue, &results, &failed);
+            let (work_error, connect_errors) = (&work_error, &connect_errors);
+            let (connect, work) = (&connect, &work);
+            scope.spawn(move || {
+                let client = match connect() {
+                    Ok(client) => client,
+                    Err(err) => {
+                        eprintln!("connection {} failed: {}", connection, err);
+                        connect_errors.lock().unwrap().push(err);
+                        return;
+                    }
+                };
+                while !failed.load(Ordering::SeqCst) {
+                    let (index, item) = match queue.lock().unwrap().pop_front() {
+                        Some(next) => next,
+                        None => return,
+                    };
+                    match work(&client, item) {
+                        Ok(result) => results.lock().unwrap()[index] = Some(result),
+                        Err(err) => {
+                            failed.store(true, Ordering::SeqCst);
+                            work_error.lock().unwrap().get_or_insert(err);
+                        }
+                    }
+                }
+            });
+        }
+    });
+
+    if let Some(err) = work_error.into_inner().unwrap() {
+        return Err(err);
+    }
+    let results = results.into_inner().unwrap();
+    if results.iter().any(Option::is_none) {
+        // nothing was left undone unless every connection failed
+        return Err(connect_errors.into_inner().unwrap().remove(0));
+    }
+    Ok(results.into_iter().map(Option::unwrap).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::AtomicUsize;
+
+    use super::*;
+    use crate::mock::MockServer;
+
+    #[test]
+    fn run_over_several_connections() {
+        let server = MockServer::new();
+        let connections = AtomicUsize::new(0);
+
+        let results = run(
+            (1..=10).collect(),
+            3,
+            || {
+                connections.fetch_add(1, Ordering::SeqCst);
+                Ok(server.client())
+            },
+            |client, n: u32| {
+                client.get("INBOX")?;
+                Ok(n * 2)
+            },
+        )
+        .unwrap();
+
+        assert_eq!(results, (1..=10).map(|n| n * 2).collect::<Vec<_>>());
+        assert_eq!(connections.load(Ordering::SeqCst), 3);
+        // every pooled session is closed
+        let logouts = server.commands().iter().filter(|c| *c == "LOGOUT").count();
+        assert_eq!(logouts, 3);
+    }
+
+    #[test]
+    fn run_surfaces_the_first_error() {
+        let server = MockServer::new();
+
+        let result = run(
+            vec!["INBOX", "Missing", "INBOX"],
+            2,
+            || Ok(server.client()),
+            |client, name| client.get(name).map(|_| ()),
+        );
+
+        assert!(matches!(
+            result,
+            Err(ClientError::MailBoxNotFound(name, _)) if name == "Missing"
+        ));
+    }
+
+    #[test]
+    fn run_without_any_connection() {
+        let result = run(
+            vec![1, 2],
+            2,
+            || Err(ClientError::MailNotFound(0)),
+            |_, n: u32| Ok(n),
+        );
+
+        assert!(matches!(result, Err(ClientError::MailNotFound(0))));
+    }
+}
@@ -0,0 +1,74 @@
+//! Text of the mails made safe to show on a terminal. A raw ESC or carriage return in
+//! a header would move the cursor, recolor the screen or retitle the window, so the
+//! control characters are shown as placeholders instead. The json output keeps the
+//! text as decoded.
+
+/// `text` with its control characters made visible: the C0 ones and DEL as their
+/// Unicode control pictures, e.g. ESC as `␛`, the C1 ones as `\x9b` and the invisible
+/// format characters as `\u{200b}`. A tab becomes a space.
+pub fn printable(text: &str) -> String {
+    let mut printable = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '\t' => printable.push(' '),
+            '\0'..='\x1f' => printable.push(char::from_u32(0x2400 + c as u32).unwrap()),
+            '\x7f' => printable.push('␡'),
+            '\u{80}'..='\u{9f}' => printable.push_str(&format!("\\x{:02x}", c as u32)),
+            c if is_invisible(c) => printable.push_str(&format!("\\u{{{:x}}}", c as u32)),
+            c => printable.push(c),
+        }
+    }
+    printable
+}
+
+/// [`printable`] line by line, the line breaks of `text` are kept
+pub fn printable_lines(text: &str) -> String {
+    text.split('\n')
+        .map(|line| printable(line.strip_suffix('\r').unwrap_or(line)))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Zero-width and direction characters, which hide or reorder the text around them.
+/// The zero-width joiner is left alone as it joins the emoji sequences.
+fn is_invisible(c: char) -> bool {
+    matches!(
+        c,
+        '\u{200b}' | '\u{200c}' | '\u{200e}' | '\u{200f}'
+            | '\u{202a}'..='\u{202e}'
+            | '\u{2060}'..='\u{2064}'
+            | '\u{2066}'..='\u{2069}'
+            | '\u{feff}'
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn show_the_control_characters() {
+        assert_eq!(
+            printable("\x1b[2J\x1b]0;pwned\x07Invoice"),
+            "␛[2J␛]0;pwned␇Invoice"
+        );
+        assert_eq!(
+            printable("Re:\tmeeting\r\nBcc: eve"),
+            "Re: meeting␍␊Bcc: eve"
+        );
+        assert_eq!(printable("a\u{9b}31mb\x7f"), "a\\x9b31mb␡");
+        assert_eq!(
+            printable("pay\u{200b}pal \u{202e}fdp.exe"),
+            "pay\\u{200b}pal \\u{202e}fdp.exe"
+        );
+        assert_eq!(printable("发票 👨\u{200d}👩"), "发票 👨\u{200d}👩");
+    }
+
+    #[test]
+    fn keep_the_line_breaks() {
+        assert_eq!(
+            printable_lines("Hi\r\n\x1b[31mBob\x1b[0m\n"),
+            "Hi\n␛[31mBob␛[0m\n"
+        );
+    }
+}
@@ -0,0 +1,176 @@
+use serde_json::{Map, Value};
+
+/// The object of the config file holding the named profiles, the top-level settings
+/// are the default profile
+const PROFILES: &str = "profiles";
+
+/// The login settings of a profile, as saved by `init`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Profile {
+    pub username: String,
+    pub password: String,
+    /// `None` connects to [`DOMAIN`](crate::client::DOMAIN)
+    pub host: Option<String>,
+    /// `None` connects to [`PORT`](crate::client::PORT)
+    pub port: Option<u16>,
+}
+
+impl Profile {
+    /// The profile `name` of `config`, or its default profile, when it has credentials
+    pub fn read(config: &Value, name: Option<&str>) -> Option<Profile> {
+        let settings = match name {
+            Some(name) => &config[PROFILES][name],
+            None => config,
+        };
+        Some(Profile {
+            username: settings["username"].as_str()?.to_string(),
+            password: settings["password"].as_str()?.to_string(),
+            host: settings["host"].as_str().map(str::to_string),
+            port: settings["port"]
+                .as_u64()
+                .and_then(|port| u16::try_from(port).ok()),
+        })
+    }
+}
+
+/// Names of the named profiles of `config`
+pub fn names(config: &Value) -> Vec<String> {
+    match &config[PROFILES] {
+        Value::Object(profiles) => profiles.keys().cloned().collect(),
+        _ => vec![],
+    }
+}
+
+/// `config` with the settings of its profile `name` over the top-level ones
+pub fn select(config: &Value, name: &str) -> Result<Value, String> {
+    let profile = match &config[PROFILES][name] {
+        Value::Object(profile) => profile,
+        _ => {
+            return Err(format!(
+                "no profile named {}, the config has {}",
+                name,
+                match names(config).join(", ") {
+                    names if names.is_empty() => "none".to_string(),
+                    names => names,
+                }
+            ))
+        }
+    };
+    let mut selected = match config {
+        Value::Object(config) => config.clone(),
+        _ => Map::new(),
+    };
+    selected.remove(PROFILES);
+    selected.extend(profile.clone());
+    Ok(Value::Object(selected))
+}
+
+/// `config` with `profile` saved as its profile `name`, or as its default profile. The
+/// other settings are kept.
+pub fn save(config: Value, name: Option<&str>, profile: &Profile) -> Value {
+    let mut config = match config {
+        Value::Object(config) => config,
+        _ => Map::new(),
+    };
+    let settings = match name {
+        Some(name) => {
+            let profiles = config
+                .entry(PROFILES)
+                .or_insert_with(|| Value::Object(Map::new()));
+            if !profiles.is_object() {
+                *profiles = Value::Object(Map::new());
+            }
+            profiles
+                .as_object_mut()
+                .unwrap()
+                .entry(name)
+                .or_insert_with(|| Value::Object(Map::new()))
+        }
+        None => return Value::Object(saved(config, profile)),
+    };
+    let previous = match settings.take() {
+        Value::Object(previous) => previous,
+        _ => Map::new(),
+    };
+    *settings = Value::Object(saved(previous, profile));
+    Value::Object(config)
+}
+
+/// `settings` with those of `profile`, an unset host or port is removed
+fn saved(mut settings: Map<String, Value>, profile: &Profile) -> Map<String, Value> {
+    settings.insert(
+        "username".to_string(),
+        Value::from(profile.username.clone()),
+    );
+    settings.insert(
+        "password".to_string(),
+        Value::from(profile.password.clone()),
+    );
+    match &profile.host {
+        Some(host) => settings.insert("host".to_string(), Value::from(host.clone())),
+        None => settings.remove("host"),
+    };
+    match profile.port {
+        Some(port) => settings.insert("port".to_string(), Value::from(port)),
+        None => settings.remove("port"),
+    };
+    settings
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn profile(username: &str) -> Profile {
+        Profile {
+            username: username.to_string(),
+            password: "secret".to_string(),
+            host: None,
+            port: None,
+        }
+    }
+
+    #[test]
+    fn save_and_select_profiles() {
+        let config = json!({"username": "alice@qq.com", "password": "old", "timeout": 30});
+
+        let config = save(
+            config,
+            Some("work"),
+            &Profile {
+                host: Some("imap.example.com".to_string()),
+                port: Some(1993),
+                ..profile("bob@example.com")
+            },
+        );
+        assert_eq!(names(&config), vec!["work"]);
+        assert_eq!(config["username"], "alice@qq.com");
+
+        let work = select(&config, "work").unwrap();
+        assert_eq!(work["username"], "bob@example.com");
+        assert_eq!(work["port"], 1993);
+        // the other settings are shared
+        assert_eq!(work["timeout"], 30);
+        assert!(work.get(PROFILES).is_none());
+        assert_eq!(
+            Profile::read(&config, Some("work"))
+                .unwrap()
+                .host
+                .as_deref(),
+            Some("imap.example.com")
+        );
+
+        // updating the default profile keeps the named ones
+        let config = save(config, None, &profile("carol@qq.com"));
+        assert_eq!(config["username"], "carol@qq.com");
+        assert_eq!(config["password"], "secret");
+        assert_eq!(names(&config), vec!["work"]);
+
+        assert_eq!(
+            select(&config, "home").unwrap_err(),
+            "no profile named home, the config has work"
+        );
+        assert_eq!(Profile::read(&Value::Null, None), None);
+    }
+}
@@ -0,0 +1,479 @@
+//! A small search language, e.g.
+//! `from:boss@corp.com subject:"weekly report" has:attachment after:2023-11-01 is:unread`.
+//!
+//! Terms are `key:value` pairs, values can be quoted, a leading `-` negates a term or a
+//! group, terms are and-ed unless separated by `OR`, and parentheses group them. A bare
+//! word matches the subject.
+//!
+//! Everything is compiled to IMAP SEARCH criteria, except for what IMAP can't do: the
+//! top level `has:attachment`, `after:`/`before:` and first `subject:` terms are lifted
+//! into the matching [`SearchOptions`](crate::search::SearchOptions) fields.
+
+use std::{fmt::Display, str::FromStr};
+
+use chrono::NaiveDate;
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Query {
+    /// Matched client side, so it may contain any character
+    pub subject: Option<String>,
+    pub after: Option<NaiveDate>,
+    pub before: Option<NaiveDate>,
+    pub has_attachment: bool,
+    /// IMAP SEARCH criteria of the remaining terms
+    pub criteria: Option<String>,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct QueryError {
+    query: String,
+    position: usize,
+    message: String,
+}
+
+impl Display for QueryError {
+    /// The message, followed by the query with a caret under the offending token
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let column = self.query[..self.position].chars().count();
+        write!(
+            f,
+            "{}\n    {}\n    {}^",
+            self.message,
+            self.query,
+            " ".repeat(column)
+        )
+    }
+}
+
+impl std::error::Error for QueryError {}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Open,
+    Close,
+    Or,
+    Not,
+    Term { key: Option<String>, value: String },
+}
+
+#[derive(Debug)]
+enum Expr {
+    And(Vec<Expr>),
+    Or(Vec<Expr>),
+    Not(Box<Expr>),
+    Term {
+        key: Option<String>,
+        value: String,
+        position: usize,
+    },
+}
+
+impl FromStr for Query {
+    type Err = QueryError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Parser {
+            query: s,
+            tokens: tokenize(s)?,
+            next: 0,
+        }
+        .parse()
+    }
+}
+
+fn tokenize(query: &str) -> Result<Vec<(usize, Token)>, QueryError> {
+    let error = |position, message: &str| QueryError {
+        query: query.to_string(),
+        position,
+        message: message.to_string(),
+    };
+
+    let mut tokens = vec![];
+    let mut chars = query.char_indices().peekable();
+    while let Some(&(start, c)) = chars.peek() {
+        match c {
+            c if c.is_whitespace() => {
+                chars.next();
+            }
+            '(' | ')' => {
+                chars.next();
+                tokens.push((start, if c == '(' { Token::Open } else { Token::Close }));
+            }
+            '-' => {
+                chars.next();
+                tokens.push((start, Token::Not));
+            }
+            _ => {
+                let mut key = None;
+                let mut value = String::new();
+                let mut quoted = false;
+                while let Some(&(position, c)) = chars.peek() {
+                    if c == '"' {
+                        chars.next();
+                        quoted = true;
+                        loop {
+                            match chars.next() {
+                                Some((_, '"')) => break,
+                                Some((_, '\\')) => match chars.next() {
+                                    Some((_, c)) => value.push(c),
+                                    None => return Err(error(position, "unterminated quote")),
+                                },
+                                Some((_, c)) => value.push(c),
+                                None => return Err(error(position, "unterminated quote")),
+                            }
+                        }
+                    } else if c.is_whitespace() || c == '(' || c == ')' {
+                        break;
+                    } else if c == ':' && key.is_none() && !quoted {
+                        chars.next();
+                        key = Some(std::mem::take(&mut value).to_lowercase());
+                    } else {
+                        chars.next();
+                        value.push(c);
+                    }
+                }
+
+                if value.is_empty() && !quoted {
+                    return Err(error(start, "missing value"));
+                }
+                if key.is_none() && !quoted && value == "OR" {
+                    tokens.push((start, Token::Or));
+                } else {
+                    tokens.push((start, Token::Term { key, value }));
+                }
+            }
+        }
+    }
+    Ok(tokens)
+}
+
+struct Parser<'a> {
+    query: &'a str,
+    tokens: Vec<(usize, Token)>,
+    next: usize,
+}
+
+impl Parser<'_> {
+    fn parse(mut self) -> Result<Query, QueryError> {
+        let expr = self.or()?;
+        if let Some((position, _)) = self.tokens.get(self.next) {
+            return Err(self.error(*position, "unexpected ')'"));
+        }
+        self.compile(expr)
+    }
+
+    fn error(&self, position: usize, message: &str) -> QueryError {
+        QueryError {
+            query: self.query.to_string(),
+            position,
+            message: message.to_string(),
+        }
+    }
+
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.next).map(|(_, token)| token)
+    }
+
+    fn position(&self) -> usize {
+        self.tokens
+            .get(self.next)
+            .map_or(self.query.len(), |(position, _)| *position)
+    }
+
+    /// or := and ("OR" and)*
+    fn or(&mut self) -> Result<Expr, QueryError> {
+        let mut alternatives = vec![self.and()?];
+        while self.peek() == Some(&Token::Or) {
+            self.next += 1;
+            alternatives.push(self.and()?);
+        }
+        Ok(match alternatives.len() {
+            1 => alternatives.pop().unwrap(),
+            _ => Expr::Or(alternatives),
+        })
+    }
+
+    /// and := unary+
+    fn and(&mut self) -> Result<Expr, QueryError> {
+        let mut terms = vec![];
+        while !matches!(self.peek(), None | Some(Token::Close) | Some(Token::Or)) {
+            terms.push(self.unary()?);
+        }
+        if terms.is_empty() {
+            return Err(self.error(self.position(), "expected a search term"));
+        }
+        Ok(match terms.len() {
+            1 => terms.pop().unwrap(),
+            _ => Expr::And(terms),
+        })
+    }
+
+    /// unary := "-" unary | "(" or ")" | term
+    fn unary(&mut self) -> Result<Expr, QueryError> {
+        let position = self.position();
+        match self.tokens.get(self.next).cloned() {
+            Some((_, Token::Not)) => {
+                self.next += 1;
+                Ok(Expr::Not(Box::new(self.unary()?)))
+            }
+            Some((_, Token::Open)) => {
+                self.next += 1;
+                let expr = self.or()?;
+                if self.peek() != Some(&Token::Close) {
+                    return Err(self.error(self.position(), "expected ')'"));
+                }
+                self.next += 1;
+                Ok(expr)
+            }
+            Some((_, Token::Term { key, value })) => {
+                self.next += 1;
+                Ok(Expr::Term {
+                    key,
+                    value,
+                    position,
+                })
+            }
+            _ => Err(self.error(position, "expected a search term")),
+        }
+    }
+
+    fn compile(&self, expr: Expr) -> Result<Query, QueryError> {
+        let mut query = Query::default();
+        let terms = match expr {
+            Expr::And(terms) => terms,
+            expr => vec![expr],
+        };
+
+        let mut criteria = vec![];
+        for term in terms {
+            // the top level terms imap can't search for, or only by whole days
+            if let Expr::Term {
+                key,
+                value,
+                position,
+            } = &term
+            {
+                match key.as_deref() {
+                    Some("has") if value == "attachment" => {
+                        query.has_attachment = true;
+                        continue;
+                    }
+                    Some("after") => {
+                        query.after = Some(self.date(value, *position)?);
+                        continue;
+                    }
+                    Some("before") => {
+                        query.before = Some(self.date(value, *position)?);
+                        continue;
+                    }
+                    Some("subject") | None if query.subject.is_none() => {
+                        query.subject = Some(value.clone());
+                        continue;
+                    }
+                    _ => {}
+                }
+            }
+            criteria.push(self.criteria(&term)?);
+        }
+
+        if !criteria.is_empty() {
+            query.criteria = Some(criteria.join(" "));
+        }
+        Ok(query)
+    }
+
+    fn criteria(&self, expr: &Expr) -> Result<String, QueryError> {
+        Ok(match expr {
+            Expr::And(terms) => format!(
+                "({})",
+                terms
+                    .iter()
+                    .map(|term| self.criteria(term))
+                    .collect::<Result<Vec<_>, _>>()?
+                    .join(" ")
+            ),
+            // OR takes exactly two keys in IMAP
+            Expr::Or(alternatives) => {
+                let mut alternatives = alternatives
+                    .iter()
+                    .map(|alternative| self.criteria(alternative))
+                    .collect::<Result<Vec<_>, _>>()?;
+                let last = alternatives.pop().unwrap();
+                alternatives
+                    .into_iter()
+                    .rev()
+                    .fold(last, |rest, alternative| {
+                        format!("OR {} {}", alternative, rest)
+                    })
+            }
+            Expr::Not(expr) => format!("NOT {}", self.criteria(expr)?),
+            Expr::Term {
+                key,
+                value,
+                position,
+            } => {
+                let position = *position;
+                let text = |name: &str| -> Result<String, QueryError> {
+                    if !value.is_ascii() {
+                        return Err(self.error(
+                            position,
+                            "only plain ascii values can be searched for here, \
+                             use a top level subject: term for other text",
+                        ));
+                    }
+                    Ok(format!("{} {}", name, quote(value)))
+                };
+                match (key.as_deref(), value.as_str()) {
+                    (None, _) | (Some("subject"), _) => text("SUBJECT")?,
+                    (Some("from"), _) => text("FROM")?,
+                    (Some("to"), _) => text("TO")?,
+                    (Some("cc"), _) => text("CC")?,
+                    (Some("after"), _) => {
+                        format!("SINCE {}", self.date(value, position)?.format("%d-%b-%Y"))
+                    }
+                    (Some("before"), _) => {
+                        format!("BEFORE {}", self.date(value, position)?.format("%d-%b-%Y"))
+                    }
+                    (Some("is"), "unread") => "UNSEEN".to_string(),
+                    (Some("is"), "read") => "SEEN".to_string(),
+                    (Some("is"), "flagged") => "FLAGGED".to_string(),
+                    (Some("is"), "unflagged") => "UNFLAGGED".to_string(),
+                    (Some("is"), "answered") => "ANSWERED".to_string(),
+                    (Some("is"), _) => {
+                        return Err(self.error(
+                            position,
+                            "expected is:unread, is:read, is:flagged, is:unflagged or is:answered",
+                        ))
+                    }
+                    (Some("has"), "attachment") => {
+                        return Err(self.error(
+                            position,
+                            "has:attachment can't be negated or used in an OR group",
+                        ))
+                    }
+                    (Some(key), _) => {
+                        return Err(self.error(position, &format!("unknown key {:?}", key)))
+                    }
+                }
+            }
+        })
+    }
+
+    fn date(&self, value: &str, position: usize) -> Result<NaiveDate, QueryError> {
+        value
+            .parse()
+            .map_err(|_| self.error(position, "expected a date like 2023-11-01"))
+    }
+}
+
+fn quote(value: &str) -> String {
+    format!("\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(query: &str) -> Query {
+        query.parse().unwrap()
+    }
+
+    fn error(query: &str) -> String {
+        query.parse::<Query>().unwrap_err().to_string()
+    }
+
+    #[test]
+    fn top_level_terms() {
+        assert_eq!(
+            parse(
+                r#"from:boss@corp.com subject:"weekly report" has:attachment after:2023-11-01 before:2023-12-01 is:unread"#
+            ),
+            Query {
+                subject: Some("weekly report".to_string()),
+                after: Some("2023-11-01".parse().unwrap()),
+                before: Some("2023-12-01".parse().unwrap()),
+                has_attachment: true,
+                criteria: Some(r#"FROM "boss@corp.com" UNSEEN"#.to_string()),
+            }
+        );
+    }
+
+    #[test]
+    fn bare_words_and_non_ascii_subject() {
+        let query = parse("发票 subject:2023");
+
+        assert_eq!(query.subject.as_deref(), Some("发票"));
+        assert_eq!(query.criteria.as_deref(), Some(r#"SUBJECT "2023""#));
+    }
+
+    #[test]
+    fn negation() {
+        assert_eq!(
+            parse("-subject:spam").criteria.as_deref(),
+            Some(r#"NOT SUBJECT "spam""#)
+        );
+        assert_eq!(
+            parse("-(from:a is:read)").criteria.as_deref(),
+            Some(r#"NOT (FROM "a" SEEN)"#)
+        );
+    }
+
+    #[test]
+    fn or_groups() {
+        assert_eq!(
+            parse("(from:a OR from:b OR from:c) -is:read")
+                .criteria
+                .as_deref(),
+            Some(r#"OR FROM "a" OR FROM "b" FROM "c" NOT SEEN"#)
+        );
+        assert_eq!(
+            parse("from:a is:flagged OR to:b").criteria.as_deref(),
+            Some(r#"OR (FROM "a" FLAGGED) TO "b""#)
+        );
+    }
+
+    #[test]
+    fn nested_dates_are_searched_by_the_server() {
+        assert_eq!(
+            parse("(after:2023-11-01 OR is:flagged)")
+                .criteria
+                .as_deref(),
+            Some("OR SINCE 01-Nov-2023 FLAGGED")
+        );
+    }
+
+    #[test]
+    fn quoted_values() {
+        assert_eq!(
+            parse(r#"from:"Alice \"A\" Smith""#).criteria.as_deref(),
+            Some(r#"FROM "Alice \"A\" Smith""#)
+        );
+    }
+
+    #[test]
+    fn errors_point_at_the_token() {
+        assert_eq!(
+            error("from:a color:red"),
+            "unknown key \"color\"\n    from:a color:red\n           ^"
+        );
+        assert_eq!(
+            error("from:a (is:read"),
+            "expected ')'\n    from:a (is:read\n                   ^"
+        );
+        assert_eq!(
+            error("after:yesterday"),
+            "expected a date like 2023-11-01\n    after:yesterday\n    ^"
+        );
+        assert_eq!(
+            error("-has:attachment"),
+            "has:attachment can't be negated or used in an OR group\n    -has:attachment\n     ^"
+        );
+        assert_eq!(
+            error("from:a OR"),
+            "expected a search term\n    from:a OR\n             ^"
+        );
+        assert!(error(r#"subject:"open"#).starts_with("unterminated quote"));
+        assert!(error("a) b").starts_with("unexpected ')'"));
+        assert!(error("(from:李)").starts_with("only plain ascii"));
+    }
+}
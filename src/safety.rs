@@ -0,0 +1,156 @@
+use std::io::{self, IsTerminal, Write};
+
+use crate::client::MailHeader;
+
+/// Changes of more mails than this are confirmed, unless the config sets
+/// `confirm_above`
+pub const DEFAULT_CONFIRM_ABOVE: usize = 10;
+
+/// Asks a yes/no question
+pub type Ask = fn(&str) -> bool;
+
+/// What the commands changing the server go through: `--dry-run` only reports the
+/// changes, and large changes are confirmed on a terminal unless `--yes` is given
+#[derive(Debug, Clone, Copy)]
+pub struct Safety {
+    pub dry_run: bool,
+    /// Ask before changing more than this many mails
+    pub confirm_above: usize,
+    /// How to ask, `None` never asks
+    pub ask: Option<Ask>,
+}
+
+/// A change to some mails of a mail box
+#[derive(Debug)]
+pub struct Change<'a> {
+    /// What is done, e.g. `move 2 mails from INBOX to Archive/2023`
+    pub description: String,
+    pub mails: Vec<&'a MailHeader>,
+}
+
+impl Safety {
+    /// Asks on stdin when it's a terminal and `yes` isn't set
+    pub fn new(dry_run: bool, yes: bool, confirm_above: usize) -> Safety {
+        Safety {
+            dry_run,
+            confirm_above,
+            ask: (!yes && io::stdin().is_terminal()).then_some(confirm as Ask),
+        }
+    }
+
+    /// Whether to make a change that doesn't concern mails, e.g. `create mail box
+    /// Alerts`, it is only printed in a dry run
+    pub fn allow(&self, action: &str) -> bool {
+        self.allow_or_tell(action, |notice| eprintln!("{}", notice))
+    }
+
+    /// [`allow`](Self::allow), with the notice of a dry run handed to `tell` rather than
+    /// printed, for the TUI which owns the terminal
+    pub fn allow_or_tell(&self, action: &str, tell: impl FnOnce(String)) -> bool {
+        if self.dry_run {
+            tell(format!("dry run: would {}", action));
+        }
+        !self.dry_run
+    }
+
+    /// Whether to apply `changes`. A dry run prints them instead, and when they touch
+    /// more than [`confirm_above`](Self::confirm_above) mails the date and subject of
+    /// every mail are listed before asking.
+    pub fn approve(&self, changes: &[Change]) -> bool {
+        if self.dry_run {
+            for change in changes {
+                eprintln!(
+                    "dry run: would {} (UID {})",
+                    change.description,
+                    uid_set(&change.mails)
+                );
+            }
+            return false;
+        }
+
+        let total = changes
+            .iter()
+            .map(|change| change.mails.len())
+            .sum::<usize>();
+        let ask = match self.ask {
+            Some(ask) if total > self.confirm_above => ask,
+            _ => return true,
+        };
+        for change in changes {
+            eprintln!("about to {}:", change.description);
+            for mail in change.mails.iter() {
+                eprintln!(
+                    "  {}  {}",
+                    mail.internal_date.format("%Y-%m-%d %H:%M"),
+                    mail.subject
+                );
+            }
+        }
+        ask(&format!("Change {} mails?", total))
+    }
+}
+
+fn uid_set(mails: &[&MailHeader]) -> String {
+    mails
+        .iter()
+        .map(|mail| mail.uid.to_string())
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+/// Ask `question` on the terminal, anything but y or yes is a no
+pub fn confirm(question: &str) -> bool {
+    eprint!("{} [y/N] ", question);
+    io::stderr().flush().unwrap();
+    let mut answer = String::new();
+    io::stdin().read_line(&mut answer).unwrap();
+    matches!(answer.trim().to_lowercase().as_str(), "y" | "yes")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn header(uid: u32) -> MailHeader {
+        MailHeader {
+            uid,
+            subject: "report".to_string(),
+            from: String::new(),
+            internal_date: chrono::DateTime::parse_from_rfc3339("2024-05-02T09:30:00+08:00")
+                .unwrap(),
+        }
+    }
+
+    #[test]
+    fn ask_above_the_threshold() {
+        let mails = [header(1), header(2), header(3)];
+        let change = |count| Change {
+            description: "move mails".to_string(),
+            mails: mails.iter().take(count).collect(),
+        };
+        let safety = Safety {
+            dry_run: false,
+            confirm_above: 2,
+            ask: Some(|_| false),
+        };
+
+        assert!(safety.approve(&[change(2)]));
+        assert!(!safety.approve(&[change(1), change(2)]));
+        assert!(Safety {
+            ask: None,
+            ..safety
+        }
+        .approve(&[change(3)]));
+        assert!(!Safety {
+            dry_run: true,
+            ask: None,
+            ..safety
+        }
+        .approve(&[change(1)]));
+        assert!(!Safety {
+            dry_run: true,
+            ..safety
+        }
+        .allow("create mail box Alerts"));
+    }
+}
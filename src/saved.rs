@@ -0,0 +1,450 @@
+use std::{collections::BTreeMap, fmt::Display, fs, path::Path, str::FromStr};
+
+use chrono::{Months, NaiveDate};
+use serde::{de::Error, Deserialize, Deserializer, Serialize};
+use serde_json::{Map, Value};
+
+use crate::client::{Client, ClientError, FetchWarning, Mail, SearchCriteria};
+use crate::folder;
+use crate::utf7::encode_utf7_imap;
+
+/// A search stored under `searches` in the config file, e.g.
+///
+/// ```json
+/// "searches": {
+///     "daily-invoices": {"subject": "发票", "mail_box": "Finance", "unseen": true, "window": "last 7 days"}
+/// }
+/// ```
+#[derive(Debug, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SavedSearch {
+    pub subject: Option<String>,
+    /// A `--query` expression
+    pub query: Option<String>,
+    pub mail_box: Option<String>,
+    pub regex: bool,
+    pub unseen: bool,
+    pub attachments_only: bool,
+    pub raw_query: Option<String>,
+    /// Date range relative to the day the search runs
+    pub window: Option<Window>,
+}
+
+/// The saved searches by name, an error names the search that doesn't parse
+pub fn saved_searches(config: &Value) -> Result<BTreeMap<String, SavedSearch>, String> {
+    let searches = match config.get("searches") {
+        Some(Value::Object(searches)) => searches,
+        _ => return Ok(BTreeMap::new()),
+    };
+    searches
+        .iter()
+        .map(|(name, search)| {
+            serde_json::from_value(search.clone())
+                .map(|search| (name.clone(), search))
+                .map_err(|err| format!("saved search {}: {}", name, err))
+        })
+        .collect()
+}
+
+impl Display for SavedSearch {
+    /// The parameters of the search, such as `subject="发票" unseen window="last 7 days"`
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut parameters = vec![];
+        let strings = [
+            ("subject", &self.subject),
+            ("query", &self.query),
+            ("mail_box", &self.mail_box),
+            ("raw_query", &self.raw_query),
+        ];
+        for (name, value) in strings {
+            if let Some(value) = value {
+                parameters.push(format!("{}={:?}", name, value));
+            }
+        }
+        let flags = [
+            ("regex", self.regex),
+            ("unseen", self.unseen),
+            ("attachments_only", self.attachments_only),
+        ];
+        for (name, set) in flags {
+            if set {
+                parameters.push(name.to_string());
+            }
+        }
+        if let Some(window) = &self.window {
+            parameters.push(format!("window=\"{}\"", window));
+        }
+        write!(f, "{}", parameters.join(" "))
+    }
+}
+
+/// `today` or `last <n> days|weeks|months`, the last n days include today
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Window {
+    Days(u32),
+    Weeks(u32),
+    Months(u32),
+}
+
+impl Window {
+    /// The first day of the window ending on `today`
+    pub fn start(&self, today: NaiveDate) -> NaiveDate {
+        let days = |days: u32| today - chrono::Duration::days(days.saturating_sub(1) as i64);
+        match *self {
+            Window::Days(n) => days(n),
+            Window::Weeks(n) => days(n * 7),
+            Window::Months(n) => today - Months::new(n),
+        }
+    }
+}
+
+impl FromStr for Window {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let error = || {
+            format!(
+                "invalid window {:?}, expected today or last <n> days|weeks|months",
+                s
+            )
+        };
+        let words = s.split_whitespace().collect::<Vec<_>>();
+        match words[..] {
+            ["today"] => Ok(Window::Days(1)),
+            ["last", n, unit] => {
+                let n = n.parse::<u32>().ok().filter(|n| *n > 0).ok_or_else(error)?;
+                match unit.trim_end_matches('s') {
+                    "day" => Ok(Window::Days(n)),
+                    "week" => Ok(Window::Weeks(n)),
+                    "month" => Ok(Window::Months(n)),
+                    _ => Err(error()),
+                }
+            }
+            _ => Err(error()),
+        }
+    }
+}
+
+impl Display for Window {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Window::Days(1) => write!(f, "today"),
+            Window::Days(n) => write!(f, "last {} days", n),
+            Window::Weeks(n) => write!(f, "last {} weeks", n),
+            Window::Months(n) => write!(f, "last {} months", n),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for Window {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(D::Error::custom)
+    }
+}
+
+/// Version of the format of the searches file, a file of another version is refused
+const STORE_VERSION: u64 = 1;
+
+/// A search saved with `save-search`: fixed criteria, unlike the searches of the config
+/// file, along with the mail box or mail box pattern to run them on
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct StoredSearch {
+    pub mail_box: String,
+    pub criteria: SearchCriteria,
+}
+
+impl StoredSearch {
+    /// Run the search on its mail box, or on every mail box matching it when it's a
+    /// pattern, along with the mails skipped
+    pub fn fetch(&self, client: &Client) -> Result<(Vec<Mail>, Vec<FetchWarning>), ClientError> {
+        let raw_names = match folder::is_pattern(&self.mail_box) {
+            true => client.matching_mail_boxes(&self.mail_box)?,
+            false => vec![encode_utf7_imap(&self.mail_box)],
+        };
+        let mut mails = vec![];
+        let mut warnings = vec![];
+        for raw_name in raw_names {
+            let mail_box = client.select(&raw_name)?;
+            let (found, skipped) = mail_box
+                .filter_by(self.criteria.clone())
+                .fetch_with_warnings()?;
+            mails.extend(found);
+            warnings.extend(skipped);
+        }
+        Ok((mails, warnings))
+    }
+}
+
+impl Display for StoredSearch {
+    /// The mail box and the criteria that differ from those of a new search, such as
+    /// `mail_box="INBOX" subject_pattern="report" start_datetime="2024-05-01T00:00:00+08:00"`
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let criteria = serde_json::to_value(&self.criteria).unwrap();
+        let defaults = serde_json::to_value(SearchCriteria::new("", Default::default())).unwrap();
+        let mut parameters = vec![format!("mail_box={:?}", self.mail_box)];
+        if let (Value::Object(criteria), Value::Object(defaults)) = (criteria, defaults) {
+            for (name, value) in criteria {
+                if defaults.get(&name) != Some(&value) {
+                    parameters.push(format!("{}={}", name, value));
+                }
+            }
+        }
+        write!(f, "{}", parameters.join(" "))
+    }
+}
+
+/// The searches saved with `save-search`, kept in a json file by name
+pub struct SearchStore<'a> {
+    path: &'a Path,
+}
+
+impl<'a> SearchStore<'a> {
+    pub fn new(path: &'a Path) -> Self {
+        SearchStore { path }
+    }
+
+    /// Every saved search by name, those that don't parse anymore, e.g. saved by
+    /// another version, are an error naming the problem
+    pub fn load(&self) -> Result<BTreeMap<String, Result<StoredSearch, String>>, String> {
+        let searches = match self.read()? {
+            Value::Object(mut file) => file.remove("searches").unwrap_or_default(),
+            _ => Value::Null,
+        };
+        let searches = match searches {
+            Value::Object(searches) => searches,
+            _ => return Ok(BTreeMap::new()),
+        };
+        Ok(searches
+            .into_iter()
+            .map(|(name, search)| {
+                let search = serde_json::from_value(search).map_err(|err| {
+                    format!(
+                        "saved search {} doesn't match the current format, save it again: {}",
+                        name, err
+                    )
+                });
+                (name, search)
+            })
+            .collect())
+    }
+
+    /// The search saved as `name`
+    pub fn get(&self, name: &str) -> Result<StoredSearch, String> {
+        self.load()?.remove(name).unwrap_or_else(|| {
+            Err(format!(
+                "no search saved as {} in {}",
+                name,
+                self.path.display()
+            ))
+        })
+    }
+
+    /// Save `search` as `name`, replacing the search saved under that name if any.
+    /// Returns whether one was replaced.
+    pub fn save(&self, name: &str, search: &StoredSearch) -> Result<bool, String> {
+        let mut file = match self.read()? {
+            Value::Object(file) => file,
+            _ => Map::new(),
+        };
+        file.insert("version".to_string(), Value::from(STORE_VERSION));
+        let searches = file
+            .entry("searches")
+            .or_insert_with(|| Value::Object(Map::new()));
+        if !searches.is_object() {
+            *searches = Value::Object(Map::new());
+        }
+        let replaced = searches
+            .as_object_mut()
+            .unwrap()
+            .insert(name.to_string(), serde_json::to_value(search).unwrap())
+            .is_some();
+
+        let error = |err: std::io::Error| format!("can't save {}: {}", self.path.display(), err);
+        if let Some(dir) = self.path.parent() {
+            fs::create_dir_all(dir).map_err(error)?;
+        }
+        let content = serde_json::to_string_pretty(&Value::Object(file)).unwrap();
+        fs::write(self.path, content).map_err(error)?;
+        Ok(replaced)
+    }
+
+    /// The content of the file, null when it doesn't exist
+    fn read(&self) -> Result<Value, String> {
+        let content = match fs::read_to_string(self.path) {
+            Ok(content) => content,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(Value::Null),
+            Err(err) => return Err(format!("can't read {}: {}", self.path.display(), err)),
+        };
+        let file = serde_json::from_str::<Value>(&content)
+            .map_err(|err| format!("invalid {}: {}", self.path.display(), err))?;
+        match file["version"].as_u64() {
+            Some(STORE_VERSION) => Ok(file),
+            version => Err(format!(
+                "{} has version {}, this version of qmail reads version {}",
+                self.path.display(),
+                version.map_or("none".to_string(), |version| version.to_string()),
+                STORE_VERSION
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::{fetch_response, MockServer};
+
+    fn date(s: &str) -> NaiveDate {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn window_start() {
+        let today = date("2023-03-31");
+        let start = |window: &str| window.parse::<Window>().unwrap().start(today);
+
+        assert_eq!(start("today"), today);
+        assert_eq!(start("last 7 days"), date("2023-03-25"));
+        assert_eq!(start("last 1 week"), date("2023-03-25"));
+        assert_eq!(start("last 2 weeks"), date("2023-03-18"));
+        assert_eq!(start("last 1 month"), date("2023-02-28"));
+    }
+
+    #[test]
+    fn invalid_windows() {
+        for window in [
+            "",
+            "yesterday",
+            "last 0 days",
+            "last -1 days",
+            "last 7 fortnights",
+        ] {
+            assert!(window.parse::<Window>().is_err(), "{:?}", window);
+        }
+    }
+
+    #[test]
+    fn saved_searches_from_config() {
+        let config = serde_json::json!({
+            "username": "a",
+            "searches": {
+                "daily-invoices": {"subject": "发票", "unseen": true, "window": "last 7 days"},
+                "reports": {"query": "from:boss has:attachment", "mail_box": "Work"},
+            }
+        });
+
+        let searches = saved_searches(&config).unwrap();
+
+        assert_eq!(
+            searches["daily-invoices"],
+            SavedSearch {
+                subject: Some("发票".to_string()),
+                unseen: true,
+                window: Some(Window::Days(7)),
+                ..SavedSearch::default()
+            }
+        );
+        assert_eq!(
+            searches["reports"].to_string(),
+            r#"query="from:boss has:attachment" mail_box="Work""#
+        );
+        assert_eq!(
+            searches["daily-invoices"].to_string(),
+            r#"subject="发票" unseen window="last 7 days""#
+        );
+    }
+
+    #[test]
+    fn invalid_saved_search() {
+        let config = serde_json::json!({"searches": {"typo": {"subjcet": "a"}}});
+
+        assert!(saved_searches(&config)
+            .unwrap_err()
+            .starts_with("saved search typo:"));
+        assert!(saved_searches(&Value::Null).unwrap().is_empty());
+    }
+
+    fn stored(mail_box: &str) -> StoredSearch {
+        let start = chrono::DateTime::parse_from_rfc3339("2022-05-01T00:00:00+08:00").unwrap();
+        let end = chrono::DateTime::parse_from_rfc3339("2022-06-01T00:00:00+08:00").unwrap();
+        let mut criteria = SearchCriteria::new("report", start);
+        criteria.end_date(end).larger(Some(5_000_000));
+        StoredSearch {
+            mail_box: mail_box.to_string(),
+            criteria,
+        }
+    }
+
+    #[test]
+    fn save_and_load_searches() {
+        let dir = std::env::temp_dir().join(format!("qmail-searches-{}", std::process::id()));
+        let path = dir.join("searches.json");
+        let store = SearchStore::new(&path);
+        assert!(store.load().unwrap().is_empty());
+
+        assert!(!store.save("big", &stored("INBOX")).unwrap());
+        assert!(store.save("big", &stored("Archive/*")).unwrap());
+        assert_eq!(store.get("big").unwrap(), stored("Archive/*"));
+        assert_eq!(
+            store.get("big").unwrap().to_string(),
+            r#"mail_box="Archive/*" end_datetime="2022-06-01T00:00:00+08:00" larger=5000000 start_datetime="2022-05-01T00:00:00+08:00" subject_pattern="report""#
+        );
+        assert!(store
+            .get("small")
+            .unwrap_err()
+            .starts_with("no search saved as small"));
+
+        // a search of an older format is reported, the others still load
+        let mut file = serde_json::from_str::<Value>(&fs::read_to_string(&path).unwrap()).unwrap();
+        file["searches"]["old"] =
+            serde_json::json!({"mail_box": "INBOX", "criteria": {"subject": "a"}});
+        fs::write(&path, file.to_string()).unwrap();
+        let searches = store.load().unwrap();
+        assert!(searches["big"].is_ok());
+        assert!(searches["old"]
+            .as_ref()
+            .unwrap_err()
+            .contains("unknown field `subject`"));
+
+        fs::write(&path, r#"{"version": 2, "searches": {}}"#).unwrap();
+        assert!(store.load().unwrap_err().contains("has version 2"));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn run_a_stored_search_on_a_pattern() {
+        let server = MockServer::new();
+        server
+            .on(
+                "LIST",
+                "* LIST () \"/\" \"Archive/2021\"\r\n* LIST () \"/\" \"Archive/2022\"\r\n",
+            )
+            .on("UID SEARCH", "* SEARCH 1\r\n")
+            .on(
+                "UID FETCH",
+                fetch_response(
+                    1,
+                    "02-May-2022 09:30:00 +0800",
+                    "Subject: report\r\n\r\n",
+                    "",
+                ),
+            );
+        let client = server.client();
+
+        let (mails, warnings) = stored("Archive/*").fetch(&client).unwrap();
+
+        assert_eq!(mails.len(), 2);
+        assert!(warnings.is_empty());
+        let searches = server
+            .commands()
+            .into_iter()
+            .filter(|c| c.starts_with("UID SEARCH"))
+            .collect::<Vec<_>>();
+        assert_eq!(searches.len(), 2);
+        assert!(searches[0].contains("LARGER 5000000"));
+    }
+}
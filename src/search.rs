@@ -1,75 +1,867 @@
-use std::{error::Error, io, vec};
+use std::{
+    collections::{hash_map::DefaultHasher, HashMap, HashSet},
+    env,
+    error::Error,
+    fs,
+    hash::{Hash, Hasher},
+    io, panic,
+    path::PathBuf,
+    process,
+    time::{Duration, Instant},
+    vec,
+};
 
 use chrono::FixedOffset;
 use crossterm::{
-    event::{self, DisableMouseCapture, EnableMouseCapture, Event, KeyCode},
+    cursor,
+    event::{self, DisableMouseCapture, EnableMouseCapture, Event, KeyCode, KeyModifiers},
     execute,
     terminal::{disable_raw_mode, enable_raw_mode, EnterAlternateScreen, LeaveAlternateScreen},
 };
 use tui::{
     backend::{Backend, CrosstermBackend},
     layout::{Constraint, Direction, Layout, Rect},
-    style::{Color, Modifier, Style},
+    style::{Modifier, Style},
     text::{Span, Spans},
-    widgets::{Block, Borders, Cell, Paragraph, Row, Table, TableState},
+    widgets::{
+        Block, Borders, Cell, Clear, List, ListItem, ListState, Paragraph, Row, Table, TableState,
+    },
     Frame, Terminal,
 };
 
-use crate::client::{Client, Mail};
+use crate::browser::{self, PageDir};
+use crate::client::{
+    strip_quotes, Client, ClientError, DateBasis, FetchWarning, FlagKey, Mail, MailBox, MailFilter,
+    MailboxInfo, SearchCriteria, SizeLimit,
+};
+use crate::columns::{self, Column};
+use crate::find;
+use crate::folder;
+use crate::printable::printable_lines;
+use crate::safety::{Safety, DEFAULT_CONFIRM_ABOVE};
+use crate::theme::Theme;
+use crate::thread;
+use crate::utf7::encode_utf7_imap;
+use unicode_width::UnicodeWidthStr;
+
+pub struct SearchOptions {
+    pub subject_query: String,
+    pub start_datetime: chrono::DateTime<FixedOffset>,
+    pub end_datetime: chrono::DateTime<FixedOffset>,
+    pub regex: bool,
+    pub reserve: bool,
+    pub mail_box: String,
+    pub raw_query: Option<String>,
+    pub since_uid: Option<u32>,
+    pub max_uid: Option<u32>,
+    /// Only match mails larger than this many bytes
+    pub larger: Option<u64>,
+    /// Only match mails smaller than this many bytes
+    pub smaller: Option<u64>,
+    pub limit: Option<usize>,
+    pub window_days: u32,
+    /// Report the windows of the search on stderr, see [`MailFilter::progress`]
+    pub progress: bool,
+    pub newest: bool,
+    pub oldest: bool,
+    pub threads: bool,
+    pub attachments_only: bool,
+    pub show_snippet: bool,
+    /// Columns of the mail list, the snippet is added by `show_snippet`
+    pub columns: Vec<Column>,
+    /// Most lines of a row of the mail list once wrapped, taller cells end with how many
+    /// lines were cut; rows are as tall as their cells when not set
+    pub row_height: Option<usize>,
+    /// Hide the quoted reply history of the displayed mail bodies
+    pub no_quotes: bool,
+    /// Command to open HTML bodies with instead of the system default
+    pub browser: Option<String>,
+    /// Only match mails with an attachment of a MIME type matching this glob
+    pub attachment_type: Option<String>,
+    /// Only match mails containing this text in their headers or body
+    pub text: Option<String>,
+    /// Only match mails containing this text in their body
+    pub body_contains: Option<String>,
+    /// Only match mails received longer than this after they were sent
+    pub lag_over: Option<chrono::Duration>,
+    /// Fetch and keep at most this many bytes of every body
+    pub max_body_bytes: Option<usize>,
+    /// The date the time window is matched against
+    pub date_basis: DateBasis,
+    /// Collapse the copies of a mail found in several mail boxes, see [`dedup`]
+    pub dedup: bool,
+    /// Flags the mails have to have set or unset
+    pub flags: Vec<FlagKey>,
+}
+
+#[cfg(test)]
+impl SearchOptions {
+    /// Options matching every mail of INBOX received from `start` to `end`, given in
+    /// RFC 3339, for the tests to change what they need of
+    pub fn between(start: &str, end: &str) -> Self {
+        SearchOptions {
+            subject_query: String::new(),
+            start_datetime: start.parse().unwrap(),
+            end_datetime: end.parse().unwrap(),
+            regex: false,
+            reserve: false,
+            mail_box: "INBOX".to_string(),
+            raw_query: None,
+            since_uid: None,
+            max_uid: None,
+            larger: None,
+            smaller: None,
+            limit: None,
+            window_days: 0,
+            progress: false,
+            newest: false,
+            oldest: false,
+            threads: false,
+            attachments_only: false,
+            show_snippet: false,
+            columns: Column::DEFAULT.to_vec(),
+            row_height: None,
+            no_quotes: false,
+            browser: None,
+            attachment_type: None,
+            text: None,
+            body_contains: None,
+            lag_over: None,
+            max_body_bytes: None,
+            date_basis: DateBasis::Received,
+            dedup: false,
+            flags: vec![],
+        }
+    }
+}
+
+/// What a search went through besides the mails it matched
+#[derive(Debug, Default)]
+pub struct Scan {
+    /// Number of mails looked at, see [`MailFilter::scanned`]
+    pub scanned: usize,
+    /// The mails skipped, see [`MailFilter::warnings`]
+    pub warnings: Vec<FetchWarning>,
+}
+
+impl Scan {
+    fn of(filter: &MailFilter) -> Scan {
+        Scan {
+            scanned: filter.scanned(),
+            warnings: filter.warnings(),
+        }
+    }
+
+    /// The scans of several mail boxes together
+    fn total(scans: Vec<Scan>) -> Scan {
+        scans.into_iter().fold(Scan::default(), |mut total, scan| {
+            total.scanned += scan.scanned;
+            total.warnings.extend(scan.warnings);
+            total
+        })
+    }
+}
+
+impl SearchOptions {
+    /// The matching mails with their [`thread_id`](Mail::thread_id), along with the
+    /// number of mails looked at and those skipped
+    pub fn fetch_scanned(&self, client: &Client) -> Result<(Vec<Mail>, Scan), ClientError> {
+        let fetched =
+            self.each_mail_box(client, |filter| Ok((filter.fetch()?, Scan::of(filter))))?;
+        let (mails, scans): (Vec<_>, Vec<_>) = fetched.into_iter().unzip();
+        Ok((self.merge(mails), Scan::total(scans)))
+    }
+
+    /// The result of the search out of the mails found in every mail box searched: the
+    /// copies collapsed with `dedup`, the mail boxes of a pattern sorted and limited as
+    /// one, narrowed down by `newest`/`oldest`, with their thread ids
+    pub(crate) fn merge(&self, mails: Vec<Vec<Mail>>) -> Vec<Mail> {
+        let mut mails = mails.into_iter().flatten().collect::<Vec<_>>();
+        if self.dedup {
+            mails = dedup(mails);
+        }
+        if folder::is_pattern(&self.mail_box) {
+            // merge the mail boxes the way a single one is sorted and limited
+            mails.sort_by_key(|v| -v.internal_date.timestamp());
+            if self.reserve {
+                mails.reverse()
+            }
+            if let Some(limit) = self.limit {
+                mails.truncate(limit);
+            }
+        }
+
+        // --newest/--oldest narrow the result down to a single mail
+        let picked = if self.newest {
+            mails
+                .iter()
+                .enumerate()
+                .max_by_key(|(_, m)| m.internal_date)
+        } else if self.oldest {
+            mails
+                .iter()
+                .enumerate()
+                .min_by_key(|(_, m)| m.internal_date)
+        } else {
+            thread::assign_ids(&mut mails);
+            return mails;
+        }
+        .map(|(i, _)| i);
+
+        let mut mails = match picked {
+            Some(i) => vec![mails.swap_remove(i)],
+            None => vec![],
+        };
+        thread::assign_ids(&mut mails);
+        mails
+    }
+
+    /// Hand the matching mails to `on_mail` as they are fetched, in the order of
+    /// [`MailFilter::fetch_iter`], one mail box after the other when `mail_box` is a
+    /// pattern. `newest`/`oldest` need the whole result and are ignored.
+    ///
+    /// With `dedup` the mail boxes are searched in the order of [`folder_preference`]
+    /// and the later copies of a mail are left out, but the mail was handed over before
+    /// they were found: its `folders` only holds its own mail box and `also_in` stays
+    /// empty.
+    ///
+    /// Returns the number of mails looked at and those skipped.
+    pub fn fetch_each<F: FnMut(Mail)>(
+        &self,
+        client: &Client,
+        mut on_mail: F,
+    ) -> Result<Scan, ClientError> {
+        let mut yielded = 0;
+        let mut seen = HashSet::new();
+        let scans = self.each_mail_box(client, |filter| {
+            for mail in filter.fetch_iter() {
+                if self.limit.is_some_and(|limit| yielded >= limit) {
+                    break;
+                }
+                let mut mail = mail?;
+                if self.dedup {
+                    if !seen.insert(CopyKey::of(&mail)) {
+                        continue;
+                    }
+                    mail.folders = mail.folder.iter().cloned().collect();
+                }
+                on_mail(mail);
+                yielded += 1;
+            }
+            Ok(Scan::of(filter))
+        })?;
+        Ok(Scan::total(scans))
+    }
+
+    pub fn count(&self, client: &Client) -> Result<usize, ClientError> {
+        let counts = self.each_mail_box(client, |filter| filter.count())?;
+        Ok(counts.into_iter().sum())
+    }
+
+    /// Run `run` on the filter of the searched mail box, or of every mail box matching
+    /// `mail_box` when it's a pattern such as `Archive/*`, see [`folder::matches`]
+    fn each_mail_box<T, F>(&self, client: &Client, mut run: F) -> Result<Vec<T>, ClientError>
+    where
+        F: FnMut(&MailFilter) -> Result<T, ClientError>,
+    {
+        if !folder::is_pattern(&self.mail_box) {
+            let mail_box = client.get(&self.mail_box)?;
+            return Ok(vec![run(&self.filter(&mail_box))?]);
+        }
+        let mut raw_names = client.matching_mail_boxes(&self.mail_box)?;
+        self.search_order(&mut raw_names);
+        raw_names
+            .iter()
+            .map(|raw_name| {
+                let mail_box = client.select(raw_name)?;
+                let filter = self.filter(&mail_box);
+                run(&filter)
+            })
+            .collect()
+    }
+
+    /// Sort the raw names of the mail boxes matching `mail_box` in the order they are
+    /// searched, that of [`folder_preference`] with `dedup`
+    pub(crate) fn search_order(&self, raw_names: &mut [String]) {
+        if self.dedup {
+            raw_names.sort_by_cached_key(|raw_name| {
+                folder_preference(Some(&utf7_imap::decode_utf7_imap(raw_name.clone())))
+            });
+        }
+    }
+
+    /// The mail box `mail` was fetched from, selected again
+    pub fn mail_box_of<'c>(
+        &self,
+        client: &'c Client,
+        mail: &Mail,
+    ) -> Result<MailBox<'c>, ClientError> {
+        match &mail.folder {
+            Some(folder) => client.select(&encode_utf7_imap(folder)),
+            None => client.get(&self.mail_box),
+        }
+    }
+
+    /// `columns` and the snippet when `show_snippet` is set, in the order of
+    /// [`Column::ALL`]
+    pub fn shown_columns(&self) -> Vec<Column> {
+        Column::ALL
+            .into_iter()
+            .filter(|column| {
+                self.columns.contains(column) || (*column == Column::Snippet && self.show_snippet)
+            })
+            .collect()
+    }
+
+    /// The criteria of the search, to run on any mail box
+    pub fn criteria(&self) -> SearchCriteria {
+        let mut criteria = SearchCriteria::new(&self.subject_query, self.start_datetime);
+        criteria
+            .end_date(self.end_datetime)
+            .regex(self.regex)
+            .reverse(self.reserve)
+            .raw_query(self.raw_query.clone())
+            .since_uid(self.since_uid)
+            .max_uid(self.max_uid)
+            .larger(self.larger)
+            .smaller(self.smaller)
+            .limit(self.limit)
+            .window_days(self.window_days)
+            .attachments_only(self.attachments_only)
+            .attachment_type(self.attachment_type.clone())
+            .text(self.text.clone())
+            .body_contains(self.body_contains.clone())
+            .lag_over(self.lag_over)
+            .max_body_bytes(self.max_body_bytes)
+            .date_basis(self.date_basis)
+            .flags(self.flags.clone());
+        criteria
+    }
 
-struct App {
+    pub fn filter<'c>(&self, mail_box: &'c MailBox<'c>) -> MailFilter<'c> {
+        let mut filter = mail_box.filter_by(self.criteria());
+        filter.progress(self.progress);
+        filter
+    }
+}
+
+/// Collapse the copies of a mail found in several mail boxes into one, the copy of the
+/// preferred mail box is kept: INBOX, then the others alphabetically, see
+/// [`folder_preference`]. Its `folders` lists the mail boxes of every copy in the same
+/// order, and `also_in` those of the other copies. Copies are told apart by their Message-ID, or by a hash of their sender,
+/// date and subject without one. The mails stay in the order of their first copy.
+pub fn dedup(mails: Vec<Mail>) -> Vec<Mail> {
+    let mut kept: Vec<Mail> = vec![];
+    let mut first: HashMap<CopyKey, usize> = HashMap::new();
+    for mut mail in mails {
+        mail.also_in.clear();
+        mail.folders.clear();
+        let i = match first.get(&CopyKey::of(&mail)) {
+            Some(&i) => i,
+            None => {
+                first.insert(CopyKey::of(&mail), kept.len());
+                kept.push(mail);
+                continue;
+            }
+        };
+        let copy = &mut kept[i];
+        if folder_preference(mail.folder.as_deref()) < folder_preference(copy.folder.as_deref()) {
+            mail.also_in = std::mem::take(&mut copy.also_in);
+            std::mem::swap(copy, &mut mail);
+        }
+        // `mail` is the copy left out
+        if let Some(folder) = mail.folder {
+            if copy.folder.as_ref() != Some(&folder) && !copy.also_in.contains(&folder) {
+                copy.also_in.push(folder);
+            }
+        }
+    }
+    for mail in kept.iter_mut() {
+        mail.also_in
+            .sort_by_key(|folder| folder_preference(Some(folder)));
+        mail.folders = mail.folder.iter().chain(&mail.also_in).cloned().collect();
+    }
+    kept
+}
+
+/// What the copies of a mail have in common
+#[derive(PartialEq, Eq, Hash)]
+enum CopyKey {
+    MessageId(String),
+    /// Of the sender, the Date header, or the received date when it has none, and the
+    /// subject
+    Hash(u64),
+}
+
+impl CopyKey {
+    fn of(mail: &Mail) -> Self {
+        match &mail.message_id {
+            Some(message_id) => CopyKey::MessageId(message_id.clone()),
+            None => {
+                let mut hasher = DefaultHasher::new();
+                mail.from.hash(&mut hasher);
+                mail.sent_date
+                    .unwrap_or(mail.internal_date)
+                    .timestamp()
+                    .hash(&mut hasher);
+                mail.subject.hash(&mut hasher);
+                CopyKey::Hash(hasher.finish())
+            }
+        }
+    }
+}
+
+/// Sorts the mail boxes whose copy of a mail is kept first: INBOX, then the others
+/// alphabetically
+fn folder_preference(folder: Option<&str>) -> (bool, String) {
+    match folder {
+        Some(folder) => (!folder.eq_ignore_ascii_case("INBOX"), folder.to_string()),
+        None => (true, String::new()),
+    }
+}
+
+/// Reconnection attempts after the session dropped before the error is shown
+const RECONNECT_ATTEMPTS: u32 = 5;
+/// Delay before the second attempt, doubled on every further one, the first attempt is
+/// made right away
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+
+/// How long to wait for a key before checking on a pending reconnection
+const TICK: Duration = Duration::from_millis(200);
+
+/// How often the server is asked for new mails while the list is shown
+const POLL_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Most lines of a row of the mail list when `--row-height` isn't given
+pub const DEFAULT_ROW_HEIGHT: usize = 4;
+
+/// Marks the selected row of the mail list
+const HIGHLIGHT_SYMBOL: &str = ">> ";
+
+type Connect<'a> = &'a dyn Fn() -> Result<Client, ClientError>;
+
+/// The next reconnection attempt, counted from 0
+struct Reconnect {
+    attempt: u32,
+    at: Instant,
+    /// Why the session dropped
+    reason: String,
+}
+
+/// What `v` and `e` open the selected mail with
+enum Viewer {
+    Pager,
+    Editor,
+}
+
+impl Viewer {
+    fn command(&self) -> String {
+        let (var, fallback) = match self {
+            Viewer::Pager => ("PAGER", "less"),
+            Viewer::Editor => ("EDITOR", "vi"),
+        };
+        env::var(var)
+            .ok()
+            .filter(|command| !command.trim().is_empty())
+            .unwrap_or_else(|| fallback.to_string())
+    }
+}
+
+struct App<'a> {
     state: TableState,
     client: Client,
-    subject_query: String,
-    start_datetime: chrono::DateTime<FixedOffset>,
-    end_datetime: chrono::DateTime<FixedOffset>,
-    regex: bool,
-    reserve: bool,
-    mail_box: String,
+    /// Opens a new session when the current one dropped
+    connect: Connect<'a>,
+    reconnect: Option<Reconnect>,
+    /// Number of mails in the mail box when the list was fetched
+    known_exists: Option<u32>,
+    /// The searched mail box as of the last refresh, `None` for a pattern
+    mail_box_info: Option<MailboxInfo>,
+    /// Number of mails the last refresh skipped, see [`MailFilter::warnings`]
+    skipped: usize,
+    last_poll: Instant,
+    options: SearchOptions,
     show_body: bool,
+    /// The shown columns, in the order of [`Column::ALL`]
+    columns: Vec<Column>,
+    /// Widths of the resized columns
+    widths: HashMap<Column, Constraint>,
+    /// Index into `columns` of the column `<` and `>` resize
+    column_cursor: usize,
+    /// Index into [`Column::ALL`] of the highlighted entry of the column chooser, when
+    /// it's open
+    chooser: Option<usize>,
+    /// Index into [`open_entries`](Self::open_entries) of the highlighted entry of the
+    /// picker of what to open, when it's open
+    opener: Option<usize>,
     body: String,
+    /// The full values of the cells of the selected mail cut by the row height, shown
+    /// above its body
+    details: String,
     mails: Vec<Mail>,
+    threads: Vec<Vec<usize>>,
+    expanded: HashSet<usize>,
+    error: Option<String>,
+    /// Shown in the footer in place of a change the dry run didn't make
+    notice: Option<String>,
+    /// Whether the key bindings may change the mails on the server
+    safety: Safety,
+    /// Narrows the displayed rows to the fetched mails containing it, without asking
+    /// the server
+    local_filter: String,
+    /// Whether the keys are typed into the local filter
+    filtering: bool,
+    /// First line of the wrapped body shown in the body pane
+    body_scroll: usize,
+    /// Size of the body pane inside its borders, as of the last draw
+    body_area: (usize, usize),
+    /// Width of every shown column of the mail list, as of the last draw
+    cell_widths: Vec<usize>,
+    /// Highlighted in the body, `n`/`N` jump between the matches
+    body_search: String,
+    /// Whether the keys are typed into the body search
+    searching: bool,
+    /// Index of the match jumped to last
+    current_match: Option<usize>,
+    /// Created on the first mail opened in the browser
+    pages: Option<PageDir>,
+    theme: Theme,
+}
+
+/// A displayed table row, pointing into `App::mails`
+struct MailRow {
+    mail: usize,
+    thread: usize,
+    depth: usize,
+    replies: usize,
 }
 
-impl App {
-    pub fn new(
-        client: Client,
-        subject_query: String,
-        start_datetime: chrono::DateTime<FixedOffset>,
-        end_datetime: chrono::DateTime<FixedOffset>,
-        regex: bool,
-        reserve: bool,
-        mail_box: String,
-    ) -> App {
+impl<'a> App<'a> {
+    pub fn new(client: Client, connect: Connect<'a>, options: SearchOptions) -> App<'a> {
+        let columns = options.shown_columns();
         App {
             state: TableState::default(),
             client,
-            subject_query,
-            start_datetime,
-            end_datetime,
-            regex,
-            reserve,
-            mail_box,
+            connect,
+            reconnect: None,
+            known_exists: None,
+            mail_box_info: None,
+            skipped: 0,
+            last_poll: Instant::now(),
+            options,
             show_body: false,
+            columns,
+            widths: HashMap::new(),
+            column_cursor: 0,
+            chooser: None,
+            opener: None,
             body: "".to_string(),
+            details: String::new(),
             mails: vec![],
+            threads: vec![],
+            expanded: HashSet::new(),
+            error: None,
+            notice: None,
+            safety: Safety {
+                dry_run: false,
+                confirm_above: DEFAULT_CONFIRM_ABOVE,
+                ask: None,
+            },
+            local_filter: String::new(),
+            filtering: false,
+            body_scroll: 0,
+            body_area: (80, 20),
+            cell_widths: vec![],
+            body_search: String::new(),
+            searching: false,
+            current_match: None,
+            pages: None,
+            theme: Theme::default(),
         }
     }
 
     pub fn refresh(&mut self) {
-        let mail_box = self.client.get(&self.mail_box).unwrap();
-        self.mails = mail_box
-            .filter(&self.subject_query, self.start_datetime)
-            .end_date(self.end_datetime)
-            .regex(self.regex)
-            .reverse(self.reserve)
-            .fetch();
+        let fetched = self.options.fetch_scanned(&self.client);
+        self.load(fetched, 0);
+    }
+
+    /// Make the pending reconnection attempt once it's due, refreshing over the new
+    /// session
+    pub fn reconnect(&mut self) {
+        let attempt = match &self.reconnect {
+            Some(reconnect) if reconnect.at <= Instant::now() => reconnect.attempt,
+            _ => return,
+        };
+        let fetched = (self.connect)().and_then(|client| {
+            self.client = client;
+            self.options.fetch_scanned(&self.client)
+        });
+        self.load(fetched, attempt + 1);
+    }
+
+    /// Give the server a chance to report new mails once in a while, see
+    /// [`new_mails`](Self::new_mails)
+    pub fn poll(&mut self) {
+        if self.reconnect.is_some() || self.last_poll.elapsed() < POLL_INTERVAL {
+            return;
+        }
+        self.last_poll = Instant::now();
+        if let Err(err) = self.client.noop() {
+            self.load(Err(err), 0);
+        }
+    }
+
+    /// Number of mails delivered to the mail box since the list was fetched, as far as
+    /// the server told
+    fn new_mails(&self) -> Option<u32> {
+        match (self.client.exists(), self.known_exists) {
+            (Some(exists), Some(known)) if exists > known => Some(exists - known),
+            _ => None,
+        }
+    }
+
+    /// Show the fetched mails, or schedule reconnection attempt `attempt` when the
+    /// session dropped
+    fn load(&mut self, fetched: Result<(Vec<Mail>, Scan), ClientError>, attempt: u32) {
+        self.reconnect = None;
+        // keep showing the previous result when the refresh fails
+        match fetched {
+            Ok((mails, scan)) => {
+                self.mails = mails;
+                self.skipped = scan.warnings.len();
+                self.error = None;
+                self.known_exists = self.client.exists();
+                self.mail_box_info = match folder::is_pattern(&self.options.mail_box) {
+                    true => None,
+                    false => self
+                        .client
+                        .get(&self.options.mail_box)
+                        .map(|mail_box| mail_box.info())
+                        .ok(),
+                };
+            }
+            Err(err) if err.is_connection() && attempt < RECONNECT_ATTEMPTS => {
+                let delay = match attempt {
+                    0 => Duration::ZERO,
+                    n => RECONNECT_DELAY * 2u32.pow(n - 1),
+                };
+                self.reconnect = Some(Reconnect {
+                    attempt,
+                    at: Instant::now() + delay,
+                    reason: err.to_string(),
+                });
+                return;
+            }
+            Err(err) => {
+                self.error = Some(err.to_string());
+                return;
+            }
+        }
+        if self.options.threads {
+            self.threads = thread::group(&self.mails);
+            if self.options.reserve {
+                self.threads.reverse();
+            }
+            self.expanded.clear();
+        }
+        // the local filter applies to the new result too, which may leave fewer rows
+        let rows = self.rows().len();
+        match self.state.selected() {
+            Some(i) if i >= rows && rows > 0 => self.select(rows - 1),
+            Some(i) if i >= rows => self.state.select(None),
+            _ => {}
+        }
+    }
+
+    /// Show or hide `column`, the last shown column stays
+    fn toggle_column(&mut self, column: Column) {
+        if self.columns.contains(&column) {
+            if self.columns.len() > 1 {
+                self.columns.retain(|shown| *shown != column);
+            }
+        } else {
+            self.columns.push(column);
+            self.columns
+                .sort_by_key(|shown| Column::ALL.iter().position(|c| c == shown));
+        }
+        self.column_cursor = self.column_cursor.min(self.columns.len() - 1);
+    }
+
+    fn width(&self, column: Column) -> Constraint {
+        self.widths
+            .get(&column)
+            .copied()
+            .unwrap_or_else(|| column.default_width(self.columns.contains(&Column::Snippet)))
+    }
+
+    /// Make the column under the cursor wider or narrower
+    fn resize_column(&mut self, wider: bool) {
+        let column = self.columns[self.column_cursor];
+        let width = columns::resize(self.width(column), wider);
+        self.widths.insert(column, width);
+    }
+
+    /// Whether the subject, sender or an attachment name of `mail` contains the local
+    /// filter, case-insensitively
+    fn matches_local_filter(&self, mail: &Mail) -> bool {
+        if self.local_filter.is_empty() {
+            return true;
+        }
+        let filter = self.local_filter.to_lowercase();
+        mail.subject.to_lowercase().contains(&filter)
+            || mail.from.to_lowercase().contains(&filter)
+            || mail
+                .attachments
+                .iter()
+                .any(|attachment| attachment.name.to_lowercase().contains(&filter))
+    }
+
+    /// Number of fetched mails matching the local filter
+    fn local_matches(&self) -> usize {
+        self.mails
+            .iter()
+            .filter(|mail| self.matches_local_filter(mail))
+            .count()
+    }
+
+    /// Change the local filter, keeping the selected mail selected when it still matches
+    fn set_local_filter(&mut self, filter: String) {
+        let selected = self
+            .state
+            .selected()
+            .and_then(|i| self.rows().get(i).map(|row| row.mail));
+        self.local_filter = filter;
+        let rows = self.rows();
+        match selected.and_then(|mail| rows.iter().position(|row| row.mail == mail)) {
+            Some(i) => self.select(i),
+            None if !rows.is_empty() => self.select(0),
+            None => {
+                self.state.select(None);
+                self.body.clear();
+            }
+        }
+    }
+
+    fn rows(&self) -> Vec<MailRow> {
+        if !self.options.threads {
+            return (0..self.mails.len())
+                .filter(|&i| self.matches_local_filter(&self.mails[i]))
+                .map(|i| MailRow {
+                    mail: i,
+                    thread: i,
+                    depth: 0,
+                    replies: 0,
+                })
+                .collect();
+        }
+
+        let mut rows = vec![];
+        for (t, thread) in self.threads.iter().enumerate() {
+            // a thread is shown whole when any of its mails matches
+            if !thread
+                .iter()
+                .any(|&i| self.matches_local_filter(&self.mails[i]))
+            {
+                continue;
+            }
+            rows.push(MailRow {
+                mail: thread[0],
+                thread: t,
+                depth: 0,
+                replies: thread.len() - 1,
+            });
+            if self.expanded.contains(&t) {
+                rows.extend(thread[1..].iter().map(|&i| MailRow {
+                    mail: i,
+                    thread: t,
+                    depth: 1,
+                    replies: 0,
+                }));
+            }
+        }
+        rows
+    }
+
+    fn select(&mut self, i: usize) {
+        let rows = self.rows();
+        let mail = &self.mails[rows[i].mail];
+        self.body = if self.options.no_quotes {
+            strip_quotes(&mail.body)
+        } else {
+            mail.body.clone()
+        };
+        self.body_scroll = 0;
+        self.current_match = None;
+        self.state.select(Some(i));
+        self.refresh_details();
+    }
+
+    /// Update the cells of the selected mail cut by the row height, e.g. once the
+    /// columns were resized
+    fn refresh_details(&mut self) {
+        let mail = match self.state.selected() {
+            Some(i) => &self.mails[self.rows()[i].mail],
+            None => return,
+        };
+        self.details = match self.options.row_height {
+            Some(height) => columns::cut_cells(mail, &self.columns, &self.cell_widths, height),
+            None => String::new(),
+        };
+    }
+
+    /// The body wrapped to the width of the body pane, along with the matches of the
+    /// body search
+    fn wrapped_body(&self) -> (Vec<String>, Vec<find::Match>) {
+        let text = printable_lines(&format!("{}{}", self.details, self.body));
+        let lines = find::wrap(&text, self.body_area.0);
+        let matches = find::find(&lines, &self.body_search);
+        (lines, matches)
+    }
+
+    fn scroll_body(&mut self, lines: isize) {
+        let (wrapped, _) = self.wrapped_body();
+        let last = wrapped.len().saturating_sub(self.body_area.1);
+        self.body_scroll = self.body_scroll.saturating_add_signed(lines).min(last);
+    }
+
+    fn set_body_search(&mut self, term: String) {
+        self.body_search = term;
+        self.current_match = None;
+    }
+
+    /// Jump to the next match of the body search, or the previous one, scrolling the
+    /// body to show it. The first jump goes to the first match from the top of the pane.
+    pub fn jump_to_match(&mut self, forward: bool) {
+        let (_, matches) = self.wrapped_body();
+        if matches.is_empty() {
+            self.current_match = None;
+            return;
+        }
+        let next = match self.current_match {
+            Some(i) if forward => (i + 1) % matches.len(),
+            Some(i) => (i + matches.len() - 1) % matches.len(),
+            None => matches
+                .iter()
+                .position(|m| m.line >= self.body_scroll)
+                .unwrap_or(0),
+        };
+        self.current_match = Some(next);
+
+        let line = matches[next].line;
+        let height = self.body_area.1.max(1);
+        if line < self.body_scroll || line >= self.body_scroll + height {
+            self.body_scroll = line.saturating_sub(height / 2);
+        }
     }
 
     pub fn next(&mut self) {
+        let len = self.rows().len();
+        if len == 0 {
+            return;
+        }
         let i = match self.state.selected() {
             Some(i) => {
-                if i >= self.mails.len() - 1 {
+                if i >= len - 1 {
                     0
                 } else {
                     i + 1
@@ -77,63 +869,248 @@ impl App {
             }
             None => 0,
         };
-        self.body = self.mails[i].body.clone();
-        self.state.select(Some(i));
+        self.select(i);
     }
 
     pub fn previous(&mut self) {
+        let len = self.rows().len();
+        if len == 0 {
+            return;
+        }
         let i = match self.state.selected() {
             Some(i) => {
                 if i == 0 {
-                    self.mails.len() - 1
+                    len - 1
                 } else {
                     i - 1
                 }
             }
             None => 0,
         };
-        self.body = self.mails[i].body.clone();
-        self.state.select(Some(i));
+        self.select(i);
+    }
+
+    /// UID and text of the selected mail, its body as shown in the body pane
+    fn selected_text(&self) -> Option<(u32, String)> {
+        let i = self.state.selected()?;
+        let mut mail = self.mails[self.rows()[i].mail].clone();
+        mail.body = self.body.clone();
+        Some((mail.uid, mail.to_string()))
+    }
+
+    /// Open the HTML body of the selected mail in the browser, failures are shown in
+    /// the footer
+    pub fn open_html(&mut self) {
+        if let Some(i) = self.state.selected() {
+            self.error = self.write_page(self.rows()[i].mail).err();
+        }
+    }
+
+    /// Open the HTML body of the selected mail, or let pick between it and the
+    /// attachments when it has some
+    pub fn open(&mut self) {
+        match self.open_entries().len() {
+            0 => {}
+            1 => self.open_html(),
+            _ => self.opener = Some(0),
+        }
+    }
+
+    /// What can be opened of the selected mail: its HTML body, then its attachments
+    fn open_entries(&self) -> Vec<String> {
+        let i = match self.state.selected() {
+            Some(i) => i,
+            None => return vec![],
+        };
+        let mail = &self.mails[self.rows()[i].mail];
+        let attachments = mail.attachments.iter().map(|a| a.name.clone());
+        std::iter::once("HTML body".to_string())
+            .chain(attachments)
+            .collect()
+    }
+
+    /// Open entry `entry` of [`open_entries`](Self::open_entries) and close the picker,
+    /// attachments are opened with the default application of the platform
+    pub fn open_entry(&mut self, entry: usize) {
+        self.opener = None;
+        let mail = match self.state.selected() {
+            Some(i) => self.rows()[i].mail,
+            None => return,
+        };
+        if entry == 0 {
+            return self.open_html();
+        }
+        self.error = self
+            .write_attachment(mail, entry - 1)
+            .and_then(|path| {
+                browser::open(&path, None)
+                    .map_err(|err| format!("failed to open {}: {}", path.display(), err))
+            })
+            .err();
+    }
+
+    /// Download attachment `index` of mail `mail` into the page directory
+    fn write_attachment(&mut self, mail: usize, index: usize) -> Result<PathBuf, String> {
+        let uid = self.mails[mail].uid;
+        let name = &self.mails[mail].attachments[index].name;
+        let (_, files, _) = self
+            .options
+            .mail_box_of(&self.client, &self.mails[mail])
+            .and_then(|mail_box| mail_box.download(uid, false, None, SizeLimit::default()))
+            .map_err(|err| err.to_string())?;
+        // the attachments are listed in the order they are downloaded
+        let (name, content) = files
+            .iter()
+            .find(|(file, _)| file == name)
+            .or_else(|| files.get(index))
+            .ok_or_else(|| format!("attachment {} not found", name))?;
+        self.pages()?
+            .write_attachment(uid, name, content)
+            .map_err(|err| err.to_string())
+    }
+
+    /// The directory the opened files are written to, removed on quit
+    fn pages(&mut self) -> Result<&PageDir, String> {
+        let pages = match self.pages.take() {
+            Some(pages) => pages,
+            None => PageDir::new().map_err(|err| err.to_string())?,
+        };
+        Ok(self.pages.insert(pages))
+    }
+
+    /// Mark the selected mail read or unread on the server and in the list, failures
+    /// are shown in the footer
+    pub fn set_seen(&mut self, seen: bool) {
+        let mail = match self.state.selected() {
+            Some(i) => self.rows()[i].mail,
+            None => return,
+        };
+        let uid = self.mails[mail].uid;
+        let action = format!("mark UID {} {}", uid, if seen { "read" } else { "unread" });
+        self.notice = None;
+        if !self
+            .safety
+            .allow_or_tell(&action, |notice| self.notice = Some(notice))
+        {
+            return;
+        }
+        let stored = self
+            .options
+            .mail_box_of(&self.client, &self.mails[mail])
+            .and_then(|mail_box| mail_box.set_seen(uid, seen));
+        match stored {
+            Ok(()) => {
+                self.mails[mail].set_seen(seen);
+                self.error = None;
+            }
+            Err(err) => self.error = Some(err.to_string()),
+        }
+    }
+
+    fn write_page(&mut self, mail: usize) -> Result<(), String> {
+        let uid = self.mails[mail].uid;
+        let body = self
+            .options
+            .mail_box_of(&self.client, &self.mails[mail])
+            .and_then(|mail_box| mail_box.html_body(uid))
+            .map_err(|err| err.to_string())?
+            .ok_or_else(|| "the mail has no HTML body".to_string())?;
+        let page = self
+            .pages()?
+            .write(uid, &body)
+            .map_err(|err| err.to_string())?;
+        browser::open(&page, self.options.browser.as_deref())
+            .map_err(|err| format!("failed to open the browser: {}", err))
+    }
+
+    pub fn expand(&mut self) {
+        if let Some(i) = self.state.selected() {
+            let thread = self.rows()[i].thread;
+            self.expanded.insert(thread);
+        }
     }
+
+    pub fn collapse(&mut self) {
+        if let Some(i) = self.state.selected() {
+            let thread = self.rows()[i].thread;
+            self.expanded.remove(&thread);
+            if let Some(root) = self
+                .rows()
+                .iter()
+                .position(|row| row.thread == thread && row.depth == 0)
+            {
+                self.select(root);
+            }
+        }
+    }
+}
+
+/// Raw mode and the alternate screen, restored when dropped, so the terminal is
+/// usable again however the TUI ends
+struct TerminalGuard;
+
+impl TerminalGuard {
+    fn new() -> io::Result<TerminalGuard> {
+        setup_terminal()?;
+
+        // restore before the panic message is printed, or it ends up on the
+        // alternate screen
+        let hook = panic::take_hook();
+        panic::set_hook(Box::new(move |info| {
+            restore_terminal();
+            hook(info);
+        }));
+        Ok(TerminalGuard)
+    }
+}
+
+impl Drop for TerminalGuard {
+    fn drop(&mut self) {
+        restore_terminal();
+        // back to the default hook, which can't be swapped while unwinding
+        if !std::thread::panicking() {
+            let _ = panic::take_hook();
+        }
+    }
+}
+
+fn setup_terminal() -> io::Result<()> {
+    enable_raw_mode()?;
+    execute!(io::stdout(), EnterAlternateScreen, EnableMouseCapture)
+}
+
+fn restore_terminal() {
+    let _ = disable_raw_mode();
+    let _ = execute!(
+        io::stdout(),
+        LeaveAlternateScreen,
+        DisableMouseCapture,
+        cursor::Show
+    );
 }
 
 pub fn run(
     client: Client,
-    subject_query: String,
-    start_datetime: chrono::DateTime<FixedOffset>,
-    end_datetime: chrono::DateTime<FixedOffset>,
-    regex: bool,
-    reserve: bool,
-    mail_box: String,
+    connect: Connect,
+    options: SearchOptions,
+    theme: Theme,
+    safety: Safety,
 ) -> Result<(), Box<dyn Error>> {
-    // setup terminal
-    enable_raw_mode()?;
-    let mut stdout = io::stdout();
-    execute!(stdout, EnterAlternateScreen, EnableMouseCapture)?;
-    let backend = CrosstermBackend::new(stdout);
+    let guard = TerminalGuard::new()?;
+    let backend = CrosstermBackend::new(io::stdout());
     let mut terminal = Terminal::new(backend)?;
 
     // create app and run it
-    let mut app = App::new(
-        client,
-        subject_query,
-        start_datetime,
-        end_datetime,
-        regex,
-        reserve,
-        mail_box,
-    );
+    let mut app = App {
+        theme,
+        safety,
+        ..App::new(client, connect, options)
+    };
     app.refresh();
-    let res = run_app(&mut terminal, app);
-
-    // restore terminal
-    disable_raw_mode()?;
-    execute!(
-        terminal.backend_mut(),
-        LeaveAlternateScreen,
-        DisableMouseCapture
-    )?;
-    terminal.show_cursor()?;
+    let res = run_app(&mut terminal, &mut app);
+    drop(guard);
+    // the session may be gone already
+    let _ = app.client.logout();
 
     if let Err(err) = res {
         println!("{:?}", err)
@@ -142,13 +1119,87 @@ pub fn run(
     Ok(())
 }
 
-fn run_app<B: Backend>(terminal: &mut Terminal<B>, mut app: App) -> io::Result<()> {
+fn run_app<B: Backend>(terminal: &mut Terminal<B>, app: &mut App) -> io::Result<()> {
     loop {
-        terminal.draw(|f| ui(f, &mut app))?;
+        app.reconnect();
+        app.poll();
+        terminal.draw(|f| ui(f, app))?;
 
+        if !event::poll(TICK)? {
+            continue;
+        }
         if let Event::Key(key) = event::read()? {
+            if let Some(entry) = app.chooser {
+                let entries = Column::ALL.len();
+                match key.code {
+                    KeyCode::Esc | KeyCode::Char('c') | KeyCode::Char('q') => app.chooser = None,
+                    KeyCode::Down => app.chooser = Some((entry + 1) % entries),
+                    KeyCode::Up => app.chooser = Some((entry + entries - 1) % entries),
+                    KeyCode::Char(' ') | KeyCode::Enter => app.toggle_column(Column::ALL[entry]),
+                    _ => {}
+                }
+                continue;
+            }
+            if let Some(entry) = app.opener {
+                let entries = app.open_entries().len();
+                match key.code {
+                    KeyCode::Esc | KeyCode::Char('o') | KeyCode::Char('q') => app.opener = None,
+                    KeyCode::Down => app.opener = Some((entry + 1) % entries),
+                    KeyCode::Up => app.opener = Some((entry + entries - 1) % entries),
+                    KeyCode::Enter => app.open_entry(entry),
+                    _ => {}
+                }
+                continue;
+            }
+            if app.searching {
+                match key.code {
+                    KeyCode::Esc => {
+                        app.searching = false;
+                        app.set_body_search(String::new());
+                    }
+                    KeyCode::Enter => {
+                        app.searching = false;
+                        app.jump_to_match(true);
+                    }
+                    KeyCode::Backspace => {
+                        let mut term = app.body_search.clone();
+                        term.pop();
+                        app.set_body_search(term);
+                    }
+                    KeyCode::Char(c) if !key.modifiers.contains(KeyModifiers::CONTROL) => {
+                        app.set_body_search(format!("{}{}", app.body_search, c));
+                    }
+                    _ => {}
+                }
+                continue;
+            }
+            if app.filtering {
+                match key.code {
+                    KeyCode::Esc => {
+                        app.filtering = false;
+                        app.set_local_filter(String::new());
+                    }
+                    KeyCode::Enter => app.filtering = false,
+                    KeyCode::Backspace => {
+                        let mut filter = app.local_filter.clone();
+                        filter.pop();
+                        app.set_local_filter(filter);
+                    }
+                    KeyCode::Char(c) if !key.modifiers.contains(KeyModifiers::CONTROL) => {
+                        app.set_local_filter(format!("{}{}", app.local_filter, c));
+                    }
+                    KeyCode::Down => app.next(),
+                    KeyCode::Up => app.previous(),
+                    _ => {}
+                }
+                continue;
+            }
             match key.code {
                 KeyCode::Char('q') => return Ok(()),
+                // raw mode delivers Ctrl-C as a key instead of SIGINT
+                KeyCode::Char('c') if key.modifiers.contains(KeyModifiers::CONTROL) => {
+                    return Ok(())
+                }
                 KeyCode::Char('r') => {
                     app.refresh();
                     terminal.clear().unwrap();
@@ -157,21 +1208,170 @@ fn run_app<B: Backend>(terminal: &mut Terminal<B>, mut app: App) -> io::Result<(
                     app.show_body = !app.show_body;
                     terminal.clear().unwrap();
                 }
+                KeyCode::Char('s') => app.toggle_column(Column::Snippet),
+                KeyCode::Char('c') => app.chooser = Some(0),
+                KeyCode::Tab => app.column_cursor = (app.column_cursor + 1) % app.columns.len(),
+                KeyCode::BackTab => {
+                    app.column_cursor =
+                        (app.column_cursor + app.columns.len() - 1) % app.columns.len()
+                }
+                KeyCode::Char('<') => app.resize_column(false),
+                KeyCode::Char('>') => app.resize_column(true),
+                KeyCode::Char('F') => app.filtering = true,
+                KeyCode::Char('f') if key.modifiers.contains(KeyModifiers::CONTROL) => {
+                    app.filtering = true
+                }
+                KeyCode::Char('/') if app.show_body => {
+                    app.searching = true;
+                    app.set_body_search(String::new());
+                }
+                KeyCode::Char('n') if !app.body_search.is_empty() => app.jump_to_match(true),
+                KeyCode::Char('N') if !app.body_search.is_empty() => app.jump_to_match(false),
+                KeyCode::Esc if !app.body_search.is_empty() => app.set_body_search(String::new()),
+                KeyCode::PageDown if app.show_body => {
+                    app.scroll_body(app.body_area.1.max(1) as isize)
+                }
+                KeyCode::PageUp if app.show_body => {
+                    app.scroll_body(-(app.body_area.1.max(1) as isize))
+                }
+                KeyCode::Esc if !app.local_filter.is_empty() => app.set_local_filter(String::new()),
+                KeyCode::Char('o') => app.open(),
+                KeyCode::Char('m') => app.set_seen(true),
+                KeyCode::Char('u') => app.set_seen(false),
+                KeyCode::Char('v') => view(terminal, app, Viewer::Pager)?,
+                KeyCode::Char('e') => view(terminal, app, Viewer::Editor)?,
                 KeyCode::Down => app.next(),
                 KeyCode::Up => app.previous(),
+                KeyCode::Enter | KeyCode::Right if app.options.threads => app.expand(),
+                KeyCode::Left if app.options.threads => app.collapse(),
                 _ => {}
             }
         }
     }
 }
 
+/// Suspend the TUI to read the selected mail in a pager or editor, the mail is written
+/// to a temporary file removed once the viewer exits
+fn view<B: Backend>(terminal: &mut Terminal<B>, app: &mut App, viewer: Viewer) -> io::Result<()> {
+    let (uid, text) = match app.selected_text() {
+        Some(selected) => selected,
+        None => return Ok(()),
+    };
+    let command = viewer.command();
+    let path = env::temp_dir().join(format!("qmail-{}-{}.txt", process::id(), uid));
+    let status = fs::write(&path, text).and_then(|_| {
+        restore_terminal();
+        let status =
+            browser::command_line(&command).and_then(|mut child| child.arg(&path).status());
+        // back to the TUI whether the viewer ran or not
+        setup_terminal()?;
+        status
+    });
+    let _ = fs::remove_file(&path);
+    terminal.clear()?;
+
+    app.error = match status {
+        Ok(status) if status.success() => None,
+        Ok(status) => Some(format!("`{}` exited with {}", command, status)),
+        Err(err) => Some(format!("failed to run `{}`: {}", command, err)),
+    };
+    Ok(())
+}
+
 fn ui<B: Backend>(f: &mut Frame<B>, app: &mut App) {
     let chunks = Layout::default()
         .constraints([Constraint::Min(1), Constraint::Length(1)].as_ref())
         .split(f.size());
 
     draw_mail(f, app, chunks[0]);
-    draw_footer(f, chunks[1]);
+    draw_footer(f, app, chunks[1]);
+    if let Some(entry) = app.chooser {
+        draw_column_chooser(f, app, entry);
+    }
+    if let Some(entry) = app.opener {
+        let items = app.open_entries();
+        let width = items.iter().map(|item| item.width()).max().unwrap_or(0) + 4;
+        draw_popup(f, app, "Open", items, entry, width.max(24) as u16);
+    }
+}
+
+/// A popup listing every column with whether it's shown
+fn draw_column_chooser<B: Backend>(f: &mut Frame<B>, app: &App, entry: usize) {
+    let items = Column::ALL
+        .iter()
+        .map(|column| {
+            let shown = if app.columns.contains(column) {
+                "[x]"
+            } else {
+                "[ ]"
+            };
+            format!("{} {}", shown, column.title())
+        })
+        .collect::<Vec<_>>();
+    draw_popup(f, app, "Columns", items, entry, 24);
+}
+
+/// A list of `items` in the middle of the screen, with `entry` highlighted
+fn draw_popup<B: Backend>(
+    f: &mut Frame<B>,
+    app: &App,
+    title: &str,
+    items: Vec<String>,
+    entry: usize,
+    width: u16,
+) {
+    let height = items.len() as u16 + 2;
+    let list = List::new(items.into_iter().map(ListItem::new).collect::<Vec<_>>())
+        .block(
+            Block::default()
+                .borders(Borders::ALL)
+                .title(Span::styled(title.to_string(), app.theme.title)),
+        )
+        .highlight_style(app.theme.selected);
+    let mut state = ListState::default();
+    state.select(Some(entry));
+
+    let size = f.size();
+    let area = Rect::new(
+        size.width.saturating_sub(width) / 2,
+        size.height.saturating_sub(height) / 2,
+        width.min(size.width),
+        height.min(size.height),
+    );
+    f.render_widget(Clear, area);
+    f.render_stateful_widget(list, area, &mut state);
+}
+
+/// The width of the columns of the mail list drawn in `area` with the `widths`
+/// constraints, the way the table lays them out
+fn cell_widths(area: Rect, widths: &[Constraint], has_selection: bool) -> Vec<usize> {
+    let mut constraints = vec![];
+    if has_selection {
+        constraints.push(Constraint::Length(HIGHLIGHT_SYMBOL.width() as u16));
+    }
+    for width in widths {
+        constraints.push(*width);
+        // the spacing between the columns
+        constraints.push(Constraint::Length(1));
+    }
+    if !widths.is_empty() {
+        constraints.pop();
+    }
+    // takes the width left, which the table leaves blank
+    constraints.push(Constraint::Min(0));
+    let inner = Block::default().borders(Borders::ALL).inner(area);
+    let mut chunks = Layout::default()
+        .direction(Direction::Horizontal)
+        .constraints(constraints)
+        .split(Rect::new(0, 0, inner.width, 1));
+    if has_selection {
+        chunks.remove(0);
+    }
+    chunks
+        .iter()
+        .step_by(2)
+        .map(|chunk| chunk.width as usize)
+        .collect()
 }
 
 fn draw_mail<B: Backend>(f: &mut Frame<B>, app: &mut App, area: Rect) {
@@ -185,93 +1385,691 @@ fn draw_mail<B: Backend>(f: &mut Frame<B>, app: &mut App, area: Rect) {
         .direction(Direction::Horizontal)
         .split(area);
 
-    let selected_style = Style::default().add_modifier(Modifier::REVERSED);
-    let normal_style = Style::default().bg(Color::Blue);
-    let header_cells = ["id", "Subject", "From", "To", "CC", "Date", "Attachments"]
-        .iter()
-        .map(|h| Cell::from(*h).style(Style::default().fg(Color::Red)));
+    let header_cells = app.columns.iter().enumerate().map(|(i, column)| {
+        let title = match column {
+            Column::Attachments => {
+                let attachments = app
+                    .mails
+                    .iter()
+                    .filter(|mail| !mail.attachments.is_empty())
+                    .count();
+                format!("Attachments ({})", attachments)
+            }
+            column => column.title().to_string(),
+        };
+        let mut style = Style::default();
+        if i == app.column_cursor {
+            style = style.add_modifier(Modifier::UNDERLINED);
+        }
+        Cell::from(title).style(style)
+    });
     let header = Row::new(header_cells)
-        .style(normal_style)
+        .style(app.theme.header)
         .height(1)
         .bottom_margin(1);
-    let rows = app.mails.iter().map(|item| {
-        let mail_fields = [
-            item.uid.to_string(),
-            item.subject.to_string(),
-            item.from.to_string(),
-            item.to.join("\n"),
-            item.cc.join("\n"),
-            item.internal_date.format("%Y-%m-%dT%H:%M:%S").to_string(),
-            item.attachments
-                .iter()
-                .map(|a| a.name.clone())
-                .collect::<Vec<String>>()
-                .join("\n"),
-        ];
+    let widths = app
+        .columns
+        .iter()
+        .map(|column| app.width(*column))
+        .collect::<Vec<_>>();
+    let cell_widths = cell_widths(chunks[0], &widths, app.state.selected().is_some());
+    if cell_widths != app.cell_widths {
+        app.cell_widths = cell_widths;
+        app.refresh_details();
+    }
+    let rows = app.rows();
+    let rows = rows.iter().map(|row| {
+        let item = &app.mails[row.mail];
+        // only the shown columns are formatted
+        let mail_fields = app
+            .columns
+            .iter()
+            .map(|column| match column {
+                Column::Id => item.uid.to_string(),
+                Column::Subject if row.depth > 0 => {
+                    format!("  ↳ {}", column.text(item, ""))
+                }
+                Column::Subject if row.replies > 0 => {
+                    let marker = if app.expanded.contains(&row.thread) {
+                        "▾"
+                    } else {
+                        "▸"
+                    };
+                    format!("{} ({}) {}", marker, row.replies + 1, column.text(item, ""))
+                }
+                column => column.text(item, "\n"),
+            })
+            .zip(app.cell_widths.iter())
+            .map(|(content, width)| columns::cap_lines(&content, *width, app.options.row_height))
+            .collect::<Vec<_>>();
 
         let height = mail_fields
             .iter()
-            .map(|content| content.chars().filter(|c| *c == '\n').count())
+            .map(|content| content.lines().count())
             .max()
             .unwrap_or(0)
-            + 1;
-        let cells = mail_fields.iter().enumerate().map(|(idx, c)| {
-            let style = match idx {
-                0 => Style::default().fg(Color::DarkGray),
+            .max(1);
+        let cells = app.columns.iter().zip(mail_fields).map(|(column, c)| {
+            let style = match column {
+                Column::Id => app.theme.id,
                 _ => Style::default(),
             };
-            Cell::from(c.clone()).style(style)
+            Cell::from(c).style(style)
         });
-        Row::new(cells).height(height as u16).bottom_margin(1)
+        let style = match item.is_seen() {
+            true => Style::default(),
+            false => app.theme.unread,
+        };
+        Row::new(cells)
+            .style(style)
+            .height(height as u16)
+            .bottom_margin(1)
     });
     let t = Table::new(rows)
         .header(header)
         .block(
-            Block::default().borders(Borders::ALL).title(Span::styled(
-                "Mails",
-                Style::default()
-                    .fg(Color::Magenta)
-                    .add_modifier(Modifier::BOLD),
-            )),
+            Block::default()
+                .borders(Borders::ALL)
+                .title(Span::styled("Mails", app.theme.title)),
         )
-        .highlight_style(selected_style)
-        .highlight_symbol(">> ")
-        .widths(&[
-            Constraint::Length(5),
-            Constraint::Percentage(20),
-            Constraint::Percentage(20),
-            Constraint::Percentage(20),
-            Constraint::Percentage(10),
-            Constraint::Length(20),
-            Constraint::Percentage(20),
-        ]);
+        .highlight_style(app.theme.selected)
+        .highlight_symbol(HIGHLIGHT_SYMBOL)
+        .widths(&widths);
 
     f.render_stateful_widget(t, chunks[0], &mut app.state);
 
     if app.show_body {
-        let block = Block::default().borders(Borders::ALL).title(Span::styled(
-            "Body",
-            Style::default()
-                .fg(Color::Magenta)
-                .add_modifier(Modifier::BOLD),
-        ));
-        let paragraph = Paragraph::new(app.body.clone()).block(block);
+        let block = Block::default()
+            .borders(Borders::ALL)
+            .title(Span::styled("Body", app.theme.title));
+        let inner = block.inner(chunks[1]);
+        app.body_area = (inner.width as usize, inner.height as usize);
+        let (wrapped, matches) = app.wrapped_body();
+        let lines = wrapped
+            .iter()
+            .enumerate()
+            .skip(app.body_scroll)
+            .take(inner.height as usize)
+            .map(|(i, line)| {
+                let chars = line.chars().collect::<Vec<_>>();
+                let mut spans = vec![];
+                let mut from = 0;
+                for (j, m) in matches.iter().enumerate().filter(|(_, m)| m.line == i) {
+                    let style = match app.current_match == Some(j) {
+                        true => app.theme.current_match,
+                        false => app.theme.search_match,
+                    };
+                    spans.push(Span::raw(chars[from..m.start].iter().collect::<String>()));
+                    spans.push(Span::styled(
+                        chars[m.start..m.end].iter().collect::<String>(),
+                        style,
+                    ));
+                    from = m.end;
+                }
+                spans.push(Span::raw(chars[from..].iter().collect::<String>()));
+                Spans::from(spans)
+            })
+            .collect::<Vec<_>>();
+        // the body is wrapped beforehand so that the matches are found in the lines shown
+        let paragraph = Paragraph::new(lines).block(block);
         f.render_widget(paragraph, chunks[1]);
     }
 }
 
-fn draw_footer<B: Backend>(f: &mut Frame<B>, area: Rect) {
-    let text = vec![Spans::from(vec![
+fn draw_footer<B: Backend>(f: &mut Frame<B>, app: &App, area: Rect) {
+    if app.searching || !app.body_search.is_empty() {
+        let (_, matches) = app.wrapped_body();
+        let position = match app.current_match {
+            Some(i) => format!("{}/{}", i + 1, matches.len()),
+            None => format!("{} matches", matches.len()),
+        };
+        let mut spans = vec![
+            Span::raw("  "),
+            Span::styled("/", app.theme.key),
+            Span::raw(format!(
+                "{}{}  ({})",
+                app.body_search,
+                if app.searching { "▏" } else { "" },
+                position
+            )),
+        ];
+        if app.searching {
+            spans.extend([
+                Span::raw("  "),
+                Span::styled("enter", app.theme.key),
+                Span::raw(": search"),
+            ]);
+        } else {
+            spans.extend([
+                Span::raw("  "),
+                Span::styled("n/N", app.theme.key),
+                Span::raw(": next/previous match"),
+            ]);
+        }
+        spans.extend([
+            Span::raw("  "),
+            Span::styled("esc", app.theme.key),
+            Span::raw(": clear"),
+        ]);
+        let paragraph = Paragraph::new(Spans::from(spans)).style(app.theme.footer);
+        f.render_widget(paragraph, area);
+        return;
+    }
+    if app.filtering || !app.local_filter.is_empty() {
+        let mut spans = vec![
+            Span::raw("  "),
+            Span::styled("filter", app.theme.key),
+            Span::raw(format!(
+                ": {}{}  ({} of {})",
+                app.local_filter,
+                if app.filtering { "▏" } else { "" },
+                app.local_matches(),
+                app.mails.len()
+            )),
+            Span::raw("  "),
+            Span::styled("esc", app.theme.key),
+            Span::raw(": clear"),
+        ];
+        if app.filtering {
+            spans.extend([
+                Span::raw("  "),
+                Span::styled("enter", app.theme.key),
+                Span::raw(": done"),
+            ]);
+        }
+        let paragraph = Paragraph::new(Spans::from(spans)).style(app.theme.footer);
+        f.render_widget(paragraph, area);
+        return;
+    }
+
+    let mut spans = vec![];
+    if let Some(info) = &app.mail_box_info {
+        spans.extend([
+            Span::raw("  "),
+            Span::styled(
+                format!("{}: {} mails", info.name, info.exists),
+                app.theme.title,
+            ),
+        ]);
+    }
+    if app.skipped > 0 {
+        spans.extend([
+            Span::raw("  "),
+            Span::styled(format!("{} skipped", app.skipped), app.theme.error),
+        ]);
+    }
+    spans.extend([
         Span::raw("  "),
-        Span::styled("q", Style::default().fg(Color::Yellow)),
+        Span::styled("q", app.theme.key),
         Span::raw(": quit"),
         Span::raw("  "),
-        Span::styled("r", Style::default().fg(Color::Yellow)),
+        Span::styled("r", app.theme.key),
         Span::raw(": refresh"),
         Span::raw("  "),
-        Span::styled("space", Style::default().fg(Color::Yellow)),
+        Span::styled("space", app.theme.key),
         Span::raw(": show mail body"),
-    ])];
-    let paragraph = Paragraph::new(text).style(Style::default().bg(Color::DarkGray));
+        Span::raw("  "),
+        Span::styled("/", app.theme.key),
+        Span::raw(": search in the body"),
+        Span::raw("  "),
+        Span::styled("s", app.theme.key),
+        Span::raw(": show snippet"),
+        Span::raw("  "),
+        Span::styled("c", app.theme.key),
+        Span::raw(": columns"),
+        Span::raw("  "),
+        Span::styled("tab/</>", app.theme.key),
+        Span::raw(": resize column"),
+        Span::raw("  "),
+        Span::styled("o", app.theme.key),
+        Span::raw(": open body/attachment"),
+        Span::raw("  "),
+        Span::styled("v/e", app.theme.key),
+        Span::raw(": view in pager/editor"),
+        Span::raw("  "),
+        Span::styled("m/u", app.theme.key),
+        Span::raw(": mark read/unread"),
+        Span::raw("  "),
+        Span::styled("F", app.theme.key),
+        Span::raw(": filter"),
+    ]);
+    if app.options.threads {
+        spans.extend([
+            Span::raw("  "),
+            Span::styled("enter/→", app.theme.key),
+            Span::raw(": expand thread"),
+            Span::raw("  "),
+            Span::styled("←", app.theme.key),
+            Span::raw(": collapse thread"),
+        ]);
+    }
+    if let Some(reconnect) = &app.reconnect {
+        spans.extend([
+            Span::raw("  "),
+            Span::styled(
+                format!(
+                    "reconnecting… ({}/{}) after {}",
+                    reconnect.attempt + 1,
+                    RECONNECT_ATTEMPTS,
+                    reconnect.reason
+                ),
+                app.theme.notice,
+            ),
+        ]);
+    } else if let Some(error) = &app.error {
+        spans.extend([
+            Span::raw("  "),
+            Span::styled(format!("error: {}", error), app.theme.error),
+        ]);
+    } else if let Some(notice) = &app.notice {
+        spans.extend([
+            Span::raw("  "),
+            Span::styled(notice.clone(), app.theme.notice),
+        ]);
+    } else if let Some(new_mails) = app.new_mails() {
+        spans.extend([
+            Span::raw("  "),
+            Span::styled(
+                format!(
+                    "{} new mail{} arrived — press r",
+                    new_mails,
+                    if new_mails == 1 { "" } else { "s" }
+                ),
+                app.theme.notice,
+            ),
+        ]);
+    }
+    let text = vec![Spans::from(spans)];
+    let paragraph = Paragraph::new(text).style(app.theme.footer);
     f.render_widget(paragraph, area);
 }
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+
+    use super::*;
+    use crate::mock::{fetch_body_response, MockServer};
+
+    fn options() -> SearchOptions {
+        SearchOptions::between("2022-05-01T00:00:00+08:00", "2022-06-01T00:00:00+08:00")
+    }
+
+    fn dropped() -> ClientError {
+        ClientError::Imap("SELECT".to_string(), imap::Error::ConnectionLost)
+    }
+
+    #[test]
+    fn reconnect_after_the_session_dropped() {
+        let server = MockServer::new();
+        let connections = Cell::new(0);
+        let connect = || {
+            connections.set(connections.get() + 1);
+            Ok(server.client())
+        };
+        let mut app = App::new(server.client(), &connect, options());
+
+        app.load(Err(dropped()), 0);
+        assert!(app.reconnect.is_some());
+        assert!(app.error.is_none(), "only the reconnection is shown");
+
+        // the first attempt is made right away
+        app.reconnect();
+        assert_eq!(connections.get(), 1);
+        assert!(app.reconnect.is_none());
+        assert!(app.error.is_none());
+    }
+
+    #[test]
+    fn give_up_reconnecting() {
+        let server = MockServer::new();
+        let connections = Cell::new(0);
+        let connect = || {
+            connections.set(connections.get() + 1);
+            Err(ClientError::Timeout(
+                "CONNECT imap.exmail.qq.com".to_string(),
+            ))
+        };
+        let mut app = App::new(server.client(), &connect, options());
+
+        app.load(Err(dropped()), 0);
+        while let Some(reconnect) = &mut app.reconnect {
+            reconnect.at = Instant::now();
+            app.reconnect();
+        }
+
+        assert_eq!(connections.get(), RECONNECT_ATTEMPTS);
+        assert_eq!(
+            app.error.as_deref(),
+            Some("CONNECT imap.exmail.qq.com timed out")
+        );
+    }
+
+    fn mail(uid: u32, subject: &str, from: &str, attachment: Option<&str>) -> Mail {
+        serde_json::from_value(serde_json::json!({
+            "subject": subject,
+            "from": from,
+            "sender": null,
+            "reply_to": null,
+            "to": [],
+            "cc": [],
+            "message_id": null,
+            "in_reply_to": null,
+            "references": [],
+            "id": uid,
+            "body": format!("body {}", uid),
+            "snippet": "",
+            "date": "2022-05-20T09:30:00+08:00",
+            "attachments": attachment
+                .map(|name| vec![serde_json::json!({"name": name, "size": null})])
+                .unwrap_or_default(),
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn local_filter() {
+        let server = MockServer::new();
+        let connect = || Ok(server.client());
+        let mut app = App::new(server.client(), &connect, options());
+        app.mails = vec![
+            mail(1, "Weekly report", "alice@qq.com", None),
+            mail(2, "Lunch", "bob@qq.com", Some("menu.pdf")),
+            mail(3, "Invoice", "Carol <carol@qq.com>", Some("report.xlsx")),
+        ];
+        app.select(2);
+
+        app.set_local_filter("REPORT".to_string());
+        let rows = app.rows().iter().map(|row| row.mail).collect::<Vec<_>>();
+        assert_eq!(rows, vec![0, 2]);
+        assert_eq!(app.local_matches(), 2);
+        assert_eq!(
+            app.state.selected(),
+            Some(1),
+            "the selected mail stays selected"
+        );
+        assert_eq!(app.body, "body 3");
+
+        app.set_local_filter("bob".to_string());
+        assert_eq!(app.state.selected(), Some(0));
+        assert_eq!(app.body, "body 2");
+
+        app.set_local_filter("nothing".to_string());
+        assert!(app.rows().is_empty());
+        assert_eq!(app.state.selected(), None);
+
+        app.set_local_filter(String::new());
+        assert_eq!(app.rows().len(), 3);
+    }
+
+    #[test]
+    fn search_in_the_body() {
+        let server = MockServer::new();
+        let connect = || Ok(server.client());
+        let mut app = App::new(server.client(), &connect, options());
+        let mut long = mail(1, "Weekly report", "alice@qq.com", None);
+        long.body = format!("Total due\n{}the total\n", "filler\n".repeat(20));
+        app.mails = vec![long];
+        app.select(0);
+        // 12 columns and 4 lines inside the borders
+        app.body_area = (12, 4);
+
+        app.set_body_search("total".to_string());
+        assert_eq!(app.wrapped_body().1.len(), 2, "smart case");
+        app.jump_to_match(true);
+        assert_eq!((app.current_match, app.body_scroll), (Some(0), 0));
+        app.jump_to_match(true);
+        assert_eq!(app.current_match, Some(1));
+        assert_eq!(app.body_scroll, 19, "the match on line 21 is centered");
+        app.jump_to_match(true);
+        assert_eq!((app.current_match, app.body_scroll), (Some(0), 0));
+        app.jump_to_match(false);
+        assert_eq!(app.current_match, Some(1));
+
+        // the matches follow the wrapping of a narrower pane
+        app.body_area = (6, 4);
+        app.set_body_search("Total".to_string());
+        assert_eq!(
+            app.wrapped_body().1,
+            vec![find::Match {
+                line: 0,
+                start: 0,
+                end: 5
+            }]
+        );
+
+        app.set_body_search(String::new());
+        app.jump_to_match(true);
+        assert_eq!(app.current_match, None);
+    }
+
+    #[test]
+    fn search_every_matching_mail_box() {
+        let server = MockServer::new();
+        server
+            .on(
+                "LIST",
+                "* LIST (\\HasNoChildren) \"/\" \"INBOX\"\r\n\
+                 * LIST (\\Noselect \\HasChildren) \"/\" \"Archive\"\r\n\
+                 * LIST (\\HasNoChildren) \"/\" \"Archive/2023\"\r\n\
+                 * LIST (\\HasChildren) \"/\" \"Archive/2024\"\r\n\
+                 * LIST (\\HasNoChildren) \"/\" \"Archive/2024/Q1\"\r\n",
+            )
+            .on("UID SEARCH", "* SEARCH 3 5\r\n");
+        let client = server.client();
+        let options = SearchOptions {
+            mail_box: "Archive/*".to_string(),
+            ..options()
+        };
+
+        assert_eq!(options.count(&client).unwrap(), 4);
+        let examined = server
+            .commands()
+            .into_iter()
+            .filter(|c| c.starts_with("EXAMINE"))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            examined,
+            vec!["EXAMINE \"Archive/2023\"", "EXAMINE \"Archive/2024\""]
+        );
+
+        let options = SearchOptions {
+            mail_box: "Projects/*".to_string(),
+            ..options
+        };
+        assert!(matches!(
+            options.count(&client),
+            Err(ClientError::MailBoxNotFound(..))
+        ));
+    }
+
+    #[test]
+    fn dedup_by_message_id() {
+        let copy = |uid: u32, message_id: Option<&str>, folder: &str| Mail {
+            message_id: message_id.map(str::to_string),
+            folder: Some(folder.to_string()),
+            ..mail(uid, "Weekly report", "alice@qq.com", None)
+        };
+        let mails = dedup(vec![
+            copy(7, Some("<1@qq.com>"), "Reports"),
+            copy(2, None, "Archive/2024"),
+            copy(1, Some("<1@qq.com>"), "INBOX"),
+            copy(3, None, "INBOX"),
+            copy(9, Some("<1@qq.com>"), "Archive/2024"),
+            copy(8, Some("<1@qq.com>"), "Reports"),
+            Mail {
+                subject: "Monthly report".to_string(),
+                ..copy(4, None, "INBOX")
+            },
+        ]);
+
+        // in the order of the first copies, the copy of INBOX kept
+        assert_eq!(
+            mails.iter().map(|m| m.uid).collect::<Vec<_>>(),
+            vec![1, 3, 4]
+        );
+        assert_eq!(mails[0].folders, vec!["INBOX", "Archive/2024", "Reports"]);
+        assert_eq!(mails[0].also_in, vec!["Archive/2024", "Reports"]);
+        // without a Message-ID by sender, date and subject
+        assert_eq!(mails[1].also_in, vec!["Archive/2024"]);
+        assert!(mails[2].also_in.is_empty());
+    }
+
+    #[test]
+    fn new_mail_hint() {
+        let server = MockServer::new();
+        server
+            .on("EXAMINE", "* 2 EXISTS\r\n")
+            .on("NOOP", "* 3 EXISTS\r\n");
+        let connect = || Ok(server.client());
+        let mut app = App::new(server.client(), &connect, options());
+        app.refresh();
+        assert_eq!(app.new_mails(), None);
+
+        app.poll();
+        assert!(!server.commands().contains(&"NOOP".to_string()));
+        app.last_poll -= POLL_INTERVAL;
+        app.poll();
+        assert_eq!(app.new_mails(), Some(1));
+
+        app.refresh();
+        assert_eq!(app.new_mails(), None);
+    }
+
+    #[test]
+    fn bye_schedules_a_reconnection() {
+        let server = MockServer::new();
+        server.bye("UID SEARCH", "Autologout; idle for too long");
+        let connect = || Ok(server.client());
+        let mut app = App::new(server.client(), &connect, options());
+
+        app.refresh();
+
+        let reconnect = app.reconnect.as_ref().unwrap();
+        assert_eq!(reconnect.attempt, 0);
+        assert!(reconnect.reason.ends_with("Autologout; idle for too long"));
+        assert!(app.error.is_none());
+    }
+
+    #[test]
+    fn mark_read_and_unread() {
+        let server = MockServer::new();
+        let connect = || Ok(server.client());
+        let mut app = App::new(server.client(), &connect, options());
+        app.mails = vec![mail(1, "Weekly report", "alice@qq.com", None)];
+        app.select(0);
+
+        app.set_seen(true);
+        assert!(app.mails[0].is_seen());
+        app.set_seen(false);
+        assert!(!app.mails[0].is_seen());
+        assert!(app.error.is_none());
+
+        server.fail("UID STORE", "[READ-ONLY] mailbox is read-only");
+        app.set_seen(true);
+        assert!(!app.mails[0].is_seen());
+        assert_eq!(
+            app.error.as_deref(),
+            Some("UID STORE 1 failed: No Response: mailbox is read-only")
+        );
+    }
+
+    #[test]
+    fn dry_run_marks_nothing() {
+        let server = MockServer::new();
+        let connect = || Ok(server.client());
+        let mut app = App::new(server.client(), &connect, options());
+        app.safety.dry_run = true;
+        app.mails = vec![mail(1, "Weekly report", "alice@qq.com", None)];
+        app.select(0);
+
+        app.set_seen(true);
+
+        assert!(!app.mails[0].is_seen());
+        assert_eq!(
+            app.notice.as_deref(),
+            Some("dry run: would mark UID 1 read")
+        );
+        assert!(!server.commands().iter().any(|c| c.contains("STORE")));
+    }
+
+    #[test]
+    fn toggle_and_resize_columns() {
+        let server = MockServer::new();
+        let connect = || Ok(server.client());
+        let options = SearchOptions {
+            columns: vec![Column::Date, Column::Subject],
+            show_snippet: true,
+            ..options()
+        };
+        let mut app = App::new(server.client(), &connect, options);
+        assert_eq!(
+            app.columns,
+            vec![Column::Subject, Column::Snippet, Column::Date]
+        );
+        assert_eq!(app.width(Column::Subject), Constraint::Percentage(15));
+
+        app.toggle_column(Column::Snippet);
+        app.toggle_column(Column::Id);
+        assert_eq!(app.columns, vec![Column::Id, Column::Subject, Column::Date]);
+        assert_eq!(app.width(Column::Subject), Constraint::Percentage(20));
+
+        app.column_cursor = 1;
+        app.resize_column(true);
+        assert_eq!(app.width(Column::Subject), Constraint::Percentage(25));
+        app.toggle_column(Column::Snippet);
+        assert_eq!(
+            app.width(Column::Subject),
+            Constraint::Percentage(25),
+            "resized widths are kept"
+        );
+
+        for column in app.columns.clone() {
+            app.toggle_column(column);
+        }
+        assert_eq!(app.columns.len(), 1, "the last column can't be hidden");
+        assert_eq!(app.column_cursor, 0);
+    }
+
+    #[test]
+    fn pick_the_attachment_to_open() {
+        let server = MockServer::new();
+        server.on(
+            "UID FETCH 7",
+            fetch_body_response(
+                7,
+                "20-Nov-2023 09:30:00 +0800",
+                include_bytes!("../tests/fixtures/nested_multipart.eml"),
+            ),
+        );
+        let connect = || Ok(server.client());
+        let mut app = App::new(server.client(), &connect, options());
+        app.mails = vec![
+            mail(3, "lunch", "carol", None),
+            mail(7, "report", "alice", Some("report.csv")),
+        ];
+        app.select(1);
+
+        app.open();
+        assert_eq!(app.opener, Some(0));
+        assert_eq!(app.open_entries(), vec!["HTML body", "report.csv"]);
+
+        let path = app.write_attachment(1, 0).unwrap();
+        assert!(path.ends_with("7-report.csv"));
+        assert_eq!(fs::read(&path).unwrap(), b"a,b\n1,2\n");
+        // the files go with the app
+        drop(app);
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn other_errors_are_shown_right_away() {
+        let server = MockServer::new();
+        let connect = || Ok(server.client());
+        let mut app = App::new(server.client(), &connect, options());
+
+        app.load(Err(ClientError::MailNotFound(1)), 0);
+        assert!(app.reconnect.is_none());
+        assert!(app.error.is_some());
+    }
+}
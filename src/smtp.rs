@@ -0,0 +1,613 @@
+use std::{
+    fmt::Display,
+    future::Future,
+    io,
+    net::{TcpStream, ToSocketAddrs},
+    pin::Pin,
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use lettre::message::{header::ContentType, Mailbox, MultiPart, SinglePart};
+use lettre::transport::smtp::{
+    authentication::{Credentials, Mechanism},
+    client::{AsyncSmtpConnection, AsyncTokioStream, TlsParameters},
+    extension::ClientId,
+};
+use serde::Deserialize;
+use serde_json::Value;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+use crate::proxy::Proxy;
+
+pub const DEFAULT_HOST: &str = "smtp.exmail.qq.com";
+pub const DEFAULT_PORT: u16 = 465;
+
+/// The server mails are sent through and the account sending them
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SmtpConfig {
+    pub host: String,
+    pub port: u16,
+    pub username: String,
+    pub password: String,
+}
+
+/// The `smtp` object of the config file, e.g.
+///
+/// ```json
+/// "smtp": {"host": "smtp.exmail.qq.com", "port": 465, "username": "me@corp.com", "password": "..."}
+/// ```
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct SmtpSection {
+    host: Option<String>,
+    port: Option<u16>,
+    username: Option<String>,
+    password: Option<String>,
+}
+
+impl SmtpConfig {
+    /// The `smtp` object of the config, the IMAP credentials stand in for the missing
+    /// username and password
+    pub fn from_config(config: &Value, username: &str, password: &str) -> Result<Self, String> {
+        let section = match config.get("smtp") {
+            None | Some(Value::Null) => SmtpSection::default(),
+            Some(section) => SmtpSection::deserialize(section).map_err(|err| err.to_string())?,
+        };
+        Ok(SmtpConfig {
+            host: section.host.unwrap_or_else(|| DEFAULT_HOST.to_string()),
+            port: section.port.unwrap_or(DEFAULT_PORT),
+            username: section.username.unwrap_or_else(|| username.to_string()),
+            password: section.password.unwrap_or_else(|| password.to_string()),
+        })
+    }
+
+    /// Whether the connection is encrypted from the start, as on port 465, rather than
+    /// upgraded with STARTTLS, as on the submission port 587 and on port 25
+    pub fn implicit_tls(&self) -> bool {
+        self.port == 465
+    }
+}
+
+#[derive(Debug)]
+pub enum SmtpError {
+    /// The connection failed during the operation
+    Io(String, io::Error),
+    Tls(String),
+    /// An address or the mail itself can't be sent as given
+    Message(String),
+    /// The server turned the username and password down, with its response
+    Auth(String),
+    /// The server refused the mail of this size as too large
+    TooLarge(usize),
+    /// The server answered the operation with an error
+    Rejected(String, lettre::transport::smtp::Error),
+}
+
+impl Display for SmtpError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SmtpError::Io(operation, err) => write!(f, "{} failed: {}", operation, err),
+            SmtpError::Tls(err) => write!(f, "TLS handshake failed: {}", err),
+            SmtpError::Message(err) => f.write_str(err),
+            SmtpError::Auth(response) => write!(
+                f,
+                "SMTP login failed: {}; check the username and password under \"smtp\" in \
+                 the config file, the server may want an authorization code with SMTP enabled \
+                 in the mail settings",
+                response
+            ),
+            SmtpError::TooLarge(size) => write!(
+                f,
+                "the server refused the mail of {} bytes as too large, send fewer or smaller \
+                 attachments",
+                size
+            ),
+            SmtpError::Rejected(operation, err) => write!(f, "{}: {}", operation, err),
+        }
+    }
+}
+
+pub struct Attachment {
+    pub name: String,
+    pub content: Vec<u8>,
+}
+
+/// A plain text mail, with its attachments
+#[derive(Default)]
+pub struct Message {
+    pub from: String,
+    pub to: Vec<String>,
+    pub cc: Vec<String>,
+    pub subject: String,
+    pub body: String,
+    pub in_reply_to: Option<String>,
+    pub references: Vec<String>,
+    pub attachments: Vec<Attachment>,
+}
+
+impl Message {
+    /// Answer the raw mail `original`: link to it with In-Reply-To and References, and
+    /// take its subject with `Re:` unless a subject is set
+    pub fn reply_to(&mut self, original: &[u8]) -> Result<(), String> {
+        let (headers, _) = mailparse::parse_headers(original).map_err(|err| err.to_string())?;
+        let header = |name: &str| {
+            headers
+                .iter()
+                .find(|h| h.get_key().eq_ignore_ascii_case(name))
+                .map(|h| h.get_value().trim().to_string())
+                .filter(|value| !value.is_empty())
+        };
+        let message_id = header("Message-ID").ok_or("the mail has no Message-ID")?;
+        self.references = header("References")
+            .map(|references| references.split_whitespace().map(str::to_string).collect())
+            .unwrap_or_default();
+        self.references.push(message_id.clone());
+        self.in_reply_to = Some(message_id);
+        if self.subject.is_empty() {
+            let subject = header("Subject").unwrap_or_default();
+            let replied = subject
+                .get(..3)
+                .is_some_and(|prefix| prefix.eq_ignore_ascii_case("re:"));
+            self.subject = match replied {
+                true => subject,
+                false => format!("Re: {}", subject),
+            };
+        }
+        Ok(())
+    }
+
+    /// The mail with its `message_id`, dated now
+    pub fn build(&self, message_id: &str) -> Result<lettre::Message, SmtpError> {
+        let mut builder = lettre::Message::builder()
+            .from(mailbox(&self.from)?)
+            .subject(self.subject.as_str())
+            .message_id(Some(message_id.to_string()));
+        for to in &self.to {
+            builder = builder.to(mailbox(to)?);
+        }
+        for cc in &self.cc {
+            builder = builder.cc(mailbox(cc)?);
+        }
+        if let Some(in_reply_to) = &self.in_reply_to {
+            builder = builder.in_reply_to(in_reply_to.clone());
+        }
+        if !self.references.is_empty() {
+            builder = builder.references(self.references.join(" "));
+        }
+
+        let text = SinglePart::plain(self.body.clone());
+        let mail = match self.attachments.is_empty() {
+            true => builder.singlepart(text),
+            false => {
+                let content_type = ContentType::parse("application/octet-stream").unwrap();
+                let parts = self.attachments.iter().fold(
+                    MultiPart::mixed().singlepart(text),
+                    |parts, attachment| {
+                        parts.singlepart(
+                            lettre::message::Attachment::new(attachment.name.clone())
+                                .body(attachment.content.clone(), content_type.clone()),
+                        )
+                    },
+                );
+                builder.multipart(parts)
+            }
+        };
+        mail.map_err(|err| SmtpError::Message(err.to_string()))
+    }
+}
+
+fn mailbox(address: &str) -> Result<Mailbox, SmtpError> {
+    address
+        .parse()
+        .map_err(|err| SmtpError::Message(format!("invalid address `{}`: {}", address, err)))
+}
+
+/// Send `message` to its To and Cc recipients, over implicit TLS or STARTTLS depending
+/// on the port. Every step of the dialog fails once it took longer than `timeout`.
+pub fn send(
+    config: &SmtpConfig,
+    proxy: Option<&Proxy>,
+    timeout: Option<Duration>,
+    message: &Message,
+) -> Result<(), SmtpError> {
+    let domain = message
+        .from
+        .rsplit_once('@')
+        .map_or("localhost", |(_, domain)| domain.trim_end_matches('>'));
+    let unique = chrono::Local::now()
+        .timestamp_nanos_opt()
+        .unwrap_or_default();
+    let mail = message.build(&format!("<{}.{}@{}>", unique, std::process::id(), domain))?;
+
+    let connecting = || format!("CONNECT {}:{}", config.host, config.port);
+    let stream = match proxy {
+        Some(proxy) => proxy.connect((&config.host, config.port)),
+        None => connect_direct((&config.host, config.port), timeout),
+    }
+    .and_then(|stream| stream.set_nonblocking(true).map(|_| stream))
+    .map_err(|err| SmtpError::Io(connecting(), err))?;
+
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .map_err(|err| SmtpError::Io(connecting(), err))?;
+    runtime.block_on(async {
+        let stream = tokio::net::TcpStream::from_std(stream)
+            .map_err(|err| SmtpError::Io(connecting(), err))?;
+        let mut connection = within(timeout, "CONNECT", open(config, stream)).await?;
+        deliver(&mut connection, config, timeout, &mail).await
+    })
+}
+
+fn connect_direct(target: (&str, u16), timeout: Option<Duration>) -> io::Result<TcpStream> {
+    let mut last_error = None;
+    for addr in target.to_socket_addrs()? {
+        let stream = match timeout {
+            Some(timeout) => TcpStream::connect_timeout(&addr, timeout),
+            None => TcpStream::connect(addr),
+        };
+        match stream {
+            Ok(stream) => return Ok(stream),
+            Err(err) => last_error = Some(err),
+        }
+    }
+    Err(last_error
+        .unwrap_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no address for the host")))
+}
+
+fn hello() -> ClientId {
+    ClientId::Domain("qmail".to_string())
+}
+
+/// The SMTP session on `stream`, encrypted before the greeting or with STARTTLS after it
+async fn open(
+    config: &SmtpConfig,
+    stream: tokio::net::TcpStream,
+) -> Result<AsyncSmtpConnection, SmtpError> {
+    let connecting = |err: lettre::transport::smtp::Error| match err.is_tls() {
+        true => SmtpError::Tls(err.to_string()),
+        false => SmtpError::Rejected("CONNECT".to_string(), err),
+    };
+    if config.implicit_tls() {
+        let tls = native_tls::TlsConnector::new().map_err(|err| SmtpError::Tls(err.to_string()))?;
+        let stream = tokio_native_tls::TlsConnector::from(tls)
+            .connect(&config.host, stream)
+            .await
+            .map_err(|err| SmtpError::Tls(err.to_string()))?;
+        return AsyncSmtpConnection::connect_with_transport(Box::new(TlsStream(stream)), &hello())
+            .await
+            .map_err(connecting);
+    }
+    let mut connection = AsyncSmtpConnection::connect_with_transport(Box::new(stream), &hello())
+        .await
+        .map_err(connecting)?;
+    let tls =
+        TlsParameters::new(config.host.clone()).map_err(|err| SmtpError::Tls(err.to_string()))?;
+    connection
+        .starttls(tls, &hello())
+        .await
+        .map_err(|err| match err.is_tls() {
+            true => SmtpError::Tls(err.to_string()),
+            false => SmtpError::Rejected("STARTTLS".to_string(), err),
+        })?;
+    Ok(connection)
+}
+
+/// Log in on the open `connection` and hand `mail` over for its recipients
+async fn deliver(
+    connection: &mut AsyncSmtpConnection,
+    config: &SmtpConfig,
+    timeout: Option<Duration>,
+    mail: &lettre::Message,
+) -> Result<(), SmtpError> {
+    let credentials = Credentials::new(config.username.clone(), config.password.clone());
+    within(timeout, "AUTH", async {
+        connection
+            .auth(&[Mechanism::Plain, Mechanism::Login], &credentials)
+            .await
+            .map_err(|err| SmtpError::Auth(err.to_string()))
+    })
+    .await?;
+
+    let data = mail.formatted();
+    within(timeout, "DATA", async {
+        connection
+            .send(mail.envelope(), &data)
+            .await
+            .map_err(|err| match err.status().map(u16::from) {
+                Some(552) => SmtpError::TooLarge(data.len()),
+                _ => SmtpError::Rejected("send".to_string(), err),
+            })
+    })
+    .await?;
+    // the mail is accepted, a failing QUIT doesn't matter any more
+    connection.quit().await.ok();
+    Ok(())
+}
+
+/// Run `run`, failing with a timeout of `operation` once it took longer than `timeout`
+async fn within<T>(
+    timeout: Option<Duration>,
+    operation: &str,
+    run: impl Future<Output = Result<T, SmtpError>>,
+) -> Result<T, SmtpError> {
+    match timeout {
+        Some(timeout) => tokio::time::timeout(timeout, run)
+            .await
+            .unwrap_or_else(|_| {
+                Err(SmtpError::Io(
+                    operation.to_string(),
+                    io::ErrorKind::TimedOut.into(),
+                ))
+            }),
+        None => run.await,
+    }
+}
+
+/// A TLS stream lettre can carry a session on
+#[derive(Debug)]
+struct TlsStream(tokio_native_tls::TlsStream<tokio::net::TcpStream>);
+
+impl AsyncTokioStream for TlsStream {
+    fn peer_addr(&self) -> io::Result<std::net::SocketAddr> {
+        self.0.get_ref().get_ref().get_ref().peer_addr()
+    }
+}
+
+impl AsyncRead for TlsStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.0).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for TlsStream {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.0).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.0).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.0).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::TcpListener;
+    use std::thread::{self, JoinHandle};
+
+    use super::*;
+
+    fn config() -> SmtpConfig {
+        SmtpConfig::from_config(&Value::Null, "alice@qq.com", "secret").unwrap()
+    }
+
+    const EHLO: &str = "250-smtp.qq.com\r\n250 AUTH LOGIN PLAIN\r\n";
+
+    /// A plain text server greeting, then answering every command with the next of
+    /// `responses`; it returns the lines it read
+    fn server(responses: &[&str]) -> (std::net::SocketAddr, JoinHandle<Vec<String>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let responses = responses.iter().map(|r| r.to_string()).collect::<Vec<_>>();
+        let handle = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut writer = stream.try_clone().unwrap();
+            let mut reader = BufReader::new(stream);
+            writer.write_all(b"220 smtp.qq.com Esmtp\r\n").unwrap();
+            let mut lines = vec![];
+            let mut data = false;
+            let mut responses = responses.into_iter();
+            loop {
+                let mut line = String::new();
+                if reader.read_line(&mut line).unwrap_or_default() == 0 {
+                    break;
+                }
+                let line = line.trim_end().to_string();
+                lines.push(line.clone());
+                if data && line != "." {
+                    continue;
+                }
+                let Some(response) = responses.next() else {
+                    break;
+                };
+                data = response.starts_with("354");
+                writer.write_all(response.as_bytes()).unwrap();
+            }
+            lines
+        });
+        (addr, handle)
+    }
+
+    fn message() -> Message {
+        Message {
+            from: "alice@qq.com".to_string(),
+            to: vec!["bob@qq.com".to_string(), "Carol <carol@qq.com>".to_string()],
+            subject: "hi".to_string(),
+            body: ".hidden".to_string(),
+            ..Message::default()
+        }
+    }
+
+    /// Deliver `message` to a plain text server answering `responses`
+    fn deliver_to(responses: &[&str], message: &Message) -> (Result<(), SmtpError>, Vec<String>) {
+        let (addr, server) = server(responses);
+        let mail = message.build("<3@qq.com>").unwrap();
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        let result = runtime.block_on(async {
+            let stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+            let mut connection =
+                AsyncSmtpConnection::connect_with_transport(Box::new(stream), &hello())
+                    .await
+                    .unwrap();
+            let timeout = Some(Duration::from_secs(5));
+            deliver(&mut connection, &config(), timeout, &mail).await
+        });
+        (result, server.join().unwrap())
+    }
+
+    #[test]
+    fn read_the_config() {
+        assert_eq!(
+            config(),
+            SmtpConfig {
+                host: "smtp.exmail.qq.com".to_string(),
+                port: 465,
+                username: "alice@qq.com".to_string(),
+                password: "secret".to_string(),
+            }
+        );
+        assert!(config().implicit_tls());
+        let config = serde_json::json!({"smtp": {"port": 587, "password": "code"}});
+        let config = SmtpConfig::from_config(&config, "alice@qq.com", "secret").unwrap();
+        assert_eq!((config.port, config.password.as_str()), (587, "code"));
+        assert!(!config.implicit_tls());
+        let config = serde_json::json!({"smtp": {"server": "smtp.qq.com"}});
+        assert!(SmtpConfig::from_config(&config, "alice@qq.com", "secret").is_err());
+    }
+
+    #[test]
+    fn deliver_a_mail() {
+        let (result, lines) = deliver_to(
+            &[
+                EHLO,
+                "235 Authentication successful\r\n",
+                "250 OK\r\n",
+                "250 OK\r\n",
+                "250 OK\r\n",
+                "354 End data with <CR><LF>.<CR><LF>\r\n",
+                "250 OK: queued\r\n",
+                "221 Bye\r\n",
+            ],
+            &message(),
+        );
+        result.unwrap();
+
+        let commands = lines
+            .iter()
+            .filter(|line| line.chars().next().is_some_and(|c| c.is_ascii_uppercase()))
+            .filter(|line| !line.contains(": "))
+            .map(String::as_str)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            commands,
+            vec![
+                "EHLO qmail",
+                "AUTH PLAIN AGFsaWNlQHFxLmNvbQBzZWNyZXQ=",
+                "MAIL FROM:<alice@qq.com>",
+                "RCPT TO:<bob@qq.com>",
+                "RCPT TO:<carol@qq.com>",
+                "DATA",
+                "QUIT",
+            ]
+        );
+        // the body line starting with a dot doesn't end the data early
+        assert!(lines.iter().any(|line| line == "..hidden"));
+    }
+
+    #[test]
+    fn report_a_failed_login() {
+        let (result, _) = deliver_to(&[EHLO, "535 Error: authentication failed\r\n"], &message());
+        let err = result.unwrap_err();
+
+        assert!(matches!(err, SmtpError::Auth(_)));
+        assert!(err.to_string().starts_with("SMTP login failed: "));
+        assert!(err.to_string().contains("authentication failed"));
+        assert!(!err.to_string().contains("secret"));
+    }
+
+    #[test]
+    fn report_a_mail_over_the_size_limit() {
+        let mut message = message();
+        message.attachments.push(Attachment {
+            name: "big.bin".to_string(),
+            content: vec![b'a'; 3000],
+        });
+        let (result, _) = deliver_to(
+            &[
+                EHLO,
+                "235 Authentication successful\r\n",
+                "552 Message size exceeds fixed limit\r\n",
+            ],
+            &message,
+        );
+
+        let err = result.unwrap_err();
+        assert!(matches!(err, SmtpError::TooLarge(size) if size > 3000));
+        assert!(err
+            .to_string()
+            .contains("send fewer or smaller attachments"));
+    }
+
+    #[test]
+    fn build_a_reply_with_an_attachment() {
+        let mut message = Message {
+            from: "alice@qq.com".to_string(),
+            to: vec!["bob@qq.com".to_string()],
+            body: "see attached".to_string(),
+            attachments: vec![Attachment {
+                name: "报表.xlsx".to_string(),
+                content: vec![0, 159, 146, 150],
+            }],
+            ..Message::default()
+        };
+        message
+            .reply_to(
+                b"Subject: =?UTF-8?B?5Y+R56Wo?=\r\n\
+                  Message-ID: <2@qq.com>\r\n\
+                  References: <1@qq.com>\r\n\r\nbody",
+            )
+            .unwrap();
+        assert_eq!(message.subject, "Re: 发票");
+
+        let mail = message.build("<3@qq.com>").unwrap().formatted();
+        let parsed = mailparse::parse_mail(&mail).unwrap();
+        let header = |name: &str| {
+            parsed
+                .headers
+                .iter()
+                .find(|h| h.get_key() == name)
+                .map(|h| h.get_value())
+        };
+        assert_eq!(header("Subject").as_deref(), Some("Re: 发票"));
+        assert_eq!(header("Message-ID").as_deref(), Some("<3@qq.com>"));
+        assert_eq!(header("In-Reply-To").as_deref(), Some("<2@qq.com>"));
+        assert_eq!(
+            header("References").as_deref(),
+            Some("<1@qq.com> <2@qq.com>")
+        );
+        assert_eq!(parsed.subparts.len(), 2);
+        assert_eq!(
+            parsed.subparts[0].get_body().unwrap().trim_end(),
+            "see attached"
+        );
+        let attachment = &parsed.subparts[1];
+        assert_eq!(
+            attachment.get_content_disposition().params.get("filename"),
+            Some(&"报表.xlsx".to_string())
+        );
+        assert_eq!(attachment.get_body_raw().unwrap(), [0, 159, 146, 150]);
+        assert!(message.build("<4@qq.com>").is_ok());
+        message.to.push("not an address".to_string());
+        assert!(matches!(
+            message.build("<4@qq.com>"),
+            Err(SmtpError::Message(err)) if err.starts_with("invalid address `not an address`")
+        ));
+    }
+}
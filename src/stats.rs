@@ -0,0 +1,96 @@
+use std::collections::HashMap;
+
+use crate::client::{Client, ClientError, MailHeader};
+use crate::pool;
+use crate::search::SearchOptions;
+
+#[derive(clap::ArgEnum, Clone, Copy, Debug)]
+pub enum GroupBy {
+    From,
+    Day,
+    Mailbox,
+}
+
+/// Count the mails matching `options` per group, sorted by count in descending order,
+/// or chronologically when grouping by day.
+///
+/// Mail boxes are counted over `concurrency` connections opened by `connect`, see
+/// [`pool::run`].
+pub fn run<C>(
+    client: &Client,
+    options: &SearchOptions,
+    group_by: GroupBy,
+    concurrency: Option<usize>,
+    connect: C,
+) -> Result<Vec<(String, usize)>, ClientError>
+where
+    C: Fn() -> Result<Client, ClientError> + Sync,
+{
+    let mut groups: HashMap<String, usize> = HashMap::new();
+    match group_by {
+        GroupBy::Mailbox => {
+            let raw_names = client
+                .mail_boxes()?
+                .iter()
+                .map(|mail_box| mail_box.raw_name().to_string())
+                .collect::<Vec<_>>();
+            let concurrency =
+                concurrency.unwrap_or_else(|| pool::default_concurrency(raw_names.len()));
+            let count = |client: &Client, raw_name: String| {
+                let mail_box = client.select(&raw_name)?;
+                let count = options.filter(&mail_box).count()?;
+                Ok((mail_box.name().to_string(), count))
+            };
+            let counts = if concurrency <= 1 {
+                raw_names
+                    .into_iter()
+                    .map(|raw_name| count(client, raw_name))
+                    .collect::<Result<Vec<_>, _>>()?
+            } else {
+                pool::run(raw_names, concurrency, connect, count)?
+            };
+            groups.extend(counts.into_iter().filter(|(_, count)| *count > 0));
+        }
+        GroupBy::From | GroupBy::Day => {
+            let mail_box = client.get(&options.mail_box)?;
+            let mails = options.filter(&mail_box).fetch_headers()?;
+            for mail in mails.iter() {
+                *groups
+                    .entry(group_key(mail, options, group_by))
+                    .or_default() += 1;
+            }
+        }
+    }
+
+    let mut groups = groups.into_iter().collect::<Vec<_>>();
+    match group_by {
+        GroupBy::Day => groups.sort(),
+        _ => groups.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0))),
+    }
+    Ok(groups)
+}
+
+fn group_key(mail: &MailHeader, options: &SearchOptions, group_by: GroupBy) -> String {
+    match group_by {
+        GroupBy::From => email_address(&mail.from),
+        // days are split in the timezone the search window was given in
+        GroupBy::Day => mail
+            .internal_date
+            .with_timezone(options.start_datetime.offset())
+            .format("%Y-%m-%d")
+            .to_string(),
+        GroupBy::Mailbox => options.mail_box.clone(),
+    }
+}
+
+/// The bare address of a `From` header, falling back to the raw value
+fn email_address(from: &str) -> String {
+    match mailparse::addrparse(from).ok().and_then(|addrs| {
+        addrs
+            .extract_single_info()
+            .map(|info| info.addr.to_lowercase())
+    }) {
+        Some(addr) => addr,
+        None => from.trim().to_string(),
+    }
+}
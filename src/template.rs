@@ -0,0 +1,250 @@
+use std::{
+    fs::{File, OpenOptions},
+    io,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use chrono::format::{Item, StrftimeItems};
+
+use crate::client::MailHeader;
+
+/// Subject and sender are cut to this many characters in file names
+const MAX_FIELD_LENGTH: usize = 60;
+
+/// A file name template for downloaded attachments, such as `{date}_{subject}_{name}`.
+///
+/// Placeholders: `{uid}`, `{date}` (`%Y-%m-%d`), `{date:<strftime format>}`, `{subject}`,
+/// `{from}`, `{name}` (the attachment name) and `{index}` (1-based position of the
+/// attachment in the mail). `{{` and `}}` are literal braces.
+#[derive(Debug, Clone)]
+pub struct NameTemplate {
+    parts: Vec<Part>,
+}
+
+#[derive(Debug, Clone)]
+enum Part {
+    Literal(String),
+    Uid,
+    Date(String),
+    Subject,
+    From,
+    Name,
+    Index,
+}
+
+impl NameTemplate {
+    pub fn render(&self, mail: &MailHeader, name: &str, index: usize) -> String {
+        self.parts
+            .iter()
+            .map(|part| match part {
+                Part::Literal(literal) => literal.clone(),
+                Part::Uid => mail.uid.to_string(),
+                Part::Date(format) => mail.internal_date.format(format).to_string(),
+                Part::Subject => sanitize(&mail.subject, MAX_FIELD_LENGTH),
+                Part::From => sanitize(&mail.from, MAX_FIELD_LENGTH),
+                // the name is kept whole for its extension, a name of only dots would
+                // still point at the directory or its parent
+                Part::Name => match sanitize(name, usize::MAX) {
+                    name if name.chars().all(|c| c == '.') => "_".repeat(name.len().max(1)),
+                    name => name,
+                },
+                Part::Index => index.to_string(),
+            })
+            .collect()
+    }
+}
+
+impl Default for NameTemplate {
+    fn default() -> Self {
+        NameTemplate {
+            parts: vec![Part::Name],
+        }
+    }
+}
+
+impl FromStr for NameTemplate {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = vec![];
+        let mut literal = String::new();
+        let mut chars = s.chars();
+        while let Some(c) = chars.next() {
+            match c {
+                '{' if chars.as_str().starts_with('{') => {
+                    chars.next();
+                    literal.push('{');
+                }
+                '}' if chars.as_str().starts_with('}') => {
+                    chars.next();
+                    literal.push('}');
+                }
+                '{' => {
+                    let rest = chars.as_str();
+                    let end = rest
+                        .find('}')
+                        .ok_or_else(|| format!("unclosed placeholder in {:?}", s))?;
+                    let placeholder = &rest[..end];
+                    chars = rest[end + 1..].chars();
+
+                    if !literal.is_empty() {
+                        parts.push(Part::Literal(std::mem::take(&mut literal)));
+                    }
+                    parts.push(match placeholder {
+                        "uid" => Part::Uid,
+                        "date" => Part::Date("%Y-%m-%d".to_string()),
+                        "subject" => Part::Subject,
+                        "from" => Part::From,
+                        "name" => Part::Name,
+                        "index" => Part::Index,
+                        _ => match placeholder.strip_prefix("date:") {
+                            Some(format) if valid_date_format(format) => {
+                                Part::Date(format.to_string())
+                            }
+                            _ => return Err(format!("unknown placeholder {{{}}}", placeholder)),
+                        },
+                    });
+                }
+                '}' => return Err(format!("unmatched '}}' in {:?}", s)),
+                c => literal.push(c),
+            }
+        }
+        if !literal.is_empty() {
+            parts.push(Part::Literal(literal));
+        }
+
+        Ok(NameTemplate { parts })
+    }
+}
+
+pub fn valid_date_format(format: &str) -> bool {
+    !format.is_empty() && StrftimeItems::new(format).all(|item| !matches!(item, Item::Error))
+}
+
+/// Create the file `name` in `dir`, or `name (2)`, `name (3)`... with the suffix
+/// before the extension when it exists, so that no file is overwritten
+pub fn create_unique(dir: &Path, name: &str) -> io::Result<(PathBuf, File)> {
+    let (stem, extension) = match name.rsplit_once('.') {
+        Some((stem, extension)) if !stem.is_empty() => (stem, Some(extension)),
+        _ => (name, None),
+    };
+    let mut copy = 1;
+    loop {
+        let path = match (copy, extension) {
+            (1, _) => dir.join(name),
+            (copy, Some(extension)) => dir.join(format!("{} ({}).{}", stem, copy, extension)),
+            (copy, None) => dir.join(format!("{} ({})", stem, copy)),
+        };
+        match OpenOptions::new().write(true).create_new(true).open(&path) {
+            Ok(file) => return Ok((path, file)),
+            Err(err) if err.kind() == io::ErrorKind::AlreadyExists => copy += 1,
+            Err(err) => return Err(err),
+        }
+    }
+}
+
+/// Make a header value safe to use in a file name, cut to `max_length` characters
+fn sanitize(value: &str, max_length: usize) -> String {
+    value
+        .chars()
+        .map(|c| match c {
+            '/' | '\\' | ':' | '*' | '?' | '"' | '<' | '>' | '|' => '_',
+            c if c.is_control() => '_',
+            c => c,
+        })
+        .take(max_length)
+        .collect::<String>()
+        .trim()
+        .to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mail() -> MailHeader {
+        MailHeader {
+            uid: 42,
+            subject: "INV-1234: report/Q4".to_string(),
+            from: "Alice <alice@qq.com>".to_string(),
+            internal_date: "2023-11-20T09:30:00+08:00".parse().unwrap(),
+        }
+    }
+
+    #[test]
+    fn render_placeholders() {
+        let template: NameTemplate = "{date}_{uid}_{index}_{name}".parse().unwrap();
+        assert_eq!(
+            template.render(&mail(), "report.xlsx", 2),
+            "2023-11-20_42_2_report.xlsx"
+        );
+
+        let template: NameTemplate = "{date:%Y%m%d}-{subject}-{from}".parse().unwrap();
+        assert_eq!(
+            template.render(&mail(), "report.xlsx", 1),
+            "20231120-INV-1234_ report_Q4-Alice _alice@qq.com_"
+        );
+    }
+
+    #[test]
+    fn default_keeps_the_name() {
+        assert_eq!(
+            NameTemplate::default().render(&mail(), "report.xlsx", 1),
+            "report.xlsx"
+        );
+    }
+
+    #[test]
+    fn names_stay_in_the_directory() {
+        let template = NameTemplate::default();
+        assert_eq!(template.render(&mail(), "/etc/passwd", 1), "_etc_passwd");
+        assert_eq!(template.render(&mail(), "../../x", 1), ".._.._x");
+        assert_eq!(template.render(&mail(), "..", 1), "__");
+        assert_eq!(template.render(&mail(), "", 1), "_");
+        let long = format!("{}.pdf", "a".repeat(100));
+        assert_eq!(template.render(&mail(), &long, 1), long);
+    }
+
+    #[test]
+    fn number_the_names_taken() {
+        let dir = std::env::temp_dir().join(format!("qmail-template-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let names = [
+            "report.xlsx",
+            "report.xlsx",
+            "report.xlsx",
+            "README",
+            "README",
+        ]
+        .map(|name| create_unique(&dir, name).unwrap().0);
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(
+            names.map(|path| path.file_name().unwrap().to_string_lossy().to_string()),
+            [
+                "report.xlsx",
+                "report (2).xlsx",
+                "report (3).xlsx",
+                "README",
+                "README (2)"
+            ]
+        );
+    }
+
+    #[test]
+    fn escaped_braces() {
+        let template: NameTemplate = "{{{name}}}".parse().unwrap();
+        assert_eq!(template.render(&mail(), "a.txt", 1), "{a.txt}");
+    }
+
+    #[test]
+    fn invalid_templates() {
+        assert!("{size}".parse::<NameTemplate>().is_err());
+        assert!("{date:}".parse::<NameTemplate>().is_err());
+        assert!("{date:%Q}".parse::<NameTemplate>().is_err());
+        assert!("{name".parse::<NameTemplate>().is_err());
+        assert!("name}".parse::<NameTemplate>().is_err());
+    }
+}
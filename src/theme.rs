@@ -0,0 +1,229 @@
+use std::{
+    io::{self, IsTerminal},
+    str::FromStr,
+};
+
+use serde::Deserialize;
+use serde_json::Value;
+use tui::style::{Color, Modifier, Style};
+
+/// When to use colors
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ColorChoice {
+    /// When stdout is a terminal and `NO_COLOR` isn't set
+    Auto,
+    Always,
+    Never,
+}
+
+impl ColorChoice {
+    pub fn enabled(self) -> bool {
+        match self {
+            ColorChoice::Always => true,
+            ColorChoice::Never => false,
+            ColorChoice::Auto => {
+                std::env::var_os("NO_COLOR").is_none_or(|value| value.is_empty())
+                    && io::stdout().is_terminal()
+            }
+        }
+    }
+}
+
+impl FromStr for ColorChoice {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "auto" => Ok(ColorChoice::Auto),
+            "always" => Ok(ColorChoice::Always),
+            "never" => Ok(ColorChoice::Never),
+            _ => Err(format!("expected auto, always or never, got `{}`", s)),
+        }
+    }
+}
+
+/// The styles of the TUI
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Theme {
+    pub header: Style,
+    pub selected: Style,
+    pub footer: Style,
+    pub id: Style,
+    /// Rows of the mails not read yet
+    pub unread: Style,
+    /// Block titles
+    pub title: Style,
+    /// Keys in the footer
+    pub key: Style,
+    pub error: Style,
+    pub notice: Style,
+    /// Matches of the search in the mail body
+    pub search_match: Style,
+    /// The match jumped to last
+    pub current_match: Style,
+}
+
+impl Default for Theme {
+    fn default() -> Self {
+        Theme {
+            header: Style::default().fg(Color::Red).bg(Color::Blue),
+            selected: Style::default().add_modifier(Modifier::REVERSED),
+            footer: Style::default().bg(Color::DarkGray),
+            id: Style::default().fg(Color::DarkGray),
+            unread: Style::default().add_modifier(Modifier::BOLD),
+            title: Style::default()
+                .fg(Color::Magenta)
+                .add_modifier(Modifier::BOLD),
+            key: Style::default().fg(Color::Yellow),
+            error: Style::default().fg(Color::Red).add_modifier(Modifier::BOLD),
+            notice: Style::default().fg(Color::Yellow),
+            search_match: Style::default().fg(Color::Black).bg(Color::Yellow),
+            current_match: Style::default().fg(Color::Black).bg(Color::LightRed),
+        }
+    }
+}
+
+/// Colors overridden by the `theme` object of the config, by name
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ThemeConfig {
+    header_fg: Option<String>,
+    header_bg: Option<String>,
+    selected_fg: Option<String>,
+    selected_bg: Option<String>,
+    footer_bg: Option<String>,
+    id_fg: Option<String>,
+    key_fg: Option<String>,
+}
+
+impl Theme {
+    /// Styles without any color, only bold and reversed text
+    pub fn monochrome() -> Theme {
+        Theme {
+            header: Style::default(),
+            selected: Style::default().add_modifier(Modifier::REVERSED),
+            footer: Style::default(),
+            id: Style::default(),
+            unread: Style::default().add_modifier(Modifier::BOLD),
+            title: Style::default().add_modifier(Modifier::BOLD),
+            key: Style::default().add_modifier(Modifier::BOLD),
+            error: Style::default().add_modifier(Modifier::BOLD),
+            notice: Style::default(),
+            search_match: Style::default().add_modifier(Modifier::UNDERLINED),
+            current_match: Style::default().add_modifier(Modifier::REVERSED),
+        }
+    }
+
+    /// The default theme with the colors of the `theme` config object, `null` keeps
+    /// the defaults
+    pub fn from_config(config: &Value) -> Result<Theme, String> {
+        let config = match config {
+            Value::Null => ThemeConfig::default(),
+            config => ThemeConfig::deserialize(config).map_err(|err| err.to_string())?,
+        };
+        let color = |name: &Option<String>| name.as_deref().map(parse_color).transpose();
+
+        let mut theme = Theme::default();
+        if let Some(fg) = color(&config.header_fg)? {
+            theme.header = theme.header.fg(fg);
+        }
+        if let Some(bg) = color(&config.header_bg)? {
+            theme.header = theme.header.bg(bg);
+        }
+        let (selected_fg, selected_bg) = (color(&config.selected_fg)?, color(&config.selected_bg)?);
+        if selected_fg.is_some() || selected_bg.is_some() {
+            // explicit colors replace the reversed default
+            theme.selected = Style::default();
+            if let Some(fg) = selected_fg {
+                theme.selected = theme.selected.fg(fg);
+            }
+            if let Some(bg) = selected_bg {
+                theme.selected = theme.selected.bg(bg);
+            }
+        }
+        if let Some(bg) = color(&config.footer_bg)? {
+            theme.footer = theme.footer.bg(bg);
+        }
+        if let Some(fg) = color(&config.id_fg)? {
+            theme.id = theme.id.fg(fg);
+        }
+        if let Some(fg) = color(&config.key_fg)? {
+            theme.key = theme.key.fg(fg);
+        }
+        Ok(theme)
+    }
+}
+
+/// A color by name, e.g. `lightblue` or `dark-gray`, by its index in the 256-color
+/// palette, or as `#rrggbb`
+fn parse_color(name: &str) -> Result<Color, String> {
+    let normalized = name.trim().to_lowercase().replace(['-', '_', ' '], "");
+    let color = match normalized.as_str() {
+        "reset" | "default" => Color::Reset,
+        "black" => Color::Black,
+        "red" => Color::Red,
+        "green" => Color::Green,
+        "yellow" => Color::Yellow,
+        "blue" => Color::Blue,
+        "magenta" => Color::Magenta,
+        "cyan" => Color::Cyan,
+        "gray" | "grey" => Color::Gray,
+        "darkgray" | "darkgrey" => Color::DarkGray,
+        "lightred" => Color::LightRed,
+        "lightgreen" => Color::LightGreen,
+        "lightyellow" => Color::LightYellow,
+        "lightblue" => Color::LightBlue,
+        "lightmagenta" => Color::LightMagenta,
+        "lightcyan" => Color::LightCyan,
+        "white" => Color::White,
+        hex if hex.starts_with('#') && hex.len() == 7 => {
+            let channel = |i: usize| u8::from_str_radix(&hex[i..i + 2], 16);
+            match (channel(1), channel(3), channel(5)) {
+                (Ok(r), Ok(g), Ok(b)) => Color::Rgb(r, g, b),
+                _ => return Err(format!("invalid color `{}`", name)),
+            }
+        }
+        index => match index.parse::<u8>() {
+            Ok(index) => Color::Indexed(index),
+            Err(_) => return Err(format!("unknown color `{}`", name)),
+        },
+    };
+    Ok(color)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_colors() {
+        assert_eq!(parse_color("Light-Blue").unwrap(), Color::LightBlue);
+        assert_eq!(parse_color("dark_grey").unwrap(), Color::DarkGray);
+        assert_eq!(parse_color("#ff8000").unwrap(), Color::Rgb(255, 128, 0));
+        assert_eq!(parse_color("236").unwrap(), Color::Indexed(236));
+        assert_eq!(parse_color("#ff80").unwrap_err(), "unknown color `#ff80`");
+        assert_eq!(parse_color("teal").unwrap_err(), "unknown color `teal`");
+    }
+
+    #[test]
+    fn theme_from_config() {
+        assert_eq!(Theme::from_config(&Value::Null).unwrap(), Theme::default());
+
+        let theme = Theme::from_config(&serde_json::json!({
+            "header_bg": "white",
+            "selected_bg": "lightyellow",
+            "footer_bg": "gray",
+        }))
+        .unwrap();
+        assert_eq!(
+            theme.header,
+            Style::default().fg(Color::Red).bg(Color::White)
+        );
+        assert_eq!(theme.selected, Style::default().bg(Color::LightYellow));
+        assert_eq!(theme.footer, Style::default().bg(Color::Gray));
+        assert_eq!(theme.id, Theme::default().id);
+
+        assert!(Theme::from_config(&serde_json::json!({"header": "white"})).is_err());
+        assert!(Theme::from_config(&serde_json::json!({"footer_bg": "teal"})).is_err());
+    }
+}
@@ -0,0 +1,264 @@
+use std::{collections::HashMap, sync::OnceLock};
+
+use regex::Regex;
+
+use crate::client::Mail;
+
+pub struct Thread {
+    pub subject: String,
+    pub mails: Vec<Mail>,
+}
+
+/// Group mails into conversation threads.
+///
+/// Mails are linked through their Message-ID/In-Reply-To/References headers, mails
+/// without any of those headers fall back to their normalized subject. A reply whose
+/// parent is not among `mails` still forms a thread of its own.
+///
+/// Each thread is ordered from oldest to newest, and threads are ordered by their
+/// newest mail, newest first.
+pub fn threads(mails: Vec<Mail>) -> Vec<Thread> {
+    let groups = group(&mails);
+    let mut mails = mails.into_iter().map(Some).collect::<Vec<_>>();
+
+    groups
+        .into_iter()
+        .map(|group| {
+            let mails = group
+                .into_iter()
+                .map(|i| mails[i].take().unwrap())
+                .collect::<Vec<_>>();
+            Thread {
+                subject: normalize_subject(&mails[0].subject),
+                mails,
+            }
+        })
+        .collect()
+}
+
+/// Same as [`threads`], but returns indexes into `mails` instead of taking ownership.
+pub fn group(mails: &[Mail]) -> Vec<Vec<usize>> {
+    let mut parents = (0..mails.len()).collect::<Vec<_>>();
+    let mut owners: HashMap<String, usize> = HashMap::new();
+
+    for (i, mail) in mails.iter().enumerate() {
+        let mut keys = mail
+            .message_id
+            .iter()
+            .chain(mail.in_reply_to.iter())
+            .chain(mail.references.iter())
+            .cloned()
+            .collect::<Vec<_>>();
+        if mail.in_reply_to.is_none() && mail.references.is_empty() {
+            keys.push(format!("subject:{}", normalize_subject(&mail.subject)));
+        }
+
+        for key in keys {
+            match owners.get(&key) {
+                Some(&j) => union(&mut parents, i, j),
+                None => {
+                    owners.insert(key, i);
+                }
+            }
+        }
+    }
+
+    let mut groups: HashMap<usize, Vec<usize>> = HashMap::new();
+    for i in 0..mails.len() {
+        groups.entry(find(&mut parents, i)).or_default().push(i);
+    }
+
+    let mut groups = groups.into_values().collect::<Vec<_>>();
+    for group in groups.iter_mut() {
+        group.sort_by_key(|&i| mails[i].internal_date);
+    }
+    groups.sort_by_key(|group| -mails[*group.last().unwrap()].internal_date.timestamp());
+
+    groups
+}
+
+/// Set the `thread_id` of `mails` to that of the thread they are in, see [`thread_id`]
+pub fn assign_ids(mails: &mut [Mail]) {
+    for group in group(mails) {
+        let id = thread_id(&mails[group[0]]);
+        for i in group {
+            mails[i].thread_id = Some(id.clone());
+        }
+    }
+}
+
+/// A short id of the thread started by `root`, its oldest mail: a hash of its
+/// Message-ID, or of its subject and date without one. It stays the same across
+/// searches as long as the thread starts with the same mail.
+pub fn thread_id(root: &Mail) -> String {
+    let key = match &root.message_id {
+        Some(message_id) => message_id.clone(),
+        None => format!(
+            "{}\n{}",
+            normalize_subject(&root.subject),
+            root.internal_date.timestamp()
+        ),
+    };
+    // FNV-1a, which unlike the hasher of the standard library is the same on every
+    // build
+    let hash = key.bytes().fold(0xcbf2_9ce4_8422_2325_u64, |hash, byte| {
+        (hash ^ byte as u64).wrapping_mul(0x0100_0000_01b3)
+    });
+    format!("{:08x}", (hash ^ (hash >> 32)) as u32)
+}
+
+/// Strip reply/forward prefixes such as `Re:`, `Fwd:` or `回复：` from a subject.
+pub fn normalize_subject(subject: &str) -> String {
+    static PREFIX: OnceLock<Regex> = OnceLock::new();
+    let prefix = PREFIX.get_or_init(|| {
+        Regex::new(r"(?i)^\s*((re|fw|fwd|回复|答复|转发)\s*(\[\d+\])?\s*[:：]\s*)+").unwrap()
+    });
+    prefix.replace(subject, "").trim().to_string()
+}
+
+fn find(parents: &mut [usize], i: usize) -> usize {
+    let mut root = i;
+    while parents[root] != root {
+        root = parents[root];
+    }
+    parents[i] = root;
+    root
+}
+
+fn union(parents: &mut [usize], i: usize, j: usize) {
+    let (i, j) = (find(parents, i), find(parents, j));
+    parents[i.max(j)] = i.min(j);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mail(
+        uid: u32,
+        date: &str,
+        subject: &str,
+        message_id: Option<&str>,
+        references: &[&str],
+    ) -> Mail {
+        serde_json::from_value(serde_json::json!({
+            "subject": subject,
+            "from": "alice@qq.com",
+            "sender": null,
+            "reply_to": null,
+            "to": [],
+            "cc": [],
+            "message_id": message_id,
+            "in_reply_to": references.last(),
+            "references": references,
+            "id": uid,
+            "body": "",
+            "snippet": "",
+            "date": date,
+            "attachments": [],
+        }))
+        .unwrap()
+    }
+
+    /// The UIDs of every group
+    fn uids(mails: &[Mail]) -> Vec<Vec<u32>> {
+        group(mails)
+            .into_iter()
+            .map(|group| group.into_iter().map(|i| mails[i].uid).collect())
+            .collect()
+    }
+
+    #[test]
+    fn group_along_the_references() {
+        let mails = vec![
+            mail(1, "2022-05-01T09:30:00+08:00", "plan", Some("<a@qq>"), &[]),
+            // its parent <b@qq> isn't among the mails, the chain still leads to <a@qq>
+            mail(
+                3,
+                "2022-05-03T09:30:00+08:00",
+                "Re: Re: plan",
+                Some("<c@qq>"),
+                &["<a@qq>", "<b@qq>"],
+            ),
+            mail(
+                4,
+                "2022-05-04T09:30:00+08:00",
+                "Re: plan",
+                Some("<d@qq>"),
+                &["<b@qq>"],
+            ),
+            mail(
+                2,
+                "2022-05-02T09:30:00+08:00",
+                "Re: plan",
+                Some("<e@qq>"),
+                &["<x@qq>"],
+            ),
+        ];
+
+        // newest thread first, oldest mail first in a thread; the same subject doesn't
+        // join a mail that references another thread
+        assert_eq!(uids(&mails), vec![vec![1, 3, 4], vec![2]]);
+    }
+
+    #[test]
+    fn group_by_in_reply_to_alone() {
+        let mut reply = mail(
+            2,
+            "2022-05-02T09:30:00+08:00",
+            "答复: 发票",
+            Some("<b@qq>"),
+            &[],
+        );
+        reply.in_reply_to = Some("<a@qq>".to_string());
+        let mails = vec![
+            mail(1, "2022-05-01T09:30:00+08:00", "发票", Some("<a@qq>"), &[]),
+            reply,
+            mail(3, "2022-05-03T09:30:00+08:00", "周报", Some("<c@qq>"), &[]),
+        ];
+
+        assert_eq!(uids(&mails), vec![vec![3], vec![1, 2]]);
+    }
+
+    #[test]
+    fn group_by_subject_without_headers() {
+        let mails = vec![
+            mail(1, "2022-05-01T09:30:00+08:00", "周报", None, &[]),
+            mail(2, "2022-05-02T09:30:00+08:00", "回复：周报", None, &[]),
+            mail(3, "2022-05-03T09:30:00+08:00", "RE[2]: 周报", None, &[]),
+            mail(4, "2022-05-04T09:30:00+08:00", "Fwd: 发票", None, &[]),
+        ];
+
+        assert_eq!(uids(&mails), vec![vec![4], vec![1, 2, 3]]);
+        assert_eq!(normalize_subject(" Re: FW： 周报 "), "周报");
+    }
+
+    #[test]
+    fn share_the_thread_id_of_the_root() {
+        let mut mails = vec![
+            mail(
+                3,
+                "2022-05-03T09:30:00+08:00",
+                "Re: 发票",
+                Some("<c@qq>"),
+                &["<a@qq>"],
+            ),
+            mail(1, "2022-05-01T09:30:00+08:00", "发票", Some("<a@qq>"), &[]),
+            mail(2, "2022-05-02T09:30:00+08:00", "周报", None, &[]),
+        ];
+
+        assign_ids(&mut mails);
+
+        assert_eq!(mails[0].thread_id, mails[1].thread_id);
+        assert_eq!(mails[1].thread_id.as_deref(), Some(&*thread_id(&mails[1])));
+        assert_ne!(mails[2].thread_id, mails[1].thread_id);
+        assert_eq!(mails[2].thread_id.as_ref().unwrap().len(), 8);
+        // the id only depends on the oldest mail found of the thread
+        let mut reply = vec![mails[0].clone()];
+        assign_ids(&mut reply);
+        assert_ne!(reply[0].thread_id, mails[0].thread_id);
+        let mut root = vec![mails[1].clone()];
+        assign_ids(&mut root);
+        assert_eq!(root[0].thread_id, mails[1].thread_id);
+    }
+}
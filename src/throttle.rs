@@ -0,0 +1,236 @@
+use std::{
+    io::{self, Read, Write},
+    sync::{Arc, Mutex},
+    thread,
+    time::{Duration, Instant},
+};
+
+use imap::extensions::idle::SetReadTimeout;
+
+/// Commands per second allowed by default, far above what a single search sends but
+/// enough to keep several connections from hammering the server
+pub const DEFAULT_MAX_RPS: f64 = 20.0;
+
+/// Delay before the first retry of a throttled command, doubled on every retry
+const BACKOFF_START: Duration = Duration::from_secs(1);
+const MAX_RETRIES: u32 = 5;
+
+/// Spaces out IMAP commands to at most `max_rps` per second. Share one between the
+/// connections of a pool to bound their total load.
+#[derive(Debug)]
+pub struct Throttle {
+    interval: Duration,
+    next: Mutex<Instant>,
+}
+
+impl Throttle {
+    /// `max_rps` of 0 or less doesn't limit anything
+    pub fn new(max_rps: f64) -> Throttle {
+        Throttle {
+            interval: if max_rps > 0.0 {
+                Duration::from_secs_f64(1.0 / max_rps)
+            } else {
+                Duration::ZERO
+            },
+            next: Mutex::new(Instant::now()),
+        }
+    }
+
+    /// Block until the next command may be sent
+    pub fn wait(&self) {
+        let slot = {
+            let mut next = self.next.lock().unwrap();
+            let now = Instant::now();
+            let slot = (*next).max(now);
+            *next = slot + self.interval;
+            slot
+        };
+        thread::sleep(slot.saturating_duration_since(Instant::now()));
+    }
+}
+
+/// A stream that waits for its [`Throttle`] before every command it sends. Commands
+/// end with a flush, so the first write after a flush starts the next one.
+pub struct Throttled<S> {
+    stream: S,
+    throttle: Arc<Throttle>,
+    in_command: bool,
+}
+
+impl<S> Throttled<S> {
+    pub fn new(stream: S, throttle: Arc<Throttle>) -> Self {
+        Throttled {
+            stream,
+            throttle,
+            in_command: false,
+        }
+    }
+}
+
+impl<S: Read> Read for Throttled<S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.stream.read(buf)
+    }
+}
+
+impl<S: Write> Write for Throttled<S> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if !self.in_command {
+            self.throttle.wait();
+            self.in_command = true;
+        }
+        self.stream.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.in_command = false;
+        self.stream.flush()
+    }
+}
+
+impl<S: SetReadTimeout> SetReadTimeout for Throttled<S> {
+    fn set_read_timeout(&mut self, timeout: Option<Duration>) -> imap::Result<()> {
+        self.stream.set_read_timeout(timeout)
+    }
+}
+
+/// Whether the server refused a command because of the load, e.g. exmail's
+/// "Service temporarily unavailable". A BYE only shows up as a lost connection, without
+/// its reason, so only NO responses are recognized.
+pub fn is_throttled(err: &imap::Error) -> bool {
+    let message = match err {
+        imap::Error::No(message) => message.to_lowercase(),
+        _ => return false,
+    };
+    [
+        "temporarily unavailable",
+        "too many",
+        "throttl",
+        "rate limit",
+        "try again later",
+        "server busy",
+    ]
+    .iter()
+    .any(|pattern| message.contains(pattern))
+}
+
+/// Run `command`, retrying it with exponential backoff while the server answers with a
+/// throttling NO
+pub fn retry<T>(
+    operation: &str,
+    command: impl FnMut() -> Result<T, imap::Error>,
+) -> Result<T, imap::Error> {
+    retry_after(BACKOFF_START, operation, command)
+}
+
+fn retry_after<T>(
+    mut delay: Duration,
+    operation: &str,
+    mut command: impl FnMut() -> Result<T, imap::Error>,
+) -> Result<T, imap::Error> {
+    let mut retries = 0;
+    loop {
+        match command() {
+            Err(err) if is_throttled(&err) && retries < MAX_RETRIES => {
+                eprintln!(
+                    "warning: {} throttled by the server ({}), retrying in {:.1}s",
+                    operation,
+                    err,
+                    delay.as_secs_f64()
+                );
+                thread::sleep(delay);
+                delay *= 2;
+                retries += 1;
+            }
+            Err(err) => {
+                if is_throttled(&err) {
+                    eprintln!(
+                        "warning: {} throttled by the server, lower --max-rps or --concurrency",
+                        operation
+                    );
+                }
+                return Err(err);
+            }
+            ok => return ok,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn throttle_spaces_commands() {
+        let throttle = Throttle::new(200.0);
+        let started = Instant::now();
+        for _ in 0..5 {
+            throttle.wait();
+        }
+
+        // the first command goes out right away
+        assert!(started.elapsed() >= Duration::from_millis(20));
+    }
+
+    #[test]
+    fn unlimited_throttle() {
+        let throttle = Throttle::new(0.0);
+        let started = Instant::now();
+        for _ in 0..1000 {
+            throttle.wait();
+        }
+
+        assert!(started.elapsed() < Duration::from_millis(100));
+    }
+
+    #[test]
+    fn throttling_responses() {
+        let no = |message: &str| imap::Error::No(message.to_string());
+
+        assert!(is_throttled(&no("Service temporarily unavailable")));
+        assert!(is_throttled(&no("Too many commands, try again later")));
+        assert!(!is_throttled(&no("Mailbox doesn't exist")));
+        assert!(!is_throttled(&imap::Error::Bad(
+            "temporarily unavailable".to_string()
+        )));
+    }
+
+    #[test]
+    fn retry_until_the_server_recovers() {
+        let mut attempts = 0;
+        let result = retry_after(Duration::from_millis(1), "UID SEARCH", || {
+            attempts += 1;
+            match attempts {
+                1 | 2 => Err(imap::Error::No(
+                    "Service temporarily unavailable".to_string(),
+                )),
+                _ => Ok(attempts),
+            }
+        });
+
+        assert_eq!(result.unwrap(), 3);
+    }
+
+    #[test]
+    fn retry_gives_up() {
+        let mut attempts = 0;
+        let result: Result<(), _> = retry_after(Duration::from_millis(1), "UID SEARCH", || {
+            attempts += 1;
+            Err(imap::Error::No(
+                "Service temporarily unavailable".to_string(),
+            ))
+        });
+
+        assert!(result.is_err());
+        assert_eq!(attempts, MAX_RETRIES + 1);
+
+        let mut attempts = 0;
+        let result: Result<(), _> = retry_after(Duration::from_millis(1), "SELECT", || {
+            attempts += 1;
+            Err(imap::Error::No("no such mailbox".to_string()))
+        });
+
+        assert!(result.is_err());
+        assert_eq!(attempts, 1, "other errors aren't retried");
+    }
+}
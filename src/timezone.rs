@@ -0,0 +1,115 @@
+use chrono::{
+    DateTime, Duration, FixedOffset, Local, LocalResult, NaiveDate, NaiveDateTime, NaiveTime,
+    Offset, TimeZone,
+};
+
+/// `local` in the local timezone, see [`resolve`]
+pub fn local(local: NaiveDateTime) -> DateTime<FixedOffset> {
+    resolve(&Local, local)
+}
+
+/// The start of a day in the local timezone, with the offset of that day rather than
+/// the one of today
+pub fn local_midnight(date: NaiveDate) -> DateTime<FixedOffset> {
+    local(date.and_time(NaiveTime::MIN))
+}
+
+/// `local` in `tz`. A time that happens twice when the clocks go back is the earlier
+/// one, a time skipped when they go forward is moved past the gap, e.g. midnight of a
+/// day the clocks jump from 00:00 to 01:00 is 01:00.
+fn resolve<Tz: TimeZone>(tz: &Tz, local: NaiveDateTime) -> DateTime<FixedOffset> {
+    match tz.from_local_datetime(&local) {
+        LocalResult::Single(datetime) | LocalResult::Ambiguous(datetime, _) => {
+            datetime.fixed_offset()
+        }
+        LocalResult::None => {
+            // the offset before the gap puts the skipped time after it
+            let before = tz
+                .offset_from_utc_datetime(&(local - Duration::days(1)))
+                .fix();
+            tz.from_utc_datetime(&(local - before)).fixed_offset()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The clocks jump from 00:00 to 01:00 on 2024-09-08, like in Chile: UTC-4 before,
+    /// UTC-3 after
+    #[derive(Debug, Clone)]
+    struct SpringForward;
+
+    impl SpringForward {
+        fn gap() -> (NaiveDateTime, NaiveDateTime) {
+            let day = NaiveDate::from_ymd_opt(2024, 9, 8).unwrap();
+            (
+                day.and_hms_opt(0, 0, 0).unwrap(),
+                day.and_hms_opt(1, 0, 0).unwrap(),
+            )
+        }
+
+        fn offset(hours: i32) -> FixedOffset {
+            FixedOffset::east_opt(hours * 3600).unwrap()
+        }
+    }
+
+    impl TimeZone for SpringForward {
+        type Offset = FixedOffset;
+
+        fn from_offset(_: &FixedOffset) -> Self {
+            SpringForward
+        }
+
+        fn offset_from_local_date(&self, local: &NaiveDate) -> LocalResult<FixedOffset> {
+            self.offset_from_local_datetime(&local.and_time(NaiveTime::MIN))
+        }
+
+        fn offset_from_local_datetime(&self, local: &NaiveDateTime) -> LocalResult<FixedOffset> {
+            let (start, end) = SpringForward::gap();
+            if *local < start {
+                LocalResult::Single(SpringForward::offset(-4))
+            } else if *local < end {
+                LocalResult::None
+            } else {
+                LocalResult::Single(SpringForward::offset(-3))
+            }
+        }
+
+        fn offset_from_utc_date(&self, utc: &NaiveDate) -> FixedOffset {
+            self.offset_from_utc_datetime(&utc.and_time(NaiveTime::MIN))
+        }
+
+        fn offset_from_utc_datetime(&self, utc: &NaiveDateTime) -> FixedOffset {
+            match *utc < SpringForward::gap().0 + Duration::hours(4) {
+                true => SpringForward::offset(-4),
+                false => SpringForward::offset(-3),
+            }
+        }
+    }
+
+    fn midnight(date: &str) -> DateTime<FixedOffset> {
+        let date = date.parse::<NaiveDate>().unwrap();
+        resolve(&SpringForward, date.and_time(NaiveTime::MIN))
+    }
+
+    #[test]
+    fn midnight_around_spring_forward() {
+        assert_eq!(
+            midnight("2024-09-07").to_rfc3339(),
+            "2024-09-07T00:00:00-04:00"
+        );
+        // skipped, the day starts at 01:00
+        assert_eq!(
+            midnight("2024-09-08").to_rfc3339(),
+            "2024-09-08T01:00:00-03:00"
+        );
+        assert_eq!(
+            midnight("2024-09-09").to_rfc3339(),
+            "2024-09-09T00:00:00-03:00"
+        );
+        // the real local timezone doesn't panic on the far future default either
+        local_midnight(NaiveDate::from_ymd_opt(9999, 12, 31).unwrap());
+    }
+}
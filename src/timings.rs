@@ -0,0 +1,109 @@
+use std::{fmt::Display, time::Duration};
+
+use serde::{Serialize, Serializer};
+
+/// Where the time of a connection went, see [`Client::timings`](crate::client::Client::timings).
+/// Durations are serialized in seconds.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct Timings {
+    /// TCP connect, through the proxy if any, and TLS handshake
+    #[serde(serialize_with = "seconds")]
+    pub connect: Duration,
+    /// Greeting and LOGIN
+    #[serde(serialize_with = "seconds")]
+    pub login: Duration,
+    /// SELECT and EXAMINE
+    #[serde(serialize_with = "seconds")]
+    pub select: Duration,
+    /// UID SEARCH and UID SORT
+    #[serde(serialize_with = "seconds")]
+    pub search: Duration,
+    /// Number of FETCH commands of the searches
+    pub fetches: usize,
+    /// Wall time of those FETCH commands
+    #[serde(serialize_with = "seconds")]
+    pub fetch: Duration,
+    /// Parsing the fetched mails and applying the client-side filters
+    #[serde(serialize_with = "seconds")]
+    pub parse: Duration,
+    /// Since the connection started
+    #[serde(serialize_with = "seconds")]
+    pub total: Duration,
+}
+
+/// A step of the work of a connection that is timed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Stage {
+    Connect,
+    Login,
+    Select,
+    Search,
+    Fetch,
+    Parse,
+}
+
+impl Timings {
+    /// Account for `elapsed` spent in `stage`, a fetch counts one FETCH command
+    pub fn add(&mut self, stage: Stage, elapsed: Duration) {
+        match stage {
+            Stage::Connect => self.connect += elapsed,
+            Stage::Login => self.login += elapsed,
+            Stage::Select => self.select += elapsed,
+            Stage::Search => self.search += elapsed,
+            Stage::Fetch => {
+                self.fetches += 1;
+                self.fetch += elapsed;
+            }
+            Stage::Parse => self.parse += elapsed,
+        }
+    }
+}
+
+impl Display for Timings {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let rows = [
+            ("connect", self.connect, String::new()),
+            ("login", self.login, String::new()),
+            ("select", self.select, String::new()),
+            ("search", self.search, String::new()),
+            ("fetch", self.fetch, format!("  ({} FETCH)", self.fetches)),
+            ("parse", self.parse, String::new()),
+            ("total", self.total, String::new()),
+        ];
+        for (i, (name, duration, note)) in rows.iter().enumerate() {
+            if i > 0 {
+                writeln!(f)?;
+            }
+            write!(f, "{:<8}{:>8.3}s{}", name, duration.as_secs_f64(), note)?;
+        }
+        Ok(())
+    }
+}
+
+fn seconds<S: Serializer>(duration: &Duration, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_f64(duration.as_secs_f64())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn add_up_and_report() {
+        let mut timings = Timings::default();
+        timings.add(Stage::Fetch, Duration::from_millis(200));
+        timings.add(Stage::Fetch, Duration::from_millis(300));
+        timings.add(Stage::Search, Duration::from_millis(50));
+        timings.total = Duration::from_secs(1);
+
+        assert_eq!(timings.fetches, 2);
+        assert_eq!(timings.fetch, Duration::from_millis(500));
+        assert_eq!(
+            timings.to_string().lines().nth(4),
+            Some("fetch      0.500s  (2 FETCH)")
+        );
+        let json = serde_json::to_value(timings).unwrap();
+        assert_eq!(json["search"], 0.05);
+        assert_eq!(json["total"], 1.0);
+    }
+}
@@ -0,0 +1,79 @@
+//! Encoding of mail box names to modified UTF-7 (RFC 3501 section 5.1.3), the
+//! decoding is done by `utf7_imap`.
+
+const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+,";
+
+pub fn encode_utf7_imap(text: &str) -> String {
+    let mut encoded = String::new();
+    let mut pending: Vec<u16> = vec![];
+    for c in text.chars() {
+        if (' '..='~').contains(&c) {
+            flush(&mut pending, &mut encoded);
+            if c == '&' {
+                encoded.push_str("&-");
+            } else {
+                encoded.push(c);
+            }
+        } else {
+            let mut units = [0; 2];
+            pending.extend_from_slice(c.encode_utf16(&mut units));
+        }
+    }
+    flush(&mut pending, &mut encoded);
+    encoded
+}
+
+/// Append the pending UTF-16 units as a `&...-` base64 run
+fn flush(pending: &mut Vec<u16>, encoded: &mut String) {
+    if pending.is_empty() {
+        return;
+    }
+
+    let bytes = pending
+        .drain(..)
+        .flat_map(|unit| unit.to_be_bytes())
+        .collect::<Vec<_>>();
+    encoded.push('&');
+    for chunk in bytes.chunks(3) {
+        let n = chunk
+            .iter()
+            .enumerate()
+            .fold(0u32, |n, (i, byte)| n | (*byte as u32) << (16 - 8 * i));
+        // 1, 2 or 3 bytes make 2, 3 or 4 characters, without padding
+        for i in 0..=chunk.len() {
+            encoded.push(ALPHABET[(n >> (18 - 6 * i) & 0x3f) as usize] as char);
+        }
+    }
+    encoded.push('-');
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encode() {
+        assert_eq!(encode_utf7_imap("INBOX"), "INBOX");
+        assert_eq!(encode_utf7_imap("发票/2023"), "&U9F5aA-/2023");
+        assert_eq!(encode_utf7_imap("Tom & Jerry"), "Tom &- Jerry");
+        assert_eq!(encode_utf7_imap("日本語"), "&ZeVnLIqe-");
+    }
+
+    #[test]
+    fn round_trip() {
+        for name in ["发票/2023", "Projects/客户 A&B", "🙂 emoji"] {
+            let encoded = encode_utf7_imap(name);
+            assert!(encoded.is_ascii());
+            assert_eq!(utf7_imap::decode_utf7_imap(encoded), name);
+        }
+    }
+
+    #[test]
+    fn wire_round_trip() {
+        // as listed by the server, decoded for display then encoded back for SELECT
+        for wire in ["&U9F5aA-/2023", "&V4NXPpCuTvY-", "Tom &- Jerry", "INBOX"] {
+            let decoded = utf7_imap::decode_utf7_imap(wire.to_string());
+            assert_eq!(encode_utf7_imap(&decoded), wire);
+        }
+    }
+}
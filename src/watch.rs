@@ -0,0 +1,167 @@
+use std::{
+    io::Write,
+    process::{Command, ExitStatus, Stdio},
+    thread,
+    time::{Duration, Instant},
+};
+
+use crate::client::{Client, ClientError, Mail};
+use crate::search::SearchOptions;
+
+/// Wait for new mails matching `options` and hand them to `on_mail`, oldest first.
+///
+/// Only mails delivered after the watch started are reported, the start datetime of
+/// `options` is ignored. New mails are told apart by their UID, which the server
+/// hands out in delivery order, so that neither the clocks nor mails received in the
+/// same second can hide one.
+pub fn run<F: FnMut(Mail)>(
+    client: &Client,
+    options: &SearchOptions,
+    mut on_mail: F,
+) -> Result<(), ClientError> {
+    let mail_box = client.get(&options.mail_box)?;
+    let mut cursor = match mail_box.uid_next() {
+        Some(uid_next) => uid_next.saturating_sub(1),
+        None => mail_box.uids_after(0)?.last().copied().unwrap_or(0),
+    };
+    loop {
+        // the mails delivered while the last ones were handed over are found before
+        // idling again, as the server may not tell of them once idling
+        let exists = client.exists();
+        let uids = mail_box.uids_after(cursor)?;
+        if let Some(&last) = uids.last() {
+            let (mails, warnings) = options
+                .filter(&mail_box)
+                // the UIDs tell the new mails, whatever their date
+                .start_date(chrono::DateTime::UNIX_EPOCH.fixed_offset())
+                .window_days(0)
+                .since_uid(Some(cursor))
+                .max_uid(Some(last))
+                .reverse(true)
+                .fetch_with_warnings()?;
+            for warning in warnings {
+                eprintln!("warning: {}", warning);
+            }
+            // the mails the filters left out are done with too
+            cursor = last;
+            mails.into_iter().for_each(&mut on_mail);
+        }
+        if client.exists() == exists {
+            client.idle()?;
+        }
+    }
+}
+
+/// A shell command run for every new mail
+pub struct Hook {
+    pub command: String,
+    pub timeout: Duration,
+}
+
+impl Hook {
+    /// Run the command with the mail exposed as `QMAIL_*` environment variables and
+    /// `json` on its stdin, killing it once the timeout has passed
+    pub fn run(&self, mail: &Mail, json: &str) -> Result<ExitStatus, String> {
+        let mut command = if cfg!(windows) {
+            let mut command = Command::new("cmd");
+            command.args(["/C", &self.command]);
+            command
+        } else {
+            let mut command = Command::new("sh");
+            command.args(["-c", &self.command]);
+            command
+        };
+        let mut child = command
+            .env("QMAIL_UID", mail.uid.to_string())
+            .env("QMAIL_SUBJECT", &mail.subject)
+            .env("QMAIL_FROM", &mail.from)
+            .env("QMAIL_DATE", mail.internal_date.to_rfc3339())
+            .env(
+                "QMAIL_ATTACHMENTS",
+                mail.attachments
+                    .iter()
+                    .map(|a| a.name.as_str())
+                    .collect::<Vec<_>>()
+                    .join("\n"),
+            )
+            .stdin(Stdio::piped())
+            .spawn()
+            .map_err(|e| format!("failed to spawn `{}`: {}", self.command, e))?;
+
+        // write from another thread so a hook that doesn't read its stdin can't block us
+        let mut stdin = child.stdin.take().unwrap();
+        let json = json.to_string();
+        thread::spawn(move || {
+            let _ = stdin.write_all(json.as_bytes());
+        });
+
+        let started = Instant::now();
+        loop {
+            if let Some(status) = child.try_wait().map_err(|e| e.to_string())? {
+                return Ok(status);
+            }
+            if started.elapsed() > self.timeout {
+                let _ = child.kill();
+                let _ = child.wait();
+                return Err(format!(
+                    "`{}` timed out after {}s and was killed",
+                    self.command,
+                    self.timeout.as_secs()
+                ));
+            }
+            thread::sleep(Duration::from_millis(100));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::{fetch_response, MockServer};
+
+    #[test]
+    fn report_the_mails_after_the_last_uid() {
+        let server = MockServer::new();
+        server
+            .on(
+                "EXAMINE",
+                "* 3 EXISTS\r\n* 0 RECENT\r\n* OK [UIDNEXT 5] next\r\n",
+            )
+            .on("UID SEARCH UID 5:*", "* SEARCH 6 5\r\n")
+            .on("UID SEARCH SINCE", "* SEARCH 5 6\r\n")
+            .on(
+                "UID FETCH",
+                [
+                    // received in the same second, and before the watch started
+                    fetch_response(5, "02-May-2022 09:30:00 +0800", "Subject: a\r\n\r\n", ""),
+                    fetch_response(6, "02-May-2022 09:30:00 +0800", "Subject: b\r\n\r\n", ""),
+                ]
+                .concat(),
+            )
+            .bye("IDLE", "closing");
+        let client = server.client();
+        let options =
+            SearchOptions::between("2024-01-01T00:00:00+08:00", "9999-12-01T00:00:00+08:00");
+
+        let mut uids = vec![];
+        assert!(run(&client, &options, |mail| uids.push(mail.uid)).is_err());
+
+        assert_eq!(uids, vec![5, 6]);
+        let commands = server.commands();
+        assert!(commands
+            .iter()
+            .any(|c| c.starts_with("UID SEARCH SINCE 01-Jan-1970 ") && c.ends_with(" UID 5:6")));
+        // the mail box is opened once, not looked up again for every wakeup
+        let searched = commands
+            .iter()
+            .position(|c| c.starts_with("UID SEARCH"))
+            .unwrap();
+        assert!(
+            !commands[searched..]
+                .iter()
+                .any(|c| c.starts_with("LIST") || c.starts_with("EXAMINE")),
+            "{:?}",
+            commands
+        );
+    }
+}